@@ -0,0 +1,198 @@
+use crate::config::AssetLimits;
+use anyhow::{anyhow, bail, Context, Result};
+use image::{imageops::FilterType, DynamicImage, ImageError, ImageReader, Limits, RgbaImage};
+use log::debug;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Decodes user-supplied raster assets (logos, banners) under the configured
+/// `asset-limits`, caching decoded and scaled results for the whole run so an
+/// asset shared by several profiles is only decoded once. Safe to share
+/// between the threads rendering images.
+pub(crate) struct AssetCache {
+    limits: AssetLimits,
+    /// blake3(file bytes) → decoded image
+    decoded: Mutex<HashMap<String, Arc<DynamicImage>>>,
+    /// (blake3(file bytes), width, height) → scaled RGBA image
+    scaled: Mutex<HashMap<(String, u32, u32), Arc<RgbaImage>>>,
+}
+
+impl AssetCache {
+    pub fn new(limits: AssetLimits) -> Self {
+        Self {
+            limits,
+            decoded: Mutex::new(HashMap::new()),
+            scaled: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Read and decode `path` under the configured limits. Decoding is cached
+    /// by the blake3 hash of the file contents.
+    pub fn load(&self, path: &Path) -> Result<LoadedAsset> {
+        let bytes = read_capped(path, &self.limits)?;
+        let hash = blake3::hash(&bytes).to_hex().to_string();
+
+        let mut decoded = self.decoded.lock().unwrap_or_else(PoisonError::into_inner);
+        let image = match decoded.get(&hash) {
+            Some(img) => img.clone(),
+            None => {
+                let img = Arc::new(decode_limited(path, &bytes, &self.limits)?);
+                decoded.insert(hash.clone(), img.clone());
+                img
+            }
+        };
+        Ok(LoadedAsset { hash, image })
+    }
+
+    /// Scale a loaded asset (aspect preserved) to fit inside `box_w`×`box_h`,
+    /// cached per (asset hash, target size).
+    pub fn scaled(&self, asset: &LoadedAsset, box_w: u32, box_h: u32) -> Arc<RgbaImage> {
+        let (w, h) = (box_w.max(1), box_h.max(1));
+        let key = (asset.hash.clone(), w, h);
+        // Held while scaling, so two threads never resize the same asset twice
+        let mut cache = self.scaled.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(img) = cache.get(&key) {
+            debug!(
+                "mdbook-qr: reusing scaled asset {} ({}x{})",
+                &asset.hash[..12],
+                w,
+                h
+            );
+            return img.clone();
+        }
+
+        let src = &asset.image;
+        let scaled = if (src.width(), src.height()) == (w, h) {
+            src.to_rgba8()
+        } else {
+            debug!(
+                "mdbook-qr: scaling asset {} from {}x{} to fit {}x{}",
+                &asset.hash[..12],
+                src.width(),
+                src.height(),
+                w,
+                h
+            );
+            src.resize(w, h, FilterType::Triangle).to_rgba8()
+        };
+
+        let scaled = Arc::new(scaled);
+        cache.insert(key, scaled.clone());
+        scaled
+    }
+}
+
+/// A decoded asset plus the blake3 hash of its source file.
+pub(crate) struct LoadedAsset {
+    pub hash: String,
+    pub image: Arc<DynamicImage>,
+}
+
+/// Read an asset file, refusing files larger than `max-mb` before touching their contents.
+fn read_capped(path: &Path, limits: &AssetLimits) -> Result<Vec<u8>> {
+    let meta = fs::metadata(path).with_context(|| format!("Reading asset {}", path.display()))?;
+    if meta.len() > limits.max_bytes() {
+        bail!(
+            "asset '{}' is {} bytes, larger than asset-limits.max-mb = {}",
+            path.display(),
+            meta.len(),
+            limits.max_mb
+        );
+    }
+    fs::read(path).with_context(|| format!("Reading asset {}", path.display()))
+}
+
+fn decode_limited(path: &Path, bytes: &[u8], limits: &AssetLimits) -> Result<DynamicImage> {
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .with_context(|| format!("Reading asset {}", path.display()))?;
+
+    let mut lim = Limits::default();
+    lim.max_image_width = Some(limits.max_px);
+    lim.max_image_height = Some(limits.max_px);
+    lim.max_alloc = Some(limits.max_bytes());
+    reader.limits(lim);
+
+    reader.decode().map_err(|e| match e {
+        ImageError::Limits(_) => anyhow!(
+            "asset '{}' exceeds asset-limits (max-px = {}, max-mb = {}): {e}",
+            path.display(),
+            limits.max_px,
+            limits.max_mb
+        ),
+        other => anyhow!("could not decode asset '{}': {other}", path.display()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use image::Rgba;
+    use std::path::PathBuf;
+
+    fn png(dir: &TempDir, name: &str, w: u32, h: u32) -> PathBuf {
+        let path = dir.path().join(name);
+        RgbaImage::from_pixel(w, h, Rgba([200, 30, 30, 255]))
+            .save(&path)
+            .unwrap();
+        path
+    }
+
+    fn limits(max_px: u32, max_mb: u64) -> AssetLimits {
+        AssetLimits { max_px, max_mb }
+    }
+
+    #[test]
+    fn oversized_files_are_refused_before_reading() {
+        let dir = TempDir::new("assets-size");
+        let path = png(&dir, "logo.png", 4, 4);
+        let e = read_capped(&path, &limits(4000, 0))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.contains("logo.png") && e.contains("asset-limits.max-mb = 0"),
+            "{e}"
+        );
+        assert!(read_capped(&path, &limits(4000, 1)).is_ok());
+    }
+
+    #[test]
+    fn images_larger_than_max_px_are_refused() {
+        let dir = TempDir::new("assets-px");
+        let path = png(&dir, "banner.png", 64, 8);
+        let bytes = fs::read(&path).unwrap();
+        let e = decode_limited(&path, &bytes, &limits(32, 32))
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("banner.png") && e.contains("max-px = 32"), "{e}");
+        let image = decode_limited(&path, &bytes, &limits(64, 32)).unwrap();
+        assert_eq!((image.width(), image.height()), (64, 8));
+
+        let cache = AssetCache::new(limits(32, 32));
+        assert!(cache.load(&path).is_err());
+    }
+
+    #[test]
+    fn decodes_and_scales_are_cached_by_content() {
+        let dir = TempDir::new("assets-cache");
+        let path = png(&dir, "logo.png", 40, 20);
+        let copy = dir.path().join("copy.png");
+        fs::copy(&path, &copy).unwrap();
+        let cache = AssetCache::new(AssetLimits::default());
+
+        let first = cache.load(&path).unwrap();
+        let again = cache.load(&path).unwrap();
+        let same_bytes = cache.load(&copy).unwrap();
+        assert!(Arc::ptr_eq(&first.image, &again.image));
+        assert!(Arc::ptr_eq(&first.image, &same_bytes.image));
+
+        let small = cache.scaled(&first, 10, 10);
+        assert_eq!(small.dimensions(), (10, 5));
+        assert!(Arc::ptr_eq(&small, &cache.scaled(&again, 10, 10)));
+        assert!(!Arc::ptr_eq(&small, &cache.scaled(&first, 20, 20)));
+    }
+}
@@ -0,0 +1,321 @@
+use crate::error::QrError;
+use crate::state::{self, Artifact};
+use crate::util::write_if_changed;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// What we last wrote to a path: content hash plus the file metadata observed
+/// right after writing. If size and mtime still match, the file is unchanged
+/// and its contents need not be read again.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) struct CacheEntry {
+    pub hash: String,
+    pub size: u64,
+    pub mtime_ns: u64,
+    /// Fingerprint of the raster parameters that produced `hash`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub render: Option<String>,
+    /// Microseconds the encode behind `render` took; what reusing it saves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encode_us: Option<u64>,
+}
+
+/// Persistent record of generated files, stored as JSON next to the images.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct WriteCache {
+    #[serde(default)]
+    entries: BTreeMap<String, CacheEntry>,
+    /// Keys updated by this run; only these are merged back by [`WriteCache::save`].
+    #[serde(skip)]
+    touched: BTreeSet<String>,
+    /// Encodes skipped by [`WriteCache::reusable`] this run, and the
+    /// microseconds they took when last done.
+    #[serde(skip)]
+    saved: (usize, u64),
+    /// Paths [`WriteCache::reusable`] let skip their encode this run.
+    #[serde(skip)]
+    reused: BTreeSet<PathBuf>,
+}
+
+/// Why [`WriteCache::reusable`] lets an image skip its encode, for the log
+/// and the run summary.
+pub(crate) const REUSE_REASON: &str = "render parameters unchanged";
+
+impl Artifact for WriteCache {
+    const KIND: &'static str = "write-cache";
+
+    /// Format 1 was the bare `{ "entries": ... }` object; the fields are unchanged.
+    fn migrate(format: u32, payload: serde_json::Value) -> Option<Self> {
+        match format {
+            1 => serde_json::from_value(payload).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl WriteCache {
+    /// Load the cache, starting empty if it is missing, unreadable or from a
+    /// newer version.
+    pub fn load(path: &Path) -> Self {
+        state::load(path)
+    }
+
+    /// True if `path` still carries the metadata recorded for `hash`.
+    pub fn is_fresh(&self, path: &Path, hash: &blake3::Hash) -> bool {
+        let Some(entry) = self.entries.get(&key(path)) else {
+            return false;
+        };
+        if entry.hash != hash.to_hex().as_str() {
+            return false;
+        }
+        match file_meta(path) {
+            Some((size, mtime_ns)) => entry.size == size && entry.mtime_ns == mtime_ns,
+            None => false,
+        }
+    }
+
+    /// Record the current on-disk metadata of `path` for `hash`.
+    pub fn record(&mut self, path: &Path, hash: &blake3::Hash) {
+        let Some((size, mtime_ns)) = file_meta(path) else {
+            return;
+        };
+        let hash = hash.to_hex().to_string();
+        // The render fingerprint stays valid for as long as the content does
+        let (render, encode_us) = self
+            .entries
+            .get(&key(path))
+            .filter(|e| e.hash == hash)
+            .map_or((None, None), |e| (e.render.clone(), e.encode_us));
+        let entry = CacheEntry {
+            hash,
+            size,
+            mtime_ns,
+            render,
+            encode_us,
+        };
+        if self.entries.get(&key(path)) != Some(&entry) {
+            self.entries.insert(key(path), entry);
+            self.touched.insert(key(path));
+        }
+    }
+
+    /// Hash of `path` if it was rendered from `render` and is unchanged on disk,
+    /// so the QR need not be encoded again, with the time that encode took
+    /// when known. Counts towards [`WriteCache::savings`].
+    pub fn reusable(&mut self, path: &Path, render: &str) -> Option<(String, Option<Duration>)> {
+        let entry = self.entries.get(&key(path))?;
+        if entry.render.as_deref() != Some(render) {
+            return None;
+        }
+        let (size, mtime_ns) = file_meta(path)?;
+        if entry.size != size || entry.mtime_ns != mtime_ns {
+            return None;
+        }
+        self.saved.0 += 1;
+        self.saved.1 += entry.encode_us.unwrap_or(0);
+        self.reused.insert(path.to_path_buf());
+        Some((
+            entry.hash.clone(),
+            entry.encode_us.map(Duration::from_micros),
+        ))
+    }
+
+    /// Attach the render fingerprint to the entry last recorded for `path`,
+    /// with the time its encode took; `None` keeps the time already recorded
+    /// for the same fingerprint.
+    pub fn record_render(&mut self, path: &Path, render: &str, encode: Option<Duration>) {
+        let Some(entry) = self.entries.get_mut(&key(path)) else {
+            return;
+        };
+        let same = entry.render.as_deref() == Some(render);
+        let encode_us = match encode {
+            Some(d) => Some(u64::try_from(d.as_micros()).unwrap_or(u64::MAX)),
+            None if same => entry.encode_us,
+            None => None,
+        };
+        if !same || entry.encode_us != encode_us {
+            entry.render = Some(render.to_string());
+            entry.encode_us = encode_us;
+            self.touched.insert(key(path));
+        }
+    }
+
+    /// Encodes skipped this run, and roughly how long they would have taken.
+    pub fn savings(&self) -> (usize, Duration) {
+        (self.saved.0, Duration::from_micros(self.saved.1))
+    }
+
+    /// Paths whose encode was skipped this run, in order.
+    pub fn reused(&self) -> impl Iterator<Item = &Path> {
+        self.reused.iter().map(PathBuf::as_path)
+    }
+
+    /// The hash recorded for `path`, if any.
+    pub fn recorded(&self, path: &Path) -> Option<&str> {
+        self.entries.get(&key(path)).map(|e| e.hash.as_str())
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        !self.touched.is_empty()
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        state::encode(self)
+    }
+
+    /// Merge this run's updates into the cache at `path` and write it
+    /// atomically. The read-merge-write runs under an advisory lock on
+    /// `<path>.lock`, so builds sharing the file never drop each other's entries.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(QrError::io(parent))?;
+        }
+        let lock_path = path.with_extension("json.lock");
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .map_err(QrError::io(&lock_path))?;
+        lock.lock().map_err(QrError::io(&lock_path))?;
+
+        let mut merged = Self::load(path);
+        for k in &self.touched {
+            if let Some(entry) = self.entries.get(k) {
+                merged.entries.insert(k.clone(), entry.clone());
+            }
+        }
+        let bytes = merged.to_json()?;
+        write_if_changed(path, &bytes, &blake3::hash(&bytes), None)?;
+        // Dropping `lock` releases it
+        Ok(())
+    }
+}
+
+fn key(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+fn file_meta(path: &Path) -> Option<(u64, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((meta.len(), u64::try_from(mtime.as_nanos()).ok()?))
+}
+
+/// Location of the write cache: `<root>/<base>/qr/.mdbook-qr-cache-<key>.json`,
+/// where `base` is the directory images are generated under (see
+/// `output-base`) and `key` is derived from the renderer, book language and
+/// `src` path so parallel builds of different variants never share a file.
+pub(crate) fn cache_path(
+    root: &Path,
+    base: &Path,
+    src_dir: &Path,
+    renderer: &str,
+    language: Option<&str>,
+) -> PathBuf {
+    root.join(base).join("qr").join(format!(
+        ".mdbook-qr-cache-{}.json",
+        variant_key(src_dir, renderer, language)
+    ))
+}
+
+/// Location of the generated-files manifest, keyed like [`cache_path`]:
+/// `<root>/<base>/qr/.mdbook-qr-manifest-<key>.json`.
+pub(crate) fn manifest_path(
+    root: &Path,
+    base: &Path,
+    src_dir: &Path,
+    renderer: &str,
+    language: Option<&str>,
+) -> PathBuf {
+    root.join(base).join("qr").join(format!(
+        ".mdbook-qr-manifest-{}.json",
+        variant_key(src_dir, renderer, language)
+    ))
+}
+
+/// 12 hex digits identifying one build variant of the book.
+fn variant_key(src_dir: &Path, renderer: &str, language: Option<&str>) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for part in [
+        renderer,
+        language.unwrap_or(""),
+        &src_dir.to_string_lossy().replace('\\', "/"),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\0");
+    }
+    hasher.finalize().to_hex()[..12].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::thread;
+
+    const THREADS: usize = 8;
+    const WRITES: usize = 20;
+
+    fn path_for(root: &Path, language: Option<&str>) -> PathBuf {
+        cache_path(root, Path::new("src"), Path::new("src"), "html", language)
+    }
+
+    #[test]
+    fn variants_get_their_own_files() {
+        let root = Path::new("book");
+        let shared = path_for(root, None);
+        assert_ne!(shared, path_for(root, Some("fr")));
+        assert_ne!(
+            shared,
+            cache_path(root, Path::new("src"), Path::new("src"), "markdown", None)
+        );
+        assert_ne!(
+            shared,
+            cache_path(root, Path::new("src"), Path::new("doc"), "html", None)
+        );
+        assert_eq!(shared, path_for(root, None));
+    }
+
+    #[test]
+    fn concurrent_saves_neither_corrupt_nor_lose_entries() {
+        let dir = TempDir::new("cache-stress");
+        let shared = path_for(dir.path(), None);
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let (dir, shared) = (dir.path(), &shared);
+                s.spawn(move || {
+                    let own = path_for(dir, Some(&format!("l{t}")));
+                    for i in 0..WRITES {
+                        let img = dir.join(format!("img-{t}-{i}.png"));
+                        let bytes = format!("{t}-{i}");
+                        crate::util::write_atomic(&img, bytes.as_bytes()).unwrap();
+                        for path in [shared, &own] {
+                            let mut cache = WriteCache::load(path);
+                            cache.record(&img, &blake3::hash(bytes.as_bytes()));
+                            cache.save(path).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(WriteCache::load(&shared).entries.len(), THREADS * WRITES);
+        for t in 0..THREADS {
+            let own = path_for(dir.path(), Some(&format!("l{t}")));
+            let bytes = fs::read(&own).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap();
+            assert_eq!(WriteCache::load(&own).entries.len(), WRITES);
+        }
+        let leftovers: Vec<_> = fs::read_dir(shared.parent().unwrap())
+            .unwrap()
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .filter(|name| name.ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty(), "{leftovers:?}");
+    }
+}
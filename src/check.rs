@@ -0,0 +1,172 @@
+use crate::config::{Profile, QrConfig};
+use crate::html::count_markers_outside_code;
+use crate::preprocessor::build_profiles;
+use crate::url::{
+    check_url, cname_domain, resolve_url_with_source, site_url_fallback, DEFAULT_LOCALHOST_PORT,
+};
+use crate::util::{chapter_glob_set, in_scope, localhost_fixed_path, resolve_profile_path};
+use anyhow::{Context, Result};
+use mdbook::book::{load_book, BookItem};
+use mdbook::Config;
+use std::path::{Path, PathBuf};
+
+/// One profile as a build would see it.
+pub struct CheckRow {
+    pub profile: String,
+    pub marker: String,
+    pub enabled: bool,
+    /// The encoded URL and the setting it came from, or why there is none.
+    pub url: std::result::Result<(String, &'static str), String>,
+    /// Chapters using the marker outside code.
+    pub chapters: Vec<PathBuf>,
+    /// Image path, relative to the book root.
+    pub output: PathBuf,
+}
+
+/// Outcome of `mdbook-qr check`.
+pub struct CheckReport {
+    pub rows: Vec<CheckRow>,
+    /// Everything that fails the check.
+    pub problems: Vec<String>,
+}
+
+/// Validate the `[preprocessor.qr]` configuration of the book in `dir`
+/// without rendering: unknown keys, customs without a marker, unresolvable
+/// URLs and markers no chapter uses (unless `allow_unused`). Only an
+/// unreadable book is an `Err`; everything else lands in the report.
+pub fn check(dir: &Path, allow_unused: bool) -> Result<CheckReport> {
+    let book_toml = dir.join("book.toml");
+    let mut config = Config::from_disk(&book_toml)
+        .with_context(|| format!("reading {}", book_toml.display()))?;
+    config.update_from_env();
+    // A check never creates files, even for chapters missing from disk
+    config.build.create_missing = false;
+
+    let mut report = CheckReport {
+        rows: Vec::new(),
+        problems: Vec::new(),
+    };
+    let Some(table) = config.get_preprocessor("qr") else {
+        report.problems.push(format!(
+            "{} has no [preprocessor.qr] table",
+            book_toml.display()
+        ));
+        return Ok(report);
+    };
+    let (cfg, unknown) = match QrConfig::from_table_reporting(table) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            report
+                .problems
+                .push(format!("[preprocessor.qr] is invalid: {e:#}"));
+            return Ok(report);
+        }
+    };
+    for key in unknown {
+        report
+            .problems
+            .push(format!("unknown key preprocessor.qr.{key}"));
+    }
+    for (name, custom) in &cfg.custom {
+        if custom.primary_marker().is_none() {
+            report
+                .problems
+                .push(format!("[preprocessor.qr.custom.{name}] has no `marker`"));
+        }
+    }
+
+    let src_dir = config.book.src.clone();
+    let book = load_book(dir.join(&src_dir), &config.build)
+        .with_context(|| format!("loading the book in {}", dir.display()))?;
+    let scope = chapter_glob_set("chapters", &cfg.chapters);
+    let site_url = site_url_fallback(config.get("output.html.site-url").and_then(|v| v.as_str()));
+    let cname = cname_domain(dir, &src_dir);
+
+    for (key, mut profile) in build_profiles(&cfg) {
+        let marker = profile.primary_marker().unwrap_or_default().to_string();
+        let aliases: Vec<String> = profile.markers().into_iter().map(str::to_string).collect();
+        let alias_refs: Vec<&str> = aliases.iter().map(String::as_str).collect();
+        let enabled = profile.is_enabled();
+        // Per-renderer URLs: the HTML build's
+        let url = profile_url(&mut profile, &cfg, "html", site_url, cname.as_deref());
+        let filter = profile.chapter_filter();
+        let chapters: Vec<PathBuf> = book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(ch) if in_scope(&scope, ch) && filter.matches(ch) => Some(ch),
+                _ => None,
+            })
+            .filter(|ch| count_markers_outside_code(&ch.content, &alias_refs, cfg.syntax()) > 0)
+            .filter_map(|ch| ch.path.clone())
+            .collect();
+        let output = profile_output(&profile, &cfg, &src_dir);
+
+        if enabled {
+            if let Err(e) = &url {
+                report.problems.push(format!("'{marker}': {e}"));
+            }
+            if let Err(e) = profile.check_html_template() {
+                report.problems.push(format!("'{marker}': {e:#}"));
+            }
+            if chapters.is_empty() && !allow_unused {
+                report
+                    .problems
+                    .push(format!("'{marker}' appears in no chapter"));
+            }
+        }
+        report.rows.push(CheckRow {
+            profile: key,
+            marker,
+            enabled,
+            url,
+            chapters,
+            output,
+        });
+    }
+    Ok(report)
+}
+
+/// What `profile` encodes for `renderer`, and the setting it came from; a
+/// payload profile gets a placeholder. `Err` says why there is no URL.
+pub(crate) fn profile_url(
+    profile: &mut Profile,
+    cfg: &QrConfig,
+    renderer: &str,
+    site_url: Option<&str>,
+    cname: Option<&str>,
+) -> std::result::Result<(String, &'static str), String> {
+    match profile.expand_env() {
+        Err(e) => Err(format!("{e:#}")),
+        Ok(()) if profile.wifi.is_some() => Ok(("(Wi-Fi network)".to_string(), "wifi")),
+        Ok(()) if profile.contact.is_some() => Ok(("(vCard)".to_string(), "contact")),
+        Ok(()) if profile.data.is_some() => Ok(("(text)".to_string(), "data")),
+        Ok(()) => resolve_url_with_source(
+            profile.url.as_ref().and_then(|u| u.for_renderer(renderer)),
+            site_url,
+            cname,
+            profile.localhost_qr.unwrap_or(false),
+            profile.localhost_port.unwrap_or(DEFAULT_LOCALHOST_PORT),
+        )
+        // Templates are only complete per chapter, at build time
+        .and_then(|(u, source)| {
+            if !u.contains('{') {
+                check_url(&u, cfg.normalize_url, false)?;
+            }
+            Ok((u, source))
+        })
+        .map_err(|e| e.to_string()),
+    }
+}
+
+/// Image path of `profile`, relative to the book root.
+pub(crate) fn profile_output(profile: &Profile, cfg: &QrConfig, src_dir: &Path) -> PathBuf {
+    if profile.localhost_qr.unwrap_or(false) {
+        localhost_fixed_path(&cfg.output_dir(src_dir))
+    } else {
+        resolve_profile_path(
+            &cfg.output_dir(src_dir),
+            profile.qr_path.as_deref(),
+            profile.primary_marker().unwrap_or_default(),
+        )
+    }
+}
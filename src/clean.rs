@@ -0,0 +1,168 @@
+use crate::cache::WriteCache;
+use crate::check::profile_output;
+use crate::config::QrConfig;
+use crate::error::QrError;
+use crate::list::load_config;
+use crate::manifest::Manifest;
+use crate::preprocessor::build_profiles;
+use crate::util::{hash_file, remove_gitignore_for_localhost};
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Outcome of `mdbook-qr clean`.
+pub struct CleanReport {
+    /// Files deleted, or that would be under `dry_run`.
+    pub removed: Vec<PathBuf>,
+    /// Candidates left in place, and why.
+    pub kept: Vec<(PathBuf, &'static str)>,
+    /// Whether the `localhost-qr` glob was (or would be) dropped from `.gitignore`.
+    pub gitignore: bool,
+}
+
+/// Remove what the preprocessor wrote to the book in `dir`: the images and
+/// sidecars listed in its manifests, then the manifests and write caches,
+/// then `qr/` and `mdbook_qr/` directories left empty. Without a manifest,
+/// the image paths are derived from the config and only removed when a
+/// write cache vouches for their contents. A file that changed since it was
+/// written is never removed. `gitignore` also drops the `localhost-qr` glob.
+pub fn clean(dir: &Path, gitignore: bool, dry_run: bool) -> Result<CleanReport> {
+    // Write caches key files by the absolute paths a build used
+    let dir = &std::path::absolute(dir).map_err(QrError::io(dir))?;
+    let (config, cfg) = load_config(dir)?;
+    // Cleaning up after the preprocessor was removed from book.toml works too
+    let cfg = cfg.unwrap_or_default();
+    let src_dir = config.book.src.clone();
+    let out_dir = cfg.output_dir(&src_dir);
+    let state_dir = dir.join(&out_dir).join("qr");
+
+    let mut report = CleanReport {
+        removed: Vec::new(),
+        kept: Vec::new(),
+        gitignore: false,
+    };
+    let state_files = state_files(&state_dir);
+    let manifests: Vec<&PathBuf> = state_files
+        .iter()
+        .filter(|p| has_prefix(p, ".mdbook-qr-manifest-"))
+        .collect();
+
+    let mut candidates: Vec<(PathBuf, bool)> = Vec::new();
+    if manifests.is_empty() {
+        let caches: Vec<WriteCache> = state_files
+            .iter()
+            .filter(|p| has_prefix(p, ".mdbook-qr-cache-") && !is_lock(p))
+            .map(|p| WriteCache::load(p))
+            .collect();
+        for path in derived_paths(&cfg, dir, &src_dir) {
+            let Ok(hash) = hash_file(&path) else {
+                continue;
+            };
+            let vouched = caches
+                .iter()
+                .any(|c| c.recorded(&path) == Some(hash.to_hex().as_str()));
+            candidates.push((path, vouched));
+        }
+    } else {
+        for manifest in &manifests {
+            candidates.extend(Manifest::load(manifest).on_disk(dir));
+        }
+    }
+    candidates.sort();
+    candidates.dedup();
+
+    let mut parents: Vec<PathBuf> = Vec::new();
+    for (path, owned) in candidates {
+        if !owned {
+            let reason = if manifests.is_empty() {
+                "not recorded by mdbook-qr"
+            } else {
+                "changed since mdbook-qr wrote it"
+            };
+            report.kept.push((path, reason));
+            continue;
+        }
+        remove(&path, dry_run)?;
+        parents.extend(path.parent().map(Path::to_path_buf));
+        report.removed.push(path);
+    }
+    // Bookkeeping goes last, so an interrupted clean can be run again
+    for path in state_files {
+        remove(&path, dry_run)?;
+        report.removed.push(path);
+    }
+
+    if !dry_run {
+        parents.push(state_dir);
+        parents.sort();
+        parents.dedup();
+        for parent in parents.iter().rev() {
+            remove_empty_dirs(parent, &dir.join(&out_dir));
+        }
+    }
+    if gitignore {
+        report.gitignore = remove_gitignore_for_localhost(dir, &out_dir, dry_run)?;
+    }
+    Ok(report)
+}
+
+/// Manifests and write caches (with their lock files, and the unkeyed
+/// cache of older versions) in `state_dir`.
+fn state_files(state_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(state_dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file()
+                && (has_prefix(p, ".mdbook-qr-manifest-") || has_prefix(p, ".mdbook-qr-cache"))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+fn has_prefix(path: &Path, prefix: &str) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with(prefix))
+}
+
+fn is_lock(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "lock")
+}
+
+/// Image paths the current config writes, under `root`.
+fn derived_paths(cfg: &QrConfig, root: &Path, src_dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = build_profiles(cfg)
+        .iter()
+        .map(|(_, p)| root.join(profile_output(p, cfg, src_dir)))
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+fn remove(path: &Path, dry_run: bool) -> Result<()> {
+    if !dry_run {
+        fs::remove_file(path).map_err(QrError::io(path))?;
+    }
+    Ok(())
+}
+
+/// Remove `dir` and its parents while they are empty, up to but not
+/// including `stop`.
+fn remove_empty_dirs(dir: &Path, stop: &Path) {
+    let mut dir = dir;
+    while dir != stop && dir.starts_with(stop) {
+        // Fails, harmlessly, on a directory that is not empty
+        if fs::remove_dir(dir).is_err() {
+            return;
+        }
+        let Some(parent) = dir.parent() else {
+            return;
+        };
+        dir = parent;
+    }
+}
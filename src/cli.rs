@@ -0,0 +1,437 @@
+use crate::config::{shape_from_name, ColorCfg, Ecl, MarginCfg, Profile, QrConfig, ShapeCfg};
+use crate::preprocessor::{ENV_DISABLE, ENV_DRY_RUN, ENV_FORCE_CONTINUE};
+use anyhow::{anyhow, bail, Result};
+use clap::{arg, value_parser, ArgMatches, Command};
+use clap_complete::Shell;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The single clap definition shared by the binary, completions and the man page.
+pub fn build_cli() -> Command {
+    Command::new("mdbook-qr")
+        .about("An mdBook preprocessor that injects QR codes into pages")
+        .version(env!("CARGO_PKG_VERSION"))
+        .arg(
+            arg!(--"no-cache" "Ignore the persistent write cache and compare outputs in full")
+                .global(true),
+        )
+        .subcommand(
+            Command::new("supports")
+                .about("Check if a renderer is supported")
+                .arg(arg!(<renderer> "Renderer name")),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Print a shell completion script to stdout")
+                .arg(arg!(<shell> "Target shell").value_parser(value_parser!(Shell))),
+        )
+        .subcommand(Command::new("man").about("Print the roff man page to stdout"))
+        .subcommand(
+            Command::new("info")
+                .about("Print version and the environment variables mdbook-qr reads"),
+        )
+        .subcommand(
+            Command::new("audit")
+                .about("Check generated images against their .provenance.json sidecars")
+                .arg(arg!([dir] "Built book (or source) directory to scan").default_value("book"))
+                .arg(
+                    arg!(--"verify-signatures" "Also verify ed25519 signatures (needs the `sign` feature)")
+                        .requires("pubkey"),
+                )
+                .arg(arg!(--pubkey <path> "ed25519 public key: 32 raw bytes or 64 hex characters")),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Validate [preprocessor.qr] and marker usage without building")
+                .arg(arg!([dir] "Book directory containing book.toml").default_value("."))
+                .arg(arg!(--"allow-unused" "Do not fail on markers no chapter uses")),
+        )
+        .subcommand(
+            Command::new("clean")
+                .about("Remove the images and bookkeeping files mdbook-qr generated")
+                .arg(arg!([dir] "Book directory containing book.toml").default_value("."))
+                .arg(arg!(--gitignore "Also drop the localhost-qr glob from .gitignore"))
+                .arg(arg!(--"dry-run" "Print what would be removed without removing it")),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("Print each profile as a build would use it, after inheritance")
+                .arg(arg!([dir] "Book directory containing book.toml").default_value("."))
+                .arg(arg!(--renderer <name> "Renderer whose overrides and URLs apply").default_value("html"))
+                .arg(arg!(--json "Print the profiles as JSON")),
+        )
+        .subcommand(
+            Command::new("render")
+                .about("Write a single QR PNG, as the preprocessor would, without a book")
+                .arg(arg!(<url> "URL or text to encode"))
+                .arg(arg!(--out <path> "PNG to write").required(true))
+                .arg(arg!(--"fit-width" <px> "Image width").value_parser(value_parser!(u32)))
+                .arg(arg!(--"fit-height" <px> "Image height").value_parser(value_parser!(u32)))
+                .arg(arg!(--scale <px> "Pixels per module; overrides the fit size").value_parser(value_parser!(u32)))
+                .arg(arg!(--margin <modules> "Quiet zone (default 2)").value_parser(value_parser!(u32)))
+                .arg(arg!(--shape <name> "Module shape: square, circle, rounded-square, ..."))
+                .arg(arg!(--module <color> "Module color: hex, CSS name, rgb() or hsl()"))
+                .arg(arg!(--background <color> "Background color, as --module"))
+                .arg(arg!(--ecl <level> "Error correction: L, M, Q or H"))
+                .arg(arg!(--verify "Decode the written PNG and fail if it does not read back")),
+        )
+        .subcommand(
+            Command::new("install")
+                .about("Add a [preprocessor.qr] table to book.toml")
+                .arg(arg!([dir] "Book directory containing book.toml").default_value("."))
+                .arg(arg!(--marker <name> "Also add [preprocessor.qr.custom.<name>]")),
+        )
+}
+
+/// Environment variables that change the preprocessor's behaviour.
+pub const ENV_VARS: &[(&str, &str)] = &[
+    (
+        ENV_DISABLE,
+        "=1 passes the book through unchanged; no files are read or written",
+    ),
+    (
+        ENV_FORCE_CONTINUE,
+        "=1 treats on-failure = \"bail\" as \"continue\" for the run",
+    ),
+    (
+        ENV_DRY_RUN,
+        "=1 logs the images and replacements planned; nothing is written",
+    ),
+    (
+        "GITHUB_REPOSITORY",
+        "owner/repo; fallback URL https://{owner}.github.io/{repo}",
+    ),
+    (
+        "GITHUB_SERVER_URL",
+        "GitHub Enterprise host; fallback URL https://{owner}.pages.{host}/{repo}",
+    ),
+    (
+        "CI_PAGES_URL",
+        "GitLab Pages URL, used verbatim as a fallback",
+    ),
+    (
+        "CI_PROJECT_PATH",
+        "GitLab group/project; with CI_PAGES_DOMAIN or CI_SERVER_HOST=gitlab.com",
+    ),
+    ("RUST_LOG", "log filter (default: warn,mdbook_qr=debug)"),
+];
+
+/// Whether mdBook should run the preprocessor for `renderer`, going by the
+/// `renderers` and `skip-non-html` settings of the book in `dir` (mdBook
+/// runs preprocessors from the book root). A book without a readable
+/// `[preprocessor.qr]` table supports every renderer; a broken one is
+/// reported by the run itself.
+pub fn supports(dir: &Path, renderer: &str) -> bool {
+    match crate::list::load_config(dir) {
+        Ok((_, Some(cfg))) => cfg.runs_for(renderer),
+        _ => true,
+    }
+}
+
+/// Write the `info` report to `out`.
+pub fn write_info(out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "mdbook-qr {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(out)?;
+    writeln!(out, "Environment:")?;
+    for (name, what) in ENV_VARS {
+        let state = std::env::var(name).map_or_else(|_| "unset".to_string(), |v| format!("= {v}"));
+        writeln!(out, "  {name:<26} {what} [{state}]")?;
+    }
+    Ok(())
+}
+
+/// Audit `dir` and write one line per sidecar to `out`. Returns `true` if
+/// every asset checks out.
+pub fn write_audit(dir: &Path, pubkey: Option<&Path>, out: &mut dyn Write) -> Result<bool> {
+    let entries = crate::provenance::audit(dir, pubkey)?;
+    let mut failed = 0;
+    for entry in &entries {
+        match &entry.problem {
+            None => writeln!(out, "ok    {}", entry.sidecar.display())?,
+            Some(problem) => {
+                failed += 1;
+                writeln!(out, "FAIL  {}: {problem}", entry.sidecar.display())?;
+            }
+        }
+    }
+    writeln!(
+        out,
+        "{} checked, {failed} failed{}",
+        entries.len(),
+        if pubkey.is_some() {
+            " (signatures verified)"
+        } else {
+            ""
+        }
+    )?;
+    Ok(failed == 0)
+}
+
+/// Run the `audit` subcommand from its parsed arguments.
+pub fn run_audit(sub: &ArgMatches) -> Result<bool> {
+    let dir = sub
+        .get_one::<String>("dir")
+        .map(String::as_str)
+        .unwrap_or("book");
+    let pubkey = sub
+        .get_flag("verify-signatures")
+        .then(|| sub.get_one::<String>("pubkey"))
+        .flatten()
+        .map(Path::new);
+    write_audit(Path::new(dir), pubkey, &mut io::stdout())
+}
+
+/// Check the book in `dir` and write a marker → chapters → output table, then
+/// any problems, to `out`. Returns `true` if there are none.
+pub fn write_check(dir: &Path, allow_unused: bool, out: &mut dyn Write) -> Result<bool> {
+    let report = crate::check::check(dir, allow_unused)?;
+    for row in &report.rows {
+        let chapters = if row.chapters.is_empty() {
+            "-".to_string()
+        } else {
+            row.chapters
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let url = match &row.url {
+            Ok((url, source)) => format!("{url} (from {source})"),
+            Err(_) => "unresolved".to_string(),
+        };
+        writeln!(
+            out,
+            "{:<20} {:<9} {} -> {} [{url}]{}",
+            row.marker,
+            row.profile,
+            chapters,
+            row.output.display(),
+            if row.enabled { "" } else { " (disabled)" }
+        )?;
+    }
+    for problem in &report.problems {
+        writeln!(out, "error: {problem}")?;
+    }
+    writeln!(
+        out,
+        "{} profile(s), {} problem(s)",
+        report.rows.len(),
+        report.problems.len()
+    )?;
+    Ok(report.problems.is_empty())
+}
+
+/// Run the `check` subcommand from its parsed arguments.
+pub fn run_check(sub: &ArgMatches) -> Result<bool> {
+    let dir = sub
+        .get_one::<String>("dir")
+        .map(String::as_str)
+        .unwrap_or(".");
+    write_check(
+        Path::new(dir),
+        sub.get_flag("allow-unused"),
+        &mut io::stdout(),
+    )
+}
+
+/// Clean the book in `dir` and write what was (or, with `dry_run`, would
+/// be) removed and what was kept to `out`.
+pub fn write_clean(dir: &Path, gitignore: bool, dry_run: bool, out: &mut dyn Write) -> Result<()> {
+    let report = crate::clean::clean(dir, gitignore, dry_run)?;
+    let verb = if dry_run { "would remove" } else { "removed" };
+    for path in &report.removed {
+        writeln!(out, "{verb} {}", path.display())?;
+    }
+    for (path, reason) in &report.kept {
+        writeln!(out, "kept {}: {reason}", path.display())?;
+    }
+    if report.gitignore {
+        writeln!(out, "{verb} the localhost-qr entry from .gitignore")?;
+    }
+    writeln!(
+        out,
+        "{} file(s) {verb}, {} kept",
+        report.removed.len(),
+        report.kept.len()
+    )?;
+    Ok(())
+}
+
+/// Run the `clean` subcommand from its parsed arguments.
+pub fn run_clean(sub: &ArgMatches) -> Result<()> {
+    let dir = sub
+        .get_one::<String>("dir")
+        .map(String::as_str)
+        .unwrap_or(".");
+    write_clean(
+        Path::new(dir),
+        sub.get_flag("gitignore"),
+        sub.get_flag("dry-run"),
+        &mut io::stdout(),
+    )
+}
+
+/// List the effective profiles of the book in `dir` for `renderer` to `out`,
+/// as text or, with `json`, as a JSON array.
+pub fn write_list(dir: &Path, renderer: &str, json: bool, out: &mut dyn Write) -> Result<()> {
+    let rows = crate::list::list(dir, renderer)?;
+    if json {
+        serde_json::to_writer_pretty(&mut *out, &rows)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    for row in &rows {
+        writeln!(
+            out,
+            "{} {}{}",
+            row.profile,
+            row.markers.join(", "),
+            if row.enabled { "" } else { " (disabled)" }
+        )?;
+        match &row.url {
+            Some(url) => writeln!(out, "  url         {url} (from {})", row.url_source)?,
+            None => writeln!(out, "  url         unresolved: {}", row.url_source)?,
+        }
+        writeln!(out, "  output      {}", row.output.display())?;
+        let px = |v: Option<u32>| v.map_or("-".to_string(), |v| v.to_string());
+        writeln!(
+            out,
+            "  fit         {}x{}",
+            px(row.fit.width),
+            px(row.fit.height)
+        )?;
+        let [top, right, bottom, left] = row.margin;
+        writeln!(out, "  margin      {top} {right} {bottom} {left}")?;
+        writeln!(out, "  shape       {}", row.shape)?;
+        writeln!(out, "  colors      {} on {}", row.module, row.background)?;
+    }
+    writeln!(out, "{} profile(s)", rows.len())?;
+    Ok(())
+}
+
+/// Run the `list` subcommand from its parsed arguments.
+pub fn run_list(sub: &ArgMatches) -> Result<()> {
+    let dir = sub
+        .get_one::<String>("dir")
+        .map(String::as_str)
+        .unwrap_or(".");
+    let renderer = sub
+        .get_one::<String>("renderer")
+        .map(String::as_str)
+        .unwrap_or("html");
+    write_list(
+        Path::new(dir),
+        renderer,
+        sub.get_flag("json"),
+        &mut io::stdout(),
+    )
+}
+
+/// The default profile with the `render` flags applied. Values are checked
+/// strictly: a typo is an error here rather than a logged fallback.
+fn render_profile(sub: &ArgMatches) -> Result<Profile> {
+    let mut profile = QrConfig::default().default_profile();
+    profile.fit.width = sub.get_one::<u32>("fit-width").copied();
+    profile.fit.height = sub.get_one::<u32>("fit-height").copied();
+    profile.scale = sub.get_one::<u32>("scale").copied();
+    if profile.scale == Some(0) {
+        bail!("--scale must be at least 1");
+    }
+    if let Some(margin) = sub.get_one::<u32>("margin") {
+        profile.margin = Some(MarginCfg::Uniform(i64::from(*margin)));
+    }
+    if let Some(shape) = sub.get_one::<String>("shape") {
+        if shape_from_name(shape).is_none() {
+            bail!("unknown shape '{shape}'");
+        }
+        profile.shape = Some(ShapeCfg::Name(shape.clone()));
+    }
+    for (flag, slot) in [
+        ("module", &mut profile.module),
+        ("background", &mut profile.background),
+    ] {
+        if let Some(value) = sub.get_one::<String>(flag) {
+            let color = ColorCfg::Hex(value.clone());
+            if let Err(e) = color.to_rgba() {
+                bail!("--{flag}: {e}");
+            }
+            *slot = Some(color);
+        }
+    }
+    if let Some(level) = sub.get_one::<String>("ecl") {
+        profile.ecl = Some(
+            Ecl::from_str_loose(level)
+                .ok_or_else(|| anyhow!("unknown ecl '{level}'; use L, M, Q or H"))?,
+        );
+    }
+    if sub.get_flag("verify") {
+        profile.verify = Some(true);
+    }
+    Ok(profile)
+}
+
+/// Render `url` to `out` and write the path and content hash to `out_stream`.
+pub fn write_render(sub: &ArgMatches, out_stream: &mut dyn Write) -> Result<()> {
+    let url = sub.get_one::<String>("url").expect("required argument");
+    let out = sub.get_one::<String>("out").expect("required argument");
+    let mut profile = render_profile(sub)?;
+    profile.qr_path = Some(out.clone());
+    let qr = crate::generate(&profile, url, Path::new(""))?;
+    writeln!(out_stream, "{} {}", qr.path.display(), qr.hash)?;
+    Ok(())
+}
+
+/// Run the `render` subcommand from its parsed arguments.
+pub fn run_render(sub: &ArgMatches) -> Result<()> {
+    write_render(sub, &mut io::stdout())
+}
+
+/// Scaffold `book.toml` in `dir` and write what changed to `out`.
+pub fn write_install(dir: &Path, custom: Option<&str>, out: &mut dyn Write) -> Result<()> {
+    let report = crate::install::install(dir, custom)?;
+    for change in &report.changes {
+        writeln!(out, "{change}")?;
+    }
+    if report.modified {
+        writeln!(out, "updated {}", report.path.display())?;
+    } else {
+        writeln!(out, "{} unchanged", report.path.display())?;
+    }
+    Ok(())
+}
+
+/// Run the `install` subcommand from its parsed arguments.
+pub fn run_install(sub: &ArgMatches) -> Result<()> {
+    let dir = sub
+        .get_one::<String>("dir")
+        .map(String::as_str)
+        .unwrap_or(".");
+    let custom = sub.get_one::<String>("marker").map(String::as_str);
+    write_install(Path::new(dir), custom, &mut io::stdout())
+}
+
+/// Write the completion script for `shell` to `out`.
+pub fn write_completions(shell: Shell, out: &mut dyn Write) {
+    let mut cmd = build_cli();
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, name, out);
+}
+
+/// Write the roff man page to `out`.
+pub fn write_man(out: &mut dyn Write) -> Result<()> {
+    clap_mangen::Man::new(build_cli()).render(out)?;
+    Ok(())
+}
+
+pub fn print_completions(shell: Shell) {
+    write_completions(shell, &mut io::stdout());
+}
+
+pub fn print_man() -> Result<()> {
+    write_man(&mut io::stdout())
+}
+
+pub fn print_info() -> Result<()> {
+    write_info(&mut io::stdout())?;
+    Ok(())
+}
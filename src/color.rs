@@ -0,0 +1,330 @@
+/// Parse a CSS color: `#RGB`, `#RGBA`, `#RRGGBB`, `#RRGGBBAA` (leading `#`
+/// optional), a named color, `rgb()`/`rgba()` or `hsl()`/`hsla()`. Functional
+/// forms take commas or spaces, with an optional `/ alpha`. Case-insensitive.
+pub(crate) fn parse_css_color(s: &str) -> Option<[u8; 4]> {
+    let s = s.trim().to_ascii_lowercase();
+    if let Some((name, args)) = s.strip_suffix(')').and_then(|f| f.split_once('(')) {
+        let args = split_args(args)?;
+        return match name.trim() {
+            "rgb" | "rgba" => parse_rgb(&args),
+            "hsl" | "hsla" => parse_hsl(&args),
+            _ => None,
+        };
+    }
+    if let Ok(i) = NAMED.binary_search_by_key(&s.as_str(), |&(name, _)| name) {
+        return Some(NAMED[i].1);
+    }
+    parse_hex(&s)
+}
+
+fn parse_hex(s: &str) -> Option<[u8; 4]> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let nibble = |i: usize| u8::from_str_radix(&hex[i..=i], 16).ok().map(|v| v * 17);
+    let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    match hex.len() {
+        3 => Some([nibble(0)?, nibble(1)?, nibble(2)?, 255]),
+        4 => Some([nibble(0)?, nibble(1)?, nibble(2)?, nibble(3)?]),
+        6 => Some([byte(0)?, byte(2)?, byte(4)?, 255]),
+        8 => Some([byte(0)?, byte(2)?, byte(4)?, byte(6)?]),
+        _ => None,
+    }
+}
+
+/// `a, b, c[, d]` or `a b c[ / d]` as 3 or 4 tokens.
+fn split_args(args: &str) -> Option<Vec<&str>> {
+    let tokens: Vec<&str> = if args.contains(',') {
+        args.split(',').map(str::trim).collect()
+    } else {
+        let (channels, alpha) = match args.split_once('/') {
+            Some((c, a)) => (c, Some(a.trim())),
+            None => (args, None),
+        };
+        channels.split_whitespace().chain(alpha).collect()
+    };
+    (matches!(tokens.len(), 3 | 4) && tokens.iter().all(|t| !t.is_empty())).then_some(tokens)
+}
+
+fn parse_rgb(args: &[&str]) -> Option<[u8; 4]> {
+    let channel = |t: &str| match t.strip_suffix('%') {
+        Some(p) => percent(p).map(|f| f * 255.0),
+        None => number(t).filter(|v| (0.0..=255.0).contains(v)),
+    };
+    Some([
+        channel(args[0])?.round() as u8,
+        channel(args[1])?.round() as u8,
+        channel(args[2])?.round() as u8,
+        alpha(args.get(3).copied())?,
+    ])
+}
+
+fn parse_hsl(args: &[&str]) -> Option<[u8; 4]> {
+    let hue = number(args[0].strip_suffix("deg").unwrap_or(args[0]))?.rem_euclid(360.0) / 360.0;
+    let sat = percent(args[1].strip_suffix('%')?)?;
+    let light = percent(args[2].strip_suffix('%')?)?;
+    let q = if light < 0.5 {
+        light * (1.0 + sat)
+    } else {
+        light + sat - light * sat
+    };
+    let p = 2.0 * light - q;
+    let channel = |t: f64| {
+        let t = t.rem_euclid(1.0);
+        let v = if t < 1.0 / 6.0 {
+            p + (q - p) * 6.0 * t
+        } else if t < 0.5 {
+            q
+        } else if t < 2.0 / 3.0 {
+            p + (q - p) * (2.0 / 3.0 - t) * 6.0
+        } else {
+            p
+        };
+        (v * 255.0).round() as u8
+    };
+    Some([
+        channel(hue + 1.0 / 3.0),
+        channel(hue),
+        channel(hue - 1.0 / 3.0),
+        alpha(args.get(3).copied())?,
+    ])
+}
+
+/// Alpha as `0`–`1` or a percentage; opaque when absent.
+fn alpha(token: Option<&str>) -> Option<u8> {
+    let a = match token {
+        None => return Some(255),
+        Some(t) => match t.strip_suffix('%') {
+            Some(p) => percent(p)?,
+            None => number(t).filter(|v| (0.0..=1.0).contains(v))?,
+        },
+    };
+    Some((a * 255.0).round() as u8)
+}
+
+/// A percentage in `0..=100` as a fraction.
+fn percent(s: &str) -> Option<f64> {
+    number(s)
+        .filter(|v| (0.0..=100.0).contains(v))
+        .map(|v| v / 100.0)
+}
+
+fn number(s: &str) -> Option<f64> {
+    s.trim().parse::<f64>().ok().filter(|v| v.is_finite())
+}
+
+/// CSS named colors, sorted by name for binary search.
+const NAMED: &[(&str, [u8; 4])] = &[
+    ("aliceblue", [240, 248, 255, 255]),
+    ("antiquewhite", [250, 235, 215, 255]),
+    ("aqua", [0, 255, 255, 255]),
+    ("aquamarine", [127, 255, 212, 255]),
+    ("azure", [240, 255, 255, 255]),
+    ("beige", [245, 245, 220, 255]),
+    ("bisque", [255, 228, 196, 255]),
+    ("black", [0, 0, 0, 255]),
+    ("blanchedalmond", [255, 235, 205, 255]),
+    ("blue", [0, 0, 255, 255]),
+    ("blueviolet", [138, 43, 226, 255]),
+    ("brown", [165, 42, 42, 255]),
+    ("burlywood", [222, 184, 135, 255]),
+    ("cadetblue", [95, 158, 160, 255]),
+    ("chartreuse", [127, 255, 0, 255]),
+    ("chocolate", [210, 105, 30, 255]),
+    ("coral", [255, 127, 80, 255]),
+    ("cornflowerblue", [100, 149, 237, 255]),
+    ("cornsilk", [255, 248, 220, 255]),
+    ("crimson", [220, 20, 60, 255]),
+    ("cyan", [0, 255, 255, 255]),
+    ("darkblue", [0, 0, 139, 255]),
+    ("darkcyan", [0, 139, 139, 255]),
+    ("darkgoldenrod", [184, 134, 11, 255]),
+    ("darkgray", [169, 169, 169, 255]),
+    ("darkgreen", [0, 100, 0, 255]),
+    ("darkgrey", [169, 169, 169, 255]),
+    ("darkkhaki", [189, 183, 107, 255]),
+    ("darkmagenta", [139, 0, 139, 255]),
+    ("darkolivegreen", [85, 107, 47, 255]),
+    ("darkorange", [255, 140, 0, 255]),
+    ("darkorchid", [153, 50, 204, 255]),
+    ("darkred", [139, 0, 0, 255]),
+    ("darksalmon", [233, 150, 122, 255]),
+    ("darkseagreen", [143, 188, 143, 255]),
+    ("darkslateblue", [72, 61, 139, 255]),
+    ("darkslategray", [47, 79, 79, 255]),
+    ("darkslategrey", [47, 79, 79, 255]),
+    ("darkturquoise", [0, 206, 209, 255]),
+    ("darkviolet", [148, 0, 211, 255]),
+    ("deeppink", [255, 20, 147, 255]),
+    ("deepskyblue", [0, 191, 255, 255]),
+    ("dimgray", [105, 105, 105, 255]),
+    ("dimgrey", [105, 105, 105, 255]),
+    ("dodgerblue", [30, 144, 255, 255]),
+    ("firebrick", [178, 34, 34, 255]),
+    ("floralwhite", [255, 250, 240, 255]),
+    ("forestgreen", [34, 139, 34, 255]),
+    ("fuchsia", [255, 0, 255, 255]),
+    ("gainsboro", [220, 220, 220, 255]),
+    ("ghostwhite", [248, 248, 255, 255]),
+    ("gold", [255, 215, 0, 255]),
+    ("goldenrod", [218, 165, 32, 255]),
+    ("gray", [128, 128, 128, 255]),
+    ("green", [0, 128, 0, 255]),
+    ("greenyellow", [173, 255, 47, 255]),
+    ("grey", [128, 128, 128, 255]),
+    ("honeydew", [240, 255, 240, 255]),
+    ("hotpink", [255, 105, 180, 255]),
+    ("indianred", [205, 92, 92, 255]),
+    ("indigo", [75, 0, 130, 255]),
+    ("ivory", [255, 255, 240, 255]),
+    ("khaki", [240, 230, 140, 255]),
+    ("lavender", [230, 230, 250, 255]),
+    ("lavenderblush", [255, 240, 245, 255]),
+    ("lawngreen", [124, 252, 0, 255]),
+    ("lemonchiffon", [255, 250, 205, 255]),
+    ("lightblue", [173, 216, 230, 255]),
+    ("lightcoral", [240, 128, 128, 255]),
+    ("lightcyan", [224, 255, 255, 255]),
+    ("lightgoldenrodyellow", [250, 250, 210, 255]),
+    ("lightgray", [211, 211, 211, 255]),
+    ("lightgreen", [144, 238, 144, 255]),
+    ("lightgrey", [211, 211, 211, 255]),
+    ("lightpink", [255, 182, 193, 255]),
+    ("lightsalmon", [255, 160, 122, 255]),
+    ("lightseagreen", [32, 178, 170, 255]),
+    ("lightskyblue", [135, 206, 250, 255]),
+    ("lightslategray", [119, 136, 153, 255]),
+    ("lightslategrey", [119, 136, 153, 255]),
+    ("lightsteelblue", [176, 196, 222, 255]),
+    ("lightyellow", [255, 255, 224, 255]),
+    ("lime", [0, 255, 0, 255]),
+    ("limegreen", [50, 205, 50, 255]),
+    ("linen", [250, 240, 230, 255]),
+    ("magenta", [255, 0, 255, 255]),
+    ("maroon", [128, 0, 0, 255]),
+    ("mediumaquamarine", [102, 205, 170, 255]),
+    ("mediumblue", [0, 0, 205, 255]),
+    ("mediumorchid", [186, 85, 211, 255]),
+    ("mediumpurple", [147, 112, 219, 255]),
+    ("mediumseagreen", [60, 179, 113, 255]),
+    ("mediumslateblue", [123, 104, 238, 255]),
+    ("mediumspringgreen", [0, 250, 154, 255]),
+    ("mediumturquoise", [72, 209, 204, 255]),
+    ("mediumvioletred", [199, 21, 133, 255]),
+    ("midnightblue", [25, 25, 112, 255]),
+    ("mintcream", [245, 255, 250, 255]),
+    ("mistyrose", [255, 228, 225, 255]),
+    ("moccasin", [255, 228, 181, 255]),
+    ("navajowhite", [255, 222, 173, 255]),
+    ("navy", [0, 0, 128, 255]),
+    ("oldlace", [253, 245, 230, 255]),
+    ("olive", [128, 128, 0, 255]),
+    ("olivedrab", [107, 142, 35, 255]),
+    ("orange", [255, 165, 0, 255]),
+    ("orangered", [255, 69, 0, 255]),
+    ("orchid", [218, 112, 214, 255]),
+    ("palegoldenrod", [238, 232, 170, 255]),
+    ("palegreen", [152, 251, 152, 255]),
+    ("paleturquoise", [175, 238, 238, 255]),
+    ("palevioletred", [219, 112, 147, 255]),
+    ("papayawhip", [255, 239, 213, 255]),
+    ("peachpuff", [255, 218, 185, 255]),
+    ("peru", [205, 133, 63, 255]),
+    ("pink", [255, 192, 203, 255]),
+    ("plum", [221, 160, 221, 255]),
+    ("powderblue", [176, 224, 230, 255]),
+    ("purple", [128, 0, 128, 255]),
+    ("rebeccapurple", [102, 51, 153, 255]),
+    ("red", [255, 0, 0, 255]),
+    ("rosybrown", [188, 143, 143, 255]),
+    ("royalblue", [65, 105, 225, 255]),
+    ("saddlebrown", [139, 69, 19, 255]),
+    ("salmon", [250, 128, 114, 255]),
+    ("sandybrown", [244, 164, 96, 255]),
+    ("seagreen", [46, 139, 87, 255]),
+    ("seashell", [255, 245, 238, 255]),
+    ("sienna", [160, 82, 45, 255]),
+    ("silver", [192, 192, 192, 255]),
+    ("skyblue", [135, 206, 235, 255]),
+    ("slateblue", [106, 90, 205, 255]),
+    ("slategray", [112, 128, 144, 255]),
+    ("slategrey", [112, 128, 144, 255]),
+    ("snow", [255, 250, 250, 255]),
+    ("springgreen", [0, 255, 127, 255]),
+    ("steelblue", [70, 130, 180, 255]),
+    ("tan", [210, 180, 140, 255]),
+    ("teal", [0, 128, 128, 255]),
+    ("thistle", [216, 191, 216, 255]),
+    ("tomato", [255, 99, 71, 255]),
+    ("transparent", [0, 0, 0, 0]),
+    ("turquoise", [64, 224, 208, 255]),
+    ("violet", [238, 130, 238, 255]),
+    ("wheat", [245, 222, 179, 255]),
+    ("white", [255, 255, 255, 255]),
+    ("whitesmoke", [245, 245, 245, 255]),
+    ("yellow", [255, 255, 0, 255]),
+    ("yellowgreen", [154, 205, 50, 255]),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_takes_three_four_six_or_eight_digits() {
+        assert_eq!(parse_css_color("#abc"), Some([0xaa, 0xbb, 0xcc, 255]));
+        assert_eq!(parse_css_color("#abcd"), Some([0xaa, 0xbb, 0xcc, 0xdd]));
+        assert_eq!(parse_css_color("#1E3A8A"), Some([0x1e, 0x3a, 0x8a, 255]));
+        assert_eq!(parse_css_color("#1e3a8a80"), Some([0x1e, 0x3a, 0x8a, 0x80]));
+        assert_eq!(parse_css_color(" 1e3a8a "), Some([0x1e, 0x3a, 0x8a, 255]));
+    }
+
+    #[test]
+    fn css_names_are_case_insensitive() {
+        assert_eq!(parse_css_color("black"), Some([0, 0, 0, 255]));
+        assert_eq!(parse_css_color("RebeccaPurple"), Some([102, 51, 153, 255]));
+        assert_eq!(parse_css_color("transparent"), Some([0, 0, 0, 0]));
+        // Looked up by binary search
+        assert!(NAMED.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn functional_notation() {
+        assert_eq!(parse_css_color("rgb(12, 34, 56)"), Some([12, 34, 56, 255]));
+        assert_eq!(
+            parse_css_color("RGBA(12,34,56,0.5)"),
+            Some([12, 34, 56, 128])
+        );
+        assert_eq!(parse_css_color("rgb(0 0 0 / 50%)"), Some([0, 0, 0, 128]));
+        assert_eq!(parse_css_color("rgb(100%, 0%, 0%)"), Some([255, 0, 0, 255]));
+        assert_eq!(parse_css_color("hsl(0, 100%, 50%)"), Some([255, 0, 0, 255]));
+        assert_eq!(
+            parse_css_color("hsl(120deg 100% 25%)"),
+            Some([0, 128, 0, 255])
+        );
+        assert_eq!(
+            parse_css_color("hsla(210, 50%, 40%, 1)"),
+            Some([51, 102, 153, 255])
+        );
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        for bad in [
+            "",
+            "#12",
+            "#12345",
+            "#ggg",
+            "#ééé",
+            "notacolor",
+            "rgb(1, 2)",
+            "rgb(300, 0, 0)",
+            "rgb(1, 2, 3",
+            "hsl(0, 1, 1)",
+            "cmyk(0, 0, 0, 0)",
+        ] {
+            assert_eq!(parse_css_color(bad), None, "{bad}");
+        }
+    }
+}
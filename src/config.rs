@@ -1,7 +1,13 @@
+use crate::color::parse_css_color;
+use crate::error::QrError;
+use crate::html::Syntax;
+use crate::util::{is_truthy, ChapterFilter};
 use fast_qr::convert::{Color, Shape};
+use fast_qr::ECL;
 use log::warn;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
@@ -16,11 +22,23 @@ impl Default for FailureMode {
     }
 }
 
-/// Flexible color input accepted in TOML: hex string or RGB/RGBA arrays.
+impl FailureMode {
+    pub fn from_str_loose(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "continue" => Some(FailureMode::Continue),
+            "bail" => Some(FailureMode::Bail),
+            _ => None,
+        }
+    }
+}
+
+/// Flexible color input accepted in TOML: a CSS color string or RGB/RGBA
+/// arrays.
 ///
 /// Examples:
 /// - `"#000"` or `"#000000"`
 /// - `"#000000FF"`
+/// - `"rebeccapurple"`, `"rgb(12, 34, 56)"`, `"rgba(0 0 0 / 50%)"`, `"hsl(210, 50%, 40%)"`
 /// - `[0, 0, 0]`
 /// - `[0, 0, 0, 255]`
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -32,16 +50,444 @@ pub enum ColorCfg {
 }
 
 impl ColorCfg {
+    /// The color for fast_qr, parsed here rather than by fast_qr.
+    pub fn to_color(&self) -> anyhow::Result<Color> {
+        self.to_rgba().map(Color::from)
+    }
+
+    /// RGBA components, for renderers that draw pixels themselves.
+    pub fn to_rgba(&self) -> anyhow::Result<[u8; 4]> {
+        match self {
+            ColorCfg::Hex(s) => parse_css_color(s).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "'{s}' is not a color; use #RRGGBB(AA), #RGB(A), a CSS name, \
+                     rgb(), rgba(), hsl() or hsla()"
+                )
+            }),
+            ColorCfg::Rgba(a4) => Ok(*a4),
+            ColorCfg::Rgb([r, g, b]) => Ok([*r, *g, *b, 255]),
+        }
+    }
+}
+
+/// Contrast ratio (WCAG) below which scanners start to miss codes.
+pub const MIN_CONTRAST_RATIO: f64 = 3.0;
+
+/// `top` alpha-composited over an opaque `bottom`.
+fn composite(top: [u8; 4], bottom: [u8; 3]) -> [u8; 3] {
+    let a = top[3] as u32;
+    std::array::from_fn(|i| ((top[i] as u32 * a + bottom[i] as u32 * (255 - a)) / 255) as u8)
+}
+
+/// WCAG relative luminance of an sRGB color.
+fn relative_luminance(rgb: [u8; 3]) -> f64 {
+    let linear = |c: u8| {
+        let c = c as f64 / 255.0;
+        if c <= 0.03928 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * linear(rgb[0]) + 0.7152 * linear(rgb[1]) + 0.0722 * linear(rgb[2])
+}
+
+/// QR error-correction level (`"L"`, `"M"`, `"Q"`, `"H"`).
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Ecl {
+    #[serde(alias = "l")]
+    L,
+    #[serde(alias = "m")]
+    M,
+    #[serde(alias = "q")]
+    Q,
+    #[serde(alias = "h")]
+    H,
+}
+
+impl Ecl {
+    /// Largest payload (bytes) a QR code can hold at this level (version 40, byte mode).
+    pub fn max_bytes(self) -> usize {
+        match self {
+            Ecl::L => 2953,
+            Ecl::M => 2331,
+            Ecl::Q => 1663,
+            Ecl::H => 1273,
+        }
+    }
+
     #[inline]
-    pub fn to_color(&self) -> Color {
+    pub fn to_ecl(self) -> ECL {
+        match self {
+            Ecl::L => ECL::L,
+            Ecl::M => ECL::M,
+            Ecl::Q => ECL::Q,
+            Ecl::H => ECL::H,
+        }
+    }
+
+    pub fn from_str_loose(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_uppercase().as_str() {
+            "L" => Some(Ecl::L),
+            "M" => Some(Ecl::M),
+            "Q" => Some(Ecl::Q),
+            "H" => Some(Ecl::H),
+            _ => None,
+        }
+    }
+}
+
+/// How a marker is replaced: an HTML `<img>` tag, a Markdown image, or the
+/// code drawn in text.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputMode {
+    #[default]
+    Html,
+    Markdown,
+    /// A fenced code block of Unicode half blocks; no image is written.
+    Text,
+    /// HTML for the `html` renderer, Markdown for every other renderer.
+    Auto,
+    /// HTML for the `html` renderer, text for every other renderer.
+    AutoText,
+}
+
+impl OutputMode {
+    /// Resolve `Auto` and `AutoText` against the renderer mdBook is running for.
+    pub fn for_renderer(self, renderer: &str) -> OutputMode {
         match self {
-            ColorCfg::Hex(s) => Color::from(s.as_str()),
-            ColorCfg::Rgba(a4) => Color::from(*a4),
-            ColorCfg::Rgb(a3) => Color::from(*a3),
+            OutputMode::Auto | OutputMode::AutoText if renderer == "html" => OutputMode::Html,
+            OutputMode::Auto => OutputMode::Markdown,
+            OutputMode::AutoText => OutputMode::Text,
+            mode => mode,
+        }
+    }
+
+    pub fn from_str_loose(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "html" => Some(OutputMode::Html),
+            "markdown" | "md" => Some(OutputMode::Markdown),
+            "text" => Some(OutputMode::Text),
+            "auto" => Some(OutputMode::Auto),
+            "auto-text" => Some(OutputMode::AutoText),
+            _ => None,
+        }
+    }
+}
+
+/// What a marker is replaced with where an image cannot go: a link or image
+/// destination (`[scan]({{QR_CODE}})`) or an HTML attribute value.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum UrlSubstitution {
+    /// Leave the marker in place and warn.
+    #[default]
+    Skip,
+    /// Substitute the resolved URL.
+    Url,
+}
+
+/// `debug-comments = true` follows each image with an HTML comment naming its
+/// profile and URL host; `debug-comments = "full"` shows the whole URL.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum DebugCommentsCfg {
+    Enabled(bool),
+    Mode(String),
+}
+
+impl DebugCommentsCfg {
+    /// `None` when off, otherwise whether the full URL is shown.
+    pub fn full_url(&self) -> Option<bool> {
+        match self {
+            DebugCommentsCfg::Enabled(on) => on.then_some(false),
+            DebugCommentsCfg::Mode(m) if m.eq_ignore_ascii_case("full") => Some(true),
+            DebugCommentsCfg::Mode(m) => {
+                warn!("mdbook-qr: unknown debug-comments = \"{m}\"; showing the URL host only.");
+                Some(false)
+            }
+        }
+    }
+}
+
+/// How browsers are made to refetch an image whose content changed.
+/// `true` is read as `query` and `false` as `none`.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheBust {
+    /// `qr.png?v=<hash12>`; the parameter is `cache-bust-param`.
+    #[default]
+    Query,
+    /// `qr.<hash12>.png`; older hashed copies are removed.
+    Filename,
+    /// Plain `qr.png`.
+    None,
+}
+
+impl<'de> Deserialize<'de> for CacheBust {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        enum Named {
+            Query,
+            Filename,
+            None,
+        }
+        #[derive(Deserialize)]
+        #[serde(
+            untagged,
+            expecting = "true, false, \"query\", \"filename\" or \"none\""
+        )]
+        enum Repr {
+            Flag(bool),
+            Named(Named),
+        }
+        Ok(match Repr::deserialize(de)? {
+            Repr::Flag(true) | Repr::Named(Named::Query) => CacheBust::Query,
+            Repr::Named(Named::Filename) => CacheBust::Filename,
+            Repr::Flag(false) | Repr::Named(Named::None) => CacheBust::None,
+        })
+    }
+}
+
+/// Query parameter of `cache-bust = "query"` when `cache-bust-param` is unset.
+pub const DEFAULT_CACHE_BUST_PARAM: &str = "v";
+
+/// How image paths derived from a marker are named.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PathStyle {
+    /// `qr/<slug>.png`.
+    #[default]
+    Plain,
+    /// `qr/<slug>-<hash8>.png`, hashed over the payload and every rendering
+    /// option: a changed image gets a new name, so no cache bust is added.
+    Hashed,
+}
+
+/// Where relative image paths are resolved and generated files written.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputBase {
+    /// Under `book.src`, copied into the rendered book by mdBook.
+    #[default]
+    Src,
+    /// Under the book root, outside what `mdbook serve` watches; published
+    /// separately, at the same place relative to the site root.
+    Root,
+}
+
+/// What happens when a code gets fewer than `min-module-px` pixels per module.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DensityPolicy {
+    #[default]
+    Warn,
+    /// Fail the profile, as `on-failure` handles it.
+    Error,
+}
+
+/// Pixels per module below which codes are reported (`min-module-px`).
+pub const DEFAULT_MIN_MODULE_PX: f32 = 3.0;
+
+/// What happens when two markers map to the same image file.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionPolicy {
+    /// Warn; the later profile's image overwrites the earlier one's.
+    #[default]
+    Warn,
+    /// Fail the build.
+    Error,
+    /// Give the later profile its own file, suffixed with its marker slug.
+    Suffix,
+}
+
+/// What happens when several profiles configure the same marker. Profiles
+/// come in a fixed order: the default, then the customs by table name.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicatePolicy {
+    /// Warn; the first profile replaces the marker, the others find nothing.
+    #[default]
+    Warn,
+    /// Fail the build.
+    Error,
+    /// Drop every profile but the first using the marker.
+    FirstWins,
+    /// Drop every profile but the last using the marker.
+    LastWins,
+}
+
+/// What happens to a marker in a draft chapter, one listed in SUMMARY.md
+/// without a file: it has no path to link an image from.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DraftPolicy {
+    /// Leave the marker in place and say so at debug level.
+    #[default]
+    Keep,
+    /// Leave the marker in place and warn.
+    Warn,
+    /// Remove the marker.
+    Strip,
+    /// Replace the marker with the profile's `alt` text.
+    Alt,
+}
+
+/// `contact` table: a person, encoded as a vCard 3.0 payload.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ContactCfg {
+    pub name: String,
+    pub org: Option<String>,
+    pub title: Option<String>,
+    /// One address or several.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub email: Vec<String>,
+    /// One number or several.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub phone: Vec<String>,
+    pub url: Option<String>,
+}
+
+/// Accept `"x"` as well as `["x", "y"]`.
+fn one_or_many<'de, D>(de: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(de)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+/// `wifi` table: a network to join, encoded as a `WIFI:` payload.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WifiCfg {
+    pub ssid: String,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub security: WifiSecurity,
+    /// The network does not broadcast its SSID.
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WifiSecurity {
+    #[default]
+    Wpa,
+    Wep,
+    Nopass,
+}
+
+impl WifiSecurity {
+    pub fn from_str_loose(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "wpa" | "wpa2" | "wpa3" => Some(WifiSecurity::Wpa),
+            "wep" => Some(WifiSecurity::Wep),
+            "nopass" | "none" | "open" => Some(WifiSecurity::Nopass),
+            _ => None,
+        }
+    }
+}
+
+/// Where a `caption` goes relative to the image.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaptionPosition {
+    Above,
+    #[default]
+    Below,
+}
+
+impl CaptionPosition {
+    pub fn from_str_loose(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "above" => Some(CaptionPosition::Above),
+            "below" => Some(CaptionPosition::Below),
+            _ => None,
+        }
+    }
+}
+
+/// How the displayed size (`fit`) is written on the `<img>`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SizeMode {
+    /// `width="200" height="200"`, which browsers use to reserve space.
+    Attributes,
+    /// `style="height:200px;width:200px"`.
+    #[default]
+    Style,
+    /// Nothing; sizing is left to CSS.
+    None,
+}
+
+impl SizeMode {
+    pub fn from_str_loose(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "attributes" => Some(SizeMode::Attributes),
+            "style" => Some(SizeMode::Style),
+            "none" => Some(SizeMode::None),
+            _ => None,
+        }
+    }
+}
+
+/// `loading` values; `"none"` leaves the attribute out.
+pub const LOADING_VALUES: &[&str] = &["eager", "lazy", "none"];
+
+/// `decoding` values; `"none"` leaves the attribute out.
+pub const DECODING_VALUES: &[&str] = &["sync", "async", "auto", "none"];
+
+/// `link = true` links the image to the encoded URL; `link = "https://..."` to another page.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum LinkCfg {
+    Enabled(bool),
+    Href(String),
+}
+
+/// Decode limits for user-supplied image assets (e.g. logos).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct AssetLimits {
+    /// Maximum width or height of a decoded asset (px).
+    pub max_px: u32,
+    /// Maximum file size and decoder allocation (MiB).
+    pub max_mb: u64,
+}
+
+impl Default for AssetLimits {
+    fn default() -> Self {
+        Self {
+            max_px: 4000,
+            max_mb: 32,
         }
     }
 }
 
+impl AssetLimits {
+    #[inline]
+    pub fn max_bytes(&self) -> u64 {
+        self.max_mb.saturating_mul(1024 * 1024)
+    }
+}
+
 /// Optional fit for the injected <img> (px).
 #[derive(Clone, Debug, Deserialize, Serialize, Default)]
 pub struct FitConfig {
@@ -49,6 +495,464 @@ pub struct FitConfig {
     pub height: Option<u32>,
 }
 
+/// `marker`: one marker, or aliases that all get the same image. The first
+/// names the image: its slug and derived path come from it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum MarkerCfg {
+    One(String),
+    Aliases(Vec<String>),
+}
+
+impl MarkerCfg {
+    /// Every marker, the first alias first; empty ones left out.
+    pub fn all(&self) -> Vec<&str> {
+        match self {
+            MarkerCfg::One(m) => vec![m.as_str()],
+            MarkerCfg::Aliases(list) => list.iter().map(String::as_str).collect(),
+        }
+        .into_iter()
+        .filter(|m| !m.is_empty())
+        .collect()
+    }
+}
+
+impl From<String> for MarkerCfg {
+    fn from(marker: String) -> Self {
+        MarkerCfg::One(marker)
+    }
+}
+
+/// `url`: one address, or a table of addresses by renderer name with
+/// `default` for the rest (`url.html`, `url.pdf`, `url.default`).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(
+    untagged,
+    expecting = "a URL string, or a table of URL strings keyed by renderer name (html, pdf, ...) or `default`"
+)]
+pub enum UrlCfg {
+    One(String),
+    PerRenderer(BTreeMap<String, String>),
+}
+
+impl UrlCfg {
+    /// The URL for `renderer`: the table's entry for it, else `default`.
+    /// `None` leaves the URL to the usual fallbacks.
+    pub fn for_renderer(&self, renderer: &str) -> Option<&str> {
+        match self {
+            UrlCfg::One(url) => Some(url),
+            UrlCfg::PerRenderer(urls) => urls
+                .get(renderer)
+                .or_else(|| urls.get("default"))
+                .map(String::as_str),
+        }
+    }
+}
+
+/// `enable`: a bool, `"env:NAME"` (on when `NAME` is set to a true value:
+/// `1`, `true`, `yes` or `on`), or `{ env = "NAME", default = false }`,
+/// which also says what an unset `NAME` means.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(
+    untagged,
+    expecting = "true, false, \"env:NAME\", or a table { env = \"NAME\", default = false }"
+)]
+pub enum EnableCfg {
+    Flag(bool),
+    Env(EnvName),
+    Table {
+        env: String,
+        #[serde(default)]
+        default: bool,
+    },
+}
+
+impl EnableCfg {
+    /// Whether this enables a profile, reading variables through `var`.
+    pub fn resolve(&self, var: impl Fn(&str) -> Option<String>) -> bool {
+        match self {
+            EnableCfg::Flag(on) => *on,
+            EnableCfg::Env(EnvName(name)) => var(name).is_some_and(|v| is_truthy(&v)),
+            EnableCfg::Table { env, default } => var(env).map_or(*default, |v| is_truthy(&v)),
+        }
+    }
+
+    /// [`EnableCfg::resolve`] against the process environment.
+    pub fn is_on(&self) -> bool {
+        self.resolve(|name| std::env::var(name).ok())
+    }
+}
+
+impl From<bool> for EnableCfg {
+    fn from(on: bool) -> Self {
+        EnableCfg::Flag(on)
+    }
+}
+
+/// The variable of an `enable = "env:NAME"` string.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct EnvName(pub String);
+
+impl TryFrom<String> for EnvName {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.strip_prefix("env:").map(str::trim) {
+            Some(name) if !name.is_empty() => Ok(EnvName(name.to_string())),
+            _ => Err(format!(
+                "'{value}' is not a valid `enable`; use true, false or \"env:NAME\""
+            )),
+        }
+    }
+}
+
+impl From<EnvName> for String {
+    fn from(name: EnvName) -> Self {
+        format!("env:{}", name.0)
+    }
+}
+
+/// What a run does with a profile outside its `valid-from` /
+/// `valid-until` dates.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExpiredPolicy {
+    /// Generate the image anyway and warn.
+    #[default]
+    Warn,
+    /// Skip the profile and replace its marker with `expired-text`.
+    Disable,
+}
+
+/// A calendar date, written `"YYYY-MM-DD"` (ISO 8601) or as a bare TOML
+/// date, held as days since 1970-01-01.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct IsoDate(i64);
+
+impl IsoDate {
+    /// The current date in UTC.
+    pub fn today() -> Self {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        IsoDate((secs / 86_400) as i64)
+    }
+
+    /// Days from `self` to `later`; negative when `later` is earlier.
+    pub fn days_until(self, later: IsoDate) -> i64 {
+        later.0 - self.0
+    }
+}
+
+/// Parse `YYYY-MM-DD`; `None` for any other form or a day the month does
+/// not have.
+pub fn parse_iso_date(s: &str) -> Option<IsoDate> {
+    let s = s.trim();
+    let b = s.as_bytes();
+    if b.len() != 10 || b[4] != b'-' || b[7] != b'-' {
+        return None;
+    }
+    let num = |r: std::ops::Range<usize>| -> Option<i64> {
+        let part = &s[r];
+        part.bytes()
+            .all(|c| c.is_ascii_digit())
+            .then(|| part.parse().ok())
+            .flatten()
+    };
+    let (y, m, d) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let leap = y % 4 == 0 && (y % 100 != 0 || y % 400 == 0);
+    let month_len = match m {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if d < 1 || d > month_len {
+        return None;
+    }
+    // Days from civil (proleptic Gregorian), with years starting in March
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(IsoDate(era * 146_097 + doe - 719_468))
+}
+
+impl TryFrom<String> for IsoDate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        parse_iso_date(&value)
+            .ok_or_else(|| format!("'{value}' is not a valid date; use \"YYYY-MM-DD\""))
+    }
+}
+
+impl From<IsoDate> for String {
+    fn from(date: IsoDate) -> Self {
+        date.to_string()
+    }
+}
+
+impl std::fmt::Display for IsoDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Civil from days, the inverse of `parse_iso_date`
+        let z = self.0 + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let d = doy - (153 * mp + 2) / 5 + 1;
+        let m = if mp < 10 { mp + 3 } else { mp - 9 };
+        let y = yoe + era * 400 + i64::from(m <= 2);
+        write!(f, "{y:04}-{m:02}-{d:02}")
+    }
+}
+
+/// Quiet zone in modules: one number for every side, or a table of sides.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum MarginCfg {
+    Uniform(i64),
+    Sides(MarginSides),
+}
+
+/// `margin = { top = 2, right = 2, bottom = 2, left = 6 }`. Unset sides are
+/// inherited from the parent's margin, then default to 2.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarginSides {
+    pub top: Option<i64>,
+    pub right: Option<i64>,
+    pub bottom: Option<i64>,
+    pub left: Option<i64>,
+}
+
+/// Quiet zone used when `margin` (or one of its sides) is unset.
+pub const DEFAULT_MARGIN: u32 = 2;
+
+/// Resolved quiet zone of each side, in modules.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Margins {
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    pub left: u32,
+}
+
+impl Margins {
+    pub fn uniform(n: u32) -> Self {
+        Self {
+            top: n,
+            right: n,
+            bottom: n,
+            left: n,
+        }
+    }
+
+    /// The common value when all four sides match.
+    pub fn as_uniform(&self) -> Option<u32> {
+        let n = self.top;
+        (self.right == n && self.bottom == n && self.left == n).then_some(n)
+    }
+}
+
+/// `2`, or `top right bottom left` when the sides differ.
+impl std::fmt::Display for Margins {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.as_uniform() {
+            Some(n) => write!(f, "{n}"),
+            None => write!(
+                f,
+                "{} {} {} {}",
+                self.top, self.right, self.bottom, self.left
+            ),
+        }
+    }
+}
+
+impl MarginCfg {
+    /// Each side in modules; a negative value warns and becomes 0, and 0
+    /// warns that the code has no quiet zone on that side.
+    pub fn resolve(&self) -> Margins {
+        let side = |label: &str, value: Option<i64>| match value {
+            None => DEFAULT_MARGIN,
+            Some(v) if v < 0 => {
+                warn!("mdbook-qr: {label} {v} is negative; using 0");
+                0
+            }
+            Some(0) => {
+                warn!("mdbook-qr: {label} is 0; most scanners need a quiet zone");
+                0
+            }
+            Some(v) => u32::try_from(v).unwrap_or(u32::MAX),
+        };
+        match *self {
+            MarginCfg::Uniform(n) => Margins::uniform(side("margin", Some(n))),
+            MarginCfg::Sides(s) => Margins {
+                top: side("margin.top", s.top),
+                right: side("margin.right", s.right),
+                bottom: side("margin.bottom", s.bottom),
+                left: side("margin.left", s.left),
+            },
+        }
+    }
+
+    /// `child` over `base`: a number replaces the margin, a table replaces
+    /// only the sides it sets.
+    pub fn inherit(child: &Option<Self>, base: &Option<Self>) -> Option<Self> {
+        let base_side = |pick: fn(&MarginSides) -> Option<i64>| match base {
+            Some(MarginCfg::Uniform(n)) => Some(*n),
+            Some(MarginCfg::Sides(b)) => pick(b),
+            None => None,
+        };
+        match child {
+            Some(MarginCfg::Sides(c)) => Some(MarginCfg::Sides(MarginSides {
+                top: c.top.or_else(|| base_side(|s| s.top)),
+                right: c.right.or_else(|| base_side(|s| s.right)),
+                bottom: c.bottom.or_else(|| base_side(|s| s.bottom)),
+                left: c.left.or_else(|| base_side(|s| s.left)),
+            })),
+            Some(uniform) => Some(uniform.clone()),
+            None => base.clone(),
+        }
+    }
+}
+
+/// Largest `srcset` pixel ratio.
+pub const MAX_PIXEL_RATIO: u32 = 4;
+
+/// Shape names accepted by `shape = "..."`.
+pub const SHAPE_NAMES: &[&str] = &[
+    "square",
+    "circle",
+    "rounded-square",
+    "vertical",
+    "horizontal",
+    "diamond",
+];
+
+/// Module shape, independent of the renderer that draws it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModuleShape {
+    Square,
+    Circle,
+    RoundedSquare,
+    Vertical,
+    Horizontal,
+    Diamond,
+}
+
+impl ModuleShape {
+    /// Parse a shape token (`"circle"`, `"rounded-square"`, ...). `_` and `-` are interchangeable.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().replace('_', "-").as_str() {
+            "square" => Some(ModuleShape::Square),
+            "circle" => Some(ModuleShape::Circle),
+            "rounded-square" => Some(ModuleShape::RoundedSquare),
+            "vertical" => Some(ModuleShape::Vertical),
+            "horizontal" => Some(ModuleShape::Horizontal),
+            "diamond" => Some(ModuleShape::Diamond),
+            _ => None,
+        }
+    }
+
+    /// The token that selects this shape.
+    pub fn name(self) -> &'static str {
+        match self {
+            ModuleShape::Square => "square",
+            ModuleShape::Circle => "circle",
+            ModuleShape::RoundedSquare => "rounded-square",
+            ModuleShape::Vertical => "vertical",
+            ModuleShape::Horizontal => "horizontal",
+            ModuleShape::Diamond => "diamond",
+        }
+    }
+
+    #[inline]
+    pub fn to_shape(self) -> Shape {
+        match self {
+            ModuleShape::Square => Shape::Square,
+            ModuleShape::Circle => Shape::Circle,
+            ModuleShape::RoundedSquare => Shape::RoundedSquare,
+            ModuleShape::Vertical => Shape::Vertical,
+            ModuleShape::Horizontal => Shape::Horizontal,
+            ModuleShape::Diamond => Shape::Diamond,
+        }
+    }
+
+    /// Shapes that leave large gaps between modules; scanners cope less well
+    /// when these are used for the data region.
+    #[inline]
+    pub fn is_aggressive(self) -> bool {
+        matches!(
+            self,
+            ModuleShape::Vertical | ModuleShape::Horizontal | ModuleShape::Diamond
+        )
+    }
+}
+
+/// Parse a shape token (`"circle"`, `"rounded-square"`, ...). `_` and `-` are interchangeable.
+pub fn shape_from_name(name: &str) -> Option<Shape> {
+    ModuleShape::from_name(name).map(ModuleShape::to_shape)
+}
+
+/// Resolve a shape token, warning and falling back to square when unknown.
+fn module_shape_or_square(name: &str) -> ModuleShape {
+    ModuleShape::from_name(name).unwrap_or_else(|| {
+        warn!(
+            "unknown shape '{name}'; expected one of: {}. Using square.",
+            SHAPE_NAMES.join(", ")
+        );
+        ModuleShape::Square
+    })
+}
+
+/// Module shape: `shape = "circle"` or the legacy `[shape]` table of flags.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ShapeCfg {
+    Name(String),
+    Flags(ShapeFlags),
+}
+
+impl ShapeCfg {
+    /// Resolve to a fast_qr shape, warning about unknown names or ambiguous flags.
+    pub fn to_shape(&self) -> Shape {
+        self.to_module_shape().to_shape()
+    }
+
+    /// Resolve to a [`ModuleShape`], warning about unknown names or ambiguous flags.
+    pub fn to_module_shape(&self) -> ModuleShape {
+        match self {
+            ShapeCfg::Name(name) => module_shape_or_square(name),
+            ShapeCfg::Flags(flags) => {
+                if flags.count_set() > 1 {
+                    warn!(
+                        "more than one [shape] flag is set; using the first by precedence \
+                         (circle, rounded_square, vertical, horizontal, diamond, square). \
+                         Prefer shape = \"<name>\"."
+                    );
+                }
+                flags.to_module_shape()
+            }
+        }
+    }
+
+    /// A flags table with nothing set counts as "not configured" for inheritance.
+    pub fn is_set(&self) -> bool {
+        match self {
+            ShapeCfg::Name(_) => true,
+            ShapeCfg::Flags(flags) => flags.any_set(),
+        }
+    }
+}
+
 /// Boolean flags for QR module shape (first-true precedence).
 #[derive(Clone, Debug, Deserialize, Serialize, Default)]
 #[serde(default)]
@@ -63,158 +967,1432 @@ pub struct ShapeFlags {
 
 impl ShapeFlags {
     pub fn to_shape(&self) -> Shape {
+        self.to_module_shape().to_shape()
+    }
+
+    pub fn to_module_shape(&self) -> ModuleShape {
         if self.circle {
-            Shape::Circle
+            ModuleShape::Circle
         } else if self.rounded_square {
-            Shape::RoundedSquare
+            ModuleShape::RoundedSquare
         } else if self.vertical {
-            Shape::Vertical
+            ModuleShape::Vertical
         } else if self.horizontal {
-            Shape::Horizontal
+            ModuleShape::Horizontal
         } else if self.diamond {
-            Shape::Diamond
+            ModuleShape::Diamond
         } else {
-            Shape::Square
+            ModuleShape::Square
         }
     }
 
     fn any_set(&self) -> bool {
-        self.square
-            || self.circle
-            || self.rounded_square
-            || self.vertical
-            || self.horizontal
-            || self.diamond
+        self.count_set() > 0
+    }
+
+    fn count_set(&self) -> usize {
+        [
+            self.square,
+            self.circle,
+            self.rounded_square,
+            self.vertical,
+            self.horizontal,
+            self.diamond,
+        ]
+        .iter()
+        .filter(|b| **b)
+        .count()
+    }
+}
+
+/// `module-gradient`: modules shaded from one color to another across the
+/// code, instead of the flat `module` color.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GradientCfg {
+    pub from: ColorCfg,
+    pub to: ColorCfg,
+    #[serde(default)]
+    pub direction: GradientDirection,
+}
+
+/// Which way a [`GradientCfg`] runs: `from` at the top, left or top-left.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum GradientDirection {
+    #[default]
+    Vertical,
+    Horizontal,
+    Diagonal,
+}
+
+/// `border`: a frame drawn into the PNG around the code, with optionally
+/// rounded (transparent) corners.
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BorderCfg {
+    /// Frame thickness (px).
+    pub width: u32,
+    /// Frame color; black when unset.
+    pub color: Option<ColorCfg>,
+    /// Corner radius of the whole image (px).
+    pub radius: u32,
+}
+
+/// Colors of the dark-mode variant; unset fields keep the light colors.
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct DarkCfg {
+    pub background: Option<ColorCfg>,
+    pub module: Option<ColorCfg>,
+}
+
+impl DarkCfg {
+    #[inline]
+    pub fn is_set(&self) -> bool {
+        self.background.is_some() || self.module.is_some()
     }
 }
 
+/// Per-region module shapes. Each token falls back to `shape` when unset.
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct StyleCfg {
+    pub data_shape: Option<String>,
+    pub finder_shape: Option<String>,
+    pub alignment_shape: Option<String>,
+}
+
+impl StyleCfg {
+    #[inline]
+    pub fn is_set(&self) -> bool {
+        self.data_shape.is_some() || self.finder_shape.is_some() || self.alignment_shape.is_some()
+    }
+}
+
+/// Resolved shapes for each functional region of the symbol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegionShapes {
+    pub data: ModuleShape,
+    pub finder: ModuleShape,
+    pub alignment: ModuleShape,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, Default)]
 #[serde(rename_all = "kebab-case")]
 pub struct Profile {
     /// If None → warn & skip this profile.
-    pub marker: Option<String>,
+    pub marker: Option<MarkerCfg>,
     /// Optional explicit output path for this profile (rel to book src if not absolute).
     pub qr_path: Option<String>,
+    /// Allow `qr_path` to point outside the book root (default false).
+    pub allow_outside_root: Option<bool>,
     pub localhost_qr: Option<bool>,
-    pub enable: Option<bool>,
-    pub url: Option<String>,
+    /// Port used for the localhost-qr URL (defaults to mdbook serve's 3000).
+    pub localhost_port: Option<u16>,
+    /// A bool, or read from the environment (see [`EnableCfg`]).
+    pub enable: Option<EnableCfg>,
+    /// First date (`YYYY-MM-DD`, UTC) the content behind the code is
+    /// current; earlier builds warn, or skip under `expired = "disable"`.
+    pub valid_from: Option<IsoDate>,
+    /// Last date the content is current, inclusive.
+    pub valid_until: Option<IsoDate>,
+    /// `warn` (default) or `disable` outside the `valid-*` dates.
+    pub expired: Option<ExpiredPolicy>,
+    /// What replaces the marker of a profile disabled by its dates (default:
+    /// nothing). Markdown.
+    pub expired_text: Option<String>,
+    pub url: Option<UrlCfg>,
+    /// Query parameters appended to the resolved URL, in name order; `{slug}`
+    /// in a value is the marker slug, and an empty value drops an inherited
+    /// parameter.
+    #[serde(default)]
+    pub query_params: BTreeMap<String, String>,
+    /// Encode this text as is instead of a URL (Wi-Fi strings, instructions);
+    /// wins over `url`.
+    pub data: Option<String>,
+    /// A Wi-Fi network to join; wins over `contact`, `data` and `url`.
+    pub wifi: Option<WifiCfg>,
+    /// A vCard to save; wins over `data` and `url`.
+    pub contact: Option<ContactCfg>,
+    /// What to do when this profile's image cannot be produced; unset →
+    /// the top-level `on-failure`.
+    pub on_failure: Option<FailureMode>,
     #[serde(default)]
     pub fit: FitConfig,
-    pub margin: Option<u32>,
+    /// Pixels per module. When set the image is `(modules + 2 * margin) *
+    /// scale` px square, and `fit` only sizes the `<img>`.
+    pub scale: Option<u32>,
+    /// Fewest displayed pixels per module before `low-density` applies
+    /// (default 3; 0 turns the check off).
+    pub min_module_px: Option<f32>,
+    /// Quiet zone in modules: a number, or `{ top, right, bottom, left }`.
+    pub margin: Option<MarginCfg>,
+    pub shape: Option<ShapeCfg>,
+    /// Per-region shapes (`data-shape`, `finder-shape`, `alignment-shape`).
     #[serde(default)]
-    pub shape: ShapeFlags,
+    pub style: StyleCfg,
     pub background: Option<ColorCfg>,
+    /// Page color assumed behind a see-through `background`, written as the
+    /// `<img>`'s CSS `background-color` so the code reads on dark themes.
+    pub transparent_backdrop: Option<ColorCfg>,
     pub module: Option<ColorCfg>,
+    /// Color of the outer ring of the three finder patterns ("eyes").
+    pub eye_frame: Option<ColorCfg>,
+    /// Color of the 3×3 centre of the finder patterns.
+    pub eye_module: Option<ColorCfg>,
+    /// Two-stop gradient over the modules; wins over `module`.
+    pub module_gradient: Option<GradientCfg>,
+    /// Shape of the finder pattern modules; `style.finder-shape` wins.
+    pub eye_shape: Option<String>,
+    /// Colors for a second, dark-mode image served via `<picture>`.
+    #[serde(default)]
+    pub dark: DarkCfg,
+    /// Error-correction level; unset → fast_qr default (or `H` with a logo).
+    pub ecl: Option<Ecl>,
+    /// Image composited into the centre of the QR (relative to the book root).
+    pub logo: Option<String>,
+    /// Logo edge as a fraction of the QR edge (default 0.2).
+    pub logo_scale: Option<f32>,
+    /// White padding behind the logo (px, default 0).
+    pub logo_padding: Option<u32>,
+    /// Frame and rounded corners drawn into the image itself.
+    pub border: Option<BorderCfg>,
+    /// Decode each image after writing it and treat a code that does not
+    /// read back as a failure (default false).
+    pub verify: Option<bool>,
+    /// Silence the low-contrast and inverted-colors warnings.
+    pub suppress_contrast_warning: Option<bool>,
+    /// `html` (default), `markdown`, `text`, or `auto` / `auto-text`
+    /// (chosen per renderer).
+    pub output: Option<OutputMode>,
+    /// Draw light modules and the quiet zone rather than dark modules in
+    /// `text` output, for light-on-dark terminals.
+    pub text_invert: Option<bool>,
+    /// Image alt text (default: "QR code linking to {url}").
+    pub alt: Option<String>,
+    /// Optional `title` attribute (tooltip).
+    pub title: Option<String>,
+    /// Wrap the image in `<a href>` (HTML output only).
+    pub link: Option<LinkCfg>,
+    /// `target` of the anchor; `_blank` adds `rel="noopener"`.
+    pub link_target: Option<String>,
+    /// Text shown with the image (`<figcaption>`, or an italic line in Markdown).
+    pub caption: Option<String>,
+    /// `above` or `below` (default) the image.
+    pub caption_position: Option<CaptionPosition>,
+    /// `style` (default), `attributes` or `none`: how `fit` is written.
+    pub size_mode: Option<SizeMode>,
+    /// `loading` of the `<img>`: `eager` (default), `lazy` or `none`.
+    pub loading: Option<String>,
+    /// `decoding` of the `<img>`: `sync`, `async`, `auto` or `none` (default).
+    pub decoding: Option<String>,
+    /// HTML written instead of the built-in markup, with `{src}`, `{alt}`,
+    /// `{width}`, `{height}`, `{url}`, `{hash}` and `{marker}` placeholders.
+    pub html_template: Option<String>,
+    /// Pixel ratios rendered for `srcset`, e.g. `[1, 2, 3]`; `fit` stays the
+    /// displayed size.
+    pub srcset: Option<Vec<u32>>,
+    /// `query` (default), `filename`, `none`, or a bool: how a changed image
+    /// is refetched.
+    pub cache_bust: Option<CacheBust>,
+    /// Name of the `cache-bust = "query"` parameter (default `v`).
+    pub cache_bust_param: Option<String>,
+    /// Only chapters matching these globs (relative to `src`) get this
+    /// profile's image; unset or empty → every chapter in scope.
+    pub chapters: Option<Vec<String>>,
+    /// Chapters matching these globs never get this profile's image.
+    pub exclude_chapters: Option<Vec<String>>,
+    /// Remove the marker from chapters the two lists leave out, instead of
+    /// leaving it in place (default false).
+    pub strip_unmatched: Option<bool>,
+    /// CSS class of the `<img>`; without `fit`, no inline size is written.
+    pub class: Option<String>,
+    /// Extra `<img>` attributes, written in name order.
+    #[serde(default)]
+    pub attrs: BTreeMap<String, String>,
 }
 
 impl Profile {
+    /// The first marker: the one messages name and the image is named after.
+    pub fn primary_marker(&self) -> Option<&str> {
+        self.markers().first().copied()
+    }
+
+    /// Every marker of the profile, its aliases included; empty when unset.
+    pub fn markers(&self) -> Vec<&str> {
+        self.marker.as_ref().map(MarkerCfg::all).unwrap_or_default()
+    }
+
+    /// Whether the profile generates; an `env:` form reads the environment
+    /// now.
     #[inline]
     pub fn is_enabled(&self) -> bool {
-        self.enable.unwrap_or(true)
+        self.is_enabled_with(|name| std::env::var(name).ok())
+    }
+
+    /// [`Profile::is_enabled`], reading variables through `var`.
+    pub fn is_enabled_with(&self, var: impl Fn(&str) -> Option<String>) -> bool {
+        self.enable.as_ref().is_none_or(|e| e.resolve(var))
+    }
+
+    /// How `today` falls outside the `valid-from` / `valid-until` dates,
+    /// e.g. "expired 12 days ago"; `None` within them, boundaries included.
+    pub fn validity_on(&self, today: IsoDate) -> Option<String> {
+        let days = |n: i64| {
+            if n == 1 {
+                "1 day".to_string()
+            } else {
+                format!("{n} days")
+            }
+        };
+        if let Some(until) = self.valid_until.filter(|u| *u < today) {
+            return Some(format!("expired {} ago", days(until.days_until(today))));
+        }
+        if let Some(from) = self.valid_from.filter(|f| *f > today) {
+            return Some(format!(
+                "not valid for another {}",
+                days(today.days_until(from))
+            ));
+        }
+        None
+    }
+
+    /// The quiet zone of each side (2 modules when unset).
+    pub fn margins(&self) -> Margins {
+        self.margin
+            .as_ref()
+            .map_or(Margins::uniform(DEFAULT_MARGIN), MarginCfg::resolve)
+    }
+
+    /// Resolve the effective module shape (square when unset).
+    #[inline]
+    pub fn shape(&self) -> Shape {
+        self.shape
+            .as_ref()
+            .map(ShapeCfg::to_shape)
+            .unwrap_or(Shape::Square)
+    }
+
+    /// Per-region shapes, or `None` when no `style` token, eye setting or
+    /// `module-gradient` is set (fast_qr renders).
+    pub fn region_shapes(&self) -> Option<RegionShapes> {
+        if !self.style.is_set() && !self.eyes_set() && self.module_gradient.is_none() {
+            return None;
+        }
+        let base = self
+            .shape
+            .as_ref()
+            .map(ShapeCfg::to_module_shape)
+            .unwrap_or(ModuleShape::Square);
+        let pick = |token: &Option<String>| token.as_deref().map_or(base, module_shape_or_square);
+        Some(RegionShapes {
+            data: pick(&self.style.data_shape),
+            finder: pick(
+                &self
+                    .style
+                    .finder_shape
+                    .clone()
+                    .or_else(|| self.eye_shape.clone()),
+            ),
+            alignment: pick(&self.style.alignment_shape),
+        })
     }
 
     /// Resolve the effective background color (from the flexible `background` field).
+    /// Unparsable colors are `None`; [`Profile::check_colors`] reports them.
     #[inline]
     pub fn background_color(&self) -> Option<Color> {
-        self.background.as_ref().map(|c| c.to_color())
+        self.background.as_ref().and_then(|c| c.to_color().ok())
     }
 
     /// Resolve the effective module (foreground) color (from the flexible `module` field).
     #[inline]
     pub fn module_color(&self) -> Option<Color> {
-        self.module.as_ref().map(|c| c.to_color())
+        self.module.as_ref().and_then(|c| c.to_color().ok())
+    }
+
+    /// Whether any `eye-*` key is set; the eyes are then drawn by the
+    /// self-rendered path.
+    #[inline]
+    pub fn eyes_set(&self) -> bool {
+        self.eye_frame.is_some() || self.eye_module.is_some() || self.eye_shape.is_some()
+    }
+
+    /// Fail on the first color that does not parse, naming its key.
+    pub fn check_colors(&self) -> anyhow::Result<()> {
+        for (key, color) in [
+            ("background", &self.background),
+            ("transparent-backdrop", &self.transparent_backdrop),
+            ("module", &self.module),
+            ("eye-frame", &self.eye_frame),
+            ("eye-module", &self.eye_module),
+            (
+                "module-gradient.from",
+                &self.module_gradient.as_ref().map(|g| g.from.clone()),
+            ),
+            (
+                "module-gradient.to",
+                &self.module_gradient.as_ref().map(|g| g.to.clone()),
+            ),
+            (
+                "border.color",
+                &self.border.as_ref().and_then(|b| b.color.clone()),
+            ),
+            ("dark.background", &self.dark.background),
+            ("dark.module", &self.dark.module),
+        ] {
+            if let Some(c) = color {
+                c.to_rgba().map_err(|e| QrError::Config {
+                    field: key.to_string(),
+                    message: e.to_string(),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Alt text for the injected image; describes the target when unset.
+    pub fn alt_text(&self, url: &str) -> String {
+        self.alt
+            .clone()
+            .unwrap_or_else(|| match (&self.wifi, &self.contact, &self.data) {
+                (Some(wifi), _, _) => format!("Wi-Fi: {}", wifi.ssid),
+                (None, Some(contact), _) => format!("Contact: {}", contact.name),
+                (None, None, Some(_)) => "QR code".to_string(),
+                (None, None, None) => format!("QR code linking to {url}"),
+            })
+    }
+
+    /// This profile with its `dark` colors applied, if any are configured.
+    pub fn dark_variant(&self) -> Option<Profile> {
+        if !self.dark.is_set() {
+            return None;
+        }
+        Some(Profile {
+            background: self
+                .dark
+                .background
+                .clone()
+                .or_else(|| self.background.clone()),
+            module: self.dark.module.clone().or_else(|| self.module.clone()),
+            // Light eye colors would vanish on a dark background
+            eye_frame: None,
+            eye_module: None,
+            module_gradient: None,
+            dark: DarkCfg::default(),
+            ..self.clone()
+        })
+    }
+
+    /// Extra pixel ratios to render (sorted, without 1); ratios outside
+    /// `1..=MAX_PIXEL_RATIO` are dropped with a warning.
+    pub fn extra_pixel_ratios(&self) -> Vec<u32> {
+        let mut ratios: Vec<u32> = self
+            .srcset
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|&r| {
+                let ok = (1..=MAX_PIXEL_RATIO).contains(&r);
+                if !ok {
+                    warn!("mdbook-qr: ignoring srcset ratio {r}; expected 1 to {MAX_PIXEL_RATIO}");
+                }
+                ok && r > 1
+            })
+            .collect();
+        ratios.sort_unstable();
+        ratios.dedup();
+        ratios
+    }
+
+    /// `attrs` that can be written on the `<img>`: names must be valid HTML
+    /// attribute names and not ones mdbook-qr writes itself. Others are
+    /// dropped with a warning.
+    pub fn img_attrs(&self) -> Vec<(&str, &str)> {
+        const RESERVED: &[&str] = &[
+            "src", "alt", "title", "style", "class", "loading", "decoding",
+        ];
+        self.attrs
+            .iter()
+            .filter(|(name, _)| {
+                let reserved = RESERVED.iter().any(|r| name.eq_ignore_ascii_case(r));
+                if reserved || !crate::html_escape::is_attribute_name(name) {
+                    warn!(
+                        "mdbook-qr: ignoring attrs entry '{name}': {}",
+                        if reserved {
+                            "set by mdbook-qr itself"
+                        } else {
+                            "not a valid HTML attribute name"
+                        }
+                    );
+                    return false;
+                }
+                true
+            })
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    /// `loading` of the `<img>`: `eager` unless set, nothing for `"none"`.
+    /// An unknown value counts as unset.
+    pub fn loading_attr(&self) -> Option<&str> {
+        match self.loading.as_deref() {
+            Some("none") => None,
+            Some(v) if LOADING_VALUES.contains(&v) => Some(v),
+            _ => Some("eager"),
+        }
+    }
+
+    /// `decoding` of the `<img>`: nothing unless set, or for `"none"`. An
+    /// unknown value counts as unset.
+    pub fn decoding_attr(&self) -> Option<&str> {
+        self.decoding
+            .as_deref()
+            .filter(|v| *v != "none" && DECODING_VALUES.contains(v))
+    }
+
+    /// Fails if `html-template` has an unknown placeholder or unmatched brace.
+    pub fn check_html_template(&self) -> anyhow::Result<()> {
+        let Some(template) = &self.html_template else {
+            return Ok(());
+        };
+        let blank = crate::html::HTML_TEMPLATE_PLACEHOLDERS.map(|name| (name, ""));
+        crate::html::expand_html_template(template, &blank).map(drop)
+    }
+
+    /// `cache-bust-param` is a bare query key: letters, digits, `-`, `_`,
+    /// `.` and `~`.
+    pub fn check_cache_bust_param(&self) -> anyhow::Result<()> {
+        match self.cache_bust_param.as_deref() {
+            Some(param)
+                if param.is_empty()
+                    || !param
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.~".contains(c)) =>
+            {
+                Err(QrError::Config {
+                    field: "cache-bust-param".to_string(),
+                    message: format!(
+                        "'{param}' is not a valid query parameter name; use letters, digits, \
+                         '-', '_', '.' or '~'"
+                    ),
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Warnings for `loading` and `decoding` values outside their sets.
+    pub fn img_hint_warnings(&self) -> Vec<String> {
+        [
+            ("loading", &self.loading, LOADING_VALUES),
+            ("decoding", &self.decoding, DECODING_VALUES),
+        ]
+        .into_iter()
+        .filter_map(|(key, value, allowed)| {
+            let value = value.as_deref()?;
+            (!allowed.contains(&value)).then(|| {
+                format!(
+                    "unknown {key} '{value}', expected one of {}; using the default",
+                    allowed.join(", ")
+                )
+            })
+        })
+        .collect()
+    }
+
+    /// Anchor href for the injected image: the encoded URL for `link = true`.
+    pub fn link_href(&self, url: &str) -> Option<String> {
+        match self.link.as_ref()? {
+            // A non-URL payload is not an address
+            LinkCfg::Enabled(true) => self.payload().is_none().then(|| url.to_string()),
+            LinkCfg::Enabled(false) => None,
+            LinkCfg::Href(href) => Some(href.clone()),
+        }
+    }
+
+    /// Why the module and background colors may not scan: a contrast ratio
+    /// under [`MIN_CONTRAST_RATIO`] or, unless `inverted_ok`, modules lighter
+    /// than the background. A translucent background is taken over the
+    /// backdrop (white unless `transparent-backdrop` is set) and translucent
+    /// modules over the background. Unless `inverted_ok`, light modules on a
+    /// transparent background with no backdrop are reported too. `None` when
+    /// the colors are fine or `suppress-contrast-warning` is set.
+    pub fn contrast_warning(&self, inverted_ok: bool) -> Option<String> {
+        if self.suppress_contrast_warning.unwrap_or(false) {
+            return None;
+        }
+        let module = self.module_rgba();
+        if self.background_rgba()[3] == 0
+            && self.transparent_backdrop.is_none()
+            && !inverted_ok
+            && relative_luminance([module[0], module[1], module[2]]) > 0.5
+        {
+            return Some(
+                "the background is transparent and the modules are light, so the code only \
+                 reads on dark pages; set `transparent-backdrop`"
+                    .to_string(),
+            );
+        }
+        let background = composite(self.background_rgba(), self.backdrop_rgb());
+        let lb = relative_luminance(background);
+        let eyes = [
+            ("eye-frame", &self.eye_frame),
+            ("eye-module", &self.eye_module),
+        ];
+        // A gradient is as readable as its weaker end
+        let modules = match self.gradient_rgba() {
+            Some((from, to)) => vec![
+                ("module-gradient.from", Some(from)),
+                ("module-gradient.to", Some(to)),
+            ],
+            None => vec![("module", Some(module))],
+        };
+        let colors = modules.into_iter().chain(
+            eyes.into_iter()
+                .map(|(key, c)| (key, c.as_ref().and_then(|c| c.to_rgba().ok()))),
+        );
+        let hint = if self.module_gradient.is_some() && !self.verify.unwrap_or(false) {
+            "; a gradient loses contrast towards one end, so consider `verify = true`"
+        } else {
+            ""
+        };
+        for (key, rgba) in colors {
+            let Some(rgba) = rgba else {
+                continue;
+            };
+            let lm = relative_luminance(composite(rgba, background));
+            let ratio = (lb.max(lm) + 0.05) / (lb.min(lm) + 0.05);
+            if ratio < MIN_CONTRAST_RATIO {
+                return Some(format!(
+                    "{key} and background contrast is {ratio:.1}:1 (below {MIN_CONTRAST_RATIO}:1); \
+                     the code may not scan{hint}"
+                ));
+            }
+            if lm > lb && !inverted_ok {
+                let what = if key == "module" {
+                    "modules are"
+                } else {
+                    "the eyes are"
+                };
+                return Some(format!(
+                    "{what} lighter than the background; many scanner apps cannot read \
+                     inverted codes"
+                ));
+            }
+        }
+        None
+    }
+
+    /// Pixels `border` adds on each side of the image (0 when unset).
+    pub fn border_width(&self) -> u32 {
+        self.border.as_ref().map_or(0, |b| b.width)
+    }
+
+    /// The two `module-gradient` stops as RGBA; `None` when unset or either
+    /// does not parse.
+    pub fn gradient_rgba(&self) -> Option<([u8; 4], [u8; 4])> {
+        let g = self.module_gradient.as_ref()?;
+        Some((g.from.to_rgba().ok()?, g.to.to_rgba().ok()?))
+    }
+
+    /// `eye-frame` and `eye-module` as RGBA; `None` keeps the module color.
+    pub fn eye_rgba(&self) -> (Option<[u8; 4]>, Option<[u8; 4]>) {
+        let rgba = |c: &Option<ColorCfg>| c.as_ref().and_then(|c| c.to_rgba().ok());
+        (rgba(&self.eye_frame), rgba(&self.eye_module))
+    }
+
+    /// Background as RGBA for the self-rendered path (white when unset or unparsable).
+    pub fn background_rgba(&self) -> [u8; 4] {
+        self.background
+            .as_ref()
+            .and_then(|c| c.to_rgba().ok())
+            .unwrap_or([255, 255, 255, 255])
+    }
+
+    /// The page color behind a see-through background: `transparent-backdrop`
+    /// made opaque over white, or white.
+    pub fn backdrop_rgb(&self) -> [u8; 3] {
+        self.transparent_backdrop
+            .as_ref()
+            .and_then(|c| c.to_rgba().ok())
+            .map_or([255, 255, 255], |c| composite(c, [255, 255, 255]))
+    }
+
+    /// `transparent-backdrop` as a CSS color, when the background lets it
+    /// show through.
+    pub fn backdrop_css(&self) -> Option<String> {
+        if self.background_rgba()[3] == 255 {
+            return None;
+        }
+        let [r, g, b, a] = self.transparent_backdrop.as_ref()?.to_rgba().ok()?;
+        Some(match a {
+            255 => format!("#{r:02x}{g:02x}{b:02x}"),
+            _ => format!("#{r:02x}{g:02x}{b:02x}{a:02x}"),
+        })
+    }
+
+    /// Module color as RGBA for the self-rendered path (black when unset or unparsable).
+    pub fn module_rgba(&self) -> [u8; 4] {
+        self.module
+            .as_ref()
+            .and_then(|c| c.to_rgba().ok())
+            .unwrap_or([0, 0, 0, 255])
     }
+
+    /// The `chapters` / `exclude-chapters` filter of this profile.
+    pub fn chapter_filter(&self) -> ChapterFilter {
+        ChapterFilter::new(
+            self.chapters.as_deref().unwrap_or_default(),
+            self.exclude_chapters.as_deref().unwrap_or_default(),
+        )
+    }
+
+    /// Encoded text when not a URL: the `wifi` payload, else the `contact`
+    /// vCard, else `data`.
+    pub fn payload(&self) -> Option<String> {
+        match (&self.wifi, &self.contact) {
+            (Some(wifi), _) => Some(crate::payload::wifi(wifi)),
+            (None, Some(contact)) => Some(crate::payload::vcard(contact)),
+            (None, None) => self.data.clone(),
+        }
+    }
+
+    /// Expand `${VAR}` in `url`, `qr-path` and the Wi-Fi password (see
+    /// [`expand_env_vars`]).
+    pub fn expand_env(&mut self) -> anyhow::Result<()> {
+        let password = self.wifi.as_mut().and_then(|w| w.password.as_mut());
+        let mut values: Vec<(String, Option<&mut String>)> = match self.url.as_mut() {
+            Some(UrlCfg::One(url)) => vec![("url".to_string(), Some(url))],
+            Some(UrlCfg::PerRenderer(urls)) => urls
+                .iter_mut()
+                .map(|(renderer, url)| (format!("url.{renderer}"), Some(url)))
+                .collect(),
+            None => Vec::new(),
+        };
+        values.push(("qr-path".to_string(), self.qr_path.as_mut()));
+        values.push(("wifi.password".to_string(), password));
+        for (key, value) in values {
+            if let Some(v) = value {
+                *v = expand_env_vars(v).map_err(|e| anyhow::anyhow!("{key}: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` (default when unset or empty) from
+/// the environment; `$$` is a literal `$`. An unset variable without a
+/// default is an error.
+pub fn expand_env_vars(value: &str) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        let tail = &rest[at + 1..];
+        if let Some(after) = tail.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(body) = tail.strip_prefix('{') else {
+            out.push('$');
+            rest = tail;
+            continue;
+        };
+        let end = body
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("unclosed '${{' in '{value}'"))?;
+        let (name, default) = match body[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&body[..end], None),
+        };
+        let set = std::env::var(name).ok().filter(|v| !v.is_empty());
+        match (set, default) {
+            (Some(v), _) => out.push_str(&v),
+            (None, Some(d)) => out.push_str(d),
+            (None, None) => anyhow::bail!("environment variable {name} is not set"),
+        }
+        rest = &body[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
 }
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct QrConfig {
-    pub enable: Option<bool>,
+    pub enable: Option<EnableCfg>,
+    pub valid_from: Option<IsoDate>,
+    pub valid_until: Option<IsoDate>,
+    pub expired: Option<ExpiredPolicy>,
+    pub expired_text: Option<String>,
     pub localhost_qr: Option<bool>,
-    pub url: Option<String>,
+    pub localhost_port: Option<u16>,
+    pub url: Option<UrlCfg>,
+    #[serde(default)]
+    pub query_params: BTreeMap<String, String>,
+    /// Encode resolved URLs in their parsed, normalized form (default
+    /// port dropped, one trailing slash) rather than as written.
+    #[serde(default)]
+    pub normalize_url: bool,
+    /// Warn about resolved URLs that are not `https://`.
+    #[serde(default)]
+    pub require_https: bool,
+    pub data: Option<String>,
+    pub wifi: Option<WifiCfg>,
+    pub contact: Option<ContactCfg>,
     pub qr_path: Option<String>,
+    pub allow_outside_root: Option<bool>,
     #[serde(default)]
     pub on_failure: FailureMode,
-
+    /// Markers used as link destinations: left alone or replaced by the URL.
+    #[serde(default)]
+    pub link_destinations: UrlSubstitution,
+    /// Markers inside HTML tags (attribute values): left alone or replaced by the URL.
+    #[serde(default)]
+    pub in_attributes: UrlSubstitution,
+    /// Chapters (paths relative to `src`) produced by other tools on each
+    /// build, e.g. `["generated/**"]`; existence-based guards skip them.
+    #[serde(default)]
+    pub generated_chapter_globs: Vec<String>,
+    /// Text after `{{` that identifies a marker; unconfigured ones such as
+    /// `{{QR_OLD}}` are reported. Empty → no check. Default `QR`.
+    pub marker_prefix: Option<String>,
+    /// Fail the build when a configured marker is left in the book (outside
+    /// code); otherwise a single warning lists them.
     #[serde(default)]
-    pub include_default: bool,
+    pub strict: bool,
+    /// Only these chapters (globs over paths relative to `src`) are scanned
+    /// and injected; empty → the whole book.
+    #[serde(default)]
+    pub chapters: Vec<String>,
+    /// Leave markers inside `$$...$$` and `$...$` math alone (default true).
+    pub skip_math: Option<bool>,
+    /// Also match markers written with whitespace inside the braces,
+    /// `{{ QR_CODE }}`.
+    #[serde(default)]
+    pub lenient_markers: bool,
+    /// Also replace markers in chapter names (sidebar, page title), with
+    /// `name-replacement` rather than an image.
+    #[serde(default)]
+    pub replace_in_names: bool,
+    /// What a marker in a chapter name becomes under `replace-in-names`.
+    #[serde(default)]
+    pub name_replacement: String,
+    /// Annotate injected images with an HTML comment (HTML renderer only).
+    pub debug_comments: Option<DebugCommentsCfg>,
+
+    /// Generate the implicit `{{QR_CODE}}` profile. Unset → decided by
+    /// [`QrConfig::include_default_profile`].
+    pub include_default: Option<bool>,
     #[serde(default)]
     pub fit: FitConfig,
-    pub margin: Option<u32>,
+    pub scale: Option<u32>,
+    pub min_module_px: Option<f32>,
+    pub margin: Option<MarginCfg>,
+    pub shape: Option<ShapeCfg>,
     #[serde(default)]
-    pub shape: ShapeFlags,
+    pub style: StyleCfg,
     pub background: Option<ColorCfg>,
+    pub transparent_backdrop: Option<ColorCfg>,
     pub module: Option<ColorCfg>,
+    pub eye_frame: Option<ColorCfg>,
+    pub eye_module: Option<ColorCfg>,
+    pub module_gradient: Option<GradientCfg>,
+    pub eye_shape: Option<String>,
+    #[serde(default)]
+    pub dark: DarkCfg,
+    pub ecl: Option<Ecl>,
+    pub logo: Option<String>,
+    pub logo_scale: Option<f32>,
+    pub logo_padding: Option<u32>,
+    pub border: Option<BorderCfg>,
+    pub verify: Option<bool>,
+    pub suppress_contrast_warning: Option<bool>,
+    pub output: Option<OutputMode>,
+    pub text_invert: Option<bool>,
+    pub alt: Option<String>,
+    pub title: Option<String>,
+    pub link: Option<LinkCfg>,
+    pub link_target: Option<String>,
+    pub caption: Option<String>,
+    pub caption_position: Option<CaptionPosition>,
+    pub size_mode: Option<SizeMode>,
+    pub loading: Option<String>,
+    pub decoding: Option<String>,
+    pub html_template: Option<String>,
+    pub srcset: Option<Vec<u32>>,
+    pub cache_bust: Option<CacheBust>,
+    pub cache_bust_param: Option<String>,
+    pub exclude_chapters: Option<Vec<String>>,
+    pub strip_unmatched: Option<bool>,
+    pub class: Option<String>,
+    #[serde(default)]
+    pub attrs: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub asset_limits: AssetLimits,
+    /// Use the persistent write cache (default true).
+    pub cache: Option<bool>,
+    /// Delete images earlier runs generated that this run no longer does
+    /// (unset → true).
+    pub cleanup: Option<bool>,
+    /// Replace existing PNGs that no manifest or write cache lists, such as
+    /// images from before mdbook-qr kept a manifest (default false).
+    #[serde(default)]
+    pub overwrite_existing: bool,
+    /// `plain` (default) or `hashed`: names of images without a `qr-path`.
+    #[serde(default)]
+    pub path_style: PathStyle,
+    /// `src` (default) or `root`: the directory image paths are relative to.
+    #[serde(default)]
+    pub output_base: OutputBase,
+    /// Re-encode every PNG with pinned settings and no ancillary chunks, and
+    /// log each image's blake3 hash at info level.
+    #[serde(default)]
+    pub deterministic: bool,
+    /// `warn` (default), `error` or `suffix`.
+    #[serde(default)]
+    pub collision: CollisionPolicy,
+    /// `warn` (default), `error`, `first-wins` or `last-wins`.
+    #[serde(default)]
+    pub duplicate_markers: DuplicatePolicy,
+    /// `keep` (default), `warn`, `strip` or `alt`.
+    #[serde(default)]
+    pub draft_markers: DraftPolicy,
+    /// `warn` (default) or `error` when a code is drawn too small to scan.
+    #[serde(default)]
+    pub low_density: DensityPolicy,
+    /// ed25519 key (relative to the book root) used to sign each image into a
+    /// `.provenance.json` sidecar. Needs the `sign` feature.
+    pub sign_key: Option<String>,
+    /// JSON file (relative to the book root) the end-of-run summary is
+    /// written to.
+    pub report_path: Option<String>,
+    /// JSON file (relative to the book root) listing every injected image
+    /// by marker: its URL, path, hash, fit and chapters.
+    pub manifest: Option<String>,
+    /// Renderers to run for (`["html"]`); unset → every renderer. Under any
+    /// other renderer the book passes through untouched.
+    pub renderers: Option<Vec<String>>,
+    /// Pass the book through untouched for every renderer but `html`, such
+    /// as `mdbook test` and linkcheck.
+    #[serde(default)]
+    pub skip_non_html: bool,
+    /// Resolve URLs and paths and scan for markers, then log what would be
+    /// written and replaced instead of doing it.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// `[preprocessor.qr.renderer.<name>]`: profile settings merged over
+    /// every profile while that renderer is active.
+    #[serde(default)]
+    pub renderer: BTreeMap<String, Profile>,
 
     #[serde(default)]
     pub custom: std::collections::BTreeMap<String, Profile>,
+    /// Defaults directly under `[preprocessor.qr.custom]` (`custom.background`,
+    /// `custom.fit`) that every named custom inherits; `None` without a
+    /// `custom` table. Filled in by [`QrConfig::from_value_reporting`].
+    #[serde(skip)]
+    pub custom_defaults: Option<Profile>,
 }
 
 impl Default for QrConfig {
     fn default() -> Self {
         Self {
-            enable: Some(true),
+            enable: Some(EnableCfg::Flag(true)),
+            valid_from: None,
+            valid_until: None,
+            expired: None,
+            expired_text: None,
             localhost_qr: Some(false),
+            localhost_port: None,
             url: None,
+            query_params: BTreeMap::new(),
+            normalize_url: false,
+            require_https: false,
+            data: None,
+            wifi: None,
+            contact: None,
             qr_path: None,
+            allow_outside_root: None,
             on_failure: FailureMode::Continue,
-            include_default: true,
+            link_destinations: UrlSubstitution::Skip,
+            in_attributes: UrlSubstitution::Skip,
+            generated_chapter_globs: Vec::new(),
+            marker_prefix: None,
+            strict: false,
+            chapters: Vec::new(),
+            skip_math: None,
+            lenient_markers: false,
+            replace_in_names: false,
+            name_replacement: String::new(),
+            debug_comments: None,
+            include_default: None,
             fit: FitConfig::default(),
-            margin: Some(2),
-            shape: ShapeFlags::default(),
+            scale: None,
+            min_module_px: None,
+            margin: Some(MarginCfg::Uniform(DEFAULT_MARGIN as i64)),
+            shape: None,
+            style: StyleCfg::default(),
             background: Some(ColorCfg::Hex("#FFFFFFFF".into())),
+            transparent_backdrop: None,
             module: Some(ColorCfg::Hex("#000000FF".into())),
+            eye_frame: None,
+            eye_module: None,
+            module_gradient: None,
+            eye_shape: None,
+            dark: DarkCfg::default(),
+            ecl: None,
+            logo: None,
+            logo_scale: None,
+            logo_padding: None,
+            border: None,
+            verify: None,
+            suppress_contrast_warning: None,
+            output: None,
+            text_invert: None,
+            alt: None,
+            title: None,
+            link: None,
+            link_target: None,
+            caption: None,
+            caption_position: None,
+            size_mode: None,
+            loading: None,
+            decoding: None,
+            html_template: None,
+            srcset: None,
+            cache_bust: None,
+            cache_bust_param: None,
+            exclude_chapters: None,
+            strip_unmatched: None,
+            class: None,
+            attrs: BTreeMap::new(),
+            asset_limits: AssetLimits::default(),
+            cache: None,
+            cleanup: None,
+            overwrite_existing: false,
+            path_style: PathStyle::Plain,
+            output_base: OutputBase::Src,
+            deterministic: false,
+            collision: CollisionPolicy::Warn,
+            duplicate_markers: DuplicatePolicy::Warn,
+            draft_markers: DraftPolicy::Keep,
+            low_density: DensityPolicy::Warn,
+            sign_key: None,
+            report_path: None,
+            manifest: None,
+            renderers: None,
+            skip_non_html: false,
+            dry_run: false,
+            renderer: BTreeMap::new(),
             custom: Default::default(),
+            custom_defaults: None,
         }
     }
 }
 
+/// Keys mdBook itself reads from every `[preprocessor.*]` table.
+const MDBOOK_PREPROCESSOR_KEYS: &[&str] = &["command", "before", "after", "renderers", "optional"];
+
+/// `[preprocessor.qr.custom]` keys holding tables of defaults for every
+/// custom rather than a named custom.
+const CUSTOM_DEFAULT_TABLES: &[&str] = &[
+    "attrs",
+    "border",
+    "contact",
+    "dark",
+    "fit",
+    "margin",
+    "module-gradient",
+    "query-params",
+    "shape",
+    "style",
+    "wifi",
+];
+
+/// Deserialize `value`, appending the path of every key no field accepts to
+/// `unknown`. Errors name the offending key. `prefix` is prepended to paths.
+fn deserialize_reporting<'de, D, T>(
+    value: D,
+    prefix: &str,
+    unknown: &mut Vec<String>,
+) -> anyhow::Result<T>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let mut ignored = |path: serde_ignored::Path| {
+        let path = format!("{prefix}{path}");
+        if !MDBOOK_PREPROCESSOR_KEYS.contains(&path.as_str()) {
+            unknown.push(path);
+        }
+    };
+    serde_path_to_error::deserialize(serde_ignored::Deserializer::new(value, &mut ignored)).map_err(
+        |e| match e.path().to_string().as_str() {
+            "." => anyhow::anyhow!("{}", e.inner()),
+            path => QrError::Config {
+                field: format!("{prefix}{path}"),
+                message: e.inner().to_string(),
+            }
+            .into(),
+        },
+    )
+}
+
+impl Profile {
+    /// Deserialize one profile table, also returning the dotted path of every
+    /// key no field accepts.
+    pub fn from_value_reporting(
+        value: serde_json::Value,
+    ) -> anyhow::Result<(Profile, Vec<String>)> {
+        let mut unknown = Vec::new();
+        let profile = deserialize_reporting(value, "", &mut unknown)?;
+        Ok((profile, unknown))
+    }
+}
+
 impl QrConfig {
+    /// [`QrConfig::from_value_reporting`] for the `[preprocessor.qr]` table
+    /// mdBook parsed. mdBook links an older `toml`, so the table is copied
+    /// into this crate's [`toml::Value`] first. Serializing turns dates into
+    /// `toml`'s private one-key tables; deserializing a `Value` from that
+    /// copy turns them back, so every TOML type survives.
+    pub fn from_table_reporting<T: Serialize>(
+        table: &T,
+    ) -> anyhow::Result<(QrConfig, Vec<String>)> {
+        let copy = toml::Value::deserialize(toml::Value::try_from(table)?)?;
+        Self::from_value_reporting(copy)
+    }
+
+    /// Deserialize `[preprocessor.qr]`, also returning the dotted path of
+    /// every key no field accepts (`margn`, `custom.flyer.fitt`). Keys mdBook
+    /// itself reads are not reported. Defaults directly under `custom`
+    /// (`custom.background`, `custom.fit`) are checked as profile keys.
+    pub fn from_value_reporting(mut value: toml::Value) -> anyhow::Result<(QrConfig, Vec<String>)> {
+        let mut unknown = Vec::new();
+        let mut defaults = None;
+        if let Some(custom) = value.get_mut("custom").and_then(|c| c.as_table_mut()) {
+            let keys: Vec<String> = custom
+                .iter()
+                .filter(|(k, v)| !v.is_table() || CUSTOM_DEFAULT_TABLES.contains(&k.as_str()))
+                .map(|(k, _)| k.clone())
+                .collect();
+            let mut table = toml::Table::new();
+            for key in keys {
+                if let Some(v) = custom.remove(&key) {
+                    table.insert(key, v);
+                }
+            }
+            defaults = Some(table);
+        }
+        let mut cfg: QrConfig = deserialize_reporting(value, "", &mut unknown)?;
+        if let Some(table) = defaults {
+            cfg.custom_defaults = Some(deserialize_reporting(
+                toml::Value::Table(table),
+                "custom.",
+                &mut unknown,
+            )?);
+        }
+        Ok((cfg, unknown))
+    }
+
     pub fn is_enabled(&self) -> bool {
-        self.enable.unwrap_or(true)
+        self.enable.as_ref().is_none_or(EnableCfg::is_on)
+    }
+
+    /// Whether to run for `renderer`: it is listed in `renderers`, or that
+    /// is unset, and it is `html` under `skip-non-html`.
+    pub fn runs_for(&self, renderer: &str) -> bool {
+        (!self.skip_non_html || renderer == "html")
+            && self
+                .renderers
+                .as_ref()
+                .is_none_or(|names| names.iter().any(|n| n == renderer))
+    }
+
+    /// Directory (relative to the book root) that image paths, the `qr/`
+    /// directory and the cache files are under: `src_dir` or the root itself.
+    pub fn output_dir(&self, src_dir: &Path) -> PathBuf {
+        match self.output_base {
+            OutputBase::Src => src_dir.to_path_buf(),
+            OutputBase::Root => PathBuf::new(),
+        }
+    }
+
+    /// How chapters are scanned for markers.
+    pub fn syntax(&self) -> Syntax {
+        Syntax {
+            math: self.skip_math.unwrap_or(true),
+            lenient: self.lenient_markers,
+        }
+    }
+
+    /// Whether the implicit `{{QR_CODE}}` profile is built.
+    /// An explicit `include-default` always wins; when unset, a bare
+    /// `[preprocessor.qr.custom]` table with no named subtables suppresses it.
+    pub fn include_default_profile(&self, has_bare_custom: bool) -> bool {
+        self.include_default.unwrap_or(!has_bare_custom)
     }
 
     pub fn default_profile(&self) -> Profile {
         Profile {
-            enable: self.enable,
+            enable: self.enable.clone(),
+            valid_from: self.valid_from,
+            valid_until: self.valid_until,
+            expired: self.expired,
+            expired_text: self.expired_text.clone(),
             localhost_qr: self.localhost_qr,
-            marker: Some("{{QR_CODE}}".to_string()),
+            localhost_port: self.localhost_port,
+            marker: Some(MarkerCfg::One("{{QR_CODE}}".to_string())),
             qr_path: self.qr_path.clone(),
+            allow_outside_root: self.allow_outside_root,
             url: self.url.clone(),
+            query_params: self.query_params.clone(),
+            data: self.data.clone(),
+            wifi: self.wifi.clone(),
+            contact: self.contact.clone(),
+            // Unset so bare custom defaults can still apply; run_impl falls back to the global mode
+            on_failure: None,
             fit: self.fit.clone(),
-            margin: self.margin,
+            scale: self.scale,
+            min_module_px: self.min_module_px,
+            margin: self.margin.clone(),
             shape: self.shape.clone(),
+            style: self.style.clone(),
             background: self.background.clone(),
+            transparent_backdrop: self.transparent_backdrop.clone(),
             module: self.module.clone(),
+            eye_frame: self.eye_frame.clone(),
+            eye_module: self.eye_module.clone(),
+            module_gradient: self.module_gradient.clone(),
+            eye_shape: self.eye_shape.clone(),
+            dark: self.dark.clone(),
+            ecl: self.ecl,
+            logo: self.logo.clone(),
+            logo_scale: self.logo_scale,
+            logo_padding: self.logo_padding,
+            border: self.border.clone(),
+            verify: self.verify,
+            suppress_contrast_warning: self.suppress_contrast_warning,
+            output: self.output,
+            text_invert: self.text_invert,
+            alt: self.alt.clone(),
+            title: self.title.clone(),
+            link: self.link.clone(),
+            link_target: self.link_target.clone(),
+            caption: self.caption.clone(),
+            caption_position: self.caption_position,
+            size_mode: self.size_mode,
+            loading: self.loading.clone(),
+            decoding: self.decoding.clone(),
+            html_template: self.html_template.clone(),
+            srcset: self.srcset.clone(),
+            cache_bust: self.cache_bust,
+            cache_bust_param: self.cache_bust_param.clone(),
+            // The top-level `chapters` is the book-wide allowlist
+            chapters: None,
+            exclude_chapters: self.exclude_chapters.clone(),
+            strip_unmatched: self.strip_unmatched,
+            class: self.class.clone(),
+            attrs: self.attrs.clone(),
         }
     }
 
     /// Inherit missing presentation fields from `base`. Marker & qr_path do NOT inherit.
     pub(crate) fn inherit(base: &Profile, child: &Profile) -> Profile {
+        // A child's own payload replaces an inherited one
+        let own_payload = child.url.is_some()
+            || child.data.is_some()
+            || child.wifi.is_some()
+            || child.contact.is_some();
         Profile {
-            enable: child.enable.or(base.enable),
+            enable: child.enable.clone().or_else(|| base.enable.clone()),
+            valid_from: child.valid_from.or(base.valid_from),
+            valid_until: child.valid_until.or(base.valid_until),
+            expired: child.expired.or(base.expired),
+            expired_text: child
+                .expired_text
+                .clone()
+                .or_else(|| base.expired_text.clone()),
             localhost_qr: child.localhost_qr.or(base.localhost_qr),
+            localhost_port: child.localhost_port.or(base.localhost_port),
             marker: child.marker.clone(),
             qr_path: child.qr_path.clone(),
+            allow_outside_root: child.allow_outside_root.or(base.allow_outside_root),
             url: child.url.clone().or_else(|| base.url.clone()),
+            // Per parameter: the child's value wins
+            query_params: base
+                .query_params
+                .iter()
+                .chain(&child.query_params)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            data: child
+                .data
+                .clone()
+                .or_else(|| base.data.clone().filter(|_| !own_payload)),
+            wifi: child
+                .wifi
+                .clone()
+                .or_else(|| base.wifi.clone().filter(|_| !own_payload)),
+            contact: child
+                .contact
+                .clone()
+                .or_else(|| base.contact.clone().filter(|_| !own_payload)),
+            on_failure: child.on_failure.clone().or_else(|| base.on_failure.clone()),
             fit: FitConfig {
                 width: child.fit.width.or(base.fit.width),
                 height: child.fit.height.or(base.fit.height),
             },
-            margin: child.margin.or(base.margin),
-            shape: if child.shape.any_set() {
-                child.shape.clone()
-            } else {
-                base.shape.clone()
+            scale: child.scale.or(base.scale),
+            min_module_px: child.min_module_px.or(base.min_module_px),
+            margin: MarginCfg::inherit(&child.margin, &base.margin),
+            shape: child
+                .shape
+                .clone()
+                .filter(ShapeCfg::is_set)
+                .or_else(|| base.shape.clone()),
+            style: StyleCfg {
+                data_shape: child
+                    .style
+                    .data_shape
+                    .clone()
+                    .or_else(|| base.style.data_shape.clone()),
+                finder_shape: child
+                    .style
+                    .finder_shape
+                    .clone()
+                    .or_else(|| base.style.finder_shape.clone()),
+                alignment_shape: child
+                    .style
+                    .alignment_shape
+                    .clone()
+                    .or_else(|| base.style.alignment_shape.clone()),
             },
             background: child.background.clone().or_else(|| base.background.clone()),
+            transparent_backdrop: child
+                .transparent_backdrop
+                .clone()
+                .or_else(|| base.transparent_backdrop.clone()),
             module: child.module.clone().or_else(|| base.module.clone()),
+            eye_frame: child.eye_frame.clone().or_else(|| base.eye_frame.clone()),
+            eye_module: child.eye_module.clone().or_else(|| base.eye_module.clone()),
+            module_gradient: child
+                .module_gradient
+                .clone()
+                .or_else(|| base.module_gradient.clone()),
+            eye_shape: child.eye_shape.clone().or_else(|| base.eye_shape.clone()),
+            dark: DarkCfg {
+                background: child
+                    .dark
+                    .background
+                    .clone()
+                    .or_else(|| base.dark.background.clone()),
+                module: child
+                    .dark
+                    .module
+                    .clone()
+                    .or_else(|| base.dark.module.clone()),
+            },
+            ecl: child.ecl.or(base.ecl),
+            logo: child.logo.clone().or_else(|| base.logo.clone()),
+            logo_scale: child.logo_scale.or(base.logo_scale),
+            logo_padding: child.logo_padding.or(base.logo_padding),
+            border: child.border.clone().or_else(|| base.border.clone()),
+            verify: child.verify.or(base.verify),
+            suppress_contrast_warning: child
+                .suppress_contrast_warning
+                .or(base.suppress_contrast_warning),
+            output: child.output.or(base.output),
+            text_invert: child.text_invert.or(base.text_invert),
+            alt: child.alt.clone().or_else(|| base.alt.clone()),
+            title: child.title.clone().or_else(|| base.title.clone()),
+            link: child.link.clone().or_else(|| base.link.clone()),
+            link_target: child
+                .link_target
+                .clone()
+                .or_else(|| base.link_target.clone()),
+            caption: child.caption.clone().or_else(|| base.caption.clone()),
+            caption_position: child.caption_position.or(base.caption_position),
+            size_mode: child.size_mode.or(base.size_mode),
+            loading: child.loading.clone().or_else(|| base.loading.clone()),
+            decoding: child.decoding.clone().or_else(|| base.decoding.clone()),
+            html_template: child
+                .html_template
+                .clone()
+                .or_else(|| base.html_template.clone()),
+            srcset: child.srcset.clone().or_else(|| base.srcset.clone()),
+            cache_bust: child.cache_bust.or(base.cache_bust),
+            cache_bust_param: child
+                .cache_bust_param
+                .clone()
+                .or_else(|| base.cache_bust_param.clone()),
+            chapters: child.chapters.clone().or_else(|| base.chapters.clone()),
+            exclude_chapters: child
+                .exclude_chapters
+                .clone()
+                .or_else(|| base.exclude_chapters.clone()),
+            strip_unmatched: child.strip_unmatched.or(base.strip_unmatched),
+            class: child.class.clone().or_else(|| base.class.clone()),
+            // Per attribute: the child's value wins
+            attrs: base
+                .attrs
+                .iter()
+                .chain(&child.attrs)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        }
+    }
+
+    /// Warn about tables setting more than one of `wifi`, `contact`, `data`
+    /// and `url`;
+    /// the first of those is encoded.
+    pub fn warn_conflicting_payloads(&self) {
+        let top = (
+            "[preprocessor.qr]".to_string(),
+            [
+                self.wifi.is_some(),
+                self.contact.is_some(),
+                self.data.is_some(),
+                self.url.is_some(),
+            ],
+        );
+        let customs = self.custom.iter().map(|(name, p)| {
+            (
+                format!("custom '{name}'"),
+                [
+                    p.wifi.is_some(),
+                    p.contact.is_some(),
+                    p.data.is_some(),
+                    p.url.is_some(),
+                ],
+            )
+        });
+        for (table, set) in std::iter::once(top).chain(customs) {
+            let keys: Vec<&str> = ["`wifi`", "`contact`", "`data`", "`url`"]
+                .into_iter()
+                .zip(set)
+                .filter_map(|(key, set)| set.then_some(key))
+                .collect();
+            if keys.len() > 1 {
+                warn!(
+                    "mdbook-qr: {table} sets {}; encoding {}.",
+                    keys.join(" and "),
+                    keys[0]
+                );
+            }
         }
     }
 
     /// WARN ONCE about invalid customs (marker missing). Does not build profiles.
     pub fn warn_invalid_customs(&self) {
         for (name, p) in &self.custom {
-            if p.marker.is_none() {
+            if p.primary_marker().is_none() {
                 warn!("custom '{name}' has no `marker`; skipping.");
             }
         }
@@ -225,29 +2403,227 @@ impl QrConfig {
         let base = self.default_profile();
         let mut out = Vec::with_capacity(1 + self.custom.len());
 
-        if self.include_default {
+        if self.include_default_profile(false) {
             out.push(base.clone());
         }
         for (_name, p) in &self.custom {
-            if p.marker.is_some() {
+            if p.primary_marker().is_some() {
                 out.push(Self::inherit(&base, p));
             }
         }
         out
     }
 
-    /// Check duplicates among a slice of already-built profiles (valid only).
-    pub fn duplicate_marker_from<'a>(
-        profiles: impl IntoIterator<Item = &'a Profile>,
-    ) -> Option<String> {
-        let mut seen: HashSet<String> = HashSet::new();
-        for p in profiles {
-            if let Some(m) = p.marker.as_ref() {
-                if !seen.insert(m.clone()) {
-                    return Some(m.clone());
+    /// Markers, aliases included, that several of the built profiles
+    /// configure, each with the keys of those profiles in order.
+    pub fn duplicate_markers(profiles: &[(String, Profile)]) -> BTreeMap<String, Vec<String>> {
+        let mut users: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (key, p) in profiles {
+            for m in p.markers() {
+                let keys = users.entry(m.to_string()).or_default();
+                if !keys.contains(key) {
+                    keys.push(key.clone());
                 }
             }
         }
-        None
+        users.retain(|_, keys| keys.len() > 1);
+        users
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> anyhow::Result<(QrConfig, Vec<String>)> {
+        QrConfig::from_value_reporting(toml::Value::Table(toml::from_str(toml).unwrap()))
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_path() {
+        let (cfg, unknown) = parse(
+            "command = 'x'\nmargn = 4\n[custom]\nbackgrond = '#fff'\n[custom.a]\nmarker = '{{A}}'\nfitt = 2\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.custom.len(), 1);
+        assert_eq!(unknown, ["custom.a.fitt", "margn", "custom.backgrond"]);
+    }
+
+    #[test]
+    fn errors_name_the_key_and_the_toml_type() {
+        let e = parse("fit = 'wide'\n").unwrap_err().to_string();
+        assert!(e.starts_with("fit: invalid type: string \"wide\""), "{e}");
+        let e = parse("[custom.a]\nmargin = 'x'\n").unwrap_err().to_string();
+        assert!(e.starts_with("custom.a.margin"), "{e}");
+        let e = parse("[custom]\nmargin = 'x'\n").unwrap_err().to_string();
+        assert!(e.starts_with("custom.margin"), "{e}");
+    }
+
+    #[test]
+    fn dates_may_be_strings_or_bare_toml_dates() {
+        let (quoted, _) = parse("valid-from = '2025-01-15'\n").unwrap();
+        let (bare, _) = parse("valid-from = 2025-01-15\n").unwrap();
+        assert_eq!(quoted.valid_from, bare.valid_from);
+        assert_eq!(bare.valid_from.unwrap().to_string(), "2025-01-15");
+
+        let e = parse("valid-until = 2025-01-15T10:00:00Z\n")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.starts_with("valid-until: '2025-01-15T10:00:00Z' is not a valid date"),
+            "{e}"
+        );
+        let e = parse("valid-until = '2025-02-30'\n")
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("'2025-02-30' is not a valid date"), "{e}");
+    }
+
+    #[test]
+    fn invalid_colors_name_their_key() {
+        let (cfg, _) = parse("module = 'navy'\nbackground = 'rgb(0 0 0 / 0)'\n").unwrap();
+        cfg.default_profile().check_colors().unwrap();
+
+        let (cfg, _) = parse("[dark]\nmodule = 'nope'\n").unwrap();
+        let e = cfg
+            .default_profile()
+            .check_colors()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.contains("dark.module") && e.contains("'nope' is not a color"),
+            "{e}"
+        );
+    }
+
+    fn vars(value: Option<&'static str>) -> impl Fn(&str) -> Option<String> {
+        move |_| value.map(str::to_string)
+    }
+
+    #[test]
+    fn enable_reads_an_env_string() {
+        let (cfg, _) =
+            parse("[custom.feedback]\nmarker = '{{QR_FEEDBACK}}'\nenable = 'env:QR_FEEDBACK'\n")
+                .unwrap();
+        let enable = cfg.custom["feedback"].enable.clone().unwrap();
+        assert_eq!(enable, EnableCfg::Env(EnvName("QR_FEEDBACK".to_string())));
+        assert!(!enable.resolve(vars(None)));
+        for on in ["1", "true", "YES", "on"] {
+            assert!(enable.resolve(vars(Some(on))), "{on}");
+        }
+        for off in ["0", "false", "no", ""] {
+            assert!(!enable.resolve(vars(Some(off))), "{off}");
+        }
+        // Only the named variable is read
+        let named = |name: &str| (name == "QR_FEEDBACK").then(|| "1".to_string());
+        assert!(cfg.custom["feedback"].is_enabled_with(named));
+        assert!(!EnableCfg::Env(EnvName("OTHER".to_string())).resolve(named));
+    }
+
+    #[test]
+    fn enable_table_has_a_default_for_unset_variables() {
+        let (cfg, _) = parse("enable = { env = 'QR_ON', default = true }\n").unwrap();
+        let profile = cfg.default_profile();
+        assert!(profile.is_enabled_with(vars(None)));
+        assert!(!profile.is_enabled_with(vars(Some("off"))));
+        assert!(profile.is_enabled_with(vars(Some("1"))));
+
+        let (cfg, _) = parse("enable = { env = 'QR_ON' }\n").unwrap();
+        assert!(!cfg.default_profile().is_enabled_with(vars(None)));
+    }
+
+    #[test]
+    fn enable_accepts_bools_and_rejects_other_strings() {
+        let (cfg, _) = parse("enable = false\n").unwrap();
+        assert!(!cfg.default_profile().is_enabled_with(vars(Some("1"))));
+        assert!(parse("")
+            .unwrap()
+            .0
+            .default_profile()
+            .is_enabled_with(vars(None)));
+        for bad in ["enable = 'maybe'\n", "enable = 'env:'\n", "enable = 3\n"] {
+            let e = parse(bad).unwrap_err().to_string();
+            assert!(e.starts_with("enable:"), "{bad}: {e}");
+        }
+        let written = toml::Value::try_from(EnableCfg::Env(EnvName("X".to_string()))).unwrap();
+        assert_eq!(written.as_str(), Some("env:X"));
+    }
+
+    #[test]
+    fn cache_bust_takes_bools_or_names() {
+        for (value, want) in [
+            ("true", CacheBust::Query),
+            ("false", CacheBust::None),
+            ("'query'", CacheBust::Query),
+            ("'filename'", CacheBust::Filename),
+            ("'none'", CacheBust::None),
+        ] {
+            let (cfg, _) = parse(&format!("cache-bust = {value}\n")).unwrap();
+            assert_eq!(cfg.default_profile().cache_bust, Some(want), "{value}");
+        }
+        let e = parse("cache-bust = 'nope'\n").unwrap_err().to_string();
+        assert!(e.contains("\"filename\""), "{e}");
+    }
+
+    #[test]
+    fn cache_bust_param_is_a_bare_query_key() {
+        for good in ["v", "version", "build-id", "a_b.c~1"] {
+            let (cfg, _) = parse(&format!("cache-bust-param = '{good}'\n")).unwrap();
+            cfg.default_profile().check_cache_bust_param().unwrap();
+        }
+        for bad in ["", "ver sion", "v=1", "a&b", "q?", "ключ"] {
+            let (cfg, _) = parse(&format!("cache-bust-param = '{bad}'\n")).unwrap();
+            let e = cfg.default_profile().check_cache_bust_param().unwrap_err();
+            assert!(e.to_string().contains("cache-bust-param"), "{bad}: {e}");
+        }
+        // Profiles inherit the top-level name unless they set their own
+        let (cfg, _) = parse(
+            "cache-bust-param = 'version'\n[custom.a]\nmarker = '{{A}}'\n\
+             [custom.b]\nmarker = '{{B}}'\ncache-bust-param = 'b'\n",
+        )
+        .unwrap();
+        let params: Vec<Option<String>> = cfg
+            .profiles()
+            .into_iter()
+            .map(|p| p.cache_bust_param)
+            .collect();
+        assert_eq!(
+            params,
+            [
+                Some("version".to_string()),
+                Some("version".to_string()),
+                Some("b".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn table_from_mdbook_round_trips() {
+        let config: mdbook::Config = "[preprocessor.qr]\nvalid-from = 2025-01-15\nmargin = 2\n"
+            .parse()
+            .unwrap();
+        let table = config.get_preprocessor("qr").unwrap();
+        let (cfg, unknown) = QrConfig::from_table_reporting(table).unwrap();
+        assert!(unknown.is_empty(), "{unknown:?}");
+        assert_eq!(cfg.valid_from.unwrap().to_string(), "2025-01-15");
+    }
+
+    #[test]
+    fn include_default_wins_over_a_bare_custom_table() {
+        for (toml, bare, expected) in [
+            ("", false, true),
+            ("", true, false),
+            ("include-default = true\n", false, true),
+            ("include-default = true\n", true, true),
+            ("include-default = false\n", false, false),
+            ("include-default = false\n", true, false),
+        ] {
+            let (cfg, _) = parse(toml).unwrap();
+            assert_eq!(
+                cfg.include_default_profile(bare),
+                expected,
+                "{toml:?} with bare custom = {bare}"
+            );
+        }
     }
 }
@@ -0,0 +1,217 @@
+use crate::config::{Profile, QrConfig};
+use crate::html::{find_directives, marker_offsets, Syntax};
+use crate::util::in_scope;
+use anyhow::{anyhow, bail, Result};
+use globset::GlobSet;
+use log::{debug, warn};
+use mdbook::book::{Book, BookItem, Chapter};
+use serde_json::{Map, Value};
+use std::ops::Range;
+
+/// Apply the `<!-- mdbook-qr: key=value ... -->` comments in the chapters in
+/// `scope`. Each comment is removed, and the nearest following marker in its
+/// chapter (in running text, of an enabled profile covering the chapter) is
+/// swapped for a marker of its own. A new profile serves that marker: the
+/// directive's settings over the marker's profile, as [`QrConfig::inherit`]
+/// merges them. New profiles go first, so no other profile sees their markers.
+pub(crate) fn apply_directives(
+    book: &mut Book,
+    scope: &GlobSet,
+    syntax: Syntax,
+    profiles: &mut Vec<(String, Profile)>,
+) {
+    let mut added = Vec::new();
+    book.for_each_mut(|item| match item {
+        BookItem::Chapter(ch) if in_scope(scope, ch) && ch.content.contains("mdbook-qr:") => {
+            added.extend(apply_in_chapter(ch, syntax, profiles));
+        }
+        _ => {}
+    });
+    profiles.splice(0..0, added);
+}
+
+/// [`apply_directives`] for a single chapter; returns the profiles it adds.
+fn apply_in_chapter(
+    ch: &mut Chapter,
+    syntax: Syntax,
+    profiles: &[(String, Profile)],
+) -> Vec<(String, Profile)> {
+    let directives = find_directives(&ch.content, syntax);
+    if directives.is_empty() {
+        return Vec::new();
+    }
+    let source = ch
+        .path
+        .as_ref()
+        .map_or_else(|| ch.name.clone(), |p| p.display().to_string());
+
+    // (offset, profile index, end) of every marker or alias a directive may
+    // apply to
+    let mut occurrences: Vec<(usize, usize, usize)> = Vec::new();
+    for (i, (_, p)) in profiles.iter().enumerate() {
+        if !p.is_enabled() || !p.chapter_filter().matches(ch) {
+            continue;
+        }
+        for marker in p.markers() {
+            let offsets = marker_offsets(&ch.content, marker, syntax);
+            occurrences.extend(offsets.into_iter().map(|at| (at.start, i, at.end)));
+        }
+    }
+    occurrences.sort();
+
+    let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+    let mut added = Vec::new();
+    for (n, directive) in directives.iter().enumerate() {
+        // The comment goes whether or not it can be applied
+        edits.push((directive.range.clone(), String::new()));
+        let line = ch.content[..directive.range.start].matches('\n').count() + 1;
+        let at = format!("{source}:{line}");
+
+        let overrides = match parse_directive(&directive.body) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("mdbook-qr: {at}: ignoring malformed directive: {e}");
+                continue;
+            }
+        };
+        let next = occurrences
+            .iter()
+            .find(|(offset, _, _)| *offset >= directive.range.end);
+        let Some(&(offset, i, end)) = next else {
+            warn!("mdbook-qr: {at}: no marker follows the directive; ignoring it");
+            continue;
+        };
+        if directives
+            .get(n + 1)
+            .is_some_and(|d| d.range.start < offset)
+        {
+            warn!("mdbook-qr: {at}: another directive comes before the next marker; ignoring this one");
+            continue;
+        }
+
+        let (key, base) = &profiles[i];
+        let base_marker = base.primary_marker().unwrap_or_default();
+        let inner = base_marker.trim_start_matches('{').trim_end_matches('}');
+        let marker = format!("{{{{{inner}@{at}}}}}");
+        debug!("mdbook-qr: {at}: directive applies to '{base_marker}'");
+
+        let mut profile = QrConfig::inherit(base, &overrides);
+        profile.marker = Some(marker.clone().into());
+        edits.push((offset..end, marker));
+        added.push((format!("{key}@{at}"), profile));
+    }
+
+    // Comments and markers never overlap; edit back to front
+    edits.sort_by_key(|(range, _)| range.start);
+    for (range, text) in edits.into_iter().rev() {
+        ch.content.replace_range(range, &text);
+    }
+    added
+}
+
+/// The profile settings of a directive body. Keys are those of a profile
+/// table; `marker` is not allowed, and an unknown key makes it malformed.
+fn parse_directive(body: &str) -> Result<Profile> {
+    let (profile, unknown) = Profile::from_value_reporting(parse_pairs(body)?)?;
+    if let Some(key) = unknown.first() {
+        bail!("unknown key '{key}'");
+    }
+    if profile.marker.is_some() {
+        bail!("a directive cannot set `marker`");
+    }
+    Ok(profile)
+}
+
+/// Parse whitespace-separated `key=value` pairs into a table. Values are
+/// `"quoted"` strings (`\"` and `\\` escaped) or bare words read as booleans,
+/// numbers or strings. Dotted keys (`fit.width=200`) build nested tables, and
+/// `fit=N` sets both fit dimensions.
+fn parse_pairs(body: &str) -> Result<Value> {
+    let mut table = Map::new();
+    let mut rest = body.trim_start();
+    while !rest.is_empty() {
+        let eq = rest
+            .find('=')
+            .ok_or_else(|| anyhow!("expected key=value at '{rest}'"))?;
+        let key = &rest[..eq];
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            bail!("expected key=value at '{rest}'");
+        }
+        let after = &rest[eq + 1..];
+        let (mut value, tail) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                parse_quoted(quoted).ok_or_else(|| anyhow!("unterminated quote in '{key}'"))?
+            }
+            None => {
+                let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                if end == 0 {
+                    bail!("missing value for '{key}'");
+                }
+                (bare_value(&after[..end]), &after[end..])
+            }
+        };
+        if !tail.is_empty() && !tail.starts_with(char::is_whitespace) {
+            bail!("expected whitespace after the value of '{key}'");
+        }
+        if key == "fit" && value.is_number() {
+            value = serde_json::json!({ "width": value, "height": value });
+        }
+        insert_dotted(&mut table, key, value)?;
+        rest = tail.trim_start();
+    }
+    Ok(Value::Object(table))
+}
+
+/// A `"`-quoted string whose opening quote was stripped, and what follows
+/// its closing quote; `None` if it is never closed.
+fn parse_quoted(quoted: &str) -> Option<(Value, &str)> {
+    let mut out = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => out.push(chars.next()?.1),
+            '"' => return Some((Value::String(out), &quoted[i + 1..])),
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+/// A bare value: `true`/`false`, a number, or else the word itself.
+fn bare_value(word: &str) -> Value {
+    match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => word
+            .parse::<u64>()
+            .map(Value::from)
+            .ok()
+            .or_else(|| {
+                word.parse::<f64>()
+                    .ok()
+                    .and_then(|f| serde_json::Number::from_f64(f).map(Value::Number))
+            })
+            .unwrap_or_else(|| Value::String(word.to_string())),
+    }
+}
+
+/// Set `key` (possibly dotted) in `table`; setting a key twice is an error.
+fn insert_dotted(table: &mut Map<String, Value>, key: &str, value: Value) -> Result<()> {
+    match key.split_once('.') {
+        None => {
+            if table.insert(key.to_string(), value).is_some() {
+                bail!("'{key}' is set twice");
+            }
+        }
+        Some((head, tail)) => {
+            let entry = table
+                .entry(head.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            let Some(inner) = entry.as_object_mut() else {
+                bail!("'{head}' is both a value and a table");
+            };
+            insert_dotted(inner, tail, value)?;
+        }
+    }
+    Ok(())
+}
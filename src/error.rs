@@ -0,0 +1,136 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Failures a caller may want to tell apart. [`generate`](crate::generate)
+/// and the `run_preprocessor_*` functions return them directly; inside the
+/// crate they travel in `anyhow::Error`, and so mdBook's `Error`, with
+/// context added on the way up, and [`QrError::find`] recovers one from such
+/// an error.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum QrError {
+    /// No URL could be worked out for a profile, or its URL template does
+    /// not expand.
+    #[error("{0}")]
+    UrlResolution(String),
+    /// The payload could not be drawn as a QR code or encoded as a PNG.
+    #[error("{0}")]
+    ImageEncode(String),
+    /// Reading or writing `path` failed.
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// `field` (a dotted key of `[preprocessor.qr]`) has an invalid value.
+    #[error("{field}: {message}")]
+    Config { field: String, message: String },
+    /// Configured markers were left in the book under `strict`; one
+    /// `<chapter>: <marker> (<count>x)` entry each.
+    #[error("markers left unreplaced (strict = true):\n  {}", .0.join("\n  "))]
+    UnreplacedMarkers(Vec<String>),
+    /// Markers with the `marker-prefix` that no profile is configured for
+    /// were found under `strict`; one `<chapter>: <marker>` entry each.
+    #[error("unknown markers (strict = true):\n  {}", .0.join("\n  "))]
+    MarkerNotFound(Vec<String>),
+    /// `source`, raised while doing what `context` says.
+    #[error("{context}: {source}")]
+    Context {
+        context: String,
+        #[source]
+        source: Box<QrError>,
+    },
+    /// Any other failure, with its context, as one message.
+    #[error("{0}")]
+    Other(String),
+}
+
+/// At the library's edge: the `QrError` inside `err`, under its context,
+/// or [`QrError::Other`] when there is none.
+impl From<anyhow::Error> for QrError {
+    fn from(err: anyhow::Error) -> Self {
+        // Context messages above the `QrError`, outermost first
+        let context: Vec<String> = err
+            .chain()
+            .take_while(|e| !e.is::<QrError>())
+            .map(ToString::to_string)
+            .collect();
+        match err.downcast::<QrError>() {
+            Ok(inner) => {
+                context
+                    .into_iter()
+                    .rev()
+                    .fold(inner, |source, context| QrError::Context {
+                        context,
+                        source: Box::new(source),
+                    })
+            }
+            Err(err) => QrError::Other(format!("{err:#}")),
+        }
+    }
+}
+
+impl QrError {
+    /// The `QrError` behind `err`, under any context added to it.
+    pub fn find(err: &anyhow::Error) -> Option<&QrError> {
+        err.chain().find_map(|e| e.downcast_ref::<QrError>())
+    }
+
+    /// The failure itself, under any [`QrError::Context`].
+    pub fn kind(&self) -> &QrError {
+        match self {
+            QrError::Context { source, .. } => source.kind(),
+            other => other,
+        }
+    }
+
+    /// For `map_err`: a [`QrError::Io`] about `path`.
+    pub(crate) fn io(path: &Path) -> impl FnOnce(io::Error) -> QrError + '_ {
+        move |source| QrError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn conversion_keeps_the_kind_under_its_context() {
+        let err = Err::<(), _>(QrError::UrlResolution("no URL".to_string()))
+            .context("resolving '{{QR_CODE}}'")
+            .context("mdbook-qr")
+            .unwrap_err();
+        let err = QrError::from(err);
+        assert!(matches!(err.kind(), QrError::UrlResolution(m) if m == "no URL"));
+        assert_eq!(
+            err.to_string(),
+            "mdbook-qr: resolving '{{QR_CODE}}': no URL"
+        );
+    }
+
+    #[test]
+    fn conversion_without_a_kind_is_other() {
+        let err = Err::<(), _>(anyhow::anyhow!("bad input"))
+            .context("parsing")
+            .unwrap_err();
+        let err = QrError::from(err);
+        assert!(matches!(err.kind(), QrError::Other(m) if m == "parsing: bad input"));
+    }
+
+    #[test]
+    fn io_errors_name_their_path() {
+        let err = QrError::io(Path::new("qr/a.png"))(io::ErrorKind::NotFound.into());
+        assert!(err.to_string().starts_with("qr/a.png: "), "{err}");
+        let wrapped = anyhow::Error::from(err).context("writing");
+        assert!(matches!(
+            QrError::find(&wrapped),
+            Some(QrError::Io { path, .. }) if path == Path::new("qr/a.png")
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+/// Functional class of a module position within a QR symbol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    /// The three 7×7 position-detection patterns in the corners.
+    Finder,
+    /// 5×5 alignment patterns (version 2 and up).
+    Alignment,
+    /// Everything else: data, ECC, timing and format modules.
+    Data,
+}
+
+/// QR version (1..=40) for a symbol of `size` modules per side.
+pub fn version_from_size(size: usize) -> usize {
+    size.saturating_sub(17) / 4
+}
+
+/// Row/column coordinates of alignment pattern centres for `version`
+/// (ISO/IEC 18004, Annex E). Version 1 has none.
+pub fn alignment_coords(version: usize) -> &'static [usize] {
+    const TABLE: [&[usize]; 41] = [
+        &[],
+        &[],
+        &[6, 18],
+        &[6, 22],
+        &[6, 26],
+        &[6, 30],
+        &[6, 34],
+        &[6, 22, 38],
+        &[6, 24, 42],
+        &[6, 26, 46],
+        &[6, 28, 50],
+        &[6, 30, 54],
+        &[6, 32, 58],
+        &[6, 34, 62],
+        &[6, 26, 46, 66],
+        &[6, 26, 48, 70],
+        &[6, 26, 50, 74],
+        &[6, 30, 54, 78],
+        &[6, 30, 56, 82],
+        &[6, 30, 58, 86],
+        &[6, 34, 62, 90],
+        &[6, 28, 50, 72, 94],
+        &[6, 26, 50, 74, 98],
+        &[6, 30, 54, 78, 102],
+        &[6, 28, 54, 80, 106],
+        &[6, 32, 58, 84, 110],
+        &[6, 30, 58, 86, 114],
+        &[6, 34, 62, 90, 118],
+        &[6, 26, 50, 74, 98, 122],
+        &[6, 30, 54, 78, 102, 126],
+        &[6, 26, 52, 78, 104, 130],
+        &[6, 30, 56, 82, 108, 134],
+        &[6, 34, 60, 86, 112, 138],
+        &[6, 30, 58, 86, 114, 142],
+        &[6, 34, 62, 90, 118, 146],
+        &[6, 30, 54, 78, 102, 126, 150],
+        &[6, 24, 50, 76, 102, 128, 154],
+        &[6, 28, 54, 80, 106, 132, 158],
+        &[6, 32, 58, 84, 110, 136, 162],
+        &[6, 26, 54, 82, 110, 138, 166],
+        &[6, 30, 58, 86, 114, 142, 170],
+    ];
+    TABLE.get(version).copied().unwrap_or(&[])
+}
+
+/// Centres of all alignment patterns for `version`, skipping the three that
+/// would overlap a finder pattern.
+pub fn alignment_centers(version: usize) -> Vec<(usize, usize)> {
+    let coords = alignment_coords(version);
+    let (first, last) = match (coords.first(), coords.last()) {
+        (Some(f), Some(l)) => (*f, *l),
+        _ => return Vec::new(),
+    };
+    let mut out = Vec::new();
+    for &row in coords {
+        for &col in coords {
+            let on_finder =
+                (row == first && (col == first || col == last)) || (row == last && col == first);
+            if !on_finder {
+                out.push((row, col));
+            }
+        }
+    }
+    out
+}
+
+/// Classifies every module of a symbol by function.
+pub struct RegionMap {
+    size: usize,
+    alignment: Vec<(usize, usize)>,
+}
+
+impl RegionMap {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            alignment: alignment_centers(version_from_size(size)),
+        }
+    }
+
+    /// Region of the module at column `x`, row `y`.
+    pub fn classify(&self, x: usize, y: usize) -> Region {
+        let far = self.size.saturating_sub(7);
+        if (x < 7 && (y < 7 || y >= far)) || (x >= far && y < 7) {
+            return Region::Finder;
+        }
+        let near = |c: usize, v: usize| c.abs_diff(v) <= 2;
+        if self
+            .alignment
+            .iter()
+            .any(|&(row, col)| near(row, y) && near(col, x))
+        {
+            return Region::Alignment;
+        }
+        Region::Data
+    }
+
+    /// Whether column `x`, row `y` is in the 3×3 centre of a finder pattern.
+    pub fn in_finder_centre(&self, x: usize, y: usize) -> bool {
+        let far = self.size.saturating_sub(7);
+        let centre = |c: usize| (2..=4).contains(&c) || (far + 2..=far + 4).contains(&c);
+        self.classify(x, y) == Region::Finder && centre(x) && centre(y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_1_has_three_finders_and_no_alignment() {
+        assert_eq!(version_from_size(21), 1);
+        assert!(alignment_centers(1).is_empty());
+        let map = RegionMap::new(21);
+        for (x, y) in [(0, 0), (6, 6), (20, 0), (14, 6), (0, 20), (6, 14)] {
+            assert_eq!(map.classify(x, y), Region::Finder, "({x}, {y})");
+        }
+        for (x, y) in [(7, 7), (20, 20), (14, 14), (7, 0), (10, 10)] {
+            assert_eq!(map.classify(x, y), Region::Data, "({x}, {y})");
+        }
+    }
+
+    #[test]
+    fn version_2_has_one_alignment_pattern() {
+        assert_eq!(version_from_size(25), 2);
+        assert_eq!(alignment_centers(2), [(18, 18)]);
+        let map = RegionMap::new(25);
+        assert_eq!(map.classify(16, 16), Region::Alignment);
+        assert_eq!(map.classify(20, 20), Region::Alignment);
+        assert_eq!(map.classify(21, 18), Region::Data);
+        assert_eq!(map.classify(18, 15), Region::Data);
+    }
+
+    #[test]
+    fn alignment_patterns_skip_the_finder_corners() {
+        // Version 7 (45 modules): 3×3 grid of centres minus three corners
+        assert_eq!(version_from_size(45), 7);
+        let centers = alignment_centers(7);
+        assert_eq!(centers.len(), 6);
+        for c in [(6, 22), (22, 6), (22, 22), (22, 38), (38, 22), (38, 38)] {
+            assert!(centers.contains(&c), "{c:?} missing from {centers:?}");
+        }
+        assert!(!centers.contains(&(6, 6)) && !centers.contains(&(6, 38)));
+        // Version 40: 7×7 minus three
+        assert_eq!(version_from_size(177), 40);
+        assert_eq!(alignment_centers(40).len(), 46);
+    }
+
+    #[test]
+    fn finder_centres_are_the_inner_three_by_three() {
+        let map = RegionMap::new(25);
+        for (x, y) in [(3, 3), (2, 4), (20, 3), (22, 2), (2, 22)] {
+            assert!(map.in_finder_centre(x, y), "({x}, {y})");
+        }
+        for (x, y) in [(1, 3), (5, 3), (20, 20), (12, 12)] {
+            assert!(!map.in_finder_centre(x, y), "({x}, {y})");
+        }
+    }
+}
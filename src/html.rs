@@ -1,221 +1,2169 @@
-use mdbook::book::{Book, BookItem};
+use crate::config::{CaptionPosition, OutputMode, SizeMode};
+use crate::html_escape;
+use crate::util::{in_scope, par_map_chapters, ChapterFilter};
+use globset::GlobSet;
+use mdbook::book::{Book, Chapter};
 use pathdiff::diff_paths;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
-/// Replace `marker` with `replacement` in `content`, but:
-/// - Do NOT replace inside fenced code blocks (``` or ~~~).
-/// - Still allow replacement inside `~~~admonish ... ~~~` blocks (treated as normal text).
-/// - Do NOT replace inside inline code spans enclosed by backticks (`...` or ```` ... ````).
-fn replace_markers_outside_code(content: &str, marker: &str, replacement: &str) -> String {
-    let mut out = String::with_capacity(content.len());
+/// Detect a fence line and return (fence_char, fence_len, info_string).
+fn parse_fence(line: &str) -> Option<(char, usize, &str)> {
+    // Allow up to 3 leading spaces per CommonMark
+    let trimmed_lead = line
+        .strip_prefix("   ")
+        .or_else(|| line.strip_prefix("  "))
+        .or_else(|| line.strip_prefix(" "))
+        .unwrap_or(line);
 
-    // Fence tracking
-    let mut in_fence = false;
-    let mut fence_char = '\0';
-    let mut fence_len: usize = 0;
-
-    // Utility: detect a fence line and return (is_fence, fence_char, fence_len, info_string)
-    fn parse_fence(line: &str) -> Option<(char, usize, &str)> {
-        // Allow up to 3 leading spaces per CommonMark
-        let trimmed_lead = line
-            .strip_prefix("   ")
-            .or_else(|| line.strip_prefix("  "))
-            .or_else(|| line.strip_prefix(" "))
-            .unwrap_or(line);
-
-        let bytes = trimmed_lead.as_bytes();
-        if bytes.is_empty() {
-            return None;
+    let bytes = trimmed_lead.as_bytes();
+    if bytes.is_empty() {
+        return None;
+    }
+
+    let first = bytes[0] as char;
+    if first != '`' && first != '~' {
+        return None;
+    }
+
+    // Count run length of the same char
+    let mut i = 0;
+    while i < bytes.len() && (bytes[i] as char) == first {
+        i += 1;
+    }
+    if i < 3 {
+        return None;
+    }
+
+    // Info string after the fence run (can be empty)
+    let info = &trimmed_lead[i..];
+    Some((first, i, info))
+}
+
+/// Strip blockquote markers (`>`, after up to 3 spaces, with one optional
+/// space after each) and return how many there were with the rest.
+fn strip_blockquotes(line: &str) -> (usize, &str) {
+    let mut depth = 0;
+    let mut rest = line;
+    loop {
+        let trimmed = rest.trim_start_matches(' ');
+        let Some(after) = trimmed
+            .strip_prefix('>')
+            .filter(|_| rest.len() - trimmed.len() <= 3)
+        else {
+            return (depth, rest);
+        };
+        depth += 1;
+        rest = after.strip_prefix(' ').unwrap_or(after);
+    }
+}
+
+/// Leading indentation in columns (tabs advance to the next multiple of 4).
+fn indent_width(line: &str) -> usize {
+    let mut col = 0;
+    for ch in line.chars() {
+        match ch {
+            ' ' => col += 1,
+            '\t' => col += 4 - (col % 4),
+            _ => break,
+        }
+    }
+    col
+}
+
+/// If `line` starts a list item (`-`, `*`, `+`, `1.`, `1)`), return the
+/// column where its content begins.
+fn list_item_content_indent(line: &str) -> Option<usize> {
+    let indent = indent_width(line);
+    let rest = line.trim_start_matches([' ', '\t']);
+    let bytes = rest.as_bytes();
+
+    let marker_len = match bytes.first()? {
+        b'-' | b'*' | b'+' => 1,
+        b'0'..=b'9' => {
+            let digits = bytes.iter().take_while(|b| b.is_ascii_digit()).count();
+            match bytes.get(digits) {
+                Some(b'.') | Some(b')') if digits <= 9 => digits + 1,
+                _ => return None,
+            }
         }
+        _ => return None,
+    };
+
+    let after = &rest[marker_len..];
+    let spaces = after.len() - after.trim_start_matches(' ').len();
+    if spaces == 0 && !after.is_empty() && !after.starts_with('\t') {
+        return None;
+    }
+    // 1..=4 spaces belong to the marker; more than that starts indented code
+    let pad = if (1..=4).contains(&spaces) { spaces } else { 1 };
+    Some(indent + marker_len + pad)
+}
+
+/// Line-by-line block state: which lines belong to code and must be left alone.
+#[derive(Default)]
+struct BlockScanner {
+    in_fence: bool,
+    fence_char: char,
+    fence_len: usize,
+    /// Blockquote depth the code fence was opened at; it ends with the quote.
+    fence_quotes: usize,
+    /// Open `~~~admonish` containers, innermost last, as (fence char, run
+    /// length). Their content is text, and a fence inside one is code.
+    containers: Vec<(char, usize)>,
+
+    in_indented: bool,
+    /// Previous line was paragraph text (indented code cannot interrupt a paragraph).
+    prev_paragraph: bool,
+    prev_blank: bool,
+    /// Content column of the list item we are inside, if any.
+    list_indent: Option<usize>,
+}
+
+impl BlockScanner {
+    /// Classify one line (without its trailing newline). Returns `true` if it
+    /// is part of a code block (fenced or indented), including fence delimiters.
+    /// Blockquote markers are stripped first, so quoted code counts too.
+    fn is_code_line(&mut self, line: &str) -> bool {
+        let (quotes, line) = strip_blockquotes(line);
+
+        // Inside a fenced block: only look for the closing fence, or the end
+        // of the blockquote holding it.
+        if self.in_fence && quotes >= self.fence_quotes {
+            let closes = parse_fence(line).is_some_and(|(ch, run_len, info)| {
+                ch == self.fence_char && run_len >= self.fence_len && info.trim().is_empty()
+            });
+            if closes {
+                self.close_fence();
+            }
+            return true;
+        }
+        if self.in_fence {
+            self.close_fence();
+        }
+
+        if line.trim().is_empty() {
+            self.prev_paragraph = false;
+            self.prev_blank = true;
+            // A blank line inside an indented block stays part of it
+            return self.in_indented;
+        }
+
+        let indent = indent_width(line);
+        let base = self.list_indent.unwrap_or(0);
+        let was_blank = std::mem::replace(&mut self.prev_blank, false);
+
+        // Indented code: continuation, or a new block that does not interrupt a paragraph
+        if indent >= base + 4 && (self.in_indented || !self.prev_paragraph) {
+            self.in_indented = true;
+            return true;
+        }
+        self.in_indented = false;
+
+        if let Some((ch, run_len, info)) = parse_fence(line) {
+            self.prev_paragraph = false;
+            // Closing the innermost admonish container
+            let closes_container = self
+                .containers
+                .last()
+                .is_some_and(|&(c, len)| c == ch && run_len >= len && info.trim().is_empty());
+            if closes_container {
+                self.containers.pop();
+                return false;
+            }
+            // `~~~admonish ...` is a container, not code: keep replacing inside it
+            if info.trim().to_ascii_lowercase().starts_with("admonish") {
+                self.containers.push((ch, run_len));
+                return false;
+            }
+            self.in_fence = true;
+            self.fence_char = ch;
+            self.fence_len = run_len;
+            self.fence_quotes = quotes;
+            return true;
+        }
+
+        // List context decides how much indentation a code block needs
+        if let Some(content) = list_item_content_indent(line).filter(|_| indent < base + 4) {
+            self.list_indent = Some(content);
+        } else if was_blank && indent < base {
+            // A less-indented block after a blank line closes the list
+            self.list_indent = None;
+        }
+
+        self.prev_paragraph = !line.trim_start().starts_with('#');
+        false
+    }
 
-        let first = bytes[0] as char;
-        if first != '`' && first != '~' {
+    fn close_fence(&mut self) {
+        self.in_fence = false;
+        self.fence_char = '\0';
+        self.fence_len = 0;
+        self.fence_quotes = 0;
+    }
+}
+
+/// Raw HTML elements whose content is never rewritten.
+const RAW_HTML_TAGS: &[&str] = &["pre", "code", "script", "style"];
+
+/// If `s` starts with an opening or closing tag of a [`RAW_HTML_TAGS`]
+/// element, return `(name, is_close, tag_len)`. Attributes are allowed; the
+/// tag must end with `>` on the same line. Self-closing tags are ignored.
+fn raw_html_tag(s: &str) -> Option<(&'static str, bool, usize)> {
+    let rest = s.strip_prefix('<')?;
+    let (is_close, rest) = match rest.strip_prefix('/') {
+        Some(r) => (true, r),
+        None => (false, rest),
+    };
+    let name = RAW_HTML_TAGS.iter().copied().find(|name| {
+        rest.get(..name.len())
+            .is_some_and(|head| head.eq_ignore_ascii_case(name))
+    })?;
+    let after = &rest[name.len()..];
+    match after.chars().next() {
+        Some('>') => {}
+        Some(c) if c.is_ascii_whitespace() && c != '\n' => {}
+        Some('/') if !is_close => {}
+        _ => return None,
+    }
+    let line = after.split('\n').next().unwrap_or("");
+    let gt = line.find('>')?;
+    if !is_close && line[..gt].ends_with('/') {
+        return None;
+    }
+    let tag_len = s.len() - after.len() + gt + 1;
+    Some((name, is_close, tag_len))
+}
+
+/// Byte offset just past the tag closing the `name` element opened before
+/// `from`, counting nested same-name elements. `None` if it is never closed.
+///
+/// The search ends where a fenced code block starts, so a closing tag in a
+/// later code sample never closes this element, and an inline `<code>` also
+/// ends with its paragraph, at a blank line.
+fn find_raw_html_close(content: &str, from: usize, name: &str) -> Option<usize> {
+    let inline = name == "code";
+    let mut depth = 1usize;
+    let mut pos = from;
+    loop {
+        let line_end = content[pos..].find('\n').map_or(content.len(), |n| pos + n);
+        while let Some(rel) = content[pos..line_end].find('<') {
+            let at = pos + rel;
+            match raw_html_tag(&content[at..]) {
+                Some((n, is_close, len)) if n == name => {
+                    if is_close {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(at + len);
+                        }
+                    } else {
+                        depth += 1;
+                    }
+                    pos = at + len;
+                }
+                _ => pos = at + 1,
+            }
+        }
+        if line_end == content.len() {
+            return None;
+        }
+        pos = line_end + 1;
+        let next = content[pos..].split('\n').next().unwrap_or("");
+        let (_, next) = strip_blockquotes(next);
+        if parse_fence(next).is_some() || (inline && next.trim().is_empty()) {
             return None;
         }
+    }
+}
+
+/// Markers (aliases of one another) and what their occurrences become,
+/// depending on where they sit.
+struct Substitution<'a> {
+    markers: &'a [&'a str],
+    syntax: Syntax,
+    /// In running text; `None` leaves the marker in place.
+    text: Option<&'a str>,
+    /// Inside a link/image destination; `None` leaves the marker in place.
+    destination: Option<&'a str>,
+    /// Inside an HTML tag, already escaped; `None` leaves the marker in place.
+    attribute: Option<&'a str>,
+}
+
+impl Substitution<'_> {
+    /// The marker occurrence `s` starts with, as written; the longest when
+    /// aliases share a prefix.
+    fn marker_at<'s>(&self, s: &'s str) -> Option<&'s str> {
+        self.markers
+            .iter()
+            .filter(|m| !m.is_empty())
+            .filter_map(|m| marker_len(s, m, self.syntax.lenient))
+            .max()
+            .map(|len| &s[..len])
+    }
+
+    /// The first marker occurrence in `s` and its offset.
+    fn find_marker<'s>(&self, s: &'s str) -> Option<(usize, &'s str)> {
+        s.char_indices()
+            .find_map(|(at, _)| self.marker_at(&s[at..]).map(|m| (at, m)))
+    }
+}
 
-        // Count run length of the same char
-        let mut i = 0;
-        while i < bytes.len() && (bytes[i] as char) == first {
-            i += 1;
+/// Length of the occurrence of `marker` at the start of `s`, if there is
+/// one. With `lenient`, a `{{...}}` marker may have spaces and tabs just
+/// inside its braces (`{{ QR_CODE }}`); the token between them must still
+/// match exactly, so `{{QR_CODE_EXTRA}}` is never `{{QR_CODE}}`.
+fn marker_len(s: &str, marker: &str, lenient: bool) -> Option<usize> {
+    let blank = |c: char| c == ' ' || c == '\t';
+    let token = marker
+        .strip_prefix("{{")
+        .and_then(|m| m.strip_suffix("}}"))
+        .map(|m| m.trim_matches(blank))
+        .filter(|m| lenient && !m.is_empty());
+    let Some(token) = token else {
+        return s.starts_with(marker).then_some(marker.len());
+    };
+    let rest = s.strip_prefix("{{")?.trim_start_matches(blank);
+    let rest = rest.strip_prefix(token)?.trim_start_matches(blank);
+    let rest = rest.strip_prefix("}}")?;
+    Some(s.len() - rest.len())
+}
+
+/// Whether one of `markers` occurs anywhere in `content`, code included:
+/// the quick check before a chapter is scanned.
+pub fn contains_marker(content: &str, markers: &[&str], syntax: Syntax) -> bool {
+    markers.iter().filter(|m| !m.is_empty()).any(|m| {
+        if !syntax.lenient {
+            return content.contains(m);
+        }
+        content
+            .match_indices(|c| m.starts_with(c))
+            .any(|(at, _)| marker_len(&content[at..], m, true).is_some())
+    })
+}
+
+/// How the scanner reads chapters beyond CommonMark.
+#[derive(Clone, Copy, Debug)]
+pub struct Syntax {
+    /// `$$...$$` (possibly over several lines) and `$...$` (within a line)
+    /// delimit math, as for mdbook-katex; markers there are left alone.
+    pub math: bool,
+    /// `lenient-markers`: whitespace just inside a marker's braces is
+    /// allowed, `{{ QR_CODE }}`.
+    pub lenient: bool,
+}
+
+/// End of the math starting with `$` at `content[at]`, if it is closed:
+/// `$$...$$` within the paragraph, or `$...$` before `line_end`. As in
+/// Pandoc, an inline opener is not followed by whitespace and a closer
+/// neither follows whitespace nor precedes a digit, so `$5 and $10` is not
+/// math.
+fn math_end(content: &str, at: usize, line_end: usize) -> Option<usize> {
+    if content[at..].starts_with("$$") {
+        return display_math_end(content, at + 2);
+    }
+    let body = &content[at + 1..line_end];
+    if body.is_empty() || body.starts_with(char::is_whitespace) {
+        return None;
+    }
+    body.match_indices('$')
+        .map(|(k, _)| k)
+        .find(|&k| {
+            k > 0
+                && !body[..k].ends_with(|c: char| c.is_whitespace() || c == '\\')
+                && !body[k + 1..].starts_with(|c: char| c.is_ascii_digit() || c == '$')
+        })
+        .map(|k| at + 1 + k + 1)
+}
+
+/// Byte offset just past the `$$` closing display math opened before `from`.
+///
+/// Like [`find_raw_html_close`], the search ends where a fenced code block
+/// starts, and also at a blank line: a stray `$$` in prose is literal rather
+/// than math running into the next code sample.
+fn display_math_end(content: &str, from: usize) -> Option<usize> {
+    let mut pos = from;
+    loop {
+        let line_end = content[pos..].find('\n').map_or(content.len(), |n| pos + n);
+        if let Some(close) = content[pos..line_end].find("$$") {
+            return Some(pos + close + 2);
         }
-        if i < 3 {
+        if line_end == content.len() {
             return None;
         }
+        pos = line_end + 1;
+        let next = content[pos..].split('\n').next().unwrap_or("");
+        let (_, next) = strip_blockquotes(next);
+        if parse_fence(next).is_some() || next.trim().is_empty() {
+            return None;
+        }
+    }
+}
 
-        // Info string after the fence run (can be empty)
-        let info = &trimmed_lead[i..];
-        Some((first, i, info))
+/// Occurrences left in place because no replacement fits their context, and
+/// what else a pass saw on the way (for directives).
+#[derive(Default)]
+struct Skipped {
+    destination: usize,
+    attribute: usize,
+    /// Offsets of the occurrences replaced in running text.
+    text: Vec<usize>,
+    /// Every occurrence outside code, in running text, link destinations
+    /// and HTML tags alike, whether replaced or not.
+    found: Vec<Range<usize>>,
+    /// HTML comments outside code, `<!--` through `-->`.
+    comments: Vec<Range<usize>>,
+}
+
+/// If `line` is a link reference definition (`[label]: destination "title"`),
+/// return the offset where its destination starts.
+fn reference_destination(line: &str) -> Option<usize> {
+    let lead = line.len() - line.trim_start_matches(' ').len();
+    if lead > 3 {
+        return None;
+    }
+    let rest = line[lead..].strip_prefix('[')?;
+    let mut escaped = false;
+    let close = rest.char_indices().find_map(|(at, ch)| {
+        let hit = !escaped && ch == ']';
+        escaped = !escaped && ch == '\\';
+        hit.then_some(at)
+    })?;
+    if close == 0 {
+        return None;
     }
+    let after = rest[close + 1..].strip_prefix(':')?;
+    let dest = after.trim_start_matches([' ', '\t']);
+    if dest.is_empty() {
+        return None;
+    }
+    Some(line.len() - dest.len())
+}
 
-    // Replace marker in a *single line* but skip inline code spans marked by backticks.
-    fn replace_outside_inline_code(line: &str, marker: &str, repl: &str) -> String {
-        let mut result = String::with_capacity(line.len());
-        let mut i = 0;
-        let line_bytes = line.as_bytes();
+/// If `content[at..]` starts an HTML tag (`<name ...>` or `</name>`), return
+/// the offset just past its `>`. Quoted values may contain `>`, and the tag
+/// may span lines but not a blank line.
+fn html_tag_end(content: &str, at: usize) -> Option<usize> {
+    let rest = content.get(at..)?.strip_prefix('<')?;
+    let name = rest.strip_prefix('/').unwrap_or(rest);
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let mut quote: Option<char> = None;
+    let mut blank = false;
+    for (i, ch) in rest.char_indices() {
+        if ch == '\n' {
+            if blank {
+                return None;
+            }
+            blank = true;
+            continue;
+        }
+        if !ch.is_whitespace() {
+            blank = false;
+        }
+        match quote {
+            Some(q) if ch == q => quote = None,
+            Some(_) => {}
+            None if ch == '"' || ch == '\'' => quote = Some(ch),
+            None if ch == '>' => return Some(at + 1 + i + 1),
+            None if ch == '<' => return None,
+            None => {}
+        }
+    }
+    None
+}
 
-        // Tracks active inline code span delimited by N backticks
-        let mut inline_bt_count: Option<usize> = None;
+/// Copy the HTML tag at `content[range]` to `out`, giving markers inside it
+/// `sub.attribute`.
+fn copy_tag(
+    content: &str,
+    range: Range<usize>,
+    sub: &Substitution,
+    out: &mut String,
+    skipped: &mut Skipped,
+) {
+    let mut rest = &content[range.clone()];
+    while let Some((at, marker)) = sub.find_marker(rest) {
+        let start = range.end - rest.len() + at;
+        skipped.found.push(start..start + marker.len());
+        out.push_str(&rest[..at]);
+        match sub.attribute {
+            Some(value) => out.push_str(value),
+            None => {
+                out.push_str(marker);
+                skipped.attribute += 1;
+            }
+        }
+        rest = &rest[at + marker.len()..];
+    }
+    out.push_str(rest);
+}
 
-        while i < line.len() {
-            // SAFETY: i is always maintained at a char boundary
-            let ch = line[i..].chars().next().unwrap();
-            let ch_len = ch.len_utf8();
+/// An escaped marker as Markdown that renders as the marker itself but is no
+/// longer seen as one: the backslash is kept when it escapes ASCII
+/// punctuation (`\{{QR_CODE}}`), otherwise the first character becomes a
+/// character reference.
+fn push_escaped_marker(marker: &str, out: &mut String) {
+    let mut chars = marker.chars();
+    let first = chars.next().expect("marker is not empty");
+    if first.is_ascii_punctuation() {
+        out.push('\\');
+        out.push_str(marker);
+    } else {
+        out.push_str(&format!("&#{};", first as u32));
+        out.push_str(chars.as_str());
+    }
+}
 
-            if ch == '`' {
-                // Count a run of backticks. Backticks are ASCII => 1 byte each.
-                let mut j = i + ch_len; // i + 1
-                let mut count = 1;
-                while j < line.len() && line_bytes[j] == b'`' {
-                    j += 1;
-                    count += 1;
-                }
+/// Whether a run of exactly `count` backticks follows `from` before the
+/// paragraph ends at a blank line or a fence, so a span opened there closes.
+fn closes_code_span(content: &str, from: usize, count: usize) -> bool {
+    for (n, line) in content[from..].split('\n').enumerate() {
+        let (_, unquoted) = strip_blockquotes(line);
+        if n > 0 && (unquoted.trim().is_empty() || parse_fence(unquoted).is_some()) {
+            return false;
+        }
+        if line.split(|c| c != '`').any(|run| run.len() == count) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Replace the marker in `content[range]` (a single line, or its tail)
+/// and append the result to `out`, skipping inline code spans marked by
+/// backticks, HTML comments and raw HTML `<pre>`, `<code>`, `<script>` and
+/// `<style>` elements. `inline_span` is the backtick count of a code span
+/// still open from the previous line, updated for the next one; a run of
+/// backticks with no closing run in its paragraph is literal text.
+/// Occurrences inside a link destination `](...)`, or at or after `ref_dest`,
+/// get `sub.destination`, and those inside an HTML tag get `sub.attribute`;
+/// each one left in place is counted in `skipped`.
+///
+/// Returns the offset up to which `content` was copied: `range.end`, or
+/// further if a raw HTML element or a tag continues past this line.
+fn replace_in_line(
+    content: &str,
+    range: Range<usize>,
+    sub: &Substitution,
+    ref_dest: Option<usize>,
+    inline_span: &mut Option<usize>,
+    out: &mut String,
+    skipped: &mut Skipped,
+) -> usize {
+    let Range { start, end } = range;
+    let line = &content[..end];
+    let line_bytes = line.as_bytes();
+    let mut i = start;
+
+    // Open parentheses of the link destination we are inside (0 = none)
+    let mut dest_depth = 0usize;
+    // Inside `<...>`, where parentheses do not nest
+    let mut dest_angle = false;
+
+    while i < end {
+        // SAFETY: i is always maintained at a char boundary
+        let ch = line[i..].chars().next().unwrap();
+        let ch_len = ch.len_utf8();
 
-                // Copy the whole backtick run verbatim
-                result.push_str(&line[i..j]);
+        let in_destination = dest_depth > 0 || ref_dest.is_some_and(|at| i >= at);
+        let marker_here = sub.marker_at(&line[i..]);
+        if let Some(marker) = marker_here.filter(|_| in_destination) {
+            skipped.found.push(i..i + marker.len());
+            match sub.destination {
+                Some(url) => out.push_str(url),
+                None => {
+                    out.push_str(marker);
+                    skipped.destination += 1;
+                }
+            }
+            i += marker.len();
+            continue;
+        }
 
-                match inline_bt_count {
-                    None => inline_bt_count = Some(count), // open span
-                    Some(open) if open == count => inline_bt_count = None, // close span
-                    _ => { /* mismatched counts → treat as raw */ }
+        // Link destination: code spans do not apply; track nesting until `)`
+        if dest_depth > 0 {
+            let mut next = i + ch_len;
+            match ch {
+                '\\' => {
+                    next += line[next..].chars().next().map_or(0, char::len_utf8);
                 }
+                '<' if !dest_angle => dest_angle = true,
+                '>' if dest_angle => dest_angle = false,
+                '(' if !dest_angle => dest_depth += 1,
+                ')' if !dest_angle => dest_depth -= 1,
+                _ => {}
+            }
+            out.push_str(&line[i..next]);
+            i = next;
+            continue;
+        }
 
-                i = j;
+        // `\\` is a literal backslash, so a marker after it is still replaced;
+        // `\` + marker shows the marker itself. Not inside code spans, where
+        // backslashes are literal.
+        if inline_span.is_none() && ch == '\\' {
+            let rest = &line[i + 1..];
+            if rest.starts_with('\\') || (sub.syntax.math && rest.starts_with('$')) {
+                out.push_str(&line[i..i + 2]);
+                i += 2;
                 continue;
             }
-
-            // If not inside inline code, we can attempt marker replacement
-            if inline_bt_count.is_none() && line[i..].starts_with(marker) {
-                result.push_str(repl);
-                i += marker.len(); // marker is ASCII, so byte-len is safe
+            if let Some(marker) = sub.marker_at(rest) {
+                push_escaped_marker(marker, out);
+                i += 1 + marker.len();
                 continue;
             }
+        }
 
-            // Default: copy this character as-is
-            result.push(ch);
-            i += ch_len;
+        if inline_span.is_none() && line[i..].starts_with("](") {
+            out.push_str("](");
+            i += 2;
+            dest_depth = 1;
+            continue;
         }
 
-        result
-    }
+        if ch == '`' {
+            // Count a run of backticks. Backticks are ASCII => 1 byte each.
+            let mut j = i + ch_len; // i + 1
+            let mut count = 1;
+            while j < end && line_bytes[j] == b'`' {
+                j += 1;
+                count += 1;
+            }
 
-    for line in content.split_inclusive('\n') {
-        // We operate per physical line (including its trailing '\n')
-        // Use a copy without the trailing '\n' to parse fences cleanly
-        let (line_body, line_suffix_nl) = if line.ends_with('\n') {
-            (&line[..line.len() - 1], "\n")
+            // Copy the whole backtick run verbatim
+            out.push_str(&line[i..j]);
+
+            match *inline_span {
+                None if closes_code_span(content, j, count) => *inline_span = Some(count), // open span
+                None => { /* never closed → literal backticks */ }
+                Some(open) if open == count => *inline_span = None, // close span
+                _ => { /* mismatched counts → treat as raw */ }
+            }
+
+            i = j;
+            continue;
+        }
+
+        // Math, HTML comment or raw HTML element: copy verbatim through the
+        // closing `$`/`$$`, `-->` or closing tag. One that is never closed is
+        // treated as plain text.
+        let raw_close = if inline_span.is_some() {
+            None
+        } else if ch == '$' {
+            sub.syntax.math.then(|| math_end(content, i, end)).flatten()
+        } else if ch != '<' {
+            None
+        } else if line[i..].starts_with("<!--") {
+            let close = content[i + 4..].find("-->").map(|at| i + 4 + at + 3);
+            if let Some(close) = close {
+                skipped.comments.push(i..close);
+            }
+            close
         } else {
-            (line, "")
+            raw_html_tag(&content[i..])
+                .filter(|(_, is_close, _)| !is_close)
+                .and_then(|(name, _, tag_len)| find_raw_html_close(content, i + tag_len, name))
         };
+        if let Some(close_end) = raw_close {
+            out.push_str(&content[i..close_end]);
+            if close_end > end {
+                return close_end;
+            }
+            i = close_end;
+            continue;
+        }
 
-        // Check for a fence delimiter
-        if let Some((ch, run_len, info)) = parse_fence(line_body) {
-            // Is this a *closing* fence for the current one?
-            if in_fence {
-                if ch == fence_char && run_len >= fence_len {
-                    // Close the fence
-                    in_fence = false;
-                    fence_char = '\0';
-                    fence_len = 0;
-                }
-                // Copy the delimiter line as-is
-                out.push_str(line_body);
-                out.push_str(line_suffix_nl);
-                continue;
-            } else {
-                // Opening fence. Treat '~~~admonish ...' as NON-code fence:
-                let info_lower = info.trim().to_ascii_lowercase();
-                let is_admonish = info_lower.starts_with("admonish");
-
-                if !is_admonish {
-                    // Enter code fence
-                    in_fence = true;
-                    fence_char = ch;
-                    fence_len = run_len;
-                    out.push_str(line_body);
-                    out.push_str(line_suffix_nl);
-                    continue;
-                }
-                // For admonish, fall through (not a code fence): we still replace markers inside.
+        // Any other tag: markers there sit in attribute values, never text
+        let tag_end = (inline_span.is_none() && ch == '<')
+            .then(|| html_tag_end(content, i))
+            .flatten();
+        if let Some(tag_end) = tag_end {
+            copy_tag(content, i..tag_end, sub, out, skipped);
+            if tag_end > end {
+                return tag_end;
             }
+            i = tag_end;
+            continue;
+        }
+
+        // If not inside inline code, we can attempt marker replacement
+        if let Some(marker) = marker_here.filter(|_| inline_span.is_none()) {
+            skipped.text.push(i);
+            skipped.found.push(i..i + marker.len());
+            out.push_str(sub.text.unwrap_or(marker));
+            // A match covers whole chars, so this is a char boundary
+            i += marker.len();
+            continue;
         }
 
-        if in_fence {
-            // Inside a code fence → no replacement
+        // Default: copy this character as-is
+        out.push(ch);
+        i += ch_len;
+    }
+
+    end
+}
+
+/// Replace `marker` with `replacement` in `content`, but:
+/// - Do NOT replace inside fenced code blocks (``` or ~~~).
+/// - Do NOT replace inside indented code blocks (4+ columns, CommonMark rules incl. list items).
+/// - Still allow replacement inside `~~~admonish ... ~~~` blocks (treated as normal
+///   text), but not inside code fences nested in them.
+/// - Fences inside blockquotes (`> ````) are code blocks too.
+/// - Do NOT replace inside inline code spans enclosed by backticks (`...` or ```` ... ````),
+///   which may continue onto following lines of the same paragraph.
+/// - Do NOT replace a marker escaped with a backslash (`\{{QR_CODE}}`); `\\`
+///   before a marker is a literal backslash and the marker is replaced.
+/// - Do NOT replace inside HTML comments, raw HTML `<pre>`, `<code>`,
+///   `<script>` or `<style>` elements, or `$$` math (with `sub.syntax.math`),
+///   which may span lines, nor in `$...$` math within a line. Unclosed ones
+///   are ignored.
+/// - Inside link/image destinations and reference definitions, substitute
+///   `sub.destination` instead, and inside HTML tags (attribute values, which
+///   may span lines) `sub.attribute`.
+///
+/// Also returns the occurrences left in place by those two rules.
+fn replace_markers_outside_code(content: &str, sub: &Substitution) -> (String, Skipped) {
+    let mut out = String::with_capacity(content.len());
+    let mut skipped = Skipped::default();
+    let mut blocks = BlockScanner::default();
+    // Everything before this offset was already written (a raw HTML element
+    // or a tag running past the end of its line)
+    let mut written_until = 0;
+    let mut line_start = 0;
+    // Backtick count of a code span continuing onto the next line
+    let mut inline_span: Option<usize> = None;
+
+    for line in content.split_inclusive('\n') {
+        // We operate per physical line (including its trailing '\n')
+        // Use a copy without the trailing '\n' to parse blocks cleanly
+        let (line_body, line_suffix_nl) = match line.strip_suffix('\n') {
+            Some(body) => (body, "\n"),
+            None => (line, ""),
+        };
+        let start = line_start;
+        let body_end = start + line_body.len();
+        line_start += line.len();
+
+        let resume = if written_until >= line_start {
+            // Entirely inside a multi-line element or tag
+            continue;
+        } else if written_until > start {
+            // The element closes on this line; the tail is ordinary text
+            replace_in_line(
+                content,
+                written_until..body_end,
+                sub,
+                None,
+                &mut inline_span,
+                &mut out,
+                &mut skipped,
+            )
+        } else if blocks.is_code_line(line_body) {
+            // Inside a code block → no replacement; a code span never runs into one
+            inline_span = None;
             out.push_str(line_body);
-            out.push_str(line_suffix_nl);
+            body_end
         } else {
-            // Outside code fences → replace markers, but skip inline code spans
-            let replaced = replace_outside_inline_code(line_body, marker, replacement);
-            out.push_str(&replaced);
+            // Outside code blocks → replace markers, but skip inline code and raw HTML
+            let ref_dest = reference_destination(line_body).map(|at| start + at);
+            replace_in_line(
+                content,
+                start..body_end,
+                sub,
+                ref_dest,
+                &mut inline_span,
+                &mut out,
+                &mut skipped,
+            )
+        };
+        written_until = resume;
+        if resume <= body_end {
             out.push_str(line_suffix_nl);
         }
     }
 
-    out
+    (out, skipped)
+}
+
+/// Occurrences of `markers` that injection acts on: outside code, in text,
+/// link destinations and HTML tags alike. One pass, however many aliases.
+pub fn count_markers_outside_code(content: &str, markers: &[&str], syntax: Syntax) -> usize {
+    let sub = Substitution {
+        markers,
+        syntax,
+        text: None,
+        destination: None,
+        attribute: None,
+    };
+    replace_markers_outside_code(content, &sub).1.found.len()
+}
+
+/// Byte ranges of the occurrences of `marker` in running text outside code:
+/// the ones injection replaces with the image.
+pub fn marker_offsets(content: &str, marker: &str, syntax: Syntax) -> Vec<Range<usize>> {
+    let sub = Substitution {
+        markers: &[marker],
+        syntax,
+        text: Some(""),
+        destination: None,
+        attribute: None,
+    };
+    let (_, found) = replace_markers_outside_code(content, &sub);
+    found
+        .text
+        .into_iter()
+        .map(|at| at..at + sub.marker_at(&content[at..]).map_or(0, str::len))
+        .collect()
+}
+
+/// An `<!-- mdbook-qr: ... -->` comment outside code.
+pub struct Directive {
+    /// The whole comment.
+    pub range: Range<usize>,
+    /// What follows `mdbook-qr:`, trimmed.
+    pub body: String,
+}
+
+/// `<!-- mdbook-qr: ... -->` comments outside code, in order.
+pub fn find_directives(content: &str, syntax: Syntax) -> Vec<Directive> {
+    let sub = Substitution {
+        markers: &[],
+        syntax,
+        text: None,
+        destination: None,
+        attribute: None,
+    };
+    let (_, found) = replace_markers_outside_code(content, &sub);
+    found
+        .comments
+        .into_iter()
+        .filter_map(|range| {
+            let inner = content[range.start + 4..range.end - 3].trim_start();
+            let body = inner.strip_prefix("mdbook-qr:")?.trim().to_string();
+            Some(Directive { range, body })
+        })
+        .collect()
+}
+
+/// Distinct markers of the form `{{<prefix>...}}` outside code, such as
+/// `{{QR_OLD}}` for prefix `QR`. The part after the prefix may not contain
+/// whitespace or braces.
+pub fn find_prefixed_markers(content: &str, prefix: &str, syntax: Syntax) -> Vec<String> {
+    let opening = format!("{{{{{prefix}");
+    let sub = Substitution {
+        markers: &[&opening],
+        syntax,
+        text: None,
+        destination: None,
+        attribute: None,
+    };
+    let (_, seen) = replace_markers_outside_code(content, &sub);
+    let mut found: Vec<String> = seen
+        .found
+        .into_iter()
+        .filter_map(|at| {
+            let rest = &content[at.end..];
+            let end = rest.find("}}")?;
+            let name = &rest[..end];
+            (!name.contains(|c: char| c.is_whitespace() || c == '{' || c == '}'))
+                .then(|| format!("{opening}{name}}}}}"))
+        })
+        .collect();
+    found.sort();
+    found.dedup();
+    found
+}
+
+/// What the marker is replaced with.
+pub struct InjectOptions<'a> {
+    pub fit_w: u32,
+    pub fit_h: u32,
+    /// Content hash appended as `?v=` (or `cache_bust_param`) so browsers
+    /// refetch changed images.
+    pub cache_bust: Option<&'a str>,
+    /// Query parameter carrying `cache_bust` (and those of the variants).
+    pub cache_bust_param: &'a str,
+    /// Already resolved for the renderer; `Auto` is treated as `Html`.
+    pub output: OutputMode,
+    pub alt: &'a str,
+    pub title: Option<&'a str>,
+    /// Wrap the `<img>` in `<a href>`; ignored for Markdown output.
+    pub link: Option<&'a str>,
+    pub link_target: Option<&'a str>,
+    /// Wraps the image in `<figure>` with a `<figcaption>`; an italic line for Markdown output.
+    pub caption: Option<&'a str>,
+    pub caption_position: CaptionPosition,
+    /// How `fit_w`/`fit_h` are written; ignored for Markdown output.
+    pub size_mode: SizeMode,
+    /// The code drawn by [`crate::image::encode_text`]; when set, markers
+    /// become a fenced block of it rather than an image.
+    pub text: Option<&'a str>,
+    /// CSS `background-color` behind a see-through image, written in its
+    /// `style` whatever the `size_mode`; ignored for Markdown output.
+    pub backdrop: Option<&'a str>,
+    /// `loading` and `decoding` of the `<img>`, left out when `None`;
+    /// ignored for Markdown output.
+    pub loading: Option<&'a str>,
+    pub decoding: Option<&'a str>,
+    /// Replaces all the built-in HTML; ignored for Markdown output.
+    pub template: Option<HtmlTemplate<'a>>,
+    /// Higher-density renders, offered through `srcset`; ignored for Markdown output.
+    pub variants: &'a [Variant<'a>],
+    /// `class` of the `<img>`; ignored for Markdown output.
+    pub class: Option<&'a str>,
+    /// Further `<img>` attributes (names already validated); ignored for Markdown output.
+    pub attrs: &'a [(&'a str, &'a str)],
+    /// Dark-mode variant, served through `<picture>`; ignored for Markdown output.
+    pub dark: Option<DarkImage<'a>>,
+    /// Substituted for markers used as link destinations (`[scan]({{QR_CODE}})`);
+    /// `None` leaves them in place with a warning.
+    pub destination: Option<&'a str>,
+    /// Substituted (HTML-escaped) for markers inside HTML tags, such as
+    /// `title="{{QR_CODE}}"`; `None` leaves them in place with a warning.
+    pub attribute: Option<&'a str>,
+    /// Only inject into this chapter (path relative to `src`), for per-chapter URLs.
+    pub chapter: Option<&'a Path>,
+    /// `chapters` allowlist; chapters outside it are left untouched.
+    pub scope: &'a GlobSet,
+    /// The profile's own `chapters` / `exclude-chapters`, applied within `scope`.
+    pub filter: &'a ChapterFilter,
+    pub syntax: Syntax,
+    /// Complete HTML comment appended after each image (`debug-comments`).
+    pub debug_comment: Option<&'a str>,
+}
+
+/// `html-template` and the values of its placeholders not taken from the
+/// other options.
+pub struct HtmlTemplate<'a> {
+    pub template: &'a str,
+    /// `{url}`: the encoded address; empty for a data payload.
+    pub url: &'a str,
+    /// `{hash}`: content hash of the image.
+    pub hash: &'a str,
+}
+
+/// Placeholders of `html-template`.
+pub const HTML_TEMPLATE_PLACEHOLDERS: [&str; 7] =
+    ["src", "alt", "width", "height", "url", "hash", "marker"];
+
+/// Substitute `{name}` placeholders in `template` with the HTML-escaped
+/// values in `vars`, braces included so a substituted `{marker}` is not
+/// taken for a marker again. `{{` and `}}` stand for literal braces.
+/// Unknown placeholders and unmatched braces are errors.
+pub fn expand_html_template(template: &str, vars: &[(&str, &str)]) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(at) = rest.find(['{', '}']) {
+        out.push_str(&rest[..at]);
+        let tail = &rest[at..];
+        if let Some(after) = tail.strip_prefix("{{").or_else(|| tail.strip_prefix("}}")) {
+            out.push_str(&tail[..1]);
+            rest = after;
+            continue;
+        }
+        let end = tail
+            .find('}')
+            .filter(|_| tail.starts_with('{'))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unmatched brace in html-template; write {{{{ or }}}} for a literal one"
+                )
+            })?;
+        let name = &tail[1..end];
+        let Some((_, value)) = vars.iter().find(|(n, _)| *n == name) else {
+            anyhow::bail!(
+                "unknown placeholder {{{name}}} in html-template; expected one of {{{}}}",
+                HTML_TEMPLATE_PLACEHOLDERS.join("}, {")
+            );
+        };
+        let value = html_escape::attr(value);
+        out.push_str(&value.replace('{', "&#123;").replace('}', "&#125;"));
+        rest = &tail[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The dark-mode image of a profile (path under the book root, like the light one).
+pub struct DarkImage<'a> {
+    pub path: &'a Path,
+    pub cache_bust: Option<&'a str>,
+    pub variants: &'a [Variant<'a>],
 }
 
-/// Replace all occurrences of `marker` with an <img> whose `src` is
-/// chapter-relative to `qr_rel_under_src`.
+/// An image rendered at `ratio`× its displayed size (path under the book root).
+pub struct Variant<'a> {
+    pub ratio: u32,
+    pub path: &'a Path,
+    pub cache_bust: Option<&'a str>,
+}
+
+/// Replace all occurrences of `marker`, in every chapter including nested
+/// ones (or only `opts.chapter`), with an image whose path is
+/// chapter-relative to `qr_rel_under_src`: an `<img>` tag, or Markdown image
+/// syntax (no style, no cache-bust) when `opts.output` is `Markdown`.
+/// Markers inside link destinations get `opts.destination` instead, and
+/// markers inside HTML tags `opts.attribute`.
+/// Every interpolated value goes through [`html_escape`]; values that fail
+/// validation return an error. Returns the number of markers replaced in
+/// running text, per chapter that had any. `markers` are aliases, replaced
+/// in a single pass over each chapter; the first fills `{marker}` in
+/// `html-template` and names the marker in warnings.
 pub fn inject_marker_relative(
     book: &mut Book,
-    marker: &str,
+    markers: &[&str],
     src_dir: &Path,
     qr_rel_under_src: &Path,
-    fit_h: u32,
-    fit_w: u32,
-    cache_bust: Option<&str>, // NEW
-) -> anyhow::Result<()> {
-    for section in book.sections.iter_mut() {
-        if let BookItem::Chapter(ch) = section {
-            if !ch.content.contains(marker) {
-                continue;
+    opts: &InjectOptions,
+) -> anyhow::Result<Vec<(PathBuf, usize)>> {
+    if let Some(url) = opts.destination {
+        html_escape::check_control("link destination", url)?;
+        if url.contains(char::is_whitespace) {
+            anyhow::bail!("URL {url:?} cannot be used as a link destination");
+        }
+    }
+    if let Some(url) = opts.attribute {
+        html_escape::check_control("attribute value", url)?;
+    }
+    let attribute = opts.attribute.map(html_escape::attr);
+    // Chapters are rewritten in parallel; warnings and the first error are
+    // reported in book order
+    let results = par_map_chapters(&mut book.sections, &|ch: &mut Chapter| {
+        let wanted = in_scope(opts.scope, ch)
+            && opts.filter.matches(ch)
+            && contains_marker(&ch.content, markers, opts.syntax)
+            && opts
+                .chapter
+                .is_none_or(|only| ch.path.as_deref() == Some(only));
+        wanted.then(|| {
+            inject_chapter(
+                ch,
+                markers,
+                src_dir,
+                qr_rel_under_src,
+                opts,
+                attribute.as_deref(),
+            )
+        })
+    });
+    let mut replaced = Vec::new();
+    for result in results {
+        let (warnings, count, path) = result?;
+        for warning in warnings {
+            log::warn!("{warning}");
+        }
+        if count > 0 {
+            replaced.push((path, count));
+        }
+    }
+    Ok(replaced)
+}
+
+/// Remove `markers`, outside code, from the chapters in `scope` that
+/// `filter` leaves out (`strip-unmatched`). Occurrences in link destinations
+/// and HTML tags stay. Returns the paths of the chapters changed.
+pub fn strip_unmatched(
+    book: &mut Book,
+    markers: &[&str],
+    scope: &GlobSet,
+    filter: &ChapterFilter,
+    syntax: Syntax,
+) -> Vec<PathBuf> {
+    let sub = Substitution {
+        markers,
+        syntax,
+        text: Some(""),
+        destination: None,
+        attribute: None,
+    };
+    par_map_chapters(&mut book.sections, &|ch: &mut Chapter| {
+        if !in_scope(scope, ch)
+            || filter.matches(ch)
+            || !contains_marker(&ch.content, markers, syntax)
+        {
+            return None;
+        }
+        let (content, _) = replace_markers_outside_code(&ch.content, &sub);
+        let changed = content != ch.content;
+        ch.content = content;
+        changed.then(|| ch.path.clone().unwrap_or_default())
+    })
+}
+
+/// Replace `markers`, outside code, with `text` in the chapters in `scope`
+/// that `filter` admits (a profile disabled by its `valid-*` dates).
+/// Returns the paths of the chapters changed.
+pub fn replace_with_text(
+    book: &mut Book,
+    markers: &[&str],
+    scope: &GlobSet,
+    filter: &ChapterFilter,
+    syntax: Syntax,
+    text: &str,
+) -> Vec<PathBuf> {
+    let sub = Substitution {
+        markers,
+        syntax,
+        text: Some(text),
+        destination: None,
+        attribute: None,
+    };
+    par_map_chapters(&mut book.sections, &|ch: &mut Chapter| {
+        if !in_scope(scope, ch)
+            || !filter.matches(ch)
+            || !contains_marker(&ch.content, markers, syntax)
+        {
+            return None;
+        }
+        let (content, _) = replace_markers_outside_code(&ch.content, &sub);
+        let changed = content != ch.content;
+        ch.content = content;
+        changed.then(|| ch.path.clone().unwrap_or_default())
+    })
+}
+
+/// Names of the draft chapters (listed in SUMMARY.md without a file) in
+/// `scope` that `filter` admits and that use `markers` outside code. With
+/// `text`, the markers there are replaced by it: an image cannot be linked
+/// from a chapter without a path.
+pub fn draft_markers(
+    book: &mut Book,
+    markers: &[&str],
+    scope: &GlobSet,
+    filter: &ChapterFilter,
+    syntax: Syntax,
+    text: Option<&str>,
+) -> Vec<String> {
+    par_map_chapters(&mut book.sections, &|ch: &mut Chapter| {
+        if ch.path.is_some()
+            || !in_scope(scope, ch)
+            || !filter.matches(ch)
+            || !contains_marker(&ch.content, markers, syntax)
+        {
+            return None;
+        }
+        if let Some(text) = text {
+            let sub = Substitution {
+                markers,
+                syntax,
+                text: Some(text),
+                destination: None,
+                attribute: None,
+            };
+            ch.content = replace_markers_outside_code(&ch.content, &sub).0;
+        }
+        Some(ch.name.clone())
+    })
+}
+
+/// Replace `markers` in the names of the chapters in `scope` that `filter`
+/// admits, nested ones included, with `text`; the result is trimmed.
+/// Returns the new names of the chapters changed.
+pub fn replace_in_names(
+    book: &mut Book,
+    markers: &[&str],
+    scope: &GlobSet,
+    filter: &ChapterFilter,
+    syntax: Syntax,
+    text: &str,
+) -> Vec<String> {
+    let sub = Substitution {
+        markers,
+        syntax,
+        text: Some(text),
+        destination: None,
+        attribute: None,
+    };
+    par_map_chapters(&mut book.sections, &|ch: &mut Chapter| {
+        if !in_scope(scope, ch)
+            || !filter.matches(ch)
+            || !contains_marker(&ch.name, markers, syntax)
+        {
+            return None;
+        }
+        let (name, _) = replace_markers_outside_code(&ch.name, &sub);
+        ch.name = name.trim().to_string();
+        Some(ch.name.clone())
+    })
+}
+
+/// [`inject_marker_relative`] for a single chapter. Returns warnings about
+/// markers left in place, for the caller to log, the number replaced in
+/// running text and the chapter's path.
+fn inject_chapter(
+    ch: &mut Chapter,
+    markers: &[&str],
+    src_dir: &Path,
+    qr_rel_under_src: &Path,
+    opts: &InjectOptions,
+    attribute: Option<&str>,
+) -> anyhow::Result<(Vec<String>, usize, PathBuf)> {
+    let Some(ch_rel_path) = &ch.path else {
+        return Ok((Vec::new(), 0, PathBuf::new()));
+    };
+    let marker = markers.first().copied().unwrap_or_default();
+    let ch_dir: PathBuf = src_dir
+        .join(ch_rel_path)
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_else(|| src_dir.to_path_buf());
+    let rel_str = chapter_relative(qr_rel_under_src, &ch_dir);
+
+    // Text blocks carry each occurrence's line prefix: placed first, by
+    // offset, and the markers they cannot replace are left in place below
+    let mut warnings = Vec::new();
+    let mut replaced = 0;
+    if let Some(text) = opts.text {
+        let (content, blocks, shared) =
+            inject_text_blocks(&ch.content, markers, opts.syntax, text, opts.debug_comment);
+        ch.content = content;
+        replaced = blocks;
+        if shared > 0 {
+            warnings.push(format!(
+                "mdbook-qr: left {shared} '{marker}' sharing a line with other text in '{}' ({}); \
+                 output = \"text\" needs the marker on a line of its own.",
+                ch.name,
+                ch_rel_path.display()
+            ));
+        }
+    }
+
+    let img = if opts.text.is_some() {
+        None
+    } else if opts.output == OutputMode::Markdown {
+        Some(markdown_image(&rel_str, opts)?)
+    } else {
+        let param = opts.cache_bust_param;
+        let src = with_cache_bust(rel_str, param, opts.cache_bust);
+        let srcset = density_srcset(&src, opts.variants, &ch_dir, param);
+        let dark = opts.dark.as_ref().map(|d| {
+            let src = with_cache_bust(chapter_relative(d.path, &ch_dir), param, d.cache_bust);
+            density_srcset(&src, d.variants, &ch_dir, param).unwrap_or(src)
+        });
+        Some(match &opts.template {
+            Some(t) => templated_html(&src, marker, t, opts)?,
+            None => html_img(&src, srcset.as_deref(), dark.as_deref(), opts)?,
+        })
+    };
+    let img = img.map(|img| match opts.debug_comment {
+        Some(comment) => format!("{img}{comment}"),
+        None => img,
+    });
+    let sub = Substitution {
+        markers,
+        syntax: opts.syntax,
+        text: img.as_deref(),
+        destination: opts.destination,
+        attribute,
+    };
+    let (content, skipped) = replace_markers_outside_code(&ch.content, &sub);
+    ch.content = content;
+    if img.is_some() {
+        replaced += skipped.text.len();
+    }
+    if skipped.destination > 0 {
+        warnings.push(format!(
+            "mdbook-qr: left {} '{marker}' used as a link destination in '{}' ({}); \
+             set link-destinations = \"url\" to substitute the URL.",
+            skipped.destination,
+            ch.name,
+            ch_rel_path.display()
+        ));
+    }
+    if skipped.attribute > 0 {
+        warnings.push(format!(
+            "mdbook-qr: left {} '{marker}' inside HTML tags in '{}' ({}); \
+             set in-attributes = \"url\" to substitute the URL.",
+            skipped.attribute,
+            ch.name,
+            ch_rel_path.display()
+        ));
+    }
+    Ok((warnings, replaced, ch_rel_path.clone()))
+}
+
+/// `output = "text"`: replace each marker alone on its line, after any
+/// indentation, blockquote and list item markers, with `text` as a fenced
+/// code block that continues those containers on every line. Returns the
+/// new content, the number replaced and the number left in place because
+/// other text shares their line.
+fn inject_text_blocks(
+    content: &str,
+    markers: &[&str],
+    syntax: Syntax,
+    text: &str,
+    comment: Option<&str>,
+) -> (String, usize, usize) {
+    let mut found: Vec<Range<usize>> = markers
+        .iter()
+        .flat_map(|m| marker_offsets(content, m, syntax))
+        .collect();
+    // Aliases sharing a prefix: the longest match at each offset
+    found.sort_by_key(|r| (r.start, std::cmp::Reverse(r.end)));
+    found.dedup_by_key(|r| r.start);
+
+    let mut out = String::with_capacity(content.len());
+    let (mut copied, mut replaced, mut shared) = (0, 0, 0);
+    for at in found {
+        let line_start = content[..at.start].rfind('\n').map_or(0, |n| n + 1);
+        let line_end = content[at.end..]
+            .find('\n')
+            .map_or(content.len(), |n| at.end + n);
+        let prefix = container_prefix(&content[line_start..at.start])
+            .filter(|_| content[at.end..line_end].trim().is_empty());
+        let Some(prefix) = prefix else {
+            shared += 1;
+            continue;
+        };
+        out.push_str(&content[copied..at.start]);
+        out.push_str(&text_block(text, content, &prefix, comment));
+        copied = at.end;
+        replaced += 1;
+    }
+    out.push_str(&content[copied..]);
+    (out, replaced, shared)
+}
+
+/// If `before`, what precedes a marker on its line, holds only indentation,
+/// blockquote markers and list item markers, the prefix continuing those
+/// containers on the lines after it: the same, list item markers blanked.
+fn container_prefix(before: &str) -> Option<String> {
+    let mut prefix = String::with_capacity(before.len());
+    let mut rest = before;
+    while let Some(ch) = rest.chars().next() {
+        let len = match ch {
+            ' ' | '\t' | '>' => {
+                prefix.push(ch);
+                1
             }
+            _ => {
+                let len = list_marker_len(rest)?;
+                prefix.push_str(&" ".repeat(len));
+                len
+            }
+        };
+        rest = &rest[len..];
+    }
+    Some(prefix)
+}
 
-            if let Some(ch_rel_path) = &ch.path {
-                let ch_abs = src_dir.join(ch_rel_path);
-                let ch_dir: PathBuf = ch_abs
-                    .parent()
-                    .map(|p| p.to_path_buf())
-                    .unwrap_or_else(|| src_dir.to_path_buf());
+/// Length of the list item marker (`-`, `*`, `+`, `1.`, `1)`) `s` starts
+/// with, when whitespace follows it.
+fn list_marker_len(s: &str) -> Option<usize> {
+    let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+    let len = match s.as_bytes().get(digits)? {
+        b'-' | b'*' | b'+' if digits == 0 => 1,
+        b'.' | b')' if (1..=9).contains(&digits) => digits + 1,
+        _ => return None,
+    };
+    s[len..].starts_with([' ', '\t']).then_some(len)
+}
 
-                let rel = diff_paths(qr_rel_under_src, &ch_dir)
-                    .unwrap_or_else(|| qr_rel_under_src.to_path_buf());
+/// `text` as a fenced code block opening where the marker was, each later
+/// line led by `prefix`, and `comment` on a line after it. The fence is
+/// longer than any backtick run in `content`, so nothing there can close it
+/// early.
+fn text_block(text: &str, content: &str, prefix: &str, comment: Option<&str>) -> String {
+    let longest = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat((longest + 1).max(3));
+    let mut block = format!("{fence}text");
+    let lines = text.trim_end_matches('\n').lines();
+    for line in lines.chain([fence.as_str()]).chain(comment) {
+        block.push('\n');
+        block.push_str(prefix);
+        block.push_str(line);
+    }
+    block
+}
 
-                let mut rel_str = rel.to_string_lossy().replace('\\', "/");
-                if !rel_str.contains('/') && !rel_str.starts_with("./") {
-                    rel_str = format!("./{}", rel_str);
-                } else if rel_str.starts_with('/') {
-                    rel_str = rel_str.trim_start_matches('/').to_string();
-                }
+/// The snippet for one image at `src`, outside any chapter: Markdown or
+/// HTML per `opts.output`, without density or dark variants. `marker` fills
+/// the `{marker}` placeholder of `html-template`.
+pub fn image_snippet(src: &str, marker: &str, opts: &InjectOptions) -> anyhow::Result<String> {
+    if opts.output == OutputMode::Markdown {
+        return markdown_image(src, opts);
+    }
+    let src = with_cache_bust(src.to_string(), opts.cache_bust_param, opts.cache_bust);
+    match &opts.template {
+        Some(t) => templated_html(&src, marker, t, opts),
+        None => html_img(&src, None, None, opts),
+    }
+}
 
-                if let Some(v) = cache_bust {
-                    if rel_str.contains('?') {
-                        rel_str.push_str(&format!("&v={v}"));
-                    } else {
-                        rel_str.push_str(&format!("?v={v}"));
-                    }
-                }
+/// Path of `target` relative to the chapter directory, with `/` separators.
+fn chapter_relative(target: &Path, ch_dir: &Path) -> String {
+    let rel = diff_paths(target, ch_dir).unwrap_or_else(|| target.to_path_buf());
 
-                let mut style = String::new();
-                let mut items: Vec<String> = Vec::new();
-                if fit_h > 0 {
-                    items.push(format!("height:{}px", fit_h));
-                }
-                if fit_w > 0 {
-                    items.push(format!("width:{}px", fit_w));
-                }
-                if !items.is_empty() {
-                    style = format!(r#" style="{}""#, items.join(";"));
-                }
+    let mut rel_str = rel.to_string_lossy().replace('\\', "/");
+    if !rel_str.contains('/') && !rel_str.starts_with("./") {
+        rel_str = format!("./{}", rel_str);
+    } else if rel_str.starts_with('/') {
+        rel_str = rel_str.trim_start_matches('/').to_string();
+    }
+    rel_str
+}
+
+/// Append `v=<hash>` so browsers refetch an image whose contents changed.
+fn with_cache_bust(mut rel_str: String, param: &str, cache_bust: Option<&str>) -> String {
+    if let Some(v) = cache_bust {
+        if rel_str.contains('?') {
+            rel_str.push_str(&format!("&{param}={v}"));
+        } else {
+            rel_str.push_str(&format!("?{param}={v}"));
+        }
+    }
+    rel_str
+}
+
+/// `srcset` value listing `src` as `1x` and each variant at its ratio, or
+/// `None` without variants. Spaces and commas in paths are percent-encoded.
+fn density_srcset(src: &str, variants: &[Variant], ch_dir: &Path, param: &str) -> Option<String> {
+    if variants.is_empty() {
+        return None;
+    }
+    let candidate = |path: &str| path.replace(' ', "%20").replace(',', "%2C");
+    let mut out = format!("{} 1x", candidate(src));
+    for v in variants {
+        let path = with_cache_bust(chapter_relative(v.path, ch_dir), param, v.cache_bust);
+        out.push_str(&format!(", {} {}x", candidate(&path), v.ratio));
+    }
+    Some(out)
+}
+
+/// `<img>`, inside `<picture>` when there is a dark variant, inside `<a>` when linked.
+/// `srcset` lists the density variants; `dark` is the `srcset` of the dark `<source>`.
+fn html_img(
+    rel_str: &str,
+    srcset: Option<&str>,
+    dark: Option<&str>,
+    opts: &InjectOptions,
+) -> anyhow::Result<String> {
+    html_escape::check_control("image path", rel_str)?;
+
+    // The displayed size, per `size_mode`, and the backdrop
+    let mut size = String::new();
+    let mut items: Vec<String> = Vec::new();
+    match opts.size_mode {
+        SizeMode::Style => {
+            if opts.fit_h > 0 {
+                items.push(format!("height:{}px", opts.fit_h));
+            }
+            if opts.fit_w > 0 {
+                items.push(format!("width:{}px", opts.fit_w));
+            }
+        }
+        SizeMode::Attributes => {
+            if opts.fit_w > 0 {
+                size.push_str(&format!(r#" width="{}""#, opts.fit_w));
+            }
+            if opts.fit_h > 0 {
+                size.push_str(&format!(r#" height="{}""#, opts.fit_h));
+            }
+        }
+        SizeMode::None => {}
+    }
+    if let Some(color) = opts.backdrop {
+        items.push(format!("background-color:{color}"));
+    }
+    if !items.is_empty() {
+        let css = items.join(";");
+        html_escape::validate_css("style", &css)?;
+        size.insert_str(0, &format!(r#" style="{}""#, html_escape::attr(&css)));
+    }
+    for (name, value) in [("loading", opts.loading), ("decoding", opts.decoding)] {
+        if let Some(value) = value {
+            size.push_str(&format!(r#" {name}="{}""#, html_escape::attr(value)));
+        }
+    }
+
+    html_escape::check_control("alt", opts.alt)?;
+    let title = match opts.title {
+        Some(t) => {
+            html_escape::check_control("title", t)?;
+            format!(r#" title="{}""#, html_escape::attr(t))
+        }
+        None => String::new(),
+    };
 
-                let img = format!(
-                    r#"<img src="{rel}" alt="QR code"{style} loading="eager">"#,
-                    rel = rel_str,
-                    style = style
-                );
-                ch.content = replace_markers_outside_code(&ch.content, marker, &img);
+    let mut extra = String::new();
+    if let Some(srcset) = srcset {
+        html_escape::check_control("srcset", srcset)?;
+        extra.push_str(&format!(r#" srcset="{}""#, html_escape::attr(srcset)));
+    }
+    if let Some(class) = opts.class {
+        html_escape::check_control("class", class)?;
+        extra.push_str(&format!(r#" class="{}""#, html_escape::attr(class)));
+    }
+    for (name, value) in opts.attrs {
+        html_escape::check_control(name, value)?;
+        extra.push_str(&format!(r#" {name}="{}""#, html_escape::attr(value)));
+    }
+
+    let mut img = format!(
+        r#"<img src="{rel}" alt="{alt}"{title}{extra}{size}>"#,
+        rel = html_escape::attr(rel_str),
+        alt = html_escape::attr(opts.alt),
+        title = title,
+        size = size
+    );
+
+    if let Some(dark_rel) = dark {
+        html_escape::check_control("dark image path", dark_rel)?;
+        img = format!(
+            r#"<picture><source srcset="{dark}" media="(prefers-color-scheme: dark)">{img}</picture>"#,
+            dark = html_escape::attr(dark_rel)
+        );
+    }
+
+    if let Some(href) = opts.link {
+        html_escape::check_control("link", href)?;
+        let target = match opts.link_target {
+            Some(t) => {
+                html_escape::check_control("link-target", t)?;
+                let rel = if t == "_blank" {
+                    r#" rel="noopener""#
+                } else {
+                    ""
+                };
+                format!(r#" target="{}"{rel}"#, html_escape::attr(t))
             }
+            None => String::new(),
+        };
+        img = format!(
+            r#"<a href="{href}"{target}>{img}</a>"#,
+            href = html_escape::attr(href)
+        );
+    }
+
+    let Some(caption) = opts.caption else {
+        return Ok(img);
+    };
+    html_escape::check_control("caption", caption)?;
+    let caption = format!("<figcaption>{}</figcaption>", html_escape::text(caption));
+    Ok(match opts.caption_position {
+        CaptionPosition::Above => format!("<figure>{caption}{img}</figure>"),
+        CaptionPosition::Below => format!("<figure>{img}{caption}</figure>"),
+    })
+}
+
+/// `html-template` filled in for one chapter.
+fn templated_html(
+    src: &str,
+    marker: &str,
+    t: &HtmlTemplate,
+    opts: &InjectOptions,
+) -> anyhow::Result<String> {
+    html_escape::check_control("image path", src)?;
+    html_escape::check_control("alt", opts.alt)?;
+    let dim = |px: u32| {
+        if px > 0 {
+            px.to_string()
+        } else {
+            String::new()
         }
+    };
+    let (width, height) = (dim(opts.fit_w), dim(opts.fit_h));
+    let vars = [
+        ("src", src),
+        ("alt", opts.alt),
+        ("width", &width),
+        ("height", &height),
+        ("url", t.url),
+        ("hash", t.hash),
+        ("marker", marker),
+    ];
+    expand_html_template(t.template, &vars)
+}
+
+/// `![alt](path "title")`; paths with spaces or parentheses use the `<...>` form.
+fn markdown_image(rel_str: &str, opts: &InjectOptions) -> anyhow::Result<String> {
+    html_escape::check_control("image path", rel_str)?;
+    html_escape::check_control("alt", opts.alt)?;
+    if rel_str.contains(['<', '>']) {
+        anyhow::bail!("image path {rel_str:?} contains '<' or '>'");
+    }
+    let dest = if rel_str.contains([' ', '(', ')']) {
+        format!("<{rel_str}>")
+    } else {
+        rel_str.to_string()
+    };
+    let title = match opts.title {
+        Some(t) => {
+            html_escape::check_control("title", t)?;
+            format!(" \"{}\"", markdown_escape(t, &['"']))
+        }
+        None => String::new(),
+    };
+    let img = format!(
+        "![{alt}]({dest}{title})",
+        alt = markdown_escape(opts.alt, &['[', ']'])
+    );
+
+    // Italic line joined to the image by a hard line break
+    let Some(caption) = opts.caption.map(str::trim).filter(|c| !c.is_empty()) else {
+        return Ok(img);
+    };
+    html_escape::check_control("caption", caption)?;
+    let caption = format!(
+        "*{}*",
+        markdown_escape(caption, &['*', '_', '`', '[', ']', '<', '&'])
+    );
+    Ok(match opts.caption_position {
+        CaptionPosition::Above => format!("{caption}\\\n{img}"),
+        CaptionPosition::Below => format!("{img}\\\n{caption}"),
+    })
+}
+
+/// Backslash-escape `\` and each of `special` for Markdown link text or titles.
+fn markdown_escape(value: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(value.len());
+    for ch in value.chars() {
+        if ch == '\\' || special.contains(&ch) {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYNTAX: Syntax = Syntax {
+        math: true,
+        lenient: false,
+    };
+
+    fn found(content: &str) -> usize {
+        marker_offsets(content, "{{QR_CODE}}", SYNTAX).len()
+    }
+
+    const LENIENT: Syntax = Syntax {
+        math: true,
+        lenient: true,
+    };
+
+    fn replace(content: &str) -> String {
+        replace_with(content, SYNTAX)
+    }
+
+    fn replace_with(content: &str, syntax: Syntax) -> String {
+        let sub = Substitution {
+            markers: &["{{QR_CODE}}"],
+            syntax,
+            text: Some("X"),
+            destination: None,
+            attribute: None,
+        };
+        replace_markers_outside_code(content, &sub).0
+    }
+
+    /// `content` with markers in destinations becoming `destination`,
+    /// with the number of them left in place.
+    fn replace_destinations(content: &str, destination: Option<&str>) -> (String, usize) {
+        let sub = Substitution {
+            markers: &["{{QR_CODE}}"],
+            syntax: SYNTAX,
+            text: Some("X"),
+            destination,
+            attribute: None,
+        };
+        let (out, skipped) = replace_markers_outside_code(content, &sub);
+        (out, skipped.destination)
+    }
+
+    #[test]
+    fn link_and_image_destinations_take_the_url() {
+        let url = Some("https://e.com/");
+        let replace = |content| replace_destinations(content, url).0;
+        assert_eq!(
+            replace("[a]({{QR_CODE}}) {{QR_CODE}}"),
+            "[a](https://e.com/) X"
+        );
+        assert_eq!(
+            replace("![qr]({{QR_CODE}} \"t\")"),
+            "![qr](https://e.com/ \"t\")"
+        );
+        assert_eq!(replace("[b](<{{QR_CODE}}>)"), "[b](<https://e.com/>)");
+        assert_eq!(
+            replace("[x](<a (b {{QR_CODE}}>) {{QR_CODE}}"),
+            "[x](<a (b https://e.com/>) X"
+        );
+        assert_eq!(
+            replace("[x](a\\)b {{QR_CODE}}) {{QR_CODE}}"),
+            "[x](a\\)b https://e.com/) X"
+        );
+        assert_eq!(
+            replace("`[x]({{QR_CODE}})` [y]({{QR_CODE}})"),
+            "`[x]({{QR_CODE}})` [y](https://e.com/)"
+        );
+    }
+
+    #[test]
+    fn nested_parentheses_end_with_the_destination() {
+        let url = Some("https://e.com/");
+        assert_eq!(
+            replace_destinations("[a](http://x/(y)) {{QR_CODE}}", url).0,
+            "[a](http://x/(y)) X"
+        );
+        assert_eq!(
+            replace_destinations("[x](https://a.com/a_(b)/{{QR_CODE}}) {{QR_CODE}}", url).0,
+            "[x](https://a.com/a_(b)/https://e.com/) X"
+        );
+    }
+
+    #[test]
+    fn reference_definitions_take_the_url() {
+        let url = Some("https://e.com/");
+        assert_eq!(
+            replace_destinations("[ref]: {{QR_CODE}}\n{{QR_CODE}}\n", url).0,
+            "[ref]: https://e.com/\nX\n"
+        );
+        assert_eq!(
+            replace_destinations("   [r\\]ef]:  {{QR_CODE}} \"t\"\n", None),
+            ("   [r\\]ef]:  {{QR_CODE}} \"t\"\n".to_string(), 1)
+        );
+        // Four spaces make it indented code; an empty label is no definition
+        assert_eq!(
+            replace_destinations("    [ref]: {{QR_CODE}}\n", url).0,
+            "    [ref]: {{QR_CODE}}\n"
+        );
+        assert_eq!(replace_destinations("[]: {{QR_CODE}}", url).0, "[]: X");
+    }
+
+    #[test]
+    fn destinations_without_a_url_are_left_and_counted() {
+        assert_eq!(
+            replace_destinations("[a]({{QR_CODE}}) and {{QR_CODE}}", None),
+            ("[a]({{QR_CODE}}) and X".to_string(), 1)
+        );
+        assert_eq!(
+            replace_destinations("```\n[x]({{QR_CODE}})\n```\n", None),
+            ("```\n[x]({{QR_CODE}})\n```\n".to_string(), 0)
+        );
+    }
+
+    /// `content` with markers in HTML tags becoming `attribute`, with the
+    /// number of them left in place.
+    fn replace_attributes(content: &str, attribute: Option<&str>) -> (String, usize) {
+        let sub = Substitution {
+            markers: &["{{QR_CODE}}"],
+            syntax: SYNTAX,
+            text: Some("X"),
+            destination: None,
+            attribute,
+        };
+        let (out, skipped) = replace_markers_outside_code(content, &sub);
+        (out, skipped.attribute)
+    }
+
+    #[test]
+    fn quoted_attributes_take_the_attribute_value() {
+        assert_eq!(
+            replace_attributes("<div title=\"{{QR_CODE}}\">{{QR_CODE}}</div>", None),
+            ("<div title=\"{{QR_CODE}}\">X</div>".to_string(), 1)
+        );
+        assert_eq!(
+            replace_attributes("<div data-x='{{QR_CODE}}'>", Some("U&amp;")).0,
+            "<div data-x='U&amp;'>"
+        );
+    }
+
+    #[test]
+    fn tags_end_at_the_first_unquoted_gt() {
+        assert_eq!(
+            replace_attributes("<div title=\"a > {{QR_CODE}}\" x='>'>{{QR_CODE}}", None),
+            ("<div title=\"a > {{QR_CODE}}\" x='>'>X".to_string(), 1)
+        );
+        assert_eq!(
+            replace_attributes("<br>{{QR_CODE}}<br/>", None).0,
+            "<br>X<br/>"
+        );
+    }
+
+    #[test]
+    fn tags_may_span_lines_until_a_blank_one() {
+        assert_eq!(
+            replace_attributes(
+                "<div\n  title=\"{{QR_CODE}}\"\n  class=x>\n{{QR_CODE}}\n",
+                Some("U")
+            )
+            .0,
+            "<div\n  title=\"U\"\n  class=x>\nX\n"
+        );
+        assert_eq!(
+            replace_attributes(
+                "<div title=\"a\n{{QR_CODE}}\">x</div> {{QR_CODE}}\nnext {{QR_CODE}}",
+                None
+            ),
+            (
+                "<div title=\"a\n{{QR_CODE}}\">x</div> X\nnext X".to_string(),
+                1
+            )
+        );
+        assert_eq!(
+            replace_attributes("<div title=\"x\n\n{{QR_CODE}}", None).0,
+            "<div title=\"x\n\nX"
+        );
+    }
+
+    #[test]
+    fn comparisons_in_text_are_not_tags() {
+        assert_eq!(
+            replace_attributes("a < b {{QR_CODE}} > c", None).0,
+            "a < b X > c"
+        );
+        assert_eq!(
+            replace_attributes("if a<b {{QR_CODE}}\n", None).0,
+            "if a<b X\n"
+        );
+        assert_eq!(
+            replace_attributes("`<b title={{QR_CODE}}>` {{QR_CODE}}", None).0,
+            "`<b title={{QR_CODE}}>` X"
+        );
+    }
+
+    #[test]
+    fn math_is_left_alone() {
+        assert_eq!(
+            replace("$$\n{{QR_CODE}}\n$$\n{{QR_CODE}}\n"),
+            "$$\n{{QR_CODE}}\n$$\nX\n"
+        );
+        assert_eq!(
+            replace("a $x{{QR_CODE}}$ {{QR_CODE}}\n"),
+            "a $x{{QR_CODE}}$ X\n"
+        );
+        assert_eq!(
+            replace("costs $5 and {{QR_CODE}} $10\n"),
+            "costs $5 and X $10\n"
+        );
+        assert_eq!(
+            replace("inline $$a {{QR_CODE}} b$$ {{QR_CODE}}\n"),
+            "inline $$a {{QR_CODE}} b$$ X\n"
+        );
+        let off = Syntax {
+            math: false,
+            lenient: false,
+        };
+        assert_eq!(replace_with("$$\n{{QR_CODE}}\n$$\n", off), "$$\nX\n$$\n");
+    }
+
+    #[test]
+    fn stray_display_math_openers_are_literal() {
+        assert_eq!(replace("a $$ {{QR_CODE}}\n"), "a $$ X\n");
+        assert_eq!(
+            replace("It costs $$ a lot.\n\n```bash\necho $$\n```\n\n{{QR_CODE}}\n"),
+            "It costs $$ a lot.\n\n```bash\necho $$\n```\n\nX\n"
+        );
+        // A fence ends the search even without a blank line before it
+        assert_eq!(
+            replace("It costs $$ a lot.\n```\n$$\n```\n{{QR_CODE}}\n"),
+            "It costs $$ a lot.\n```\n$$\n```\nX\n"
+        );
+    }
+
+    #[test]
+    fn lenient_markers_allow_spaces_and_tabs_inside_the_braces() {
+        assert_eq!(
+            replace_with(
+                "a {{ QR_CODE }} b {{\tQR_CODE\t}} c {{   QR_CODE}} d {{QR_CODE \t }}",
+                LENIENT
+            ),
+            "a X b X c X d X"
+        );
+        assert_eq!(replace("{{ QR_CODE }} {{QR_CODE}}"), "{{ QR_CODE }} X");
+        assert!(contains_marker(
+            "x {{ QR_CODE }}",
+            &["{{QR_CODE}}"],
+            LENIENT
+        ));
+        assert!(!contains_marker(
+            "x {{ QR_CODE }}",
+            &["{{QR_CODE}}"],
+            SYNTAX
+        ));
+        let found = marker_offsets("ab {{ QR_CODE }}", "{{QR_CODE}}", LENIENT);
+        assert_eq!((found.len(), found.first()), (1, Some(&(3..16))));
+    }
+
+    #[test]
+    fn lenient_markers_stay_anchored_to_the_token() {
+        for other in [
+            "{{QR_CODE_EXTRA}}",
+            "{{ QR_CODE_EXTRA }}",
+            "{{ QR_CODE X}}",
+            "{{ XQR_CODE }}",
+        ] {
+            assert_eq!(replace_with(other, LENIENT), other);
+        }
+        assert!(!contains_marker(
+            "QR_CODE {{QR_CODE_X}}",
+            &["{{QR_CODE}}"],
+            LENIENT
+        ));
+        // Line breaks are not whitespace here; code and escapes still protect
+        assert_eq!(replace_with("{{\nQR_CODE}}", LENIENT), "{{\nQR_CODE}}");
+        assert_eq!(
+            replace_with("`{{ QR_CODE }}` \\{{ QR_CODE }}", LENIENT),
+            "`{{ QR_CODE }}` \\{{ QR_CODE }}"
+        );
+        assert_eq!(
+            count_markers_outside_code(
+                "<a title=\"{{ QR_CODE }}\"> {{ QR_CODE }}",
+                &["{{QR_CODE}}"],
+                LENIENT
+            ),
+            2
+        );
+    }
+
+    #[test]
+    fn indented_code_blocks_are_left_alone() {
+        assert_eq!(replace("a\n\n    {{QR_CODE}}\n"), "a\n\n    {{QR_CODE}}\n");
+        assert_eq!(replace("\t{{QR_CODE}}\n"), "\t{{QR_CODE}}\n");
+        assert_eq!(replace("a\n\n \t{{QR_CODE}}\n"), "a\n\n \t{{QR_CODE}}\n");
+        // A blank line does not end the block; an unindented line does
+        assert_eq!(
+            replace("    code\n\n    {{QR_CODE}}\nafter {{QR_CODE}}\n"),
+            "    code\n\n    {{QR_CODE}}\nafter X\n"
+        );
+    }
+
+    #[test]
+    fn indentation_without_a_blank_line_continues_the_paragraph() {
+        assert_eq!(replace("a\n    {{QR_CODE}}\n"), "a\n    X\n");
+        assert_eq!(replace("a\n\t{{QR_CODE}}\n"), "a\n\tX\n");
+    }
+
+    #[test]
+    fn indented_code_after_a_list_item_is_measured_from_the_item() {
+        assert_eq!(replace("- item\n\n    {{QR_CODE}}\n"), "- item\n\n    X\n");
+        assert_eq!(
+            replace("- item\n\n      {{QR_CODE}}\n"),
+            "- item\n\n      {{QR_CODE}}\n"
+        );
+        assert_eq!(
+            replace("1. item\n\n   {{QR_CODE}}\n\n       {{QR_CODE}}\n"),
+            "1. item\n\n   X\n\n       {{QR_CODE}}\n"
+        );
+        // A paragraph at the margin ends the list
+        assert_eq!(
+            replace("- item\n\ntext\n\n    {{QR_CODE}}\n"),
+            "- item\n\ntext\n\n    {{QR_CODE}}\n"
+        );
+    }
+
+    #[test]
+    fn fences_inside_admonish_blocks_are_tracked() {
+        assert_eq!(
+            replace("~~~admonish note\n{{QR_CODE}}\n```\n{{QR_CODE}}\n```\n{{QR_CODE}}\n~~~\n{{QR_CODE}}\n"),
+            "~~~admonish note\nX\n```\n{{QR_CODE}}\n```\nX\n~~~\nX\n"
+        );
+        assert_eq!(
+            replace("```admonish tip\n~~~rust\n{{QR_CODE}}\n~~~\n```\n{{QR_CODE}}\n"),
+            "```admonish tip\n~~~rust\n{{QR_CODE}}\n~~~\n```\nX\n"
+        );
+        // A shorter fence of the admonish's kind does not close it
+        assert_eq!(
+            replace("````admonish\n```\n{{QR_CODE}}\n```\n{{QR_CODE}}\n````\n"),
+            "````admonish\n```\n{{QR_CODE}}\n```\nX\n````\n"
+        );
+    }
+
+    #[test]
+    fn fences_inside_blockquotes_are_tracked() {
+        assert_eq!(
+            replace("> ```\n> {{QR_CODE}}\n> ```\n> {{QR_CODE}}\n"),
+            "> ```\n> {{QR_CODE}}\n> ```\n> X\n"
+        );
+        assert_eq!(
+            replace(">```\n>{{QR_CODE}}\n{{QR_CODE}}\n"),
+            ">```\n>{{QR_CODE}}\nX\n"
+        );
+        // Leaving the quote ends its fence
+        assert_eq!(
+            replace("> > ```\n> > {{QR_CODE}}\n\n{{QR_CODE}}\n"),
+            "> > ```\n> > {{QR_CODE}}\n\nX\n"
+        );
+        assert_eq!(
+            replace("~~~admonish\n> ```\n> {{QR_CODE}}\n> ```\n~~~\n{{QR_CODE}}\n"),
+            "~~~admonish\n> ```\n> {{QR_CODE}}\n> ```\n~~~\nX\n"
+        );
+    }
+
+    #[test]
+    fn inline_code_and_comments_are_left_alone() {
+        assert_eq!(
+            replace("`{{QR_CODE}}` ``a ` {{QR_CODE}}`` {{QR_CODE}}\n"),
+            "`{{QR_CODE}}` ``a ` {{QR_CODE}}`` X\n"
+        );
+        // Code spans may cross lines of a paragraph, but not a blank line
+        assert_eq!(
+            replace("`a\n{{QR_CODE}}` {{QR_CODE}}\n\n`\n\n{{QR_CODE}}\n"),
+            "`a\n{{QR_CODE}}` X\n\n`\n\nX\n"
+        );
+        assert_eq!(
+            replace("<!-- {{QR_CODE}} -->{{QR_CODE}}<!--\n{{QR_CODE}}\n-->\n"),
+            "<!-- {{QR_CODE}} -->X<!--\n{{QR_CODE}}\n-->\n"
+        );
+        assert_eq!(replace("<!-- open {{QR_CODE}}\n"), "<!-- open X\n");
+        assert_eq!(replace("`<!--` {{QR_CODE}} `-->`\n"), "`<!--` X `-->`\n");
+    }
+
+    #[test]
+    fn non_ascii_markers_are_replaced_on_char_boundaries() {
+        for marker in ["{{QR_КУРС}}", "{{QR_课程}}", "{{QR_📱}}"] {
+            let content = format!(
+                "é {marker} ü `{marker}` {marker}ß\n```\n{marker}\n```\n[x]({marker}) <a title=\"{marker}\">ö</a>\n"
+            );
+            assert_eq!(count_markers_outside_code(&content, &[marker], SYNTAX), 4);
+            let sub = Substitution {
+                markers: &[marker],
+                syntax: SYNTAX,
+                text: Some("IMG"),
+                destination: Some("U"),
+                attribute: Some("A"),
+            };
+            let (out, _) = replace_markers_outside_code(&content, &sub);
+            assert_eq!(
+                out,
+                format!(
+                    "é IMG ü `{marker}` IMGß\n```\n{marker}\n```\n[x](U) <a title=\"A\">ö</a>\n"
+                )
+            );
+        }
+        // A prefix of a multi-byte marker is not a match
+        assert_eq!(
+            count_markers_outside_code("{{QR_КУР}}", &["{{QR_КУРС}}"], SYNTAX),
+            0
+        );
+    }
+
+    #[test]
+    fn raw_html_protects_markers_until_its_closing_tag() {
+        assert_eq!(
+            found("<pre>\n{{QR_CODE}}\n\nstill pre\n</pre>\n{{QR_CODE}}\n"),
+            1
+        );
+        assert_eq!(found("a <code>{{QR_CODE}}</code> b {{QR_CODE}}\n"), 1);
+    }
+
+    #[test]
+    fn closing_tag_in_a_later_fence_does_not_close_raw_html() {
+        let content = "Type <pre> to start.\n\n{{QR_CODE}}\n\n```html\n</pre>\n```\n";
+        assert_eq!(found(content), 1);
+        let content = "> Open <code>\n> {{QR_CODE}}\n>\n> ~~~\n> </code>\n> ~~~\n";
+        assert_eq!(found(content), 1);
+    }
+
+    #[test]
+    fn inline_code_element_ends_with_its_paragraph() {
+        assert_eq!(found("A stray <code> tag.\n\n{{QR_CODE}} </code>\n"), 1);
+        assert_eq!(found("<code>\n{{QR_CODE}}\n</code>\n"), 0);
+    }
+
+    fn text_blocks(content: &str) -> (String, usize, usize) {
+        inject_text_blocks(content, &["{{QR_CODE}}"], SYNTAX, "AB\nCD\n", None)
+    }
+
+    #[test]
+    fn text_block_fence_outruns_backticks_in_the_chapter() {
+        let block = text_block("AB", "a ```` b", "", Some("<!-- c -->"));
+        assert_eq!(block, "`````text\nAB\n`````\n<!-- c -->");
+    }
+
+    #[test]
+    fn text_blocks_carry_the_container_prefix() {
+        let (out, replaced, shared) = text_blocks("Intro\n{{QR_CODE}}\nEnd\n");
+        assert_eq!(out, "Intro\n```text\nAB\nCD\n```\nEnd\n");
+        assert_eq!((replaced, shared), (1, 0));
+
+        let (out, _, _) = text_blocks("> Scan:\n> {{QR_CODE}}\n");
+        assert_eq!(out, "> Scan:\n> ```text\n> AB\n> CD\n> ```\n");
+
+        let (out, _, _) = text_blocks("1. First\n2. {{QR_CODE}}\n");
+        assert_eq!(out, "1. First\n2. ```text\n   AB\n   CD\n   ```\n");
+
+        let (out, _, _) = text_blocks("> - {{QR_CODE}}\n");
+        assert_eq!(out, "> - ```text\n>   AB\n>   CD\n>   ```\n");
+    }
+
+    #[test]
+    fn text_blocks_leave_markers_sharing_a_line() {
+        for content in [
+            "Scan {{QR_CODE}} here\n",
+            "# {{QR_CODE}}\n",
+            "| {{QR_CODE}} |\n",
+            "{{QR_CODE}} {{QR_CODE}}\n",
+        ] {
+            let (out, replaced, shared) = text_blocks(content);
+            assert_eq!(out, content);
+            assert_eq!(replaced, 0);
+            assert!(shared > 0, "{content}");
+        }
+        // Code is never touched, nor counted
+        assert_eq!(
+            text_blocks("`{{QR_CODE}}`\n"),
+            ("`{{QR_CODE}}`\n".into(), 0, 0)
+        );
+    }
+
+    #[test]
+    fn counts_markers_in_every_context_outside_code() {
+        let content = "{{QR_CODE}} [a]({{QR_CODE}}) <img src=\"{{QR_CODE}}\">\n`{{QR_CODE}}`\n";
+        assert_eq!(
+            count_markers_outside_code(content, &["{{QR_CODE}}"], SYNTAX),
+            3
+        );
+        // NUL bytes in a chapter are ordinary text
+        let content = "\u{0}{{QR_CODE}}\u{0}\n";
+        assert_eq!(
+            count_markers_outside_code(content, &["{{QR_CODE}}"], SYNTAX),
+            1
+        );
+    }
+
+    #[test]
+    fn cache_bust_joins_with_question_mark_or_ampersand() {
+        let bust = |path: &str, param, hash| with_cache_bust(path.to_string(), param, hash);
+        assert_eq!(bust("qr/a.png", "v", Some("abc")), "qr/a.png?v=abc");
+        assert_eq!(
+            bust("qr/a.png", "version", Some("abc")),
+            "qr/a.png?version=abc"
+        );
+        assert_eq!(
+            bust("qr/a.png?x=1", "version", Some("abc")),
+            "qr/a.png?x=1&version=abc"
+        );
+        assert_eq!(
+            bust("qr/a.png?x=1&y=2", "v", Some("abc")),
+            "qr/a.png?x=1&y=2&v=abc"
+        );
+        assert_eq!(bust("qr/a.png?x=1", "v", None), "qr/a.png?x=1");
+        assert_eq!(bust("qr/a.png", "v", None), "qr/a.png");
+    }
+
+    #[test]
+    fn prefixed_markers_are_found_outside_code() {
+        let content = "{{QR_OLD}} \u{0}{{QR_NEW}}\n`{{QR_CODE_X}}`\n{{QR bad}} {{QR_OLD}}\n";
+        assert_eq!(
+            find_prefixed_markers(content, "QR", SYNTAX),
+            ["{{QR_NEW}}", "{{QR_OLD}}"]
+        );
+    }
+
+    #[test]
+    fn directives_are_found_outside_code() {
+        let content = "<!-- mdbook-qr: fit=200 -->\n```\n<!-- mdbook-qr: alt=x -->\n```\n\
+                       <!-- other -->\u{0}<!--mdbook-qr:  alt=\"a b\"  -->\n";
+        let found: Vec<String> = find_directives(content, SYNTAX)
+            .into_iter()
+            .map(|d| d.body)
+            .collect();
+        assert_eq!(found, ["fit=200", "alt=\"a b\""]);
     }
-    Ok(())
 }
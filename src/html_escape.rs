@@ -0,0 +1,175 @@
+use anyhow::{bail, Result};
+
+/// Reject control characters (other than tab) in a value destined for HTML.
+pub fn check_control(field: &str, value: &str) -> Result<()> {
+    if let Some(c) = value.chars().find(|c| c.is_control() && *c != '\t') {
+        bail!(
+            "{field} contains control character U+{:04X}; refusing to inject it",
+            c as u32
+        );
+    }
+    Ok(())
+}
+
+/// Escape a value for use inside a double- or single-quoted attribute.
+pub fn attr(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Wrap `value` in an HTML comment, breaking up any `--` so the text cannot
+/// close it early.
+pub fn comment(value: &str) -> String {
+    let mut body = value.to_string();
+    while body.contains("--") {
+        body = body.replace("--", "- -");
+    }
+    if body.ends_with('-') {
+        body.push(' ');
+    }
+    format!("<!-- {body} -->")
+}
+
+/// Escape a value for use as element text content.
+pub fn text(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Whether `name` can be written as an HTML attribute name: no whitespace,
+/// controls, quotes, `>`, `/` or `=`.
+pub fn is_attribute_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.chars().any(|c| {
+            c.is_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '>' | '/' | '=' | '<')
+        })
+}
+
+/// Validate a CSS declaration list destined for a `style` attribute.
+/// Anything that could close the attribute or open a new rule is rejected.
+pub fn validate_css(field: &str, value: &str) -> Result<()> {
+    check_control(field, value)?;
+    if let Some(c) = value
+        .chars()
+        .find(|c| matches!(c, '<' | '>' | '"' | '\'' | '{' | '}' | '\\'))
+    {
+        bail!("{field} contains '{c}', which is not allowed in inline CSS");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::{Html, Selector};
+
+    /// Strings that would break out of an attribute or element if written raw.
+    const ADVERSARIAL: &[&str] = &[
+        "\"><script>alert(1)</script>",
+        "' onerror='alert(1)",
+        "a < b > c & d",
+        "&amp; &lt;b&gt; &#39; &quot;",
+        "</p><!-- x --><p>",
+        "tab\there",
+        "Ünïcødé 二维码 📱",
+        "",
+    ];
+
+    fn select<'a>(html: &'a Html, selector: &str) -> Vec<scraper::ElementRef<'a>> {
+        html.select(&Selector::parse(selector).unwrap()).collect()
+    }
+
+    #[test]
+    fn attributes_parse_back_to_the_value() {
+        for value in ADVERSARIAL {
+            let escaped = attr(value);
+            let html = Html::parse_fragment(&format!(
+                "<img alt=\"{escaped}\" title='{escaped}'><p>after</p>"
+            ));
+            let img = select(&html, "img");
+            assert_eq!(img.len(), 1, "{escaped}");
+            assert_eq!(img[0].value().attr("alt"), Some(*value));
+            assert_eq!(img[0].value().attr("title"), Some(*value));
+            assert_eq!(img[0].value().attrs().count(), 2, "{escaped}");
+            assert!(select(&html, "script").is_empty(), "{escaped}");
+            for raw in ['"', '\'', '<', '>'] {
+                assert!(!escaped.contains(raw), "{escaped}");
+            }
+        }
+    }
+
+    #[test]
+    fn text_parses_back_to_the_value() {
+        for value in ADVERSARIAL {
+            let escaped = text(value);
+            let html = Html::parse_fragment(&format!("<p>{escaped}</p>"));
+            let p = select(&html, "p");
+            assert_eq!(p.len(), 1, "{escaped}");
+            assert_eq!(p[0].text().collect::<String>(), *value);
+            assert!(select(&html, "script").is_empty(), "{escaped}");
+        }
+    }
+
+    #[test]
+    fn entities_are_escaped_again_and_non_ascii_is_kept() {
+        assert_eq!(attr("&amp;"), "&amp;amp;");
+        assert_eq!(text("&lt;b&gt;"), "&amp;lt;b&amp;gt;");
+        assert_eq!(attr("二维码 📱 é"), "二维码 📱 é");
+        assert_eq!(text("\"quoted\" 'single'"), "\"quoted\" 'single'");
+    }
+
+    #[test]
+    fn control_characters_other_than_tab_are_refused() {
+        check_control("alt", "a\tb é").unwrap();
+        for value in ["a\nb", "a\rb", "nul\0", "bell\u{7}", "c1\u{85}"] {
+            let e = check_control("alt", value).unwrap_err().to_string();
+            assert!(e.starts_with("alt contains control character U+"), "{e}");
+        }
+    }
+
+    #[test]
+    fn css_cannot_leave_its_attribute() {
+        validate_css("backdrop", "padding:4px;background:#fff").unwrap();
+        for value in ["x\"><script>", "a:b}c{", "url('x')", "a\\62", "a\nb"] {
+            assert!(validate_css("backdrop", value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn attribute_names_exclude_delimiters() {
+        assert!(is_attribute_name("data-track"));
+        for name in ["", "a b", "a=b", "a\"", "a'", "a>", "a/", "a<", "a\u{0}"] {
+            assert!(!is_attribute_name(name), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn comments_never_close_early() {
+        assert_eq!(comment("a--b"), "<!-- a- -b -->");
+        assert_eq!(comment("x --> y"), "<!-- x - -> y -->");
+        assert_eq!(comment("---"), "<!-- - - -  -->");
+        for value in ["a-", "a----b-", "--!>", "<!--x-->"] {
+            let c = comment(value);
+            let body = &c["<!-- ".len()..c.len() - " -->".len()];
+            assert!(!body.contains("--") && !body.ends_with('-'), "{c}");
+        }
+    }
+}
@@ -1,86 +1,536 @@
-use anyhow::{anyhow, Context, Result};
+use crate::assets::{AssetCache, LoadedAsset};
+use crate::cache::{WriteCache, REUSE_REASON};
+use crate::config::{Ecl, Margins};
+use crate::error::QrError;
+use crate::raster::{self, RasterStyle};
+use crate::util::{with_png_extension, write_if_changed};
+use anyhow::Result;
 use fast_qr::convert::{image::ImageBuilder, Builder, Color, Shape};
 use fast_qr::qr::QRBuilder;
+use fast_qr::QRCode;
+use image::codecs::png::{CompressionType, FilterType as PngFilter, PngEncoder};
+use image::{imageops, ExtendedColorType, ImageEncoder, ImageFormat, Rgba, RgbaImage};
+use log::{debug, info, warn};
 use std::{
+    collections::HashMap,
     fs,
-    io::Write,
+    io::Cursor,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Instant,
 };
 
+/// Rendering options for a single QR image.
+pub struct QrImageOptions<'a> {
+    pub fit_w: u32,
+    pub fit_h: u32,
+    /// Pixels per module; when set the image is sized from it rather than
+    /// `fit_w`/`fit_h`, so every module covers whole pixels.
+    pub scale: Option<u32>,
+    pub margin: Margins,
+    pub shape: Option<Shape>,
+    pub background: Option<Color>,
+    pub module: Option<Color>,
+    /// `None` keeps fast_qr's default level.
+    pub ecl: Option<Ecl>,
+    pub logo: Option<LogoOverlay<'a>>,
+    /// Frame around the finished image, which grows it by twice its width.
+    pub border: Option<Border>,
+    /// Per-region shapes; when set the QR is drawn by [`raster::render_png`]
+    /// instead of fast_qr's image builder.
+    pub regions: Option<RasterStyle>,
+    /// Fingerprint of every input that affects the pixels. When the cache
+    /// holds an unchanged file rendered from the same fingerprint, encoding
+    /// is skipped and the existing file is reused.
+    pub render_key: String,
+    /// Write `<stem>.<hash12>.png` instead of `<stem>.png`, removing older
+    /// hashed copies (`cache-bust = "filename"`).
+    pub hashed_name: bool,
+    /// Re-encode with [`canonical_png`] and log the hash at info level.
+    pub deterministic: bool,
+}
+
+/// A logo composited into the centre of the QR.
+pub struct LogoOverlay<'a> {
+    pub asset: &'a LoadedAsset,
+    /// Logo edge as a fraction of the QR image edge.
+    pub scale: f32,
+    /// White padding drawn behind the logo (px).
+    pub padding: u32,
+}
+
+/// A resolved `border` (px).
+pub struct Border {
+    pub width: u32,
+    pub color: [u8; 4],
+    pub radius: u32,
+}
+
+/// One render key's PNG, filled by the first thread to encode it.
+type RenderSlot = Arc<Mutex<Option<Arc<Vec<u8>>>>>;
+
+/// PNGs encoded this run, by render key, so profiles producing identical
+/// images (same payload, size, colors, shapes and logo) encode them once.
+/// A thread asking for a key being encoded waits for the first.
+#[derive(Default)]
+pub(crate) struct RenderMemo {
+    slots: Mutex<HashMap<String, RenderSlot>>,
+}
+
+impl RenderMemo {
+    fn slot(&self, render_key: &str) -> RenderSlot {
+        let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+        slots.entry(render_key.to_string()).or_default().clone()
+    }
+}
+
+/// The write cache is shared by the rendering threads. It is locked around
+/// lookups and the final write only, never while encoding.
+fn lock(cache: &Mutex<WriteCache>) -> MutexGuard<'_, WriteCache> {
+    cache.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Render `url` to `root`/`qr_rel` (`.png` enforced), unless the write
+/// cache shows it is up to date. Returns the written path, the first 12 hex
+/// digits of its blake3 hash, and whether the file changed.
 pub fn write_qr_png(
     url: &str,
     root: &Path,
     qr_rel: &Path,
-    fit_w: u32,
-    fit_h: u32,
-    margin: u32,
-    shape: Option<Shape>,
-    background: Option<Color>,
-    module: Option<Color>,
-) -> Result<(PathBuf, String)> {
-    let qrcode = QRBuilder::new(url)
-        .build()
-        .map_err(|e| anyhow!("QR build error: {e:?}"))?;
-
-    let mut out = root.join(qr_rel);
-    if out
-        .extension()
-        .and_then(|s| s.to_str())
-        .unwrap_or("")
-        .to_ascii_lowercase()
-        != "png"
-    {
-        out.set_extension("png");
+    opts: &QrImageOptions,
+    assets: &AssetCache,
+    cache: Option<&Mutex<WriteCache>>,
+    memo: &RenderMemo,
+) -> Result<(PathBuf, String, bool)> {
+    let mut out = with_png_extension(&root.join(qr_rel));
+
+    // The final name of a hashed image is only known once it is rendered
+    let reused = cache
+        .filter(|_| !opts.hashed_name)
+        .and_then(|c| lock(c).reusable(&out, &opts.render_key));
+    if let Some((hash, saved)) = reused {
+        match saved {
+            Some(d) => debug!(
+                "mdbook-qr: reused raster {} ({REUSE_REASON}; saved {:.1} ms)",
+                out.display(),
+                d.as_secs_f64() * 1000.0
+            ),
+            None => debug!(
+                "mdbook-qr: reused raster {} ({REUSE_REASON})",
+                out.display()
+            ),
+        }
+        log_hash(&out, &hash, opts.deterministic);
+        return Ok((out, hash[..12].to_string(), false));
     }
+
+    // Identical images for other profiles this run are encoded once
+    let slot = memo.slot(&opts.render_key);
+    let mut encoded = slot.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut took = None;
+    let bytes = match encoded.as_ref() {
+        Some(bytes) => {
+            debug!(
+                "mdbook-qr: reusing render {} for {}",
+                &opts.render_key[..12],
+                out.display()
+            );
+            bytes.clone()
+        }
+        None => {
+            let started = Instant::now();
+            let bytes = encode_png(url, opts, assets)?;
+            took = Some(started.elapsed());
+            encoded.insert(Arc::new(bytes)).clone()
+        }
+    };
+    drop(encoded);
+
     if let Some(parent) = out.parent() {
-        fs::create_dir_all(parent).with_context(|| format!("Creating {}", parent.display()))?;
+        fs::create_dir_all(parent).map_err(QrError::io(parent))?;
     }
 
-    let mut builder = ImageBuilder::default();
-    builder
-        .margin(margin as usize)
-        .fit_width(fit_w)
-        .fit_height(fit_h);
-    if let Some(s) = shape {
-        builder.shape(s);
+    let hash = blake3::hash(&bytes);
+    if opts.hashed_name {
+        out = hashed_path(&out, &hash.to_hex()[..12]);
     }
-    if let Some(bg) = background {
-        builder.background_color(bg);
+    let mut guard = cache.map(lock);
+    let changed = write_if_changed(&out, &bytes, &hash, guard.as_deref_mut())?;
+    if let Some(c) = guard.as_deref_mut() {
+        c.record_render(&out, &opts.render_key, took);
     }
-    if let Some(fg) = module {
-        builder.module_color(fg);
+    log_hash(&out, &hash.to_hex(), opts.deterministic);
+    Ok((out, hash.to_hex()[..12].to_string(), changed))
+}
+
+/// One line per image with its full blake3 hash, at info level when the
+/// output is meant to be reproducible so CI can compare builds.
+fn log_hash(out: &Path, hash: &str, deterministic: bool) {
+    let level = if deterministic {
+        log::Level::Info
+    } else {
+        log::Level::Debug
+    };
+    log::log!(level, "mdbook-qr: {} blake3 {hash}", out.display());
+}
+
+/// The QR symbol for `url`; `None` keeps fast_qr's default level. A payload
+/// too long for any version gets an error naming its length and the limit.
+fn build_qr(url: &str, ecl: Option<Ecl>) -> Result<QRCode> {
+    let mut qr_builder = QRBuilder::new(url);
+    if let Some(ecl) = ecl {
+        qr_builder.ecl(ecl.to_ecl());
     }
+    let qr = qr_builder.build().map_err(|e| {
+        // Q is fast_qr's default level
+        let level = ecl.unwrap_or(Ecl::Q);
+        if url.len() > level.max_bytes() {
+            QrError::ImageEncode(format!(
+                "the payload is {} bytes, but a QR code holds at most {} at error-correction \
+                 level {level:?}; shorten it or lower `ecl`",
+                url.len(),
+                level.max_bytes()
+            ))
+        } else {
+            QrError::ImageEncode(format!("QR build error: {e:?}"))
+        }
+    })?;
+    Ok(qr)
+}
 
-    let bytes = builder
-        .to_bytes(&qrcode)
-        .map_err(|e| anyhow!("PNG encode: {e}"))?;
+/// Modules across the symbol for `url`, and the pixels each module gets
+/// when it is drawn `fit_w`×`fit_h` with `margins`, or `scale` px.
+pub fn module_px(
+    url: &str,
+    ecl: Option<Ecl>,
+    fit_w: u32,
+    fit_h: u32,
+    margins: Margins,
+    scale: Option<u32>,
+) -> Result<(usize, f32)> {
+    let size = build_qr(url, ecl)?.size;
+    let px = match scale {
+        Some(scale) => scale as f32,
+        None => raster::placement(size, fit_w, fit_h, margins).0,
+    };
+    Ok((size, px))
+}
 
-    let _changed = write_if_changed(&out, &bytes)?;
-    let hash = blake3::hash(&bytes).to_hex()[..12].to_string();
-    Ok((out, hash))
+/// `url` drawn in Unicode half blocks, two module rows per line, with
+/// `margins` of quiet zone, for `output = "text"`. Dark modules are drawn,
+/// or with `invert` the light ones and the quiet zone (for light-on-dark
+/// terminals).
+pub fn encode_text(url: &str, ecl: Option<Ecl>, margins: Margins, invert: bool) -> Result<String> {
+    let qrcode = build_qr(url, ecl)?;
+    let size = qrcode.size as i64;
+    let (left, top) = (margins.left as i64, margins.top as i64);
+    let width = size + left + margins.right as i64;
+    let height = size + top + margins.bottom as i64;
+    let ink = |x: i64, y: i64| {
+        if y >= height {
+            return false;
+        }
+        let (cx, cy) = (x - left, y - top);
+        let dark = (0..size).contains(&cx)
+            && (0..size).contains(&cy)
+            && qrcode.data[(cy * size + cx) as usize].value();
+        dark != invert
+    };
+    let mut out = String::new();
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            out.push(match (ink(x, y), ink(x, y + 1)) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        out.push('\n');
+    }
+    Ok(out)
 }
 
-fn write_if_changed(path: &Path, bytes: &[u8]) -> Result<bool> {
-    if let Ok(existing) = fs::read(path) {
-        if existing == bytes {
-            return Ok(false);
+/// The PNG for `url` under `opts`, logo included.
+fn encode_png(url: &str, opts: &QrImageOptions, assets: &AssetCache) -> Result<Vec<u8>> {
+    let qrcode = build_qr(url, opts.ecl)?;
+
+    let size = qrcode.size;
+    let m = opts.margin;
+    let (width, height) = match opts.scale {
+        Some(scale) => {
+            let width = (size as u32 + m.left + m.right) * scale;
+            let height = (size as u32 + m.top + m.bottom) * scale;
+            info!("mdbook-qr: {size} modules + {m} margin at {scale} px each: {width}x{height} px");
+            (width, height)
         }
+        None => (opts.fit_w, opts.fit_h),
+    };
+    let (cell, x, y) = raster::placement(size, width, height, m);
+    let mut bytes = match &opts.regions {
+        Some(style) => raster::render_png(&qrcode, width, height, m, style)?,
+        None => {
+            // fast_qr's margin is the same on every side: for uneven ones,
+            // render the bare code and pad it below
+            let uniform = m.as_uniform();
+            let (fit_w, fit_h) = match uniform {
+                Some(_) => (width, height),
+                None => {
+                    let edge = (cell * size as f32).round().max(1.0) as u32;
+                    (edge, edge)
+                }
+            };
+            let mut builder = ImageBuilder::default();
+            builder
+                .margin(uniform.unwrap_or(0) as usize)
+                .fit_width(fit_w)
+                .fit_height(fit_h);
+            if let Some(s) = opts.shape.clone() {
+                builder.shape(s);
+            }
+            if let Some(bg) = opts.background.clone() {
+                builder.background_color(bg);
+            }
+            if let Some(fg) = opts.module.clone() {
+                builder.module_color(fg);
+            }
+            let png = builder
+                .to_bytes(&qrcode)
+                .map_err(|e| QrError::ImageEncode(format!("PNG encode: {e}")))?;
+            match uniform {
+                Some(_) => png,
+                None => {
+                    let background = opts.background.as_ref().map_or([255; 4], |c| c.0);
+                    pad_png(&png, width, height, (x, y), background)?
+                }
+            }
+        }
+    };
+
+    if let Some(logo) = &opts.logo {
+        // Centred on the code, which uneven margins move off the image centre
+        let shift = |near: u32, far: u32| ((near as f32 - far as f32) * cell / 2.0).round() as i64;
+        let offset = (shift(m.left, m.right), shift(m.top, m.bottom));
+        bytes = overlay_logo(&bytes, logo, assets, offset)?;
+    }
+    if let Some(border) = opts.border.as_ref().filter(|b| b.width > 0 || b.radius > 0) {
+        bytes = add_border(&bytes, border)?;
+    }
+    if opts.deterministic {
+        bytes = canonical_png(&bytes)?;
+    }
+    Ok(bytes)
+}
+
+/// The bare code in `png` placed at `at` on a `width`×`height` canvas of
+/// `background`.
+fn pad_png(
+    png: &[u8],
+    width: u32,
+    height: u32,
+    at: (f32, f32),
+    background: [u8; 4],
+) -> Result<Vec<u8>> {
+    let code = image::load_from_memory_with_format(png, ImageFormat::Png)
+        .map_err(|e| QrError::ImageEncode(format!("PNG decode: {e}")))?
+        .to_rgba8();
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba(background));
+    imageops::replace(&mut canvas, &code, at.0.round() as i64, at.1.round() as i64);
+    let mut out = Vec::new();
+    canvas
+        .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+        .map_err(|e| QrError::ImageEncode(format!("PNG encode: {e}")))?;
+    Ok(out)
+}
+
+/// `png` on a canvas `border.width` px larger on every side, filled with
+/// the border color, with the corners outside `border.radius` made
+/// transparent. A radius over half the canvas is clamped with a warning.
+fn add_border(png: &[u8], border: &Border) -> Result<Vec<u8>> {
+    let code = image::load_from_memory_with_format(png, ImageFormat::Png)
+        .map_err(|e| QrError::ImageEncode(format!("PNG decode: {e}")))?
+        .to_rgba8();
+    let b = border.width;
+    let (width, height) = (code.width() + 2 * b, code.height() + 2 * b);
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba(border.color));
+    imageops::replace(&mut canvas, &code, b as i64, b as i64);
+
+    let max = width.min(height) / 2;
+    let radius = if border.radius > max {
+        warn!(
+            "mdbook-qr: border radius {} is more than half the {width}x{height} px image; using {max}",
+            border.radius
+        );
+        max
+    } else {
+        border.radius
+    };
+    let r = radius as f32;
+    for (x, y, px) in canvas.enumerate_pixels_mut() {
+        // Offset from the centre of the corner's arc, when in a corner square
+        let dx = match x {
+            x if x < radius => r - (x as f32 + 0.5),
+            x if x >= width - radius => x as f32 + 0.5 - (width - radius) as f32,
+            _ => continue,
+        };
+        let dy = match y {
+            y if y < radius => r - (y as f32 + 0.5),
+            y if y >= height - radius => y as f32 + 0.5 - (height - radius) as f32,
+            _ => continue,
+        };
+        if dx * dx + dy * dy > r * r {
+            px.0[3] = 0;
+        }
+    }
+
+    let mut out = Vec::new();
+    canvas
+        .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+        .map_err(|e| QrError::ImageEncode(format!("PNG encode: {e}")))?;
+    Ok(out)
+}
+
+/// Re-encode `png` as 8-bit RGBA with pinned compression and filtering and
+/// only the critical chunks, so equal pixels always give equal bytes for a
+/// given build of mdbook-qr.
+fn canonical_png(png: &[u8]) -> Result<Vec<u8>> {
+    let img = image::load_from_memory_with_format(png, ImageFormat::Png)
+        .map_err(|e| QrError::ImageEncode(format!("PNG decode: {e}")))?
+        .to_rgba8();
+    let mut out = Vec::new();
+    PngEncoder::new_with_quality(&mut out, CompressionType::Best, PngFilter::Adaptive)
+        .write_image(
+            img.as_raw(),
+            img.width(),
+            img.height(),
+            ExtendedColorType::Rgba8,
+        )
+        .map_err(|e| QrError::ImageEncode(format!("PNG encode: {e}")))?;
+    Ok(out)
+}
+
+/// `dir/stem.png` → `dir/stem.<hash12>.png`.
+fn hashed_path(out: &Path, hash12: &str) -> PathBuf {
+    let stem = out
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    out.with_file_name(format!("{stem}.{hash12}.png"))
+}
+
+/// Composite `logo` into the centre of the PNG in `png`, moved by `offset`
+/// px, returning the re-encoded PNG.
+fn overlay_logo(
+    png: &[u8],
+    logo: &LogoOverlay,
+    assets: &AssetCache,
+    offset: (i64, i64),
+) -> Result<Vec<u8>> {
+    let mut base = image::load_from_memory_with_format(png, ImageFormat::Png)
+        .map_err(|e| QrError::ImageEncode(format!("PNG decode: {e}")))?
+        .to_rgba8();
+    let (w, h) = base.dimensions();
+
+    let edge = ((w.min(h) as f32) * logo.scale).round().max(1.0) as u32;
+    let scaled = assets.scaled(logo.asset, edge, edge);
+    let (lw, lh) = scaled.dimensions();
+    let x = (w.saturating_sub(lw) / 2) as i64 + offset.0;
+    let y = (h.saturating_sub(lh) / 2) as i64 + offset.1;
+
+    if logo.padding > 0 {
+        let pad = logo.padding as i64;
+        let backing = RgbaImage::from_pixel(
+            lw + 2 * logo.padding,
+            lh + 2 * logo.padding,
+            Rgba([255, 255, 255, 255]),
+        );
+        imageops::overlay(&mut base, &backing, x - pad, y - pad);
+    }
+    imageops::overlay(&mut base, scaled.as_ref(), x, y);
+
+    let mut out = Vec::new();
+    base.write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+        .map_err(|e| QrError::ImageEncode(format!("PNG encode: {e}")))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard() -> RgbaImage {
+        RgbaImage::from_fn(33, 33, |x, y| {
+            Rgba(if (x ^ y) & 1 == 0 {
+                [0, 0, 0, 255]
+            } else {
+                [255, 255, 255, 255]
+            })
+        })
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &b in bytes {
+            crc ^= u32::from(b);
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        !crc
+    }
+
+    /// Chunk types of `png`, in order.
+    fn chunks(png: &[u8]) -> Vec<String> {
+        let mut kinds = Vec::new();
+        let mut at = 8;
+        while at < png.len() {
+            let len = u32::from_be_bytes(png[at..at + 4].try_into().unwrap()) as usize;
+            kinds.push(String::from_utf8_lossy(&png[at + 4..at + 8]).into_owned());
+            at += 12 + len;
+        }
+        kinds
+    }
+
+    /// `png` with a `tEXt` chunk, as other encoders write, after `IHDR`.
+    fn with_text_chunk(png: &[u8]) -> Vec<u8> {
+        let ihdr_end = 8 + 12 + 13;
+        let body = b"tEXtCreation Time\0Thu, 01 Jan 2026";
+        let mut chunk = (body.len() as u32 - 4).to_be_bytes().to_vec();
+        chunk.extend_from_slice(body);
+        chunk.extend_from_slice(&crc32(body).to_be_bytes());
+        [&png[..ihdr_end], &chunk, &png[ihdr_end..]].concat()
+    }
+
+    #[test]
+    fn canonical_png_keeps_only_critical_chunks() {
+        let mut raw = Vec::new();
+        checkerboard()
+            .write_to(&mut Cursor::new(&mut raw), ImageFormat::Png)
+            .unwrap();
+        let tagged = with_text_chunk(&raw);
+        assert!(chunks(&tagged).contains(&"tEXt".to_string()));
+
+        let canonical = canonical_png(&tagged).unwrap();
+        assert!(
+            chunks(&canonical)
+                .iter()
+                .all(|k| ["IHDR", "IDAT", "IEND"].contains(&k.as_str())),
+            "{:?}",
+            chunks(&canonical)
+        );
+        let decoded = image::load_from_memory(&canonical).unwrap().to_rgba8();
+        assert_eq!(decoded, checkerboard());
+    }
+
+    #[test]
+    fn canonical_png_depends_only_on_the_pixels() {
+        let mut raw = Vec::new();
+        checkerboard()
+            .write_to(&mut Cursor::new(&mut raw), ImageFormat::Png)
+            .unwrap();
+        let canonical = canonical_png(&raw).unwrap();
+        assert_eq!(canonical_png(&with_text_chunk(&raw)).unwrap(), canonical);
+        assert_eq!(canonical_png(&canonical).unwrap(), canonical);
+        assert!(canonical_png(b"not a png").is_err());
     }
-    let parent = path.parent().unwrap_or_else(|| Path::new("."));
-    let tmp = parent.join(format!(
-        ".{}.tmp",
-        path.file_name()
-            .and_then(|s| s.to_str())
-            .unwrap_or("qr-image")
-    ));
-    {
-        let mut f =
-            fs::File::create(&tmp).with_context(|| format!("Creating {}", tmp.display()))?;
-        f.write_all(bytes)
-            .with_context(|| format!("Writing {}", tmp.display()))?;
-        let _ = f.sync_all();
-    }
-    fs::rename(&tmp, path)
-        .with_context(|| format!("Renaming {} → {}", tmp.display(), path.display()))?;
-    Ok(true)
 }
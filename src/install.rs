@@ -0,0 +1,119 @@
+use crate::util::{slug_from_marker, write_atomic};
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Appended when `book.toml` has no `[preprocessor.qr]`: the table itself,
+/// with the common settings commented out at their defaults.
+const QR_TABLE: &str = r#"[preprocessor.qr]
+# Address to encode; unset falls back to output.html.site-url, a CNAME
+# file or the CI environment.
+# url = "https://example.com/my-book/"
+# Image location, relative to src.
+# qr-path = "qr/qr_code.png"
+# Quiet zone around the code, in modules.
+# margin = 2
+
+# [preprocessor.qr.fit]
+# width = 256
+# height = 256
+"#;
+
+/// What `install` did to `book.toml`.
+pub struct InstallReport {
+    pub path: PathBuf,
+    /// One line per table added or already present.
+    pub changes: Vec<String>,
+    /// Whether the file was rewritten.
+    pub modified: bool,
+}
+
+/// Add `[preprocessor.qr]` (and `[preprocessor.qr.custom.<custom>]`) to the
+/// `book.toml` in `dir` unless present. The new tables are appended as text,
+/// so the rest of the file keeps its formatting and comments; re-running
+/// changes nothing.
+pub fn install(dir: &Path, custom: Option<&str>) -> Result<InstallReport> {
+    let path = dir.join("book.toml");
+    if !path.is_file() {
+        bail!(
+            "no book.toml in {}; run this in a book directory or pass it as an argument",
+            dir.display()
+        );
+    }
+    if let Some(name) = custom.filter(|n| !is_bare_key(n)) {
+        bail!("custom name '{name}' must use only letters, digits, '-' and '_'");
+    }
+    let original =
+        fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+    let doc: toml::Table =
+        toml::from_str(&original).with_context(|| format!("parsing {}", path.display()))?;
+    let qr = doc
+        .get("preprocessor")
+        .and_then(|p| p.get("qr"))
+        .and_then(|q| q.as_table());
+
+    let mut text = original.clone();
+    let mut changes = Vec::new();
+    if qr.is_some() {
+        changes.push("[preprocessor.qr] already present".to_string());
+    } else {
+        append(&mut text, QR_TABLE);
+        changes.push("added [preprocessor.qr]".to_string());
+    }
+    if let Some(name) = custom {
+        let table = format!("[preprocessor.qr.custom.{name}]");
+        let exists = qr
+            .and_then(|q| q.get("custom"))
+            .and_then(|c| c.get(name))
+            .is_some();
+        if exists {
+            changes.push(format!("{table} already present"));
+        } else {
+            let marker = format!("{{{{QR_{}}}}}", name.to_ascii_uppercase().replace('-', "_"));
+            append(
+                &mut text,
+                &format!(
+                    "{table}\nmarker = \"{marker}\"\n# qr-path = \"qr/{}.png\"\n",
+                    slug_from_marker(&marker)
+                ),
+            );
+            changes.push(format!("added {table} with marker {marker}"));
+        }
+    }
+
+    let modified = text != original;
+    if modified {
+        // An inline `qr = { ... }` cannot be extended by a later table
+        if let Err(e) = toml::from_str::<toml::Table>(&text) {
+            bail!(
+                "cannot extend the [preprocessor.qr] in {} automatically ({}); add the table by hand",
+                path.display(),
+                e.message()
+            );
+        }
+        write_atomic(&path, text.as_bytes())?;
+    }
+    Ok(InstallReport {
+        path,
+        changes,
+        modified,
+    })
+}
+
+/// Append `block` after a blank line, keeping the file newline-terminated.
+fn append(text: &mut String, block: &str) {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    text.push_str(block);
+}
+
+fn is_bare_key(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
@@ -1,10 +1,38 @@
 #![doc = include_str!("../README.md")]
 
+mod assets;
+mod cache;
+mod check;
+mod clean;
+pub mod cli;
+mod color;
 pub mod config;
+mod directive;
+mod error;
+mod geometry;
 mod html;
+mod html_escape;
 mod image;
+mod install;
+mod list;
+mod manifest;
+mod payload;
 mod preprocessor;
+mod provenance;
+mod qr_manifest;
+mod raster;
+mod state;
+mod summary;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+#[cfg(test)]
+mod testing;
 mod url;
 mod util;
+mod verify;
 
-pub use preprocessor::{run_preprocessor_once, QrPreprocessor};
+pub use error::QrError;
+pub use preprocessor::{
+    generate, run_preprocessor_once, run_preprocessor_with, GeneratedQr, QrPreprocessor,
+    ENV_DISABLE, ENV_DRY_RUN, ENV_FORCE_CONTINUE,
+};
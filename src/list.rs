@@ -0,0 +1,95 @@
+use crate::check::{profile_output, profile_url};
+use crate::config::{FitConfig, ModuleShape, QrConfig, ShapeCfg};
+use crate::preprocessor::effective_profiles;
+use crate::url::{cname_domain, site_url_fallback};
+use anyhow::{anyhow, Context, Result};
+use mdbook::Config;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// One profile after inheritance, as a build for the renderer would run it.
+#[derive(Debug, Serialize)]
+pub struct ListRow {
+    /// `default`, or the custom table name.
+    pub profile: String,
+    /// The marker, then its aliases.
+    pub markers: Vec<String>,
+    pub enabled: bool,
+    /// The encoded URL, or `None` when it cannot be resolved.
+    pub url: Option<String>,
+    /// The setting the URL came from, or why there is none.
+    pub url_source: String,
+    /// Image path, relative to the book root.
+    pub output: PathBuf,
+    pub fit: FitConfig,
+    /// Quiet zone in modules: top, right, bottom, left.
+    pub margin: [u32; 4],
+    pub shape: &'static str,
+    pub module: String,
+    pub background: String,
+}
+
+/// The effective profiles of the book in `dir` for `renderer`: the default
+/// and named customs with the default, the bare custom table and the
+/// renderer's overrides merged in. Directives in chapters are not included.
+pub fn list(dir: &Path, renderer: &str) -> Result<Vec<ListRow>> {
+    let (config, cfg) = load_config(dir)?;
+    let cfg = cfg.ok_or_else(|| {
+        anyhow!(
+            "{} has no [preprocessor.qr] table",
+            dir.join("book.toml").display()
+        )
+    })?;
+    let src_dir = config.book.src.clone();
+    let site_url = site_url_fallback(config.get("output.html.site-url").and_then(|v| v.as_str()));
+    let cname = cname_domain(dir, &src_dir);
+
+    let mut rows = Vec::new();
+    for (key, mut profile) in effective_profiles(&cfg, renderer)? {
+        let (url, url_source) =
+            match profile_url(&mut profile, &cfg, renderer, site_url, cname.as_deref()) {
+                Ok((url, source)) => (Some(url), source.to_string()),
+                Err(e) => (None, e),
+            };
+        let m = profile.margins();
+        rows.push(ListRow {
+            markers: profile.markers().into_iter().map(str::to_string).collect(),
+            enabled: profile.is_enabled(),
+            url,
+            url_source,
+            output: profile_output(&profile, &cfg, &src_dir),
+            fit: profile.fit.clone(),
+            margin: [m.top, m.right, m.bottom, m.left],
+            shape: profile
+                .shape
+                .as_ref()
+                .map_or(ModuleShape::Square, ShapeCfg::to_module_shape)
+                .name(),
+            module: hex(profile.module_rgba()),
+            background: hex(profile.background_rgba()),
+            profile: key,
+        });
+    }
+    Ok(rows)
+}
+
+/// The book config in `dir`, and its `[preprocessor.qr]` table if it has one.
+pub(crate) fn load_config(dir: &Path) -> Result<(Config, Option<QrConfig>)> {
+    let book_toml = dir.join("book.toml");
+    let mut config = Config::from_disk(&book_toml)
+        .with_context(|| format!("reading {}", book_toml.display()))?;
+    config.update_from_env();
+    let Some(table) = config.get_preprocessor("qr") else {
+        return Ok((config, None));
+    };
+    let (cfg, _) = QrConfig::from_table_reporting(table).context("[preprocessor.qr] is invalid")?;
+    Ok((config, Some(cfg)))
+}
+
+/// `#rrggbb`, with `aa` appended unless opaque.
+fn hex([r, g, b, a]: [u8; 4]) -> String {
+    match a {
+        255 => format!("#{r:02x}{g:02x}{b:02x}"),
+        _ => format!("#{r:02x}{g:02x}{b:02x}{a:02x}"),
+    }
+}
@@ -1,4 +1,6 @@
-use clap::{arg, Command};
+use clap_complete::Shell;
+use mdbook_qr::cli;
+use std::path::Path;
 use std::process;
 
 fn init_logging() {
@@ -26,21 +28,86 @@ fn init_logging() {
 fn main() {
     init_logging();
 
-    let cli = Command::new("mdbook-qr")
-        .about("An mdBook preprocessor that injects QR codes into pages")
-        .version(env!("CARGO_PKG_VERSION"))
-        .subcommand(
-            Command::new("supports")
-                .about("Check if a renderer is supported")
-                .arg(arg!(<renderer> "Renderer name")),
-        );
-
-    let matches = cli.get_matches();
-    if let Some(("supports", _)) = matches.subcommand() {
-        process::exit(0);
+    let matches = cli::build_cli().get_matches();
+    match matches.subcommand() {
+        Some(("supports", sub)) => {
+            let renderer = sub
+                .get_one::<String>("renderer")
+                .expect("required argument");
+            process::exit(if cli::supports(Path::new("."), renderer) {
+                0
+            } else {
+                1
+            });
+        }
+        Some(("completions", sub)) => {
+            let shell = *sub.get_one::<Shell>("shell").expect("required argument");
+            cli::print_completions(shell);
+            return;
+        }
+        Some(("info", _)) => {
+            if let Err(e) = cli::print_info() {
+                log::error!("could not print info: {e}");
+                process::exit(1);
+            }
+            return;
+        }
+        Some(("audit", sub)) => match cli::run_audit(sub) {
+            Ok(true) => return,
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                log::error!("audit failed: {e:#}");
+                process::exit(1);
+            }
+        },
+        Some(("check", sub)) => match cli::run_check(sub) {
+            Ok(true) => return,
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                log::error!("check failed: {e:#}");
+                process::exit(1);
+            }
+        },
+        Some(("clean", sub)) => {
+            if let Err(e) = cli::run_clean(sub) {
+                log::error!("clean failed: {e:#}");
+                process::exit(1);
+            }
+            return;
+        }
+        Some(("list", sub)) => {
+            if let Err(e) = cli::run_list(sub) {
+                log::error!("list failed: {e:#}");
+                process::exit(1);
+            }
+            return;
+        }
+        Some(("render", sub)) => {
+            if let Err(e) = cli::run_render(sub) {
+                log::error!("render failed: {e:#}");
+                process::exit(1);
+            }
+            return;
+        }
+        Some(("install", sub)) => {
+            if let Err(e) = cli::run_install(sub) {
+                log::error!("install failed: {e:#}");
+                process::exit(1);
+            }
+            return;
+        }
+        Some(("man", _)) => {
+            if let Err(e) = cli::print_man() {
+                log::error!("could not render man page: {e}");
+                process::exit(1);
+            }
+            return;
+        }
+        _ => {}
     }
 
-    if let Err(e) = mdbook_qr::run_preprocessor_once() {
+    let pre = mdbook_qr::QrPreprocessor::new().no_cache(matches.get_flag("no-cache"));
+    if let Err(e) = mdbook_qr::run_preprocessor_with(pre) {
         log::error!("preprocessor failed: {e}");
         process::exit(1);
     }
@@ -0,0 +1,181 @@
+use crate::error::QrError;
+use crate::state::{self, Artifact};
+use crate::util::{hash_file, write_if_changed};
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Files generated by a run, keyed by their path relative to the book root,
+/// with the first 12 hex digits of their blake3 hash. Stored next to the
+/// write cache so the next run can remove what it no longer produces.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Manifest {
+    #[serde(default)]
+    files: BTreeMap<String, String>,
+}
+
+impl Artifact for Manifest {
+    const KIND: &'static str = "manifest";
+}
+
+impl Manifest {
+    /// Load the manifest; a missing, corrupt or foreign file yields an empty
+    /// one, so nothing is deleted on its account.
+    pub fn load(path: &Path) -> Self {
+        state::load(path)
+    }
+
+    /// Record `abs`, written under `root` with content hash `hash12`.
+    pub fn record(&mut self, root: &Path, abs: &Path, hash12: &str) {
+        let rel = abs.strip_prefix(root).unwrap_or(abs);
+        self.files
+            .insert(key(rel), hash12.chars().take(12).collect());
+    }
+
+    /// Whether `abs`, under `root`, was recorded.
+    pub fn contains(&self, root: &Path, abs: &Path) -> bool {
+        let rel = abs.strip_prefix(root).unwrap_or(abs);
+        self.files.contains_key(&key(rel))
+    }
+
+    /// Keep `previous`'s entries alongside this run's (nothing becomes stale).
+    pub fn carry_over(&mut self, previous: Manifest) {
+        for (path, hash) in previous.files {
+            self.files.entry(path).or_insert(hash);
+        }
+    }
+
+    /// Delete files listed in `previous` that this run did not produce. Only
+    /// files still matching their recorded hash are removed; anything edited
+    /// or replaced since is left alone. Returns the number removed.
+    pub fn remove_stale(&self, previous: &Manifest, root: &Path) -> usize {
+        let mut removed = 0;
+        for (rel, hash) in &previous.files {
+            if self.files.contains_key(rel) {
+                continue;
+            }
+            let Some(path) = contained(root, rel) else {
+                warn!("mdbook-qr: ignoring manifest entry '{rel}' outside the book root");
+                continue;
+            };
+            match unchanged(&path, hash) {
+                Some(true) => match fs::remove_file(&path) {
+                    Ok(()) => {
+                        info!("mdbook-qr: removed stale {}", path.display());
+                        removed += 1;
+                    }
+                    Err(e) => warn!("mdbook-qr: could not remove {}: {e}", path.display()),
+                },
+                Some(false) => debug!(
+                    "mdbook-qr: keeping {}; it changed since mdbook-qr wrote it",
+                    path.display()
+                ),
+                // Already gone
+                None => {}
+            }
+        }
+        removed
+    }
+
+    /// Listed files still on disk under `root`, each with whether it still
+    /// matches its recorded hash. Entries outside the root are left out.
+    pub fn on_disk(&self, root: &Path) -> Vec<(PathBuf, bool)> {
+        self.files
+            .iter()
+            .filter_map(|(rel, hash)| {
+                let path = contained(root, rel)?;
+                let same = unchanged(&path, hash)?;
+                Some((path, same))
+            })
+            .collect()
+    }
+
+    /// Write the manifest atomically, leaving an unchanged file untouched.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(QrError::io(parent))?;
+        }
+        let json = state::encode(self)?;
+        write_if_changed(path, &json, &blake3::hash(&json), None)?;
+        Ok(())
+    }
+}
+
+/// Whether `path` still holds the file recorded with `hash`; `None` when it
+/// cannot be read.
+fn unchanged(path: &Path, hash: &str) -> Option<bool> {
+    let h = hash_file(path).ok()?;
+    // A hand-edited, shortened hash never matches
+    Some(hash.len() == 12 && h.to_hex().starts_with(hash))
+}
+
+/// `rel` under `root`, unless it is absolute or climbs out with `..`.
+fn contained(root: &Path, rel: &str) -> Option<PathBuf> {
+    let rel = Path::new(rel);
+    rel.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        .then(|| root.join(rel))
+}
+
+fn key(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn round_trips_through_disk() {
+        let dir = TempDir::new("manifest-round-trip");
+        let root = dir.path();
+        let image = root.join("src/qr/a.png");
+        fs::create_dir_all(image.parent().unwrap()).unwrap();
+        fs::write(&image, b"png").unwrap();
+
+        let mut manifest = Manifest::default();
+        manifest.record(root, &image, &blake3::hash(b"png").to_hex());
+        let file = root.join("src/qr/.mdbook-qr-manifest-test.json");
+        manifest.save(&file).unwrap();
+
+        let loaded = Manifest::load(&file);
+        assert!(loaded.contains(root, &image));
+        assert!(!loaded.contains(root, &root.join("src/qr/b.png")));
+        assert_eq!(loaded.on_disk(root), [(image, true)]);
+    }
+
+    #[test]
+    fn corrupt_file_loads_empty() {
+        let dir = TempDir::new("manifest-corrupt");
+        let file = dir.path().join("manifest.json");
+        fs::write(&file, "{ not json").unwrap();
+        assert!(Manifest::load(&file).files.is_empty());
+    }
+
+    #[test]
+    fn remove_stale_skips_edited_and_outside_entries() {
+        let dir = TempDir::new("manifest-stale");
+        let root = dir.path();
+        let mut previous = Manifest::default();
+        for name in ["kept.png", "stale.png", "edited.png"] {
+            let path = root.join(name);
+            fs::write(&path, name).unwrap();
+            previous.record(root, &path, &blake3::hash(name.as_bytes()).to_hex());
+        }
+        fs::write(root.join("edited.png"), b"changed").unwrap();
+        previous
+            .files
+            .insert("../outside.png".to_string(), "000000000000".to_string());
+
+        let mut current = Manifest::default();
+        current.record(root, &root.join("kept.png"), "000000000000");
+        assert_eq!(current.remove_stale(&previous, root), 1);
+        assert!(!root.join("stale.png").exists());
+        assert!(root.join("kept.png").exists());
+        assert!(root.join("edited.png").exists());
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::{ContactCfg, WifiCfg, WifiSecurity};
+
+/// Backslash-escape the characters the `WIFI:` format reserves.
+fn escape_wifi(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for ch in value.chars() {
+        if matches!(ch, '\\' | ';' | ',' | ':' | '"') {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out
+}
+
+/// `WIFI:T:WPA;S:<ssid>;P:<password>;H:true;;`, the format phone cameras
+/// offer to join. `nopass` networks carry no password.
+pub fn wifi(cfg: &WifiCfg) -> String {
+    let (kind, password) = match cfg.security {
+        WifiSecurity::Wpa => ("WPA", cfg.password.as_deref()),
+        WifiSecurity::Wep => ("WEP", cfg.password.as_deref()),
+        WifiSecurity::Nopass => ("nopass", None),
+    };
+    let mut out = format!("WIFI:T:{kind};S:{};", escape_wifi(&cfg.ssid));
+    if let Some(password) = password {
+        out.push_str(&format!("P:{};", escape_wifi(password)));
+    }
+    if cfg.hidden {
+        out.push_str("H:true;");
+    }
+    out.push(';');
+    out
+}
+
+/// Escape a vCard text value (RFC 2426 §4).
+fn escape_vcard(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '\\' | ';' | ',' => {
+                out.push('\\');
+                out.push(ch);
+            }
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Fold a content line at 75 octets: CRLF, then a space before each
+/// continuation. Never splits a UTF-8 character.
+fn fold_line(line: &str, out: &mut String) {
+    let mut width = 0;
+    for ch in line.chars() {
+        if width + ch.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(ch);
+        width += ch.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// vCard 3.0 for `cfg`. `N` is derived from `name`: the last word is the
+/// family name, the rest the given names.
+pub fn vcard(cfg: &ContactCfg) -> String {
+    let name = cfg.name.trim();
+    let (given, family) = name.rsplit_once(char::is_whitespace).unwrap_or(("", name));
+    let mut lines = vec![
+        "BEGIN:VCARD".to_string(),
+        "VERSION:3.0".to_string(),
+        format!(
+            "N:{};{};;;",
+            escape_vcard(family),
+            escape_vcard(given.trim_end())
+        ),
+        format!("FN:{}", escape_vcard(name)),
+    ];
+    if let Some(org) = &cfg.org {
+        lines.push(format!("ORG:{}", escape_vcard(org)));
+    }
+    if let Some(title) = &cfg.title {
+        lines.push(format!("TITLE:{}", escape_vcard(title)));
+    }
+    for phone in &cfg.phone {
+        lines.push(format!("TEL:{}", escape_vcard(phone)));
+    }
+    for email in &cfg.email {
+        lines.push(format!("EMAIL;TYPE=INTERNET:{}", escape_vcard(email)));
+    }
+    // A URI value, not text: only line breaks need care
+    if let Some(url) = &cfg.url {
+        lines.push(format!("URL:{}", url.replace(['\r', '\n'], "")));
+    }
+    lines.push("END:VCARD".to_string());
+
+    let mut out = String::new();
+    for line in &lines {
+        fold_line(line, &mut out);
+    }
+    out
+}
@@ -1,23 +1,74 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use globset::GlobSet;
 use log::{debug, info, warn};
-use mdbook::book::{Book, BookItem};
+use mdbook::book::{Book, BookItem, Chapter};
 use mdbook::errors::Error;
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor, PreprocessorContext};
-use std::collections::HashMap;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
 use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
 
-use crate::config::{ColorCfg, FailureMode, FitConfig, Profile, QrConfig, ShapeFlags};
-use crate::html::inject_marker_relative;
-use crate::image::write_qr_png;
+use crate::assets::{AssetCache, LoadedAsset};
+use crate::cache::{cache_path, manifest_path, WriteCache, REUSE_REASON};
+use crate::config::{
+    AssetLimits, CacheBust, CollisionPolicy, DebugCommentsCfg, DensityPolicy, DraftPolicy,
+    DuplicatePolicy, Ecl, ExpiredPolicy, FailureMode, IsoDate, Margins, OutputMode, PathStyle,
+    Profile, QrConfig, RegionShapes, UrlSubstitution, DEFAULT_CACHE_BUST_PARAM,
+    DEFAULT_MIN_MODULE_PX,
+};
+use crate::directive::apply_directives;
+use crate::error::QrError;
+use crate::html::{
+    contains_marker, count_markers_outside_code, draft_markers, find_prefixed_markers,
+    image_snippet, inject_marker_relative, replace_in_names, replace_with_text, strip_unmatched,
+    DarkImage, HtmlTemplate, InjectOptions, Syntax, Variant,
+};
+use crate::html_escape;
+use crate::image::{
+    encode_text, module_px, write_qr_png, Border, LogoOverlay, QrImageOptions, RenderMemo,
+};
+use crate::manifest::Manifest;
+use crate::provenance::AssetSigner;
+use crate::qr_manifest::{Fit, ManifestEntry, QrManifest};
+use crate::raster::{Gradient, RasterStyle};
+use crate::summary::{Reuse, Summary, WriteCounts};
+use crate::url::DEFAULT_LOCALHOST_PORT;
+use crate::url::{
+    append_query_params, check_url, expand_url_template, template_uses_path, UrlVars,
+};
 use crate::util::{
-    derived_default_path, ensure_gitignore_for_localhost, localhost_fixed_path, pass_fit_dims,
-    resolve_profile_path,
+    chapter_glob_set, chapter_output_path, chapter_variant_path, dark_variant_path,
+    density_variant_path, derived_default_path, edit_distance, ensure_gitignore_for_localhost,
+    env_flag, hash_file, in_scope, is_png_file, localhost_fixed_path, marker_variant_path,
+    options_hashed_path, pass_fit_dims, resolve_profile_path, slug_from_marker, with_png_extension,
+    within_root, ChapterFilter,
 };
+use crate::verify::verify_png;
+
+/// Emergency kill switch: pass every book through untouched.
+pub const ENV_DISABLE: &str = "MDBOOK_QR_DISABLE";
+/// Downgrade every `on-failure = "bail"` to `continue` for this run.
+pub const ENV_FORCE_CONTINUE: &str = "MDBOOK_QR_FORCE_CONTINUE";
+/// Plan the run and log it, as `dry-run = true` does.
+pub const ENV_DRY_RUN: &str = "MDBOOK_QR_DRY_RUN";
+
+/// Default logo edge as a fraction of the QR edge.
+const DEFAULT_LOGO_SCALE: f32 = 0.2;
 
-pub struct QrPreprocessor;
+pub struct QrPreprocessor {
+    no_cache: bool,
+}
 impl QrPreprocessor {
     pub fn new() -> Self {
-        Self
+        Self { no_cache: false }
+    }
+
+    /// Ignore the persistent write cache and compare every output in full.
+    pub fn no_cache(mut self, no_cache: bool) -> Self {
+        self.no_cache = no_cache;
+        self
     }
 }
 
@@ -26,7 +77,7 @@ impl Preprocessor for QrPreprocessor {
         "qr"
     }
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> std::result::Result<Book, Error> {
-        run_impl(ctx, &mut book).map_err(Error::from)?;
+        run_impl(ctx, &mut book, self).map_err(Error::from)?;
         Ok(book)
     }
     fn supports_renderer(&self, _renderer: &str) -> bool {
@@ -34,8 +85,14 @@ impl Preprocessor for QrPreprocessor {
     }
 }
 
-pub fn run_preprocessor_once() -> Result<()> {
-    let pre = QrPreprocessor::new();
+/// Read mdBook's preprocessor input from stdin and write the processed book
+/// to stdout.
+pub fn run_preprocessor_once() -> Result<(), QrError> {
+    run_preprocessor_with(QrPreprocessor::new())
+}
+
+/// Like [`run_preprocessor_once`], with a configured preprocessor.
+pub fn run_preprocessor_with(pre: QrPreprocessor) -> Result<(), QrError> {
     let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
 
     if ctx.mdbook_version != mdbook::MDBOOK_VERSION {
@@ -47,157 +104,687 @@ pub fn run_preprocessor_once() -> Result<()> {
         );
     }
 
-    pre.run(&ctx, book)
-        .map(|processed| {
-            serde_json::to_writer(io::stdout(), &processed).expect("write preprocessor output");
-        })
-        .map_err(|e| anyhow::anyhow!(e))
+    let processed = pre.run(&ctx, book)?;
+    serde_json::to_writer(io::stdout(), &processed).expect("write preprocessor output");
+    Ok(())
 }
 
-/// Does the given marker appear in any chapter?
-fn marker_in_book(book: &Book, marker: &str) -> bool {
-    book.sections.iter().any(|item| {
-        if let BookItem::Chapter(ch) = item {
-            ch.content.contains(marker)
-        } else {
-            false
-        }
+/// Every chapter, nested ones included, that passes the `chapters` allowlist.
+/// The path is checked before any content is read.
+fn chapters_in_scope<'a>(book: &'a Book, scope: &'a GlobSet) -> impl Iterator<Item = &'a Chapter> {
+    book.iter().filter_map(move |item| match item {
+        BookItem::Chapter(ch) if in_scope(scope, ch) => Some(ch),
+        BookItem::Chapter(_) | BookItem::PartTitle(_) | BookItem::Separator => None,
     })
 }
 
-/// Build a `Profile` from the bare `[preprocessor.qr.custom]` table (no marker).
-/// Avoids `toml` type/version clashes by reading primitives only.
-fn load_custom_defaults(ctx: &PreprocessorContext) -> Option<Profile> {
-    let custom = ctx
-        .config
-        .get("preprocessor")?
-        .get("qr")?
-        .get("custom")?
-        .as_table()?;
-
-    let mut p = Profile {
-        enable: None,
-        localhost_qr: None,
-        marker: None,
-        url: None,
-        qr_path: None,
-        fit: FitConfig::default(),
-        margin: None,
-        shape: ShapeFlags::default(),
-        background: None,
-        module: None,
-    };
+/// Does any of the given markers (one profile's aliases) appear in a
+/// chapter in scope that `filter` admits?
+fn marker_in_book(
+    book: &Book,
+    scope: &GlobSet,
+    filter: &ChapterFilter,
+    markers: &[&str],
+    syntax: Syntax,
+) -> bool {
+    chapters_in_scope(book, scope)
+        .any(|ch| filter.matches(ch) && contains_marker(&ch.content, markers, syntax))
+}
+
+/// Single pass over every chapter in scope, checking names too with `names`
+/// (`replace-in-names`).
+fn any_marker_in_book(
+    book: &Book,
+    scope: &GlobSet,
+    markers: &[&str],
+    syntax: Syntax,
+    names: bool,
+) -> bool {
+    chapters_in_scope(book, scope).any(|ch| {
+        contains_marker(&ch.content, markers, syntax)
+            || (names && contains_marker(&ch.name, markers, syntax))
+    })
+}
+
+/// Markers of the enabled profiles.
+fn enabled_markers(profiles: &[(String, Profile)]) -> Vec<&str> {
+    profiles
+        .iter()
+        .map(|(_, p)| p)
+        .filter(|p| p.is_enabled())
+        .flat_map(|p| p.markers())
+        .collect()
+}
 
-    if let Some(v) = custom.get("enable").and_then(|v| v.as_bool()) {
-        p.enable = Some(v);
+/// Every configured marker, enabled or not, sorted for orphan suggestions
+/// and with its chapter filter for the final unreplaced check.
+fn configured_markers(
+    profiles: &[(String, Profile)],
+) -> (Vec<String>, Vec<(String, ChapterFilter)>) {
+    let mut configured: Vec<String> = profiles
+        .iter()
+        .flat_map(|(_, p)| p.markers())
+        .map(str::to_string)
+        .collect();
+    configured.sort();
+    configured.dedup();
+    let expected = profiles
+        .iter()
+        .flat_map(|(_, p)| {
+            p.markers()
+                .into_iter()
+                .map(|m| (m.to_string(), p.chapter_filter()))
+        })
+        .collect();
+    (configured, expected)
+}
+
+/// Whether any of `markers` appears in a chapter matched by `generated`
+/// (`generated-chapter-globs`): content produced by another tool on each build.
+fn marker_in_generated(
+    book: &Book,
+    scope: &GlobSet,
+    markers: &[&str],
+    generated: &GlobSet,
+    syntax: Syntax,
+) -> bool {
+    !generated.is_empty()
+        && chapters_in_scope(book, scope).any(|ch| {
+            ch.path.as_ref().is_some_and(|p| generated.is_match(p))
+                && contains_marker(&ch.content, markers, syntax)
+        })
+}
+
+/// After injection: warn about `{{<prefix>...}}` markers no profile is
+/// configured for, suggesting the closest configured one, failing under
+/// `strict`.
+fn report_orphans(
+    book: &Book,
+    scope: &GlobSet,
+    syntax: Syntax,
+    markers: &[String],
+    prefix: &str,
+    strict: bool,
+) -> Result<()> {
+    if prefix.is_empty() {
+        return Ok(());
+    }
+    let opening = format!("{{{{{prefix}");
+    let mut found = Vec::new();
+    for ch in chapters_in_scope(book, scope) {
+        let Some(path) = ch.path.as_ref().filter(|_| ch.content.contains(&opening)) else {
+            continue;
+        };
+        for orphan in find_prefixed_markers(&ch.content, prefix, syntax) {
+            if markers.contains(&orphan) {
+                continue;
+            }
+            let closest = markers
+                .iter()
+                .map(|m| (edit_distance(&orphan, m), m))
+                .min()
+                .filter(|(distance, m)| *distance <= m.chars().count() / 2);
+            match closest {
+                Some((_, m)) => warn!(
+                    "mdbook-qr: unknown marker {orphan} in '{}'; did you mean {m}?",
+                    path.display()
+                ),
+                None => warn!(
+                    "mdbook-qr: unknown marker {orphan} in '{}'; no profile is configured for it.",
+                    path.display()
+                ),
+            }
+            found.push(format!("{}: {orphan}", path.display()));
+        }
+    }
+    if strict && !found.is_empty() {
+        return Err(QrError::MarkerNotFound(found).into());
     }
+    Ok(())
+}
 
-    if let Some(v) = custom.get("localhost-qr").and_then(|v| v.as_bool()) {
-        p.localhost_qr = Some(v);
+/// After injection: report configured markers still present outside code
+/// in chapters their profile's filter admits, failing under `strict`.
+fn report_unreplaced(
+    book: &Book,
+    scope: &GlobSet,
+    syntax: Syntax,
+    markers: &[(String, ChapterFilter)],
+    strict: bool,
+) -> Result<()> {
+    let mut found = Vec::new();
+    for ch in chapters_in_scope(book, scope) {
+        let Some(path) = &ch.path else {
+            continue;
+        };
+        let expected = markers
+            .iter()
+            .filter(|(m, filter)| {
+                contains_marker(&ch.content, &[m.as_str()], syntax) && filter.matches(ch)
+            })
+            .map(|(m, _)| m);
+        for marker in expected {
+            let count = count_markers_outside_code(&ch.content, &[marker.as_str()], syntax);
+            if count > 0 {
+                found.push(format!("{}: {marker} ({count}x)", path.display()));
+            }
+        }
+    }
+    // A marker shared by several profiles is reported once
+    found.dedup();
+    if found.is_empty() {
+        return Ok(());
     }
+    if strict {
+        return Err(QrError::UnreplacedMarkers(found).into());
+    }
+    warn!(
+        "mdbook-qr: markers left unreplaced:\n  {}",
+        found.join("\n  ")
+    );
+    Ok(())
+}
+
+/// Path and content hash of an image as written at 1x, and its extra
+/// densities as `(ratio, path, content hash)`.
+type Rendered = (PathBuf, String, Vec<(u32, PathBuf, String)>);
+
+/// [`Variant`]s borrowing from rendered densities; `query_bust` appends `?v=`.
+fn variants(densities: &[(u32, PathBuf, String)], query_bust: bool) -> Vec<Variant<'_>> {
+    densities
+        .iter()
+        .map(|(ratio, path, hash)| Variant {
+            ratio: *ratio,
+            path,
+            cache_bust: query_bust.then_some(hash.as_str()),
+        })
+        .collect()
+}
+
+/// What a profile's images share, prepared before any rendering starts.
+struct Job {
+    key: String,
+    /// The primary marker; files and logs are named after it.
+    marker: String,
+    /// `marker` and its aliases, all replaced with the same image.
+    aliases: Vec<String>,
+    profile: Profile,
+    filter: ChapterFilter,
+    on_failure: FailureMode,
+    is_data: bool,
+    /// `text` output: the code is drawn in the chapter, and no image written.
+    text: bool,
+    /// `path-style = "hashed"` on a derived path: the render key is part
+    /// of each file name.
+    hashed_path: bool,
+    /// `cache-bust = "filename"`: the content hash is part of each file name.
+    hashed_name: bool,
+    targets: Vec<Target>,
+    logo_asset: Option<LoadedAsset>,
+    ecl: Option<Ecl>,
+    logo_scale: f32,
+    region_shapes: Option<RegionShapes>,
+    fit_w: u32,
+    fit_h: u32,
+    /// Pixels per module; wins over `fit_w`/`fit_h` for the image.
+    scale: Option<u32>,
+    margin: Margins,
+    logo_padding: u32,
+    dark_variant: Option<Profile>,
+    extra_ratios: Vec<u32>,
+}
+
+/// Run-wide state the rendering threads share.
+struct RenderEnv<'a> {
+    root: &'a Path,
+    assets: &'a AssetCache,
+    cache: Option<&'a Mutex<WriteCache>>,
+    memo: &'a RenderMemo,
+    /// Every file written this run, for the manifest.
+    written: &'a Mutex<Manifest>,
+    signer: Option<&'a AssetSigner>,
+    deterministic: bool,
+    /// Images written and left unchanged, for the summary.
+    counts: &'a WriteCounts,
+}
 
-    if let Some(v) = custom.get("url").and_then(|v| v.as_str()) {
-        p.url = Some(v.to_string());
+impl RenderEnv<'_> {
+    fn record(&self, abs: &Path, hash: &str) {
+        self.written
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .record(self.root, abs, hash);
     }
-    if let Some(v) = custom.get("qr-path").and_then(|v| v.as_str()) {
-        p.qr_path = Some(v.to_string());
+}
+
+/// A target's light image, and its dark one when configured and the light
+/// one was written.
+struct Renders {
+    light: Result<Rendered>,
+    dark: Option<Result<Rendered>>,
+}
+
+impl Job {
+    fn alias_refs(&self) -> Vec<&str> {
+        self.aliases.iter().map(String::as_str).collect()
     }
-    if let Some(v) = custom.get("margin").and_then(|v| v.as_integer()) {
-        if v >= 0 {
-            p.margin = Some(v as u32);
+
+    /// Everything but the colors is shared by the light and dark images.
+    fn image_options(
+        &self,
+        url: &str,
+        colors: &Profile,
+        ratio: u32,
+        env: &RenderEnv,
+    ) -> QrImageOptions<'_> {
+        let (fit_w, fit_h, margin, ecl) = (self.fit_w, self.fit_h, self.margin, self.ecl);
+        let scale = self.scale.map(|s| s * ratio);
+        QrImageOptions {
+            fit_w: fit_w * ratio,
+            fit_h: fit_h * ratio,
+            scale,
+            margin,
+            shape: Some(self.profile.shape()),
+            background: colors.background_color(),
+            module: colors.module_color(),
+            ecl,
+            logo: self.logo_asset.as_ref().map(|asset| LogoOverlay {
+                asset,
+                scale: self.logo_scale,
+                padding: self.logo_padding * ratio,
+            }),
+            border: self.profile.border.as_ref().map(|b| Border {
+                width: b.width * ratio,
+                color: b
+                    .color
+                    .as_ref()
+                    .and_then(|c| c.to_rgba().ok())
+                    .unwrap_or([0, 0, 0, 255]),
+                radius: b.radius * ratio,
+            }),
+            regions: self.region_shapes.map(|shapes| {
+                let (eye_frame, eye_module) = colors.eye_rgba();
+                RasterStyle {
+                    shapes,
+                    background: colors.background_rgba(),
+                    module: colors.module_rgba(),
+                    eye_frame,
+                    eye_module,
+                    gradient: colors.gradient_rgba().map(|(from, to)| Gradient {
+                        from,
+                        to,
+                        direction: colors
+                            .module_gradient
+                            .as_ref()
+                            .map_or_else(Default::default, |g| g.direction),
+                    }),
+                }
+            }),
+            render_key: render_key(&[
+                url,
+                // With `scale`, `fit` only sizes the `<img>`
+                &match scale {
+                    Some(scale) => format!("scale={scale} margin={margin} ecl={ecl:?}"),
+                    None => format!(
+                        "{}x{} margin={margin} ecl={ecl:?}",
+                        fit_w * ratio,
+                        fit_h * ratio
+                    ),
+                },
+                &format!(
+                    "{:?} {:?} {:?} {:?}",
+                    self.profile.shape,
+                    self.profile.style,
+                    self.profile.eye_shape,
+                    self.profile.border
+                ),
+                &format!(
+                    "{:?} {:?} {:?} {:?} {:?}",
+                    colors.background,
+                    colors.module,
+                    colors.eye_frame,
+                    colors.eye_module,
+                    colors.module_gradient
+                ),
+                if env.deterministic { "canonical" } else { "" },
+                &format!(
+                    "{:?} {} {}",
+                    self.logo_asset.as_ref().map(|a| &a.hash),
+                    self.logo_scale,
+                    self.logo_padding * ratio
+                ),
+            ]),
+            hashed_name: self.hashed_name,
+            deterministic: env.deterministic,
         }
     }
 
-    if let Some(fit_tbl) = custom.get("fit").and_then(|v| v.as_table()) {
-        if let Some(w) = fit_tbl.get("width").and_then(|v| v.as_integer()) {
-            if w >= 0 {
-                p.fit.width = Some(w as u32);
-            }
-        }
-        if let Some(h) = fit_tbl.get("height").and_then(|v| v.as_integer()) {
-            if h >= 0 {
-                p.fit.height = Some(h as u32);
+    /// One image at 1x plus its `srcset` densities (`<stem>@2x.png`, ...).
+    fn render(&self, url: &str, colors: &Profile, rel: &Path, env: &RenderEnv) -> Result<Rendered> {
+        let rel = &if self.hashed_path {
+            options_hashed_path(rel, &self.image_options(url, colors, 1, env).render_key)
+        } else {
+            rel.to_path_buf()
+        };
+        let mut written = Vec::new();
+        for ratio in std::iter::once(1).chain(self.extra_ratios.iter().copied()) {
+            let variant_rel = if ratio == 1 {
+                rel.to_path_buf()
+            } else {
+                density_variant_path(rel, ratio)
+            };
+            let (abs, hash, changed) = write_qr_png(
+                url,
+                env.root,
+                &variant_rel,
+                &self.image_options(url, colors, ratio, env),
+                env.assets,
+                env.cache,
+                env.memo,
+            )?;
+            env.record(&abs, &hash);
+            env.counts.record(changed);
+            if let Some(signer) = env.signer {
+                let sidecar = signer.write_sidecar(&abs, url)?;
+                if let Ok(h) = hash_file(&sidecar) {
+                    env.record(&sidecar, &h.to_hex());
+                }
             }
+            // The written name: `.png` enforced, or hashed
+            let written_rel = match abs.file_name() {
+                Some(name) => variant_rel.with_file_name(name),
+                None => variant_rel,
+            };
+            written.push((ratio, written_rel, hash));
         }
+        let (_, rel, hash) = written.remove(0);
+        Ok((rel, hash, written))
     }
 
-    if let Some(shape_tbl) = custom.get("shape").and_then(|v| v.as_table()) {
-        p.shape.square = shape_tbl
-            .get("square")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
-        p.shape.circle = shape_tbl
-            .get("circle")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
-        p.shape.rounded_square = shape_tbl
-            .get("rounded_square")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
-        p.shape.vertical = shape_tbl
-            .get("vertical")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
-        p.shape.horizontal = shape_tbl
-            .get("horizontal")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
-        p.shape.diamond = shape_tbl
-            .get("diamond")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
+    /// The light image of `target`, read back when `verify` is set, then its
+    /// dark variant (`<stem>-dark.png`). Dark variants are not read back:
+    /// they are inverted by design.
+    fn render_target(&self, target: &Target, env: &RenderEnv) -> Renders {
+        let light = self
+            .render(&target.url, &self.profile, &target.rel, env)
+            .and_then(|rendered| {
+                if self.profile.verify.unwrap_or(false) {
+                    verify_png(
+                        &env.root.join(&rendered.0),
+                        &target.url,
+                        self.profile.backdrop_rgb(),
+                    )?;
+                }
+                Ok(rendered)
+            });
+        let dark = match (&light, &self.dark_variant) {
+            (Ok(_), Some(dark)) => {
+                Some(self.render(&target.url, dark, &dark_variant_path(&target.rel), env))
+            }
+            _ => None,
+        };
+        Renders { light, dark }
     }
+}
 
-    if let Some(bg) = custom.get("background").and_then(|v| v.as_str()) {
-        p.background = Some(ColorCfg::Hex(bg.to_string()));
-    }
-    if let Some(fg) = custom.get("module").and_then(|v| v.as_str()) {
-        p.module = Some(ColorCfg::Hex(fg.to_string()));
+/// A QR image written by [`generate`], and the HTML referencing it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeneratedQr {
+    /// Where the PNG was written: `out_dir` joined with the profile's
+    /// `qr-path`, or with `qr/<slug>.png` derived from its marker.
+    pub path: PathBuf,
+    /// First 12 hex digits of the PNG's blake3 hash.
+    pub hash: String,
+    /// Size of the PNG in pixels.
+    pub width: u32,
+    pub height: u32,
+    /// The snippet a book build would inject for the `html` renderer, with
+    /// `src` relative to `out_dir` and no cache-busting query.
+    pub html: String,
+}
+
+/// Render `profile`'s image of `url` under `out_dir` as a book build would,
+/// without a book: for `mdbook-qr render` and for other tools reusing the
+/// rendering. The profile's own payload (`url`, `data`, ...) is ignored in
+/// favour of `url`. Logos, dark variants and densities are not drawn;
+/// `verify` is honoured.
+///
+/// # Examples
+///
+/// ```
+/// use mdbook_qr::config::{QrConfig, ShapeCfg};
+///
+/// let mut profile = QrConfig::default().default_profile();
+/// profile.qr_path = Some("qr/home.png".to_string());
+/// profile.shape = Some(ShapeCfg::Name("rounded".to_string()));
+/// profile.fit.width = Some(200);
+///
+/// let out_dir = std::env::temp_dir().join("mdbook-qr-generate-example");
+/// let qr = mdbook_qr::generate(&profile, "https://example.com", &out_dir)?;
+/// assert_eq!(qr.path, out_dir.join("qr/home.png"));
+/// let png = std::fs::read(&qr.path)?;
+/// assert!(blake3::hash(&png).to_hex().starts_with(&qr.hash));
+/// assert_eq!((qr.width, qr.height), (200, 200));
+/// assert!(qr.html.starts_with(r#"<img src="qr/home.png""#), "{}", qr.html);
+/// # std::fs::remove_dir_all(&out_dir)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn generate(profile: &Profile, url: &str, out_dir: &Path) -> Result<GeneratedQr, QrError> {
+    if let Some(w) = profile.contrast_warning(false) {
+        warn!("mdbook-qr: {w}");
     }
+    let marker = profile.primary_marker().unwrap_or("{{QR_CODE}}");
+    let rel = resolve_profile_path(Path::new(""), profile.qr_path.as_deref(), marker);
+    let (fit_w, fit_h) = pass_fit_dims(&profile.fit);
+    let job = Job {
+        key: "render".to_string(),
+        marker: String::new(),
+        aliases: Vec::new(),
+        filter: ChapterFilter::new(&[], &[]),
+        on_failure: FailureMode::Bail,
+        is_data: false,
+        text: false,
+        hashed_path: false,
+        hashed_name: false,
+        targets: Vec::new(),
+        logo_asset: None,
+        ecl: profile
+            .ecl
+            .or(profile.module_gradient.as_ref().map(|_| Ecl::H)),
+        logo_scale: DEFAULT_LOGO_SCALE,
+        region_shapes: profile.region_shapes(),
+        fit_w,
+        fit_h,
+        scale: profile.scale.filter(|&s| s > 0),
+        margin: profile.margins(),
+        logo_padding: 0,
+        dark_variant: None,
+        extra_ratios: Vec::new(),
+        profile: profile.clone(),
+    };
+    let assets = AssetCache::new(AssetLimits::default());
+    let memo = RenderMemo::default();
+    let written = Mutex::new(Manifest::default());
+    let env = RenderEnv {
+        root: out_dir,
+        assets: &assets,
+        cache: None,
+        memo: &memo,
+        written: &written,
+        signer: None,
+        deterministic: false,
+        counts: &WriteCounts::default(),
+    };
+    let target = Target {
+        chapter: None,
+        url: url.to_string(),
+        rel,
+    };
+    let (rel, hash, _) = job.render_target(&target, &env).light?;
+    let path = out_dir.join(&rel);
+    let (width, height) = ::image::image_dimensions(&path).map_err(|e| {
+        QrError::ImageEncode(format!("reading the size of {}: {e}", path.display()))
+    })?;
 
-    Some(p)
+    let no_display_size = (profile.class.is_some() || profile.scale.is_some())
+        && profile.fit.width.is_none()
+        && profile.fit.height.is_none();
+    let img_attrs = profile.img_attrs();
+    let alt = profile.alt_text(url);
+    let link = profile.link_href(url);
+    let backdrop = profile.backdrop_css();
+    let scope = GlobSet::empty();
+    // The border is drawn outside the fit size
+    let border = 2 * profile.border_width();
+    let opts = InjectOptions {
+        fit_w: if no_display_size { 0 } else { fit_w + border },
+        fit_h: if no_display_size { 0 } else { fit_h + border },
+        cache_bust: None,
+        cache_bust_param: DEFAULT_CACHE_BUST_PARAM,
+        output: profile.output.unwrap_or_default().for_renderer("html"),
+        alt: &alt,
+        title: profile.title.as_deref(),
+        link: link.as_deref(),
+        link_target: profile.link_target.as_deref(),
+        caption: profile.caption.as_deref(),
+        caption_position: profile.caption_position.unwrap_or_default(),
+        size_mode: profile.size_mode.unwrap_or_default(),
+        backdrop: backdrop.as_deref(),
+        text: None,
+        loading: profile.loading_attr(),
+        decoding: profile.decoding_attr(),
+        template: profile
+            .html_template
+            .as_deref()
+            .map(|template| HtmlTemplate {
+                template,
+                url,
+                hash: &hash,
+            }),
+        class: profile.class.as_deref(),
+        attrs: &img_attrs,
+        dark: None,
+        variants: &[],
+        destination: None,
+        attribute: None,
+        chapter: None,
+        scope: &scope,
+        filter: &job.filter,
+        syntax: Syntax {
+            math: true,
+            lenient: false,
+        },
+        debug_comment: None,
+    };
+    let html = image_snippet(&rel.to_string_lossy().replace('\\', "/"), marker, &opts)?;
+    Ok(GeneratedQr {
+        path,
+        hash,
+        width,
+        height,
+        html,
+    })
 }
 
-fn run_impl(ctx: &PreprocessorContext, book: &mut Book) -> Result<()> {
-    let cfg: QrConfig = config_from_ctx(ctx).unwrap_or_default();
-    if !cfg.is_enabled() {
-        return Ok(());
+/// One image to generate: its URL, its path and, for per-chapter URLs, the
+/// only chapter it is injected into.
+struct Target {
+    chapter: Option<PathBuf>,
+    url: String,
+    rel: PathBuf,
+}
+
+/// Expand the URL template of `marker`'s profile: a single target, or one per
+/// chapter using the marker or one of its `aliases` when the template
+/// contains `{path}`.
+fn url_targets(
+    book: &Book,
+    scope: &GlobSet,
+    marker: &str,
+    aliases: &[&str],
+    syntax: Syntax,
+    template: &str,
+    rel: &Path,
+) -> Result<Vec<Target>> {
+    let slug = slug_from_marker(marker);
+    if !template_uses_path(template) {
+        let url = expand_url_template(
+            template,
+            &UrlVars {
+                slug: &slug,
+                path: None,
+            },
+        )?;
+        return Ok(vec![Target {
+            chapter: None,
+            url,
+            rel: rel.to_path_buf(),
+        }]);
     }
-    let on_failure = cfg.on_failure.clone();
-    let src_dir = ctx.config.book.src.clone();
+    let mut targets = Vec::new();
+    for ch in chapters_in_scope(book, scope) {
+        let Some(path) = ch
+            .path
+            .as_ref()
+            .filter(|_| contains_marker(&ch.content, aliases, syntax))
+        else {
+            continue;
+        };
+        let vars = UrlVars {
+            slug: &slug,
+            path: Some(&chapter_output_path(path)),
+        };
+        targets.push(Target {
+            chapter: Some(path.clone()),
+            url: expand_url_template(template, &vars)?,
+            rel: chapter_variant_path(rel, path),
+        });
+    }
+    Ok(targets)
+}
 
-    cfg.warn_invalid_customs();
+/// Fingerprint of the raster inputs (payload, size, colors, shapes, logo).
+/// Injection-only settings such as alt text are deliberately left out; the
+/// crate version is included so renderer changes invalidate old outputs.
+fn render_key(parts: &[&str]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    for part in parts {
+        hasher.update(b"\0");
+        hasher.update(part.as_bytes());
+    }
+    hasher.finalize().to_hex().to_string()
+}
 
+/// Profiles to generate, keyed "default" or by their custom table name: the
+/// default (unless suppressed), then each named custom with a marker,
+/// inheriting from the default and the bare `[preprocessor.qr.custom]` table.
+pub(crate) fn build_profiles(cfg: &QrConfig) -> Vec<(String, Profile)> {
     // 1) Detect a *bare* [preprocessor.qr.custom] table (no named subtables)
-    let has_bare_custom = ctx
-        .config
-        .get("preprocessor")
-        .and_then(|pp| pp.get("qr"))
-        .and_then(|qr| qr.get("custom"))
-        .map(|v| v.is_table())
-        .unwrap_or(false)
-        && cfg.custom.is_empty();
-
-    // 2) Load defaults from bare custom (for inheritance only; never generates by itself)
-    let custom_defaults = load_custom_defaults(ctx);
-
-    // 3) Build profiles
-    let mut profiles: Vec<Profile> = Vec::new();
+    let has_bare_custom = cfg.custom_defaults.is_some() && cfg.custom.is_empty();
+
+    // 2) Defaults from bare custom (for inheritance only; never generates by itself)
+    let custom_defaults = &cfg.custom_defaults;
+
+    // 3) Build profiles, keyed "default" or by their custom table name
+    let mut profiles: Vec<(String, Profile)> = Vec::new();
     let default_p = cfg.default_profile();
 
-    // ── CHANGED: only include the default if there is NOT a bare custom table
-    if !has_bare_custom {
-        profiles.push(default_p.clone());
-    } else {
+    // Explicit `include-default` wins; otherwise a bare custom table suppresses the default.
+    if cfg.include_default_profile(has_bare_custom) {
+        profiles.push(("default".to_string(), default_p.clone()));
+    } else if cfg.include_default.is_none() {
         warn!(
             "mdbook-qr: bare [preprocessor.qr.custom] present with no named subtables; \
-             suppressing default '{{QR_CODE}}' until a named custom (e.g., [preprocessor.qr.custom.flyer]) exists."
+             suppressing default '{{{{QR_CODE}}}}' until a named custom (e.g., [preprocessor.qr.custom.flyer]) exists."
         );
+    } else {
+        debug!("mdbook-qr: include-default = false; skipping default '{{{{QR_CODE}}}}' profile");
     }
 
     // Named customs (must have marker)
-    for (_name, child) in &cfg.custom {
-        if child.marker.is_none() {
+    for (name, child) in &cfg.custom {
+        if child.primary_marker().is_none() {
             warn!("mdbook-qr: custom entry missing `marker`; skipping.");
             continue;
         }
@@ -206,7 +793,7 @@ fn run_impl(ctx: &PreprocessorContext, book: &mut Book) -> Result<()> {
         let mut eff = QrConfig::inherit(&default_p, child);
 
         // overlay bare [preprocessor.qr.custom] defaults if present
-        if let Some(cd) = &custom_defaults {
+        if let Some(cd) = custom_defaults {
             eff = QrConfig::inherit(cd, &eff);
             // child's explicit fields win back
             eff.marker = child.marker.clone();
@@ -214,83 +801,466 @@ fn run_impl(ctx: &PreprocessorContext, book: &mut Book) -> Result<()> {
                 eff.qr_path = child.qr_path.clone();
             }
         }
-        profiles.push(eff);
+        profiles.push((name.clone(), eff));
+    }
+    profiles
+}
+
+/// The profiles a build for `renderer` generates before directives:
+/// [`build_profiles`] with the renderer's overrides merged in and the
+/// `duplicate-markers` policy applied.
+pub(crate) fn effective_profiles(cfg: &QrConfig, renderer: &str) -> Result<Vec<(String, Profile)>> {
+    let mut profiles = build_profiles(cfg);
+    apply_renderer_overrides(cfg, renderer, &mut profiles);
+    // Several profiles with one marker: per the `duplicate-markers` policy
+    resolve_duplicates(cfg.duplicate_markers, &mut profiles)?;
+    Ok(profiles)
+}
+
+/// The config table a profile comes from, for messages.
+fn profile_table(key: &str) -> String {
+    match key {
+        "default" => "[preprocessor.qr]".to_string(),
+        name => format!("[preprocessor.qr.custom.{name}]"),
+    }
+}
+
+/// Apply `policy` to markers configured by more than one profile: warn,
+/// fail, or keep only the first or last profile using each.
+fn resolve_duplicates(
+    policy: DuplicatePolicy,
+    profiles: &mut Vec<(String, Profile)>,
+) -> Result<()> {
+    let dupes = QrConfig::duplicate_markers(profiles);
+    let mut dropped: Vec<String> = Vec::new();
+    let mut errors = Vec::new();
+    for (marker, keys) in &dupes {
+        let tables: Vec<String> = keys.iter().map(|k| profile_table(k)).collect();
+        let (winner, losers) = match policy {
+            DuplicatePolicy::Warn => {
+                warn!(
+                    "mdbook-qr: marker {marker} is configured by {}; only {} replaces it",
+                    tables.join(", "),
+                    tables[0]
+                );
+                continue;
+            }
+            DuplicatePolicy::Error => {
+                errors.push(format!("{marker}: {}", tables.join(", ")));
+                continue;
+            }
+            DuplicatePolicy::FirstWins => (0, 1..keys.len()),
+            DuplicatePolicy::LastWins => (keys.len() - 1, 0..keys.len() - 1),
+        };
+        for loser in &keys[losers] {
+            info!(
+                "mdbook-qr: marker {marker}: {} wins over {}; skipping the latter",
+                tables[winner],
+                profile_table(loser)
+            );
+            dropped.push(loser.clone());
+        }
+    }
+    if !errors.is_empty() {
+        bail!(
+            "mdbook-qr: markers configured by more than one profile \
+             (duplicate-markers = \"error\"):\n  {}",
+            errors.join("\n  ")
+        );
+    }
+    profiles.retain(|(key, _)| !dropped.contains(key));
+    Ok(())
+}
+
+/// Report, and per `policy` strip or replace, the markers of enabled
+/// profiles in draft chapters.
+fn handle_drafts(
+    book: &mut Book,
+    scope: &GlobSet,
+    syntax: Syntax,
+    profiles: &[(String, Profile)],
+    policy: DraftPolicy,
+) {
+    for (_, p) in profiles.iter().filter(|(_, p)| p.is_enabled()) {
+        let markers = p.markers();
+        let alt;
+        let text = match policy {
+            DraftPolicy::Keep | DraftPolicy::Warn => None,
+            DraftPolicy::Strip => Some(""),
+            DraftPolicy::Alt => {
+                alt = html_escape::text(p.alt.as_deref().unwrap_or("QR code"));
+                Some(alt.as_str())
+            }
+        };
+        let drafts = draft_markers(book, &markers, scope, &p.chapter_filter(), syntax, text);
+        let marker = markers.first().copied().unwrap_or_default();
+        for name in drafts {
+            let done = match policy {
+                DraftPolicy::Keep | DraftPolicy::Warn => "left in place",
+                DraftPolicy::Strip => "removed",
+                DraftPolicy::Alt => "replaced with its alt text",
+            };
+            let level = if policy == DraftPolicy::Warn {
+                log::Level::Warn
+            } else {
+                log::Level::Debug
+            };
+            log::log!(
+                level,
+                "mdbook-qr: draft chapter '{name}' has no file to link an image from; marker {marker} {done}"
+            );
+        }
     }
+}
 
-    for p in &profiles {
-        if let Some(m) = &p.marker {
+/// Merge `[preprocessor.qr.renderer.<renderer>]` over every profile. Each
+/// profile keeps its own marker and `qr-path`.
+fn apply_renderer_overrides(cfg: &QrConfig, renderer: &str, profiles: &mut [(String, Profile)]) {
+    let Some(overrides) = cfg.renderer.get(renderer) else {
+        return;
+    };
+    if overrides.marker.is_some() || overrides.qr_path.is_some() {
+        warn!(
+            "mdbook-qr: [preprocessor.qr.renderer.{renderer}]: `marker` and `qr-path` cannot be overridden; ignoring them"
+        );
+    }
+    debug!("mdbook-qr: applying [preprocessor.qr.renderer.{renderer}] overrides");
+    for (_, p) in profiles.iter_mut() {
+        let mut eff = QrConfig::inherit(p, overrides);
+        eff.marker = p.marker.take();
+        eff.qr_path = p.qr_path.take();
+        *p = eff;
+    }
+}
+
+fn run_impl(ctx: &PreprocessorContext, book: &mut Book, pre: &QrPreprocessor) -> Result<()> {
+    // Kill switch comes first: no config parsing, no filesystem access.
+    if env_flag(ENV_DISABLE) {
+        info!("mdbook-qr: {ENV_DISABLE} is set; passing the book through unchanged.");
+        return Ok(());
+    }
+
+    let cfg = config_from_ctx(ctx)?;
+    if !cfg.is_enabled() {
+        return Ok(());
+    }
+    if !cfg.runs_for(&ctx.renderer) {
+        debug!(
+            "mdbook-qr: not running for renderer '{}' (`renderers` / `skip-non-html`); passing the book through unchanged.",
+            ctx.renderer
+        );
+        return Ok(());
+    }
+    let force_continue = env_flag(ENV_FORCE_CONTINUE);
+    // A dry run plans on a copy: directives and `strip-unmatched` edit chapters
+    let dry_run = cfg.dry_run || env_flag(ENV_DRY_RUN);
+    let mut planned;
+    let book = if dry_run {
+        info!("mdbook-qr: dry run; no files are written and the book is left unchanged");
+        planned = book.clone();
+        &mut planned
+    } else {
+        book
+    };
+    let src_dir = ctx.config.book.src.clone();
+    // Images and their bookkeeping live here: `src_dir`, or the root itself
+    let out_dir = cfg.output_dir(&src_dir);
+
+    cfg.warn_invalid_customs();
+    cfg.warn_conflicting_payloads();
+
+    let mut profiles = effective_profiles(&cfg, &ctx.renderer)?;
+
+    // `chapters` allowlist: everything else is never scanned or modified
+    let scope = chapter_glob_set("chapters", &cfg.chapters);
+    let syntax = cfg.syntax();
+
+    let marker_prefix = cfg.marker_prefix.as_deref().unwrap_or("QR");
+
+    // Nothing to do if no enabled marker appears anywhere: skip every chapter
+    // edit, URL resolution, path computation and all filesystem access.
+    // Directives only apply to these markers, so they cannot add any.
+    let markers = enabled_markers(&profiles);
+    if !any_marker_in_book(book, &scope, &markers, syntax, cfg.replace_in_names) {
+        debug!("mdbook-qr: no configured marker found in the book; passing it through unchanged");
+        // Empty a published manifest an earlier run left, without creating one
+        if !dry_run
+            && cfg
+                .manifest
+                .as_ref()
+                .is_some_and(|p| ctx.root.join(p).exists())
+        {
+            write_qr_manifest(&QrManifest::default(), &cfg, &ctx.root);
+        }
+        let (configured, expected) = configured_markers(&profiles);
+        report_orphans(book, &scope, syntax, &configured, marker_prefix, cfg.strict)?;
+        return report_unreplaced(book, &scope, syntax, &expected, cfg.strict);
+    }
+
+    // `<!-- mdbook-qr: ... -->` comments add profiles for single occurrences
+    apply_directives(book, &scope, syntax, &mut profiles);
+
+    if force_continue
+        && (matches!(cfg.on_failure, FailureMode::Bail)
+            || profiles
+                .iter()
+                .any(|(_, p)| matches!(p.on_failure, Some(FailureMode::Bail))))
+    {
+        warn!("mdbook-qr: {ENV_FORCE_CONTINUE} is set; treating on-failure = \"bail\" as \"continue\".");
+    }
+
+    for (_, p) in &profiles {
+        if let Some(m) = p.primary_marker() {
             info!("mdbook-qr: profile queued -> marker {}", m);
         }
     }
 
-    // Optional: warn on duplicate markers
-    if let Some(dupe) = QrConfig::duplicate_marker_from(profiles.iter()) {
-        warn!("duplicate marker configured: {dupe}");
+    let (configured, expected) = configured_markers(&profiles);
+    // Directives may have added profiles
+    let markers = enabled_markers(&profiles);
+    let mut skipped_chapters = 0;
+    if !scope.is_empty() {
+        skipped_chapters = book
+            .iter()
+            .filter(|item| matches!(item, BookItem::Chapter(ch) if !in_scope(&scope, ch)))
+            .count();
+        debug!("mdbook-qr: {skipped_chapters} chapter(s) outside the `chapters` allowlist skipped");
     }
 
-    // Track file-path collisions (warn only)
-    let mut path_to_marker: HashMap<std::path::PathBuf, String> = HashMap::new();
+    // Drafts have no path to link an image from: per `draft-markers`
+    handle_drafts(book, &scope, syntax, &profiles, cfg.draft_markers);
+    if cfg.replace_in_names {
+        for (_, p) in profiles.iter().filter(|(_, p)| p.is_enabled()) {
+            let renamed = replace_in_names(
+                book,
+                &p.markers(),
+                &scope,
+                &p.chapter_filter(),
+                syntax,
+                &cfg.name_replacement,
+            );
+            for name in renamed {
+                debug!("mdbook-qr: replaced marker in chapter name, now '{name}'");
+            }
+        }
+    }
 
-    for profile in profiles.into_iter().filter(|p| p.is_enabled()) {
-        let marker = profile
-            .marker
-            .as_ref()
-            .expect("profiles here always have marker");
+    let mut summary = Summary {
+        profiles: markers.len(),
+        skipped_chapters,
+        ..Summary::default()
+    };
+    let mut published = QrManifest::default();
+
+    // Chapters regenerated by another tool on every build
+    let generated = chapter_glob_set("generated-chapter-globs", &cfg.generated_chapter_globs);
+
+    // Canonical address from [output.html]; relative values are ignored with a warning
+    let site_url = crate::url::site_url_fallback(
+        ctx.config
+            .get("output.html.site-url")
+            .and_then(|v| v.as_str()),
+    );
+
+    // Custom Pages domain replaces the github.io fallback
+    let cname = crate::url::cname_domain(&ctx.root, &src_dir);
+
+    // Provenance signing: an unreadable key fails the run
+    let signer = AssetSigner::from_config(&ctx.root, cfg.sign_key.as_deref())?;
+
+    // Decoded logo assets, shared across profiles for this run
+    let assets = AssetCache::new(cfg.asset_limits.clone());
+
+    // Persistent record of written files (skips re-reading unchanged outputs)
+    let cache_file = cache_path(
+        &ctx.root,
+        &out_dir,
+        &src_dir,
+        &ctx.renderer,
+        ctx.config.book.language.as_deref(),
+    );
+    let manifest_file = manifest_path(
+        &ctx.root,
+        &out_dir,
+        &src_dir,
+        &ctx.renderer,
+        ctx.config.book.language.as_deref(),
+    );
+    // What earlier runs generated: theirs to overwrite, and to clean up
+    let previous = Manifest::load(&manifest_file);
+    let write_cache = if cfg.cache.unwrap_or(true) && !pre.no_cache {
+        Some(Mutex::new(WriteCache::load(&cache_file)))
+    } else {
+        debug!("mdbook-qr: write cache disabled; comparing outputs in full");
+        None
+    };
+
+    // Track file-path collisions (handled per `collision`)
+    let mut path_to_marker: HashMap<PathBuf, String> = HashMap::new();
+
+    // `<!-- mdbook-qr ... -->` after each image; comments only make sense in HTML
+    let debug_comments = cfg
+        .debug_comments
+        .as_ref()
+        .and_then(DebugCommentsCfg::full_url)
+        .filter(|_| ctx.renderer == "html");
+
+    // Per-profile setup runs in order: URLs, paths, collisions, logos
+    let mut jobs: Vec<Job> = Vec::new();
+    // A profile that failed may have produced files last run; keep them
+    let mut incomplete = false;
+    // `valid-*` dates are compared against one date for the whole run
+    let today = IsoDate::today();
+    for (key, mut profile) in profiles.into_iter().filter(|(_, p)| p.is_enabled()) {
+        // Owned: `profile` is updated below by `${VAR}` expansion
+        let marker = &profile
+            .primary_marker()
+            .expect("profiles here always have marker")
+            .to_string();
+        // Every name the profile answers to; `marker` names its files
+        let aliases: Vec<String> = profile.markers().into_iter().map(str::to_string).collect();
+        let alias_refs: Vec<&str> = aliases.iter().map(String::as_str).collect();
+        let on_failure = if force_continue {
+            FailureMode::Continue
+        } else {
+            profile
+                .on_failure
+                .clone()
+                .unwrap_or_else(|| cfg.on_failure.clone())
+        };
+
+        // Chapters outside the profile's own filter: leave the marker, or strip it
+        let filter = profile.chapter_filter();
+        if profile.strip_unmatched.unwrap_or(false) {
+            for path in strip_unmatched(book, &alias_refs, &scope, &filter, syntax) {
+                debug!("mdbook-qr: removed '{marker}' from '{}'", path.display());
+            }
+        } else {
+            for ch in chapters_in_scope(book, &scope).filter(|ch| {
+                !filter.matches(ch) && contains_marker(&ch.content, &alias_refs, syntax)
+            }) {
+                debug!(
+                    "mdbook-qr: '{marker}' left in '{}' (outside the profile's chapters)",
+                    ch.name
+                );
+            }
+        }
 
         // Only generate if the marker is used
-        if !marker_in_book(book, marker) {
+        if !marker_in_book(book, &scope, &filter, &alias_refs, syntax) {
             debug!(
                 "mdbook-qr: marker '{}' not found in any chapter; skipping",
                 marker
             );
+            summary.skip(marker, "marker not used");
             continue;
         }
 
-        // Resolve URL (explicit -> localhost-qr -> env fallback)
-        let url = match crate::url::resolve_url(
-            profile.url.as_deref(),
-            profile.localhost_qr.unwrap_or(false),
-        ) {
-            Ok(u) => u,
-            Err(_) => match on_failure {
+        // `valid-from` / `valid-until`: warn, or stand the marker down
+        if let Some(why) = profile.validity_on(today) {
+            if profile.expired.unwrap_or_default() == ExpiredPolicy::Disable {
+                let text = profile.expired_text.as_deref().unwrap_or("");
+                for path in replace_with_text(book, &alias_refs, &scope, &filter, syntax, text) {
+                    debug!("mdbook-qr: replaced '{marker}' in '{}'", path.display());
+                }
+                warn!("mdbook-qr: QR for marker {marker} {why}; not generated");
+                summary.skip(marker, "outside its valid dates");
+                continue;
+            }
+            warn!("mdbook-qr: QR for marker {marker} {why}");
+        }
+
+        // `${VAR}` in url / qr-path, for profiles and inherited custom defaults alike
+        let checked = profile
+            .expand_env()
+            .and_then(|()| profile.check_colors())
+            .and_then(|()| profile.check_html_template())
+            .and_then(|()| profile.check_cache_bust_param());
+        if let Err(e) = checked {
+            match on_failure {
                 FailureMode::Continue => {
-                    warn!(
-                        "could not resolve URL for '{}'; set `preprocessor.qr.url` \
-                         or export GITHUB_REPOSITORY; skipping image.",
-                        marker
-                    );
+                    warn!("mdbook-qr: skipping '{}': {e:#}", marker);
+                    summary.skip(marker, "invalid settings");
+                    incomplete = true;
                     continue;
                 }
-                FailureMode::Bail => {
-                    anyhow::bail!(
-                        "mdbook-qr: could not resolve URL for '{}'; \
-                         set `preprocessor.qr.url` or export GITHUB_REPOSITORY.",
+                FailureMode::Bail => return Err(e.context(format!("mdbook-qr: '{marker}'"))),
+            }
+        }
+
+        // Resolve URL (explicit -> site-url -> CNAME / GITHUB_REPOSITORY -> GitLab -> localhost-qr)
+        // `data` is encoded as is; otherwise resolve the URL
+        let payload = profile.payload();
+        let is_data = payload.is_some();
+        let url = if let Some(data) = payload {
+            debug!("mdbook-qr: '{}' encodes {:?}", marker, data);
+            data
+        } else {
+            match crate::url::resolve_url(
+                profile
+                    .url
+                    .as_ref()
+                    .and_then(|u| u.for_renderer(&ctx.renderer)),
+                site_url,
+                cname.as_deref(),
+                profile.localhost_qr.unwrap_or(false),
+                profile.localhost_port.unwrap_or(DEFAULT_LOCALHOST_PORT),
+            ) {
+                Ok(u) => u,
+                Err(_) => match on_failure {
+                    FailureMode::Continue => {
+                        warn!(
+                        "could not resolve URL for '{}'; set `preprocessor.qr.url` \
+                         or `output.html.site-url`, or export GITHUB_REPOSITORY / CI_PAGES_URL; skipping image.",
                         marker
                     );
-                }
-            },
+                        summary.skip(marker, "URL not resolved");
+                        incomplete = true;
+                        continue;
+                    }
+                    FailureMode::Bail => {
+                        return Err(QrError::UrlResolution(format!(
+                            "mdbook-qr: could not resolve URL for '{marker}'; \
+                             set `preprocessor.qr.url` or `output.html.site-url`, \
+                             or export GITHUB_REPOSITORY / CI_PAGES_URL."
+                        ))
+                        .into());
+                    }
+                },
+            }
         };
 
         // Decide mode up front
         let is_localhost = profile.localhost_qr.unwrap_or(false);
+        // `path-style = "hashed"` names derived paths after the image
+        let hashed_path =
+            !is_localhost && cfg.path_style == PathStyle::Hashed && profile.qr_path.is_none();
 
         //  Compute the normal path first (respects qr-path/marker)
-        let normal_rel = resolve_profile_path(&src_dir, profile.qr_path.as_deref(), marker);
+        let normal_rel = resolve_profile_path(&out_dir, profile.qr_path.as_deref(), marker);
 
         //  Safety guard ONLY for non-localhost runs:
         //    If about to write to the derived default for the *default marker*
         //    and the file already exists AND no explicit qr-path was given, skip to avoid clobbering.
-        if !is_localhost {
-            let derived_default = derived_default_path(&src_dir, "{{QR_CODE}}");
+        //    Generated chapters are rebuilt each run, and files the previous manifest
+        //    lists were written by an earlier run, so either image is our own.
+        //    A hashed name is never the derived default.
+        if !is_localhost
+            && !hashed_path
+            && !marker_in_generated(book, &scope, &alias_refs, &generated, syntax)
+        {
+            let derived_default = derived_default_path(&out_dir, "{{QR_CODE}}");
             if normal_rel == derived_default && profile.qr_path.is_none() {
                 let abs_candidate = ctx.root.join(&normal_rel);
-                if abs_candidate.exists() {
+                if abs_candidate.exists() && !previous.contains(&ctx.root, &abs_candidate) {
                     warn!(
                         "mdbook-qr: '{}' already exists; refusing to overwrite derived default. \
                         Set an explicit `qr-path` for marker {} to proceed.",
                         abs_candidate.display(),
                         marker
                     );
+                    summary.skip(marker, "derived default image exists");
+                    incomplete = true;
                     continue;
                 }
             }
@@ -299,69 +1269,809 @@ fn run_impl(ctx: &PreprocessorContext, book: &mut Book) -> Result<()> {
         // Pick the effective output path
         let qr_rel_under_src = if is_localhost {
             // {book.src}/mdbook-qr/qr_localhost.png
-            localhost_fixed_path(&src_dir)
+            localhost_fixed_path(&out_dir)
         } else {
             normal_rel
         };
 
-        // Warn on two markers mapping to same file
-        if let Some(prev) = path_to_marker.insert(qr_rel_under_src.clone(), marker.clone()) {
-            if prev != *marker {
-                warn!(
-                    "image path collision: '{}' and '{}' both map to '{}'. \
-                     The latter may overwrite the former.",
-                    prev,
-                    marker,
+        // Two markers mapping to the same file: per the `collision` policy
+        let qr_rel_under_src = match path_to_marker.get(&qr_rel_under_src).cloned() {
+            Some(prev) if prev != *marker => match cfg.collision {
+                CollisionPolicy::Warn => {
+                    warn!(
+                        "image path collision: '{}' and '{}' both map to '{}'. \
+                         The latter may overwrite the former.",
+                        prev,
+                        marker,
+                        qr_rel_under_src.display()
+                    );
+                    qr_rel_under_src
+                }
+                CollisionPolicy::Error => bail!(
+                    "mdbook-qr: image path collision: '{prev}' and '{marker}' both map to '{}'; \
+                     give one of them its own qr-path",
                     qr_rel_under_src.display()
+                ),
+                CollisionPolicy::Suffix => {
+                    let mut own = marker_variant_path(&qr_rel_under_src, marker, false);
+                    if path_to_marker.contains_key(&own) {
+                        own = marker_variant_path(&qr_rel_under_src, marker, true);
+                    }
+                    info!(
+                        "mdbook-qr: '{prev}' already uses '{}'; writing '{marker}' to '{}'",
+                        qr_rel_under_src.display(),
+                        own.display()
+                    );
+                    own
+                }
+            },
+            _ => qr_rel_under_src,
+        };
+        path_to_marker.insert(qr_rel_under_src.clone(), marker.clone());
+
+        // Never outside the book root unless allowed, nor over a foreign file
+        let allow_outside = profile.allow_outside_root.unwrap_or(false);
+        let checked = check_output_path(
+            &ctx.root,
+            &qr_rel_under_src,
+            &previous,
+            write_cache.as_ref(),
+            cfg.overwrite_existing,
+            allow_outside,
+        );
+        if let Err(e) = checked {
+            match on_failure {
+                FailureMode::Continue => {
+                    warn!("mdbook-qr: skipping '{}': {e:#}", marker);
+                    summary.skip(marker, "output path refused");
+                    incomplete = true;
+                    continue;
+                }
+                FailureMode::Bail => return Err(e.context(format!("mdbook-qr: '{marker}'"))),
+            }
+        }
+
+        // Logo (optional): load under asset-limits, honouring on_failure
+        let logo_asset = match profile.logo.as_deref() {
+            None => None,
+            Some(rel) => match assets.load(&ctx.root.join(rel)) {
+                Ok(asset) => Some(asset),
+                Err(e) => match on_failure {
+                    FailureMode::Continue => {
+                        warn!("mdbook-qr: {e:#}; rendering '{}' without a logo.", marker);
+                        None
+                    }
+                    FailureMode::Bail => {
+                        return Err(e.context(format!("mdbook-qr: logo for '{marker}'")));
+                    }
+                },
+            },
+        };
+
+        // A logo hides modules and a gradient fades them → use the highest
+        // error correction unless pinned
+        let ecl = match (profile.ecl, logo_asset.is_some()) {
+            (None, true) => Some(Ecl::H),
+            (None, false) if profile.module_gradient.is_some() => Some(Ecl::H),
+            (Some(pinned), true) if pinned < Ecl::H => {
+                warn!(
+                    "mdbook-qr: '{}' has a logo but ecl = {:?}; codes may not scan. Consider ecl = \"H\".",
+                    marker, pinned
                 );
+                Some(pinned)
             }
+            (pinned, _) => pinned,
+        };
+
+        // fast_qr's own error for an oversized payload is opaque; Q is its default level
+        let capacity = ecl.unwrap_or(Ecl::Q).max_bytes();
+        if url.len() > capacity {
+            warn!(
+                "mdbook-qr: '{}' encodes {} bytes, more than a QR code holds at \
+                 error-correction level {:?} ({capacity}); shorten it or lower `ecl`.",
+                marker,
+                url.len(),
+                ecl.unwrap_or(Ecl::Q)
+            );
+        }
+
+        let logo_scale = match profile.logo_scale {
+            Some(s) if s > 0.0 && s <= 0.5 => s,
+            Some(s) => {
+                warn!(
+                    "mdbook-qr: logo-scale {s} out of range (0, 0.5]; using {DEFAULT_LOGO_SCALE}"
+                );
+                DEFAULT_LOGO_SCALE
+            }
+            None => DEFAULT_LOGO_SCALE,
+        };
+
+        // Per-region shapes switch to the self-rendered raster path
+        let region_shapes = profile.region_shapes();
+        if let Some(shapes) = region_shapes.filter(|s| s.data.is_aggressive()) {
+            warn!(
+                "mdbook-qr: '{}' uses data-shape {:?}, which some scanners struggle with; \
+                 scan-test the generated image before publishing.",
+                marker, shapes.data
+            );
         }
 
         // Render + inject
         let (fit_w, fit_h) = pass_fit_dims(&profile.fit);
-        let margin = profile.margin.unwrap_or(2);
-        let shape = profile.shape.to_shape();
-        let bg = profile.background_color();
-        let fg = profile.module_color();
+        let margin = profile.margins();
+        let logo_padding = profile.logo_padding.unwrap_or(0);
+        // URL templates: `{path}` gives each chapter its own URL and image
+        let targets = if is_data {
+            Ok(vec![Target {
+                chapter: None,
+                url: url.clone(),
+                rel: qr_rel_under_src.clone(),
+            }])
+        } else {
+            url_targets(
+                book,
+                &scope,
+                marker,
+                &alias_refs,
+                syntax,
+                &url,
+                &qr_rel_under_src,
+            )
+        };
+        let mut targets = match targets {
+            Ok(targets) => targets,
+            Err(e) => match on_failure {
+                FailureMode::Continue => {
+                    warn!("mdbook-qr: skipping '{}': {e:#}", marker);
+                    summary.skip(marker, "URL template not expanded");
+                    incomplete = true;
+                    continue;
+                }
+                FailureMode::Bail => {
+                    return Err(e.context(format!("mdbook-qr: URL for '{marker}'")))
+                }
+            },
+        };
 
-        let (_abs_out, content_hash) = write_qr_png(
-            &url,
-            &ctx.root,
-            &qr_rel_under_src,
-            fit_w,
-            fit_h,
-            margin,
-            Some(shape),
-            bg,
-            fg,
-        )?;
+        // `query-params` go on every address, after template expansion; the
+        // result must be a valid URL
+        if !is_data {
+            let slug = slug_from_marker(marker);
+            let checked: Result<Vec<String>> = targets
+                .iter()
+                .map(|t| {
+                    let url = append_query_params(&t.url, &profile.query_params, &slug);
+                    check_url(&url, cfg.normalize_url, cfg.require_https)
+                })
+                .collect();
+            match checked {
+                Ok(urls) => {
+                    for (target, url) in targets.iter_mut().zip(urls) {
+                        debug!("mdbook-qr: '{marker}' encodes {url}");
+                        target.url = url;
+                    }
+                }
+                Err(e) => match on_failure {
+                    FailureMode::Continue => {
+                        warn!("mdbook-qr: skipping '{}': {e:#}", marker);
+                        summary.skip(marker, "invalid URL");
+                        incomplete = true;
+                        continue;
+                    }
+                    FailureMode::Bail => {
+                        return Err(e.context(format!("mdbook-qr: URL for '{marker}'")))
+                    }
+                },
+            }
+        }
+
+        // Too few pixels per module do not scan; the longest payload has the most modules
+        let text = profile
+            .output
+            .unwrap_or_default()
+            .for_renderer(&ctx.renderer)
+            == OutputMode::Text;
+        let scale = profile.scale.filter(|&s| s > 0);
+        let min_px = profile.min_module_px.unwrap_or(DEFAULT_MIN_MODULE_PX);
+        let longest = targets
+            .iter()
+            .map(|t| t.url.as_str())
+            .max_by_key(|u| u.len());
+        if let Some(url) = longest.filter(|_| !text && min_px > 0.0) {
+            // With `fit`, the displayed size is what a scanner sees
+            let scale =
+                scale.filter(|_| profile.fit.width.is_none() && profile.fit.height.is_none());
+            // Payloads that do not encode fail when rendered, with their own error
+            if let Ok((modules, px)) = module_px(url, ecl, fit_w, fit_h, margin, scale)
+                && px < min_px
+            {
+                let e = anyhow!(
+                    "the code is {modules} modules across at {px:.1} px per module, below \
+                     min-module-px = {min_px}, and may not scan; use a larger `fit` or \
+                     `scale`, or a shorter URL"
+                );
+                match cfg.low_density {
+                    DensityPolicy::Warn => warn!("mdbook-qr: '{marker}': {e}"),
+                    DensityPolicy::Error => match on_failure {
+                        FailureMode::Continue => {
+                            warn!("mdbook-qr: skipping '{}': {e:#}", marker);
+                            summary.skip(marker, "module density too low");
+                            incomplete = true;
+                            continue;
+                        }
+                        FailureMode::Bail => {
+                            return Err(e.context(format!("mdbook-qr: '{marker}'")))
+                        }
+                    },
+                }
+            }
+        }
 
         // If localhost-qr is active, ensure .gitignore excludes this pattern.
-        if profile.localhost_qr.unwrap_or(false) {
-            match ensure_gitignore_for_localhost(&ctx.root, &src_dir) {
+        if profile.localhost_qr.unwrap_or(false) && !dry_run {
+            match ensure_gitignore_for_localhost(&ctx.root, &out_dir) {
                 Ok(true) => log::info!("mdbook-qr: added glob to .gitignore for qr_localhost.png"),
                 Ok(false) => {}
                 Err(e) => log::warn!("mdbook-qr: could not update .gitignore: {e}"),
             }
         }
 
-        inject_marker_relative(
-            book,
-            marker,
-            &src_dir,
-            &qr_rel_under_src,
+        let dark_variant = if profile.background.is_none() && profile.module.is_none() {
+            if profile.dark.is_set() {
+                warn!(
+                    "mdbook-qr: '{}' sets dark colors but no light background/module; \
+                     generating a single image.",
+                    marker
+                );
+            }
+            None
+        } else {
+            profile.dark_variant()
+        };
+        if let Some(w) = profile.contrast_warning(false) {
+            warn!("mdbook-qr: '{marker}': {w}");
+        }
+        for w in profile.img_hint_warnings() {
+            warn!("mdbook-qr: '{marker}': {w}");
+        }
+        // Dark variants are inverted on purpose; only their contrast matters
+        if let Some(w) = dark_variant.as_ref().and_then(|d| d.contrast_warning(true)) {
+            warn!("mdbook-qr: '{marker}' (dark): {w}");
+        }
+
+        jobs.push(Job {
+            key,
+            marker: marker.clone(),
+            aliases,
+            filter,
+            on_failure,
+            is_data,
+            targets,
+            logo_asset,
+            ecl,
+            logo_scale,
+            region_shapes,
+            fit_w,
             fit_h,
+            scale,
+            margin,
+            logo_padding,
+            dark_variant,
+            extra_ratios: profile.extra_pixel_ratios(),
+            text,
+            hashed_path,
+            // A hashed path already changes with the image
+            hashed_name: !hashed_path
+                && profile.cache_bust.unwrap_or_default() == CacheBust::Filename,
+            profile,
+        });
+    }
+
+    if dry_run {
+        log_plan(book, &scope, syntax, &jobs);
+        return Ok(());
+    }
+
+    // Render every image in parallel. Targets sharing an output path are
+    // rendered in turn, in profile order, so no two threads write one file.
+    let memo = RenderMemo::default();
+    let written = Mutex::new(Manifest::default());
+    let counts = WriteCounts::default();
+    let env = RenderEnv {
+        root: &ctx.root,
+        assets: &assets,
+        cache: write_cache.as_ref(),
+        memo: &memo,
+        written: &written,
+        signer: signer.as_ref(),
+        deterministic: cfg.deterministic,
+        counts: &counts,
+    };
+    let mut by_path: BTreeMap<&Path, Vec<(usize, usize)>> = BTreeMap::new();
+    for (j, job) in jobs.iter().enumerate().filter(|(_, job)| !job.text) {
+        for (t, target) in job.targets.iter().enumerate() {
+            by_path.entry(&target.rel).or_default().push((j, t));
+        }
+    }
+    let mut rendered: HashMap<(usize, usize), Renders> = by_path
+        .into_par_iter()
+        .flat_map_iter(|(_, group)| {
+            group.into_iter().map(|(j, t)| {
+                let job = &jobs[j];
+                ((j, t), job.render_target(&job.targets[t], &env))
+            })
+        })
+        .collect();
+
+    // Inject in profile order, so warnings and failures read the same every run
+    for (j, job) in jobs.iter().enumerate() {
+        let Job {
+            key,
+            marker,
+            on_failure,
+            is_data,
+            profile,
             fit_w,
-            Some(&content_hash),
-        )?;
+            fit_h,
+            ..
+        } = job;
+        let (is_data, fit_w, fit_h) = (*is_data, *fit_w, *fit_h);
+        // Validated once per profile; `class` without `fit` leaves sizing to
+        // CSS, and `scale` without `fit` shows the image at its own size
+        let img_attrs = profile.img_attrs();
+        let no_display_size = (profile.class.is_some() || profile.scale.is_some())
+            && profile.fit.width.is_none()
+            && profile.fit.height.is_none();
+
+        for (t, target) in job.targets.iter().enumerate() {
+            let url = &target.url;
+            let text = if job.text {
+                let invert = profile.text_invert.unwrap_or(false);
+                match encode_text(url, job.ecl, job.margin, invert) {
+                    Ok(text) => Some(text),
+                    Err(e) => match on_failure {
+                        FailureMode::Continue => {
+                            warn!("mdbook-qr: not drawing '{}': {e:#}", marker);
+                            summary.skip(marker, "text not drawn");
+                            incomplete = true;
+                            continue;
+                        }
+                        FailureMode::Bail => {
+                            return Err(e.context(format!("mdbook-qr: drawing '{marker}'")))
+                        }
+                    },
+                }
+            } else {
+                None
+            };
+            let Renders { light, dark } = match text {
+                // Drawn in the chapter itself; there is no file
+                Some(_) => Renders {
+                    light: Ok((target.rel.clone(), String::new(), Vec::new())),
+                    dark: None,
+                },
+                None => rendered
+                    .remove(&(j, t))
+                    .expect("every image target is rendered"),
+            };
+
+            let (light_rel, content_hash, light_densities) = match light {
+                Ok(rendered) => rendered,
+                Err(e) => match on_failure {
+                    FailureMode::Continue => {
+                        warn!("mdbook-qr: not writing image for '{}': {e:#}", marker);
+                        summary.skip(marker, "image not written");
+                        incomplete = true;
+                        continue;
+                    }
+                    FailureMode::Bail => {
+                        return Err(e.context(format!("mdbook-qr: writing image for '{marker}'")))
+                    }
+                },
+            };
+
+            // Dark-mode variant: same symbol, dark colors, `<stem>-dark.png`
+            let dark = match dark {
+                Some(Ok(rendered)) => Some(rendered),
+                Some(Err(e)) => match on_failure {
+                    FailureMode::Continue => {
+                        warn!("mdbook-qr: not writing dark image for '{}': {e:#}", marker);
+                        summary.skip(marker, "dark image not written");
+                        incomplete = true;
+                        continue;
+                    }
+                    FailureMode::Bail => {
+                        return Err(
+                            e.context(format!("mdbook-qr: writing dark image for '{marker}'"))
+                        )
+                    }
+                },
+                None => None,
+            };
+            // `?v=` only in query mode; hashed names carry the hash themselves
+            let query_bust =
+                profile.cache_bust.unwrap_or_default() == CacheBust::Query && !job.hashed_path;
+            let light_variants = variants(&light_densities, query_bust);
+            let dark_variants = dark
+                .as_ref()
+                .map(|(_, _, densities)| variants(densities, query_bust))
+                .unwrap_or_default();
+
+            let debug_comment = debug_comments.map(|full_url| {
+                html_escape::comment(&format!(
+                    "mdbook-qr {} profile={key} marker={marker} url={} params={}",
+                    env!("CARGO_PKG_VERSION"),
+                    match (full_url, is_data) {
+                        (true, _) => url.as_str(),
+                        // Never echo a payload (it may hold a password)
+                        (false, true) => "(data)",
+                        (false, false) => crate::url::url_host(url),
+                    },
+                    &job.image_options(url, profile, 1, &env).render_key[..12],
+                ))
+            });
+            let alt = profile.alt_text(url);
+            let link = profile.link_href(url);
+            let backdrop = profile.backdrop_css();
+            // The border is drawn outside the fit size
+            let border = 2 * profile.border_width();
+            let inject = InjectOptions {
+                fit_w: if no_display_size { 0 } else { fit_w + border },
+                fit_h: if no_display_size { 0 } else { fit_h + border },
+                cache_bust: query_bust.then_some(content_hash.as_str()),
+                cache_bust_param: profile
+                    .cache_bust_param
+                    .as_deref()
+                    .unwrap_or(DEFAULT_CACHE_BUST_PARAM),
+                output: profile
+                    .output
+                    .unwrap_or_default()
+                    .for_renderer(&ctx.renderer),
+                alt: &alt,
+                title: profile.title.as_deref(),
+                link: link.as_deref(),
+                link_target: profile.link_target.as_deref(),
+                caption: profile.caption.as_deref(),
+                caption_position: profile.caption_position.unwrap_or_default(),
+                size_mode: profile.size_mode.unwrap_or_default(),
+                backdrop: backdrop.as_deref(),
+                text: text.as_deref(),
+                loading: profile.loading_attr(),
+                decoding: profile.decoding_attr(),
+                template: profile
+                    .html_template
+                    .as_deref()
+                    .map(|template| HtmlTemplate {
+                        template,
+                        // Never echo a payload (it may hold a password)
+                        url: if is_data { "" } else { url.as_str() },
+                        hash: &content_hash,
+                    }),
+                class: profile.class.as_deref(),
+                attrs: &img_attrs,
+                dark: dark.as_ref().map(|(path, hash, _)| DarkImage {
+                    path,
+                    cache_bust: query_bust.then_some(hash.as_str()),
+                    variants: &dark_variants,
+                }),
+                variants: &light_variants,
+                // Only an address makes sense as a link or attribute value
+                destination: (cfg.link_destinations == UrlSubstitution::Url && !is_data)
+                    .then_some(url.as_str()),
+                attribute: (cfg.in_attributes == UrlSubstitution::Url && !is_data)
+                    .then_some(url.as_str()),
+                chapter: target.chapter.as_deref(),
+                scope: &scope,
+                filter: &job.filter,
+                syntax,
+                debug_comment: debug_comment.as_deref(),
+            };
+            match inject_marker_relative(book, &job.alias_refs(), &out_dir, &light_rel, &inject) {
+                Ok(replaced) => {
+                    published.add(
+                        marker,
+                        ManifestEntry {
+                            url: (!is_data).then(|| url.clone()),
+                            path: text
+                                .is_none()
+                                .then(|| light_rel.to_string_lossy().replace('\\', "/")),
+                            hash: text.is_none().then(|| content_hash.clone()),
+                            fit: Fit {
+                                width: profile.fit.width,
+                                height: profile.fit.height,
+                            },
+                            chapters: replaced.iter().map(|(path, _)| path.clone()).collect(),
+                        },
+                    );
+                    summary.replaced_in(replaced);
+                }
+                Err(e) => match on_failure {
+                    FailureMode::Continue => {
+                        warn!("mdbook-qr: not injecting '{}': {e:#}", marker);
+                        summary.skip(marker, "not injected");
+                    }
+                    FailureMode::Bail => {
+                        return Err(e.context(format!("mdbook-qr: injecting '{marker}'")))
+                    }
+                },
+            }
+        }
+    }
+
+    let write_cache = write_cache.map(|c| c.into_inner().unwrap_or_else(PoisonError::into_inner));
+    if let Some((skipped, saved)) = write_cache.as_ref().map(WriteCache::savings)
+        && skipped > 0
+    {
+        debug!(
+            "mdbook-qr: write cache skipped {skipped} encode(s), saving about {:.1} ms",
+            saved.as_secs_f64() * 1000.0
+        );
+    }
+    for path in write_cache.iter().flat_map(WriteCache::reused) {
+        summary.reused.push(Reuse {
+            path: path.strip_prefix(&ctx.root).unwrap_or(path).to_path_buf(),
+            reason: REUSE_REASON.to_string(),
+        });
+    }
+
+    // Remove what earlier runs generated and this one no longer does
+    let mut written = written.into_inner().unwrap_or_else(PoisonError::into_inner);
+    if !cfg.cleanup.unwrap_or(true) {
+        written.carry_over(previous);
+    } else if incomplete {
+        debug!("mdbook-qr: some images were not generated; skipping stale-file cleanup");
+        written.carry_over(previous);
+    } else {
+        let removed = written.remove_stale(&previous, &ctx.root);
+        if removed > 0 {
+            info!("mdbook-qr: removed {removed} stale generated file(s)");
+        }
     }
+    if let Err(e) = written.save(&manifest_file) {
+        warn!("mdbook-qr: could not write manifest: {e:#}");
+    }
+    if let Some(c) = write_cache.as_ref().filter(|c| c.is_dirty())
+        && let Err(e) = c.save(&cache_file)
+    {
+        warn!("mdbook-qr: could not write cache: {e:#}");
+    }
+
+    (summary.generated, summary.unchanged) = counts.get();
+    report_summary(&summary, &cfg, &ctx.root);
+    write_qr_manifest(&published, &cfg, &ctx.root);
+    report_orphans(book, &scope, syntax, &configured, marker_prefix, cfg.strict)?;
+    report_unreplaced(book, &scope, syntax, &expected, cfg.strict)
+}
+
+/// Write the `manifest` document when it is configured.
+fn write_qr_manifest(published: &QrManifest, cfg: &QrConfig, root: &Path) {
+    let Some(path) = &cfg.manifest else {
+        return;
+    };
+    let path = root.join(path);
+    match published.write(&path) {
+        Ok(true) => debug!("mdbook-qr: wrote the manifest {}", path.display()),
+        Ok(false) => debug!("mdbook-qr: manifest {} unchanged", path.display()),
+        Err(e) => warn!("mdbook-qr: could not write the manifest: {e:#}"),
+    }
+}
 
+/// Log, for `dry-run`, the image each job would write and the chapters its
+/// marker would be replaced in (occurrences outside code only).
+fn log_plan(book: &Book, scope: &GlobSet, syntax: Syntax, jobs: &[Job]) {
+    for job in jobs {
+        for target in &job.targets {
+            let chapters = chapters_in_scope(book, scope)
+                .filter(|ch| job.filter.matches(ch))
+                .filter(|ch| {
+                    target
+                        .chapter
+                        .as_ref()
+                        .is_none_or(|only| ch.path.as_ref() == Some(only))
+                })
+                .filter(|ch| count_markers_outside_code(&ch.content, &job.alias_refs(), syntax) > 0)
+                .count();
+            if job.text {
+                info!(
+                    "mdbook-qr: would draw marker {} as text, found in {chapters} chapter(s)",
+                    job.marker
+                );
+                continue;
+            }
+            let size = match job.scale {
+                Some(scale) => format!("{scale} px per module"),
+                None => format!("{}x{}", job.fit_w, job.fit_h),
+            };
+            info!(
+                "mdbook-qr: would write {} ({size}) for marker {}, found in {chapters} chapter(s)",
+                with_png_extension(&target.rel).display(),
+                job.marker
+            );
+        }
+    }
+}
+
+/// Log `summary` in one line, and write it to `report-path` when set.
+fn report_summary(summary: &Summary, cfg: &QrConfig, root: &Path) {
+    info!("{}", summary.line());
+    if let Some(path) = &cfg.report_path {
+        let path = root.join(path);
+        match summary.write(&path) {
+            Ok(()) => debug!("mdbook-qr: wrote the run report to {}", path.display()),
+            Err(e) => warn!("mdbook-qr: could not write the run report: {e:#}"),
+        }
+    }
+}
+
+/// Refuse to write `rel` (relative to `root`, or absolute) outside the book
+/// root unless `allow_outside`, or over an existing file mdbook-qr did not
+/// generate: one neither in the `previous` manifest nor recorded, unchanged,
+/// in the write `cache`. `overwrite_existing` also lets any PNG be replaced.
+fn check_output_path(
+    root: &Path,
+    rel: &Path,
+    previous: &Manifest,
+    cache: Option<&Mutex<WriteCache>>,
+    overwrite_existing: bool,
+    allow_outside: bool,
+) -> Result<()> {
+    let rel = with_png_extension(rel);
+    if !allow_outside && !within_root(root, &rel) {
+        bail!(
+            "'{}' is outside the book root; set allow-outside-root = true to write there",
+            rel.display()
+        );
+    }
+    let abs = root.join(&rel);
+    if !abs.exists() || previous.contains(root, &abs) {
+        return Ok(());
+    }
+    let recorded = cache.is_some_and(|c| {
+        let cache = c.lock().unwrap_or_else(PoisonError::into_inner);
+        cache
+            .recorded(&abs)
+            .is_some_and(|h| hash_file(&abs).is_ok_and(|actual| actual.to_hex().as_str() == h))
+    });
+    let adopted = overwrite_existing && is_png_file(&abs);
+    if !(recorded || adopted) {
+        bail!(
+            "'{}' exists and is not an image mdbook-qr generated; refusing to overwrite it \
+             (set overwrite-existing = true to replace PNGs from earlier versions)",
+            abs.display()
+        );
+    }
     Ok(())
 }
 
-/// Deserialize [preprocessor.qr] from the mdBook context.
-fn config_from_ctx(ctx: &PreprocessorContext) -> Option<QrConfig> {
-    ctx.config
-        .get_preprocessor("qr")
-        .and_then(|table| toml::from_str(&toml::to_string(table).ok()?).ok())
+/// Deserialize [preprocessor.qr] from the mdBook context, warning about keys
+/// nothing reads. An invalid table falls back to the defaults with a warning
+/// naming the key, or fails the build under `strict`.
+fn config_from_ctx(ctx: &PreprocessorContext) -> Result<QrConfig> {
+    let Some(table) = ctx.config.get_preprocessor("qr") else {
+        return Ok(QrConfig::default());
+    };
+    let parsed = QrConfig::from_table_reporting(table);
+    match parsed {
+        Ok((cfg, unknown)) => {
+            for key in unknown {
+                warn!("mdbook-qr: ignoring unknown key preprocessor.qr.{key}");
+            }
+            Ok(cfg)
+        }
+        Err(e) if table.get("strict").and_then(|v| v.as_bool()) == Some(true) => {
+            Err(e.context("mdbook-qr: invalid [preprocessor.qr] (strict = true)"))
+        }
+        Err(e) => {
+            warn!("mdbook-qr: invalid [preprocessor.qr], using the defaults: {e:#}");
+            Ok(QrConfig::default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::fs;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\nrest";
+
+    #[test]
+    fn output_path_refuses_foreign_files() {
+        let dir = TempDir::new("check-output-foreign");
+        let root = dir.path();
+        fs::write(root.join("mine.png"), PNG).unwrap();
+        fs::write(root.join("notes.png"), b"not an image").unwrap();
+        let none = Manifest::default();
+
+        // A PNG nobody recorded is a user's image
+        let e = check_output_path(root, Path::new("mine.png"), &none, None, false, false);
+        assert!(e.unwrap_err().to_string().contains("overwrite-existing"));
+        // Unless the book opts in, which never covers other files
+        assert!(check_output_path(root, Path::new("mine.png"), &none, None, true, false).is_ok());
+        assert!(check_output_path(root, Path::new("notes.md"), &none, None, true, false).is_err());
+        // Files that do not exist yet are always fine
+        assert!(check_output_path(root, Path::new("new.png"), &none, None, false, false).is_ok());
+    }
+
+    #[test]
+    fn output_path_accepts_recorded_files() {
+        let dir = TempDir::new("check-output-recorded");
+        let root = dir.path();
+        let abs = root.join("qr.png");
+        fs::write(&abs, PNG).unwrap();
+        let hash = blake3::hash(PNG);
+
+        let mut previous = Manifest::default();
+        previous.record(root, &abs, &hash.to_hex());
+        assert!(
+            check_output_path(root, Path::new("qr.png"), &previous, None, false, false).is_ok()
+        );
+
+        let mut cache = WriteCache::default();
+        cache.record(&abs, &hash);
+        let cache = Mutex::new(cache);
+        let none = Manifest::default();
+        let ok = check_output_path(root, Path::new("qr"), &none, Some(&cache), false, false);
+        assert!(ok.is_ok());
+        // Edited since it was written: no longer ours
+        fs::write(&abs, b"\x89PNG\r\n\x1a\nedited").unwrap();
+        let e = check_output_path(root, Path::new("qr.png"), &none, Some(&cache), false, false);
+        assert!(e.is_err());
+    }
+
+    #[test]
+    fn output_path_stays_in_root() {
+        let dir = TempDir::new("check-output-root");
+        let root = dir.path();
+        let none = Manifest::default();
+        let escape = Path::new("../../etc/something.png");
+        assert!(check_output_path(root, escape, &none, None, false, false).is_err());
+        assert!(check_output_path(root, escape, &none, None, false, true).is_ok());
+        let inside = Path::new("src/./qr/../b.png");
+        assert!(check_output_path(root, inside, &none, None, false, false).is_ok());
+        // Windows-style escapes are refused whatever the build platform
+        for windows in ["..\\..\\x.png", "C:\\Windows\\x.png"] {
+            let e = check_output_path(root, Path::new(windows), &none, None, false, false);
+            assert!(e.is_err(), "{windows}");
+        }
+        #[cfg(unix)]
+        {
+            let absolute = Path::new("/etc/qr.png");
+            assert!(check_output_path(root, absolute, &none, None, false, false).is_err());
+            // A symlinked directory counts where it points
+            std::os::unix::fs::symlink(std::env::temp_dir(), root.join("link")).unwrap();
+            let linked = Path::new("link/qr.png");
+            assert!(check_output_path(root, linked, &none, None, false, false).is_err());
+        }
+    }
+
+    fn profile_keys(toml: &str) -> Vec<String> {
+        let value = toml::Value::Table(toml::from_str(toml).unwrap());
+        let (cfg, _) = QrConfig::from_value_reporting(value).unwrap();
+        build_profiles(&cfg)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    #[test]
+    fn include_default_with_a_bare_custom_table() {
+        let bare = "[custom]\nbackground = '#fff'\n";
+        let named = "[custom.slides]\nmarker = '{{QR_SLIDES}}'\n";
+        // Unset: a bare custom table alone suppresses the default
+        assert_eq!(profile_keys(bare), Vec::<String>::new());
+        assert_eq!(
+            profile_keys(&format!("{bare}{named}")),
+            ["default", "slides"]
+        );
+        assert_eq!(profile_keys(""), ["default"]);
+        // Explicit values win either way
+        let on = "include-default = true\n";
+        assert_eq!(profile_keys(&format!("{on}{bare}")), ["default"]);
+        let off = "include-default = false\n";
+        assert_eq!(profile_keys(&format!("{off}{bare}")), Vec::<String>::new());
+        assert_eq!(profile_keys(&format!("{off}{bare}{named}")), ["slides"]);
+        assert_eq!(profile_keys(off), Vec::<String>::new());
+    }
 }
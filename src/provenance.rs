@@ -0,0 +1,362 @@
+use crate::state::{self, Artifact};
+use crate::util::hash_file;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "sign")]
+use anyhow::{anyhow, bail};
+#[cfg(feature = "sign")]
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+/// Sidecar describing how an image was produced: `<stem>.provenance.json`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Provenance {
+    /// File name of the image, relative to the sidecar.
+    pub asset: String,
+    /// The string encoded in the QR code.
+    pub payload: String,
+    /// blake3 of the image bytes (hex).
+    pub blake3: String,
+    /// ed25519 public key (hex).
+    pub public_key: String,
+    /// ed25519 signature over [`signed_message`] (hex).
+    pub signature: String,
+}
+
+impl Artifact for Provenance {
+    const KIND: &'static str = "provenance";
+}
+
+/// Sidecar path for `asset`.
+#[cfg(feature = "sign")]
+pub(crate) fn sidecar_path(asset: &Path) -> PathBuf {
+    let stem = asset
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    asset.with_file_name(format!("{stem}.provenance.json"))
+}
+
+/// The bytes covered by a signature: a domain tag, the asset, then the payload.
+#[cfg(feature = "sign")]
+fn signed_message(asset: &[u8], payload: &str) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(asset.len() + payload.len() + 32);
+    msg.extend_from_slice(b"mdbook-qr provenance v1\0");
+    msg.extend_from_slice(asset);
+    msg.push(0);
+    msg.extend_from_slice(payload.as_bytes());
+    msg
+}
+
+/// Read a 32-byte ed25519 key: raw bytes or 64 hex characters. Errors name
+/// the file, never its contents.
+#[cfg(feature = "sign")]
+fn read_key_bytes(path: &Path, what: &str) -> Result<[u8; 32]> {
+    let raw = fs::read(path).with_context(|| format!("reading {what} {}", path.display()))?;
+    if let Ok(bytes) = <[u8; 32]>::try_from(raw.as_slice()) {
+        return Ok(bytes);
+    }
+    let text = std::str::from_utf8(&raw).map(str::trim).unwrap_or("");
+    let mut out = [0u8; 32];
+    hex::decode_to_slice(text, &mut out).map_err(|_| {
+        anyhow!(
+            "{what} {} is not 32 raw bytes or 64 hex characters",
+            path.display()
+        )
+    })?;
+    Ok(out)
+}
+
+/// Signs generated images with the configured `sign-key`.
+#[cfg(feature = "sign")]
+pub(crate) struct AssetSigner {
+    key: SigningKey,
+}
+
+#[cfg(feature = "sign")]
+impl AssetSigner {
+    /// Load `sign-key` (relative to `root`) if configured.
+    pub fn from_config(root: &Path, sign_key: Option<&str>) -> Result<Option<Self>> {
+        let Some(rel) = sign_key else {
+            return Ok(None);
+        };
+        let seed = read_key_bytes(&root.join(rel), "sign-key")?;
+        Ok(Some(Self {
+            key: SigningKey::from_bytes(&seed),
+        }))
+    }
+
+    /// Sign the image at `asset` (as written) and store the sidecar beside it.
+    /// ed25519 is deterministic, so an unchanged image leaves the sidecar untouched.
+    pub fn write_sidecar(&self, asset: &Path, payload: &str) -> Result<PathBuf> {
+        let bytes = fs::read(asset).with_context(|| format!("reading {}", asset.display()))?;
+        let signature = self.key.sign(&signed_message(&bytes, payload));
+        let record = Provenance {
+            asset: asset
+                .file_name()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            payload: payload.to_string(),
+            blake3: blake3::hash(&bytes).to_hex().to_string(),
+            public_key: hex::encode(self.key.verifying_key().as_bytes()),
+            signature: hex::encode(signature.to_bytes()),
+        };
+        let json = state::encode(&record)?;
+        let sidecar = sidecar_path(asset);
+        crate::util::write_if_changed(&sidecar, &json, &blake3::hash(&json), None)?;
+        Ok(sidecar)
+    }
+}
+
+/// Without the `sign` feature no signer can exist.
+#[cfg(not(feature = "sign"))]
+pub(crate) enum AssetSigner {}
+
+#[cfg(not(feature = "sign"))]
+impl AssetSigner {
+    pub fn from_config(_root: &Path, sign_key: Option<&str>) -> Result<Option<Self>> {
+        if sign_key.is_some() {
+            log::warn!(
+                "mdbook-qr: sign-key is set but this build lacks the `sign` feature; images are not signed."
+            );
+        }
+        Ok(None)
+    }
+
+    pub fn write_sidecar(&self, _asset: &Path, _payload: &str) -> Result<PathBuf> {
+        match *self {}
+    }
+}
+
+/// Key used by `audit` to verify signatures; cannot exist without `sign`.
+#[cfg(feature = "sign")]
+type PublicKey = VerifyingKey;
+#[cfg(not(feature = "sign"))]
+type PublicKey = std::convert::Infallible;
+
+#[cfg(feature = "sign")]
+fn load_public_key(path: &Path) -> Result<PublicKey> {
+    VerifyingKey::from_bytes(&read_key_bytes(path, "public key")?)
+        .map_err(|_| anyhow!("{} is not a valid ed25519 public key", path.display()))
+}
+
+#[cfg(not(feature = "sign"))]
+fn load_public_key(_path: &Path) -> Result<PublicKey> {
+    anyhow::bail!("signature verification needs mdbook-qr built with the `sign` feature")
+}
+
+/// Why the signature on `asset` does not verify against `key`, if it doesn't.
+#[cfg(feature = "sign")]
+fn signature_problem(record: &Provenance, asset: &Path, key: &PublicKey) -> Option<String> {
+    let check = || -> Result<()> {
+        if hex::encode(key.as_bytes()) != record.public_key {
+            bail!("signed by a different key");
+        }
+        let mut sig = [0u8; 64];
+        hex::decode_to_slice(&record.signature, &mut sig)
+            .map_err(|_| anyhow!("malformed signature"))?;
+        let bytes = fs::read(asset)?;
+        key.verify(
+            &signed_message(&bytes, &record.payload),
+            &Signature::from_bytes(&sig),
+        )
+        .map_err(|_| anyhow!("signature does not match"))
+    };
+    check().err().map(|e| format!("{}: {e}", record.asset))
+}
+
+#[cfg(not(feature = "sign"))]
+fn signature_problem(_record: &Provenance, _asset: &Path, key: &PublicKey) -> Option<String> {
+    match *key {}
+}
+
+/// Outcome of auditing one sidecar.
+pub struct AuditEntry {
+    pub sidecar: PathBuf,
+    /// `None` when the asset checks out.
+    pub problem: Option<String>,
+}
+
+/// Check every `*.provenance.json` under `dir`: the asset must exist and
+/// match the recorded hash, and (with `pubkey`) carry a valid signature.
+pub fn audit(dir: &Path, pubkey: Option<&Path>) -> Result<Vec<AuditEntry>> {
+    let key = pubkey.map(load_public_key).transpose()?;
+
+    let mut sidecars = Vec::new();
+    collect_sidecars(dir, &mut sidecars)?;
+    sidecars.sort();
+
+    let mut out = Vec::with_capacity(sidecars.len());
+    for sidecar in sidecars {
+        let record: Provenance = state::load(&sidecar);
+        let asset = sidecar.with_file_name(&record.asset);
+        let problem = if record.asset.is_empty() {
+            Some("unreadable sidecar".to_string())
+        } else {
+            match hash_file(&asset) {
+                Err(e) => Some(format!("cannot read {}: {e}", asset.display())),
+                Ok(h) if h.to_hex().as_str() != record.blake3 => {
+                    Some(format!("{} does not match its recorded hash", record.asset))
+                }
+                Ok(_) => key
+                    .as_ref()
+                    .and_then(|k| signature_problem(&record, &asset, k)),
+            }
+        };
+        out.push(AuditEntry { sidecar, problem });
+    }
+    Ok(out)
+}
+
+fn collect_sidecars(dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_sidecars(&path, out)?;
+        } else if path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().ends_with(".provenance.json"))
+        {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn problems(dir: &Path, pubkey: Option<&Path>) -> Vec<Option<String>> {
+        audit(dir, pubkey)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.problem)
+            .collect()
+    }
+
+    #[test]
+    fn audit_checks_hashes_without_a_key() {
+        let dir = TempDir::new("provenance-hash");
+        let asset = dir.path().join("qr/a.png");
+        fs::create_dir_all(asset.parent().unwrap()).unwrap();
+        fs::write(&asset, b"png bytes").unwrap();
+        let record = Provenance {
+            asset: "a.png".to_string(),
+            payload: "https://example.com/".to_string(),
+            blake3: blake3::hash(b"png bytes").to_hex().to_string(),
+            ..Provenance::default()
+        };
+        fs::write(
+            dir.path().join("qr/a.provenance.json"),
+            state::encode(&record).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(problems(dir.path(), None), [None]);
+
+        fs::write(&asset, b"swapped").unwrap();
+        let found = problems(dir.path(), None);
+        assert!(
+            found[0].as_deref().unwrap().contains("recorded hash"),
+            "{found:?}"
+        );
+
+        fs::remove_file(&asset).unwrap();
+        let found = problems(dir.path(), None);
+        assert!(
+            found[0].as_deref().unwrap().starts_with("cannot read"),
+            "{found:?}"
+        );
+    }
+
+    #[cfg(not(feature = "sign"))]
+    #[test]
+    fn verifying_signatures_needs_the_feature() {
+        let dir = TempDir::new("provenance-nosign");
+        let e = audit(dir.path(), Some(&dir.path().join("pub.key")))
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("`sign` feature"), "{e}");
+        assert!(AssetSigner::from_config(dir.path(), Some("k.key"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[cfg(feature = "sign")]
+    fn key_pair(dir: &Path, seed: u8) -> (AssetSigner, PathBuf) {
+        fs::write(dir.join(format!("{seed}.key")), hex::encode([seed; 32])).unwrap();
+        let signer = AssetSigner::from_config(dir, Some(&format!("{seed}.key")))
+            .unwrap()
+            .unwrap();
+        let public = dir.join(format!("{seed}.pub"));
+        fs::write(&public, signer.key.verifying_key().as_bytes()).unwrap();
+        (signer, public)
+    }
+
+    #[cfg(feature = "sign")]
+    #[test]
+    fn signatures_verify_until_the_asset_or_payload_changes() {
+        let dir = TempDir::new("provenance-sign");
+        let (signer, public) = key_pair(dir.path(), 1);
+        let asset = dir.path().join("qr/a.png");
+        fs::create_dir_all(asset.parent().unwrap()).unwrap();
+        fs::write(&asset, b"png bytes").unwrap();
+        let sidecar = signer
+            .write_sidecar(&asset, "https://example.com/")
+            .unwrap();
+        assert!(sidecar.ends_with("qr/a.provenance.json"));
+        assert_eq!(problems(dir.path(), Some(&public)), [None]);
+
+        // Re-pointing the payload breaks the signature but not the hash
+        let edited = fs::read_to_string(&sidecar)
+            .unwrap()
+            .replace("https://example.com/", "https://evil.example/");
+        fs::write(&sidecar, edited).unwrap();
+        assert_eq!(problems(dir.path(), None), [None]);
+        let found = problems(dir.path(), Some(&public));
+        assert!(found[0]
+            .as_deref()
+            .unwrap()
+            .ends_with("signature does not match"));
+
+        signer
+            .write_sidecar(&asset, "https://example.com/")
+            .unwrap();
+        fs::write(&asset, b"png bytez").unwrap();
+        let found = problems(dir.path(), Some(&public));
+        assert!(
+            found[0].as_deref().unwrap().contains("recorded hash"),
+            "{found:?}"
+        );
+
+        fs::write(&asset, b"png bytes").unwrap();
+        let (_, other) = key_pair(dir.path(), 2);
+        let found = problems(dir.path(), Some(&other));
+        assert!(found[0]
+            .as_deref()
+            .unwrap()
+            .ends_with("signed by a different key"));
+    }
+
+    #[cfg(feature = "sign")]
+    #[test]
+    fn key_errors_name_the_file_but_not_its_contents() {
+        let dir = TempDir::new("provenance-keys");
+        fs::write(dir.path().join("bad.key"), "not-a-secret-key").unwrap();
+        let e = AssetSigner::from_config(dir.path(), Some("bad.key"))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            e.contains("bad.key") && !e.contains("not-a-secret-key"),
+            "{e}"
+        );
+        assert!(AssetSigner::from_config(dir.path(), Some("missing.key")).is_err());
+        assert!(AssetSigner::from_config(dir.path(), None)
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,68 @@
+use crate::util::write_if_changed;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Version of the `manifest` document; bumped when a field changes meaning
+/// or goes away. New fields do not bump it.
+const SCHEMA: u32 = 1;
+
+/// The `manifest` document: every image a run injected, by marker, for
+/// tools that audit or verify them after the build.
+#[derive(Debug, Serialize)]
+pub(crate) struct QrManifest {
+    schema: u32,
+    version: &'static str,
+    markers: BTreeMap<String, Vec<ManifestEntry>>,
+}
+
+/// One injected image. A marker has several when its URL uses `{path}`.
+#[derive(Debug, Serialize)]
+pub(crate) struct ManifestEntry {
+    /// The encoded address; `None` for a `data`, `wifi` or `contact`
+    /// payload, which may hold a secret.
+    pub url: Option<String>,
+    /// Path relative to the book root, `/`-separated; `None` for `text`
+    /// output, which writes no file.
+    pub path: Option<String>,
+    /// First 12 hex digits of the image's blake3 hash.
+    pub hash: Option<String>,
+    pub fit: Fit,
+    /// Chapters (paths relative to `src`) the image was injected into.
+    pub chapters: Vec<PathBuf>,
+}
+
+/// Displayed size in pixels; `None` when unset.
+#[derive(Debug, Serialize)]
+pub(crate) struct Fit {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl Default for QrManifest {
+    fn default() -> Self {
+        Self {
+            schema: SCHEMA,
+            version: env!("CARGO_PKG_VERSION"),
+            markers: BTreeMap::new(),
+        }
+    }
+}
+
+impl QrManifest {
+    pub fn add(&mut self, marker: &str, entry: ManifestEntry) {
+        self.markers
+            .entry(marker.to_string())
+            .or_default()
+            .push(entry);
+    }
+
+    /// Write the document to `path`, leaving the file alone when its
+    /// contents would not change.
+    pub fn write(&self, path: &Path) -> Result<bool> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        write_if_changed(path, json.as_bytes(), &blake3::hash(json.as_bytes()), None)
+    }
+}
@@ -0,0 +1,161 @@
+use crate::config::{GradientDirection, Margins, ModuleShape, RegionShapes};
+use crate::geometry::{Region, RegionMap};
+use anyhow::{anyhow, Result};
+use fast_qr::QRCode;
+use image::{ImageFormat, Rgba, RgbaImage};
+use std::io::Cursor;
+
+/// Colors and per-region shapes for the self-rendered path.
+pub struct RasterStyle {
+    pub shapes: RegionShapes,
+    pub background: [u8; 4],
+    pub module: [u8; 4],
+    /// Outer ring of the finder patterns; `None` → `module`.
+    pub eye_frame: Option<[u8; 4]>,
+    /// 3×3 centre of the finder patterns; `None` → `module`.
+    pub eye_module: Option<[u8; 4]>,
+    /// Shades every module drawn in `module` instead.
+    pub gradient: Option<Gradient>,
+}
+
+/// Two colors blended across the code, for `module-gradient`.
+pub struct Gradient {
+    pub from: [u8; 4],
+    pub to: [u8; 4],
+    pub direction: GradientDirection,
+}
+
+impl Gradient {
+    /// The color at pixel centre (`px`, `py`) of a code whose top-left
+    /// corner is (`x`, `y`) and whose edge is `edge` px.
+    fn at(&self, px: f32, py: f32, (x, y, edge): (f32, f32, f32)) -> [u8; 4] {
+        let t = match self.direction {
+            GradientDirection::Vertical => (py - y) / edge,
+            GradientDirection::Horizontal => (px - x) / edge,
+            GradientDirection::Diagonal => (px - x + py - y) / (2.0 * edge),
+        }
+        .clamp(0.0, 1.0);
+        std::array::from_fn(|i| {
+            let (a, b) = (self.from[i] as f32, self.to[i] as f32);
+            (a + (b - a) * t).round() as u8
+        })
+    }
+}
+
+/// Draw `qr` into a `fit_w`×`fit_h` PNG, centred, with `margins` quiet-zone
+/// modules around it and each module drawn with its region's shape.
+pub fn render_png(
+    qr: &QRCode,
+    fit_w: u32,
+    fit_h: u32,
+    margins: Margins,
+    style: &RasterStyle,
+) -> Result<Vec<u8>> {
+    let size = qr.size;
+    let (w, h) = (fit_w.max(1), fit_h.max(1));
+    let (cell, ox, oy) = placement(size, w, h, margins);
+
+    let mut img = RgbaImage::from_pixel(w, h, Rgba(style.background));
+    let regions = RegionMap::new(size);
+    let code = (ox, oy, cell * size as f32);
+    // `None` paints with the module color, or its gradient
+    let paint = |color: Option<[u8; 4]>, px: u32, py: u32| match (color, &style.gradient) {
+        (None, Some(gradient)) => gradient.at(px as f32 + 0.5, py as f32 + 0.5, code),
+        (color, _) => color.unwrap_or(style.module),
+    };
+    for y in 0..size {
+        for x in 0..size {
+            if !qr.data[y * size + x].value() {
+                continue;
+            }
+            let (shape, color) = match regions.classify(x, y) {
+                Region::Finder if regions.in_finder_centre(x, y) => {
+                    (style.shapes.finder, style.eye_module)
+                }
+                Region::Finder => (style.shapes.finder, style.eye_frame),
+                Region::Alignment => (style.shapes.alignment, None),
+                Region::Data => (style.shapes.data, None),
+            };
+            let x0 = ox + x as f32 * cell;
+            let y0 = oy + y as f32 * cell;
+            draw_module(&mut img, x0, y0, cell, shape, &|px, py| {
+                paint(color, px, py)
+            });
+        }
+    }
+
+    let mut out = Vec::new();
+    img.write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+        .map_err(|e| anyhow!("PNG encode: {e}"))?;
+    Ok(out)
+}
+
+/// Module edge and top-left corner of the code (px) when a `size`-module
+/// symbol with `margins` is centred in a `width`×`height` image.
+pub fn placement(size: usize, width: u32, height: u32, margins: Margins) -> (f32, f32, f32) {
+    let total_x = size as f32 + (margins.left + margins.right) as f32;
+    let total_y = size as f32 + (margins.top + margins.bottom) as f32;
+    let cell = (width as f32 / total_x).min(height as f32 / total_y);
+    let ox = (width as f32 - cell * total_x) / 2.0 + margins.left as f32 * cell;
+    let oy = (height as f32 - cell * total_y) / 2.0 + margins.top as f32 * cell;
+    (cell, ox, oy)
+}
+
+/// Fill the pixels of one module cell whose centres fall inside `shape`,
+/// each with `color` of its coordinates.
+fn draw_module(
+    img: &mut RgbaImage,
+    x0: f32,
+    y0: f32,
+    cell: f32,
+    shape: ModuleShape,
+    color: &dyn Fn(u32, u32) -> [u8; 4],
+) {
+    let (w, h) = img.dimensions();
+    let px_start = x0.floor().max(0.0) as u32;
+    let py_start = y0.floor().max(0.0) as u32;
+    let px_end = ((x0 + cell).ceil() as u32).min(w);
+    let py_end = ((y0 + cell).ceil() as u32).min(h);
+    for py in py_start..py_end {
+        for px in px_start..px_end {
+            let u = (px as f32 + 0.5 - x0) / cell;
+            let v = (py as f32 + 0.5 - y0) / cell;
+            if (0.0..1.0).contains(&u) && (0.0..1.0).contains(&v) && covers(shape, u, v) {
+                blend(img.get_pixel_mut(px, py), color(px, py));
+            }
+        }
+    }
+}
+
+/// Whether the point (`u`, `v`) in unit-cell coordinates lies inside `shape`.
+fn covers(shape: ModuleShape, u: f32, v: f32) -> bool {
+    let dx = (u - 0.5).abs();
+    let dy = (v - 0.5).abs();
+    match shape {
+        ModuleShape::Square => true,
+        ModuleShape::Circle => dx * dx + dy * dy <= 0.25,
+        ModuleShape::Diamond => dx + dy <= 0.5,
+        ModuleShape::RoundedSquare => {
+            const R: f32 = 0.3;
+            let cx = (dx - (0.5 - R)).max(0.0);
+            let cy = (dy - (0.5 - R)).max(0.0);
+            cx * cx + cy * cy <= R * R
+        }
+        ModuleShape::Vertical => dx <= 0.4,
+        ModuleShape::Horizontal => dy <= 0.4,
+    }
+}
+
+/// Source-over blend of `src` onto `dst`.
+fn blend(dst: &mut Rgba<u8>, src: [u8; 4]) {
+    let a = src[3] as u32;
+    if a == 255 {
+        *dst = Rgba(src);
+        return;
+    }
+    let inv = 255 - a;
+    for (d, s) in dst.0.iter_mut().zip(src).take(3) {
+        *d = ((s as u32 * a + *d as u32 * inv) / 255) as u8;
+    }
+    dst.0[3] = (a + dst.0[3] as u32 * inv / 255).min(255) as u8;
+}
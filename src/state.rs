@@ -0,0 +1,217 @@
+use log::{debug, info};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Current envelope format. Files without a `format` field are format 1.
+pub(crate) const FORMAT: u32 = 2;
+
+/// A file persisted between runs, written as `{ "format": N, "kind": "...", ... }`
+/// with its own fields flattened alongside. Reading never fails: newer,
+/// foreign or corrupt files are logged and replaced by a fresh default.
+pub(crate) trait Artifact: Serialize + DeserializeOwned + Default {
+    /// Stored as `kind` so one artifact is never read as another.
+    const KIND: &'static str;
+
+    /// Upgrade the payload of an older `format`. `None` discards it.
+    fn migrate(format: u32, payload: serde_json::Value) -> Option<Self> {
+        let _ = (format, payload);
+        None
+    }
+}
+
+#[derive(Serialize)]
+struct EnvelopeOut<'a, T> {
+    format: u32,
+    kind: &'static str,
+    #[serde(flatten)]
+    payload: &'a T,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeIn {
+    #[serde(default = "legacy_format")]
+    format: u32,
+    kind: Option<String>,
+    #[serde(flatten)]
+    payload: serde_json::Map<String, serde_json::Value>,
+}
+
+fn legacy_format() -> u32 {
+    1
+}
+
+/// Serialize `value` inside the current envelope.
+pub(crate) fn encode<T: Artifact>(value: &T) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec_pretty(&EnvelopeOut {
+        format: FORMAT,
+        kind: T::KIND,
+        payload: value,
+    })
+}
+
+/// Parse an envelope, falling back to `T::default()` on anything unexpected.
+/// `origin` only appears in log messages.
+pub(crate) fn decode<T: Artifact>(bytes: &[u8], origin: &Path) -> T {
+    let env: EnvelopeIn = match serde_json::from_slice(bytes) {
+        Ok(env) => env,
+        Err(e) => {
+            debug!(
+                "mdbook-qr: ignoring unreadable {} {}: {e}",
+                T::KIND,
+                origin.display()
+            );
+            return T::default();
+        }
+    };
+    if let Some(kind) = env.kind.as_deref().filter(|k| *k != T::KIND) {
+        info!(
+            "mdbook-qr: {} holds a '{kind}', not a '{}'; regenerating",
+            origin.display(),
+            T::KIND
+        );
+        return T::default();
+    }
+
+    let payload = serde_json::Value::Object(env.payload);
+    let parsed = match env.format {
+        FORMAT => serde_json::from_value(payload).ok(),
+        newer if newer > FORMAT => {
+            info!(
+                "mdbook-qr: {} uses format {newer}, newer than this build understands ({FORMAT}); regenerating",
+                origin.display()
+            );
+            return T::default();
+        }
+        older => T::migrate(older, payload),
+    };
+    parsed.unwrap_or_else(|| {
+        info!(
+            "mdbook-qr: discarding {} {} (format {}); regenerating",
+            T::KIND,
+            origin.display(),
+            env.format
+        );
+        T::default()
+    })
+}
+
+/// Read and decode `path`; a missing file yields `T::default()`.
+pub(crate) fn load<T: Artifact>(path: &Path) -> T {
+    match fs::read(path) {
+        Ok(bytes) => decode(&bytes, path),
+        Err(_) => T::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::WriteCache;
+    use crate::manifest::Manifest;
+    use crate::provenance::Provenance;
+    use serde_json::json;
+
+    const ORIGIN: &str = "test.json";
+
+    fn from_fields<T: Artifact>(fields: serde_json::Value) -> T {
+        serde_json::from_value(fields).unwrap()
+    }
+
+    /// Encode, decode and encode again: both encodings must be identical and
+    /// carry the current envelope.
+    fn round_trip<T: Artifact>(value: &T) -> serde_json::Value {
+        let bytes = encode(value).unwrap();
+        let again = encode(&decode::<T>(&bytes, Path::new(ORIGIN))).unwrap();
+        assert_eq!(bytes, again);
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["format"], FORMAT);
+        assert_eq!(json["kind"], T::KIND);
+        json
+    }
+
+    fn cache_fields() -> serde_json::Value {
+        json!({
+            "entries": {
+                "src/qr/qr_code.png": { "hash": "abc", "size": 3, "mtime_ns": 7 }
+            }
+        })
+    }
+
+    #[test]
+    fn every_artifact_round_trips() {
+        let json = round_trip(&from_fields::<WriteCache>(cache_fields()));
+        assert_eq!(json["entries"]["src/qr/qr_code.png"]["size"], 3);
+
+        let manifest = json!({ "files": { "src/qr/qr_code.png": "0123456789ab" } });
+        let json = round_trip(&from_fields::<Manifest>(manifest));
+        assert_eq!(json["files"]["src/qr/qr_code.png"], "0123456789ab");
+
+        let provenance = json!({
+            "asset": "qr_code.png",
+            "payload": "https://example.com/",
+            "blake3": "ff",
+            "public_key": "00",
+            "signature": "11",
+        });
+        let json = round_trip(&from_fields::<Provenance>(provenance));
+        assert_eq!(json["payload"], "https://example.com/");
+    }
+
+    #[test]
+    fn newer_formats_are_regenerated() {
+        let mut future = cache_fields();
+        future["format"] = json!(FORMAT + 1);
+        future["kind"] = json!(WriteCache::KIND);
+        let bytes = serde_json::to_vec(&future).unwrap();
+        let cache: WriteCache = decode(&bytes, Path::new(ORIGIN));
+        assert_eq!(
+            encode(&cache).unwrap(),
+            encode(&WriteCache::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn format_1_write_caches_are_migrated() {
+        // Format 1 had no envelope: the bare fields
+        let bytes = serde_json::to_vec(&cache_fields()).unwrap();
+        let cache: WriteCache = decode(&bytes, Path::new(ORIGIN));
+        let json: serde_json::Value = serde_json::from_slice(&encode(&cache).unwrap()).unwrap();
+        assert_eq!(json["entries"]["src/qr/qr_code.png"]["hash"], "abc");
+
+        // Other artifacts have nothing to migrate from
+        let bytes = br#"{ "files": { "a.png": "0123456789ab" } }"#;
+        let manifest: Manifest = decode(bytes, Path::new(ORIGIN));
+        assert_eq!(
+            encode(&manifest).unwrap(),
+            encode(&Manifest::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn foreign_and_corrupt_files_are_regenerated() {
+        let manifest = encode(&from_fields::<Manifest>(
+            json!({ "files": { "a.png": "ab" } }),
+        ))
+        .unwrap();
+        let cache: WriteCache = decode(&manifest, Path::new(ORIGIN));
+        assert_eq!(
+            encode(&cache).unwrap(),
+            encode(&WriteCache::default()).unwrap()
+        );
+
+        for corrupt in [&b""[..], b"{", b"[1, 2]", b"{\"format\": \"two\"}"] {
+            let cache: WriteCache = decode(corrupt, Path::new(ORIGIN));
+            assert_eq!(
+                encode(&cache).unwrap(),
+                encode(&WriteCache::default()).unwrap()
+            );
+        }
+        let missing: Manifest = load(Path::new("/nonexistent/mdbook-qr/state.json"));
+        assert_eq!(
+            encode(&missing).unwrap(),
+            encode(&Manifest::default()).unwrap()
+        );
+    }
+}
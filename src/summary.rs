@@ -0,0 +1,139 @@
+use crate::util::write_atomic;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What a run did: logged as one line at its end, and written as JSON to
+/// `report-path`.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Summary {
+    /// Enabled profiles.
+    pub profiles: usize,
+    /// Images written this run.
+    pub generated: usize,
+    /// Images already up to date on disk.
+    pub unchanged: usize,
+    /// Markers replaced in running text.
+    pub replaced: usize,
+    /// Markers replaced per chapter (path relative to `src`).
+    pub chapters: BTreeMap<PathBuf, usize>,
+    /// Chapters outside the `chapters` allowlist, never scanned.
+    pub skipped_chapters: usize,
+    /// Profiles not injected, and why.
+    pub skipped: Vec<Skip>,
+    /// Images kept without encoding them again (path relative to the book
+    /// root), and why; counted among `unchanged` too.
+    pub reused: Vec<Reuse>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Skip {
+    pub marker: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Reuse {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl Summary {
+    /// Record that `marker`'s profile was not injected, for `reason`; once
+    /// per profile, however many of its images failed alike.
+    pub fn skip(&mut self, marker: &str, reason: &str) {
+        if self
+            .skipped
+            .iter()
+            .any(|s| s.marker == marker && s.reason == reason)
+        {
+            return;
+        }
+        self.skipped.push(Skip {
+            marker: marker.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Add the replacements of one marker, per chapter.
+    pub fn replaced_in(&mut self, chapters: Vec<(PathBuf, usize)>) {
+        for (path, count) in chapters {
+            self.replaced += count;
+            *self.chapters.entry(path).or_default() += count;
+        }
+    }
+
+    /// The closing log line.
+    pub fn line(&self) -> String {
+        let mut line = format!(
+            "mdbook-qr: summary: {} profile(s), {} image(s) generated, {} unchanged, \
+             {} marker(s) replaced in {} chapter(s), {} profile(s) skipped",
+            self.profiles,
+            self.generated,
+            self.unchanged,
+            self.replaced,
+            self.chapters.len(),
+            self.skipped.len()
+        );
+        if !self.skipped.is_empty() {
+            let reasons: Vec<String> = self
+                .skipped
+                .iter()
+                .map(|s| format!("{}: {}", s.marker, s.reason))
+                .collect();
+            line.push_str(&format!(" ({})", reasons.join("; ")));
+        }
+        if !self.reused.is_empty() {
+            let mut reasons: Vec<&str> = self.reused.iter().map(|r| r.reason.as_str()).collect();
+            reasons.sort_unstable();
+            reasons.dedup();
+            line.push_str(&format!(
+                ", {} raster(s) reused ({})",
+                self.reused.len(),
+                reasons.join("; ")
+            ));
+        }
+        if self.skipped_chapters > 0 {
+            line.push_str(&format!(
+                ", {} chapter(s) outside the `chapters` allowlist",
+                self.skipped_chapters
+            ));
+        }
+        line
+    }
+
+    /// Write the summary as pretty-printed JSON to `path`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        write_atomic(path, json.as_bytes())
+    }
+}
+
+/// Images written and left unchanged, counted while rendering in parallel.
+#[derive(Debug, Default)]
+pub(crate) struct WriteCounts {
+    generated: AtomicUsize,
+    unchanged: AtomicUsize,
+}
+
+impl WriteCounts {
+    pub fn record(&self, changed: bool) {
+        let counter = if changed {
+            &self.generated
+        } else {
+            &self.unchanged
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `(generated, unchanged)`.
+    pub fn get(&self) -> (usize, usize) {
+        (
+            self.generated.load(Ordering::Relaxed),
+            self.unchanged.load(Ordering::Relaxed),
+        )
+    }
+}
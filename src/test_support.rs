@@ -0,0 +1,203 @@
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::preprocess::PreprocessorContext;
+use mdbook::Config;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Builds the `(PreprocessorContext, Book)` pair mdBook hands a preprocessor,
+/// without hand-writing the JSON payload.
+///
+/// Images are written under the fixture root, a fresh directory in the system
+/// temp dir unless [`BookFixture::root`] picks one.
+///
+/// ```
+/// use mdbook::preprocess::Preprocessor;
+/// use mdbook_qr::test_support::{assert_injected, BookFixture};
+/// use mdbook_qr::QrPreprocessor;
+///
+/// let (ctx, book) = BookFixture::new()
+///     .chapter("intro.md", "# Intro\n\n{{QR_CODE}}\n")
+///     .nested("guide/setup.md", "No code here.\n")
+///     .context_with_config(
+///         r#"
+///         [preprocessor.qr]
+///         url = "https://example.com/book/"
+///         "#,
+///     )
+///     .build();
+///
+/// let book = QrPreprocessor::new().no_cache(true).run(&ctx, book).unwrap();
+/// assert_injected(&book, "intro.md", "{{QR_CODE}}");
+/// ```
+#[derive(Debug)]
+pub struct BookFixture {
+    root: PathBuf,
+    renderer: String,
+    config: String,
+    chapters: Vec<Chapter>,
+}
+
+impl Default for BookFixture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BookFixture {
+    /// An empty book rendered with `html`, rooted in a new temp directory.
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let root = std::env::temp_dir().join(format!(
+            "mdbook-qr-fixture-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        Self {
+            root,
+            renderer: "html".to_string(),
+            config: String::new(),
+            chapters: Vec::new(),
+        }
+    }
+
+    /// Book root on disk (where `book.toml` would live).
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// Renderer name reported to the preprocessor (default `html`).
+    pub fn renderer(mut self, renderer: &str) -> Self {
+        self.renderer = renderer.to_string();
+        self
+    }
+
+    /// Contents of `book.toml`. `[book]` may be omitted; `src` defaults to `src`.
+    pub fn context_with_config(mut self, toml: &str) -> Self {
+        self.config = toml.to_string();
+        self
+    }
+
+    /// Add a top-level chapter at `path` (relative to `src`).
+    pub fn chapter(mut self, path: &str, content: &str) -> Self {
+        self.chapters.push(Chapter::new(
+            &chapter_name(path),
+            content.to_string(),
+            path,
+            Vec::new(),
+        ));
+        self
+    }
+
+    /// Add a chapter under the one for its parent directory: `a/b.md` nests
+    /// under `a.md`, `a/README.md` or `a/index.md`, and an empty `a/README.md`
+    /// is created when none of those exist.
+    pub fn nested(mut self, path: &str, content: &str) -> Self {
+        let dir = Path::new(path)
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or_else(|| panic!("nested chapter '{path}' has no parent directory"))
+            .to_path_buf();
+        let candidates = [
+            dir.with_extension("md"),
+            dir.join("README.md"),
+            dir.join("index.md"),
+        ];
+        let parent = match self
+            .chapters
+            .iter()
+            .position(|c| c.path.as_ref().is_some_and(|p| candidates.contains(p)))
+        {
+            Some(i) => &mut self.chapters[i],
+            None => {
+                let name = chapter_name(&dir.to_string_lossy());
+                let readme = dir.join("README.md");
+                self.chapters
+                    .push(Chapter::new(&name, String::new(), readme, Vec::new()));
+                self.chapters.last_mut().expect("just pushed")
+            }
+        };
+        let mut parents = parent.parent_names.clone();
+        parents.push(parent.name.clone());
+        let child = Chapter::new(&chapter_name(path), content.to_string(), path, parents);
+        parent.sub_items.push(BookItem::Chapter(child));
+        self
+    }
+
+    /// Assemble the context and book.
+    ///
+    /// # Panics
+    ///
+    /// If the configured `book.toml` is not valid.
+    pub fn build(self) -> (PreprocessorContext, Book) {
+        let config = Config::from_str(&self.config)
+            .unwrap_or_else(|e| panic!("fixture book.toml is invalid: {e}"));
+        // mdBook only builds contexts itself; go through the wire format.
+        let ctx = serde_json::from_value(serde_json::json!({
+            "root": self.root,
+            "config": config,
+            "renderer": self.renderer,
+            "mdbook_version": mdbook::MDBOOK_VERSION,
+        }))
+        .expect("PreprocessorContext round-trips through JSON");
+
+        let mut book = Book::new();
+        for chapter in self.chapters {
+            book.push_item(chapter);
+        }
+        (ctx, book)
+    }
+}
+
+/// Title-cased name for a chapter file: `getting-started.md` → `Getting started`.
+fn chapter_name(path: &str) -> String {
+    let stem = Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().replace(['-', '_'], " "))
+        .unwrap_or_default();
+    let mut chars = stem.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => stem,
+    }
+}
+
+/// Content of the chapter at `path` (relative to `src`), nested or not.
+pub fn chapter_content<'a>(book: &'a Book, path: &str) -> Option<&'a str> {
+    book.iter().find_map(|item| match item {
+        BookItem::Chapter(ch) if ch.path.as_deref() == Some(Path::new(path)) => {
+            Some(ch.content.as_str())
+        }
+        _ => None,
+    })
+}
+
+fn expect_chapter<'a>(book: &'a Book, path: &str) -> &'a str {
+    chapter_content(book, path).unwrap_or_else(|| panic!("no chapter '{path}' in the book"))
+}
+
+/// Assert the chapter at `path` carries an injected image and no longer
+/// contains `marker` (the profile's marker, e.g. `{{QR_CODE}}`).
+#[track_caller]
+pub fn assert_injected(book: &Book, path: &str, marker: &str) {
+    let content = expect_chapter(book, path);
+    assert!(
+        !content.contains(marker),
+        "'{marker}' is still present in '{path}':\n{content}"
+    );
+    assert!(
+        content.contains("<img ") || content.contains("!["),
+        "no QR image was injected into '{path}':\n{content}"
+    );
+}
+
+/// Assert `marker` is still present, untouched, in the chapter at `path`.
+#[track_caller]
+pub fn assert_not_injected(book: &Book, path: &str, marker: &str) {
+    let content = expect_chapter(book, path);
+    assert!(
+        content.contains(marker),
+        "'{marker}' was replaced in '{path}':\n{content}"
+    );
+}
@@ -1,42 +1,467 @@
-use anyhow::{anyhow, Result};
+use crate::error::QrError;
+use anyhow::Result;
 use log::{debug, warn};
+use std::collections::BTreeMap;
 use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Default `mdbook serve` port used by the localhost-qr fallback.
+pub const DEFAULT_LOCALHOST_PORT: u16 = 3000;
 
 fn is_abs_http(u: &str) -> bool {
     let lu = u.trim().to_lowercase();
     lu.starts_with("http://") || lu.starts_with("https://")
 }
 
-/// Resolve URL (site-url intentionally ignored):
+/// Host (and port) of an absolute URL, without credentials; other values are
+/// returned whole.
+pub fn url_host(url: &str) -> &str {
+    let Some((_, rest)) = url.split_once("://") else {
+        return url;
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host)
+}
+
+/// GitHub Pages URL from `GITHUB_REPOSITORY`:
+/// - `owner/repo` → `https://{owner}.github.io/{repo}`
+/// - `owner/{owner}.github.io` (user/organization pages) → `https://{owner}.github.io/`
+/// - on GitHub Enterprise (`GITHUB_SERVER_URL` not github.com) →
+///   `https://{owner}.pages.{host}/{repo}`
+fn github_pages_url() -> Option<String> {
+    github_pages_url_with(|name| env::var(name).ok())
+}
+
+/// [`github_pages_url`] reading variables through `var`.
+fn github_pages_url_with(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let repo = var("GITHUB_REPOSITORY")?;
+    let (owner, repo_name) = repo.split_once('/')?;
+    let host = var("GITHUB_SERVER_URL").and_then(|u| {
+        let rest = u.trim().split_once("://").map_or(u.trim(), |(_, r)| r);
+        let host = rest.split('/').next().unwrap_or("").to_ascii_lowercase();
+        (!host.is_empty() && host != "github.com").then_some(host)
+    });
+    Some(match host {
+        Some(host) => format!("https://{owner}.pages.{host}/{repo_name}"),
+        None if repo_name.eq_ignore_ascii_case(&format!("{owner}.github.io")) => {
+            format!("https://{}.github.io/", owner.to_ascii_lowercase())
+        }
+        None => format!("https://{owner}.github.io/{repo_name}"),
+    })
+}
+
+/// Whether `host` is a plausible DNS name: dot-separated labels of letters,
+/// digits and inner hyphens.
+fn is_hostname(host: &str) -> bool {
+    host.len() <= 253
+        && host.contains('.')
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Custom Pages domain from a `CNAME` file in the book root or `src`.
+/// A file that does not hold a hostname is ignored with a warning.
+pub fn cname_domain(root: &Path, src_dir: &Path) -> Option<String> {
+    let path = [root.join("CNAME"), root.join(src_dir).join("CNAME")]
+        .into_iter()
+        .find(|p| p.is_file())?;
+    let text = fs::read_to_string(&path).unwrap_or_default();
+    let host = text.trim().trim_end_matches('.').to_ascii_lowercase();
+    if !is_hostname(&host) {
+        warn!(
+            "{} does not contain a valid hostname; ignoring it for the QR URL",
+            path.display()
+        );
+        return None;
+    }
+    Some(host)
+}
+
+/// GitLab Pages URL from CI: `CI_PAGES_URL` verbatim, otherwise
+/// `https://{namespace}.{pages-domain}/{project}` from `CI_PROJECT_PATH` and
+/// `CI_PAGES_DOMAIN` (or `gitlab.io` when `CI_SERVER_HOST` is `gitlab.com`).
+fn gitlab_pages_url() -> Option<(String, &'static str)> {
+    if let Some(u) = env::var("CI_PAGES_URL")
+        .ok()
+        .filter(|u| !u.trim().is_empty())
+    {
+        return Some((u, "CI_PAGES_URL"));
+    }
+    let path = env::var("CI_PROJECT_PATH").ok()?;
+    let domain = env::var("CI_PAGES_DOMAIN").ok().or_else(|| {
+        env::var("CI_SERVER_HOST")
+            .ok()
+            .filter(|h| h == "gitlab.com")
+            .map(|_| "gitlab.io".to_string())
+    })?;
+    // Subgroups stay in the path: group/sub/project -> group.<domain>/sub/project
+    let (namespace, project) = path.split_once('/')?;
+    Some((
+        format!("https://{namespace}.{domain}/{project}"),
+        "CI_PROJECT_PATH",
+    ))
+}
+
+/// `output.html.site-url`, if it is an absolute http(s) URL. mdBook also
+/// accepts a path like `/my-book/`, which cannot be encoded on its own.
+pub fn site_url_fallback(site_url: Option<&str>) -> Option<&str> {
+    let u = site_url?.trim();
+    if u.is_empty() {
+        return None;
+    }
+    if !is_abs_http(u) {
+        warn!(
+            "output.html.site-url '{}' is not an absolute http(s) URL; not using it for QR codes",
+            u
+        );
+        return None;
+    }
+    Some(u)
+}
+
+/// Values for the placeholders of a URL template.
+pub struct UrlVars<'a> {
+    /// `{slug}`: the profile marker slug (`{{QR_FLYER}}` → `qr_flyer`).
+    pub slug: &'a str,
+    /// `{path}`: output path of the chapter (`guide/intro.html`).
+    pub path: Option<&'a str>,
+}
+
+/// Whether `template` makes the URL chapter-specific through `{path}`.
+pub fn template_uses_path(template: &str) -> bool {
+    template.replace("{{", "").contains("{path}")
+}
+
+/// Expand `{owner}`, `{repo}` (from `GITHUB_REPOSITORY`), `{path}` and
+/// `{slug}` in a configured URL. `{{` and `}}` stand for literal braces.
+/// Unknown placeholders and unmatched braces are errors.
+pub fn expand_url_template(template: &str, vars: &UrlVars) -> Result<String> {
+    expand_url_template_with(template, vars, |name| env::var(name).ok())
+}
+
+/// [`expand_url_template`] reading variables through `var`.
+fn expand_url_template_with(
+    template: &str,
+    vars: &UrlVars,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(at) = rest.find(['{', '}']) {
+        out.push_str(&rest[..at]);
+        let tail = &rest[at..];
+        if let Some(after) = tail.strip_prefix("{{").or_else(|| tail.strip_prefix("}}")) {
+            out.push_str(&tail[..1]);
+            rest = after;
+            continue;
+        }
+        let end = tail
+            .find('}')
+            .filter(|_| tail.starts_with('{'))
+            .ok_or_else(|| {
+                QrError::UrlResolution(format!("unmatched brace in URL template '{template}'; write {{{{ or }}}} for a literal one"))
+            })?;
+        let name = &tail[1..end];
+        let value = match name {
+            "owner" | "repo" => {
+                let repo = var("GITHUB_REPOSITORY").unwrap_or_default();
+                let (owner, repo_name) = repo.split_once('/').ok_or_else(|| {
+                    QrError::UrlResolution(format!("URL template '{template}' uses {{{name}}} but GITHUB_REPOSITORY is not set"))
+                })?;
+                if name == "owner" { owner } else { repo_name }.to_string()
+            }
+            "path" => vars
+                .path
+                .ok_or_else(|| {
+                    QrError::UrlResolution("{path} is only available per chapter".to_string())
+                })?
+                .to_string(),
+            "slug" => vars.slug.to_string(),
+            _ => {
+                return Err(QrError::UrlResolution(format!(
+                    "unknown placeholder {{{name}}} in URL template '{template}'"
+                ))
+                .into())
+            }
+        };
+        out.push_str(&value);
+        rest = &tail[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters.
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+/// Append `params` (`query-params`) to `url`: after any query it already
+/// has and before its `#fragment`. Keys and values are percent-encoded,
+/// `{slug}` in a value becomes `slug` first, and empty values are left out.
+pub fn append_query_params(url: &str, params: &BTreeMap<String, String>, slug: &str) -> String {
+    let pairs: Vec<String> = params
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| {
+            let value = value.replace("{slug}", slug);
+            format!("{}={}", percent_encode(key), percent_encode(&value))
+        })
+        .collect();
+    if pairs.is_empty() {
+        return url.to_string();
+    }
+    let (base, fragment) = url.split_at(url.find('#').unwrap_or(url.len()));
+    let separator = match base.find('?') {
+        None => "?",
+        Some(_) if base.ends_with(['?', '&']) => "",
+        Some(_) => "&",
+    };
+    format!("{base}{separator}{}{fragment}", pairs.join("&"))
+}
+
+/// Check that `url`, a resolved address rather than a `data`, `wifi` or
+/// `contact` payload, is a valid absolute URL, and return the form to
+/// encode. A non-ASCII host becomes punycode, and spaces and non-ASCII
+/// characters elsewhere are percent-encoded; an ASCII URL is otherwise kept
+/// as written. With `normalize` (`normalize-url`), the parsed form is always
+/// used: scheme and host lowercased, a default port dropped, an empty path
+/// made `/` and repeated trailing slashes collapsed into one. With
+/// `require_https`, an `http://` URL to a host other than the local machine
+/// is warned about.
+pub fn check_url(url: &str, normalize: bool, require_https: bool) -> Result<String> {
+    let mut parsed = ::url::Url::parse(url).map_err(|e| {
+        QrError::UrlResolution(format!(
+            "'{url}' is not a valid URL ({e}); use `data` to encode plain text"
+        ))
+    })?;
+    let local = matches!(parsed.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    if require_https && parsed.scheme() == "http" && !local {
+        warn!("mdbook-qr: '{url}' is not https:// (require-https = true)");
+    }
+    if normalize {
+        let path = parsed.path();
+        let trimmed = path.trim_end_matches('/');
+        if path.len() > trimmed.len() + 1 {
+            let path = format!("{trimmed}/");
+            parsed.set_path(&path);
+        }
+        return Ok(parsed.into());
+    }
+    let plain =
+        url.is_ascii() && !url.contains(|c: char| c.is_ascii_whitespace() || c.is_ascii_control());
+    Ok(if plain {
+        url.to_string()
+    } else {
+        parsed.into()
+    })
+}
+
+/// Resolve URL:
 /// 1) explicit profile url (preprocessor.qr.url or custom profile url)
-/// 2) CI fallback from GITHUB_REPOSITORY -> https://{owner}.github.io/{repo}
-/// 3) localhost-qr flag -> http://127.0.0.1:3000/
-pub fn resolve_url(url: Option<&str>, localhost_qr: bool) -> Result<String> {
+/// 2) `site_url` (an absolute `output.html.site-url`, see [`site_url_fallback`])
+/// 3) CI fallback from GITHUB_REPOSITORY (see [`github_pages_url`]), or
+///    `https://{cname}/` when the book has a `CNAME` file (see [`cname_domain`])
+/// 4) CI fallback from GitLab (CI_PAGES_URL, or CI_PROJECT_PATH + pages domain)
+/// 5) localhost-qr flag -> http://127.0.0.1:{port}/
+pub fn resolve_url(
+    url: Option<&str>,
+    site_url: Option<&str>,
+    cname: Option<&str>,
+    localhost_qr: bool,
+    localhost_port: u16,
+) -> Result<String> {
+    resolve_url_with_source(url, site_url, cname, localhost_qr, localhost_port).map(|(u, _)| u)
+}
+
+/// [`resolve_url`], also naming the setting or fallback that supplied the URL.
+pub fn resolve_url_with_source(
+    url: Option<&str>,
+    site_url: Option<&str>,
+    cname: Option<&str>,
+    localhost_qr: bool,
+    localhost_port: u16,
+) -> Result<(String, &'static str)> {
     // 1) explicit preprocessor url wins
     if let Some(u) = url {
-        if !is_abs_http(u) {
-            warn!(
-                "preprocessor.qr.url is not absolute ('{}'); QR will encode it as-is",
-                u
-            );
-        }
         debug!("using explicit preprocessor.qr.url = {}", u);
-        return Ok(u.to_string());
+        return Ok((u.to_string(), "url"));
+    }
+
+    // 2) the book's own canonical address
+    if let Some(u) = site_url {
+        debug!("using output.html.site-url fallback = {}", u);
+        return Ok((u.to_string(), "output.html.site-url"));
     }
 
-    // 2) GitHub Pages fallback from CI
-    if let Ok(repo) = env::var("GITHUB_REPOSITORY") {
-        if let Some((owner, repo_name)) = repo.split_once('/') {
-            let gh_pages = format!("https://{}.github.io/{}", owner, repo_name);
-            debug!("using GITHUB_REPOSITORY fallback = {}", gh_pages);
-            return Ok(gh_pages);
+    // 3) GitHub Pages fallback from CI
+    if let Some(gh_pages) = github_pages_url() {
+        if let Some(host) = cname {
+            let custom = format!("https://{host}/");
+            debug!(
+                "using CNAME fallback = {} (instead of {})",
+                custom, gh_pages
+            );
+            return Ok((custom, "CNAME"));
         }
+        debug!("using GITHUB_REPOSITORY fallback = {}", gh_pages);
+        return Ok((gh_pages, "GITHUB_REPOSITORY"));
+    }
+
+    // 4) GitLab Pages fallback from CI
+    if let Some((gl_pages, source)) = gitlab_pages_url() {
+        debug!("using {} fallback = {}", source, gl_pages);
+        return Ok((gl_pages, source));
     }
 
     if localhost_qr {
-        let u = "http://127.0.0.1:3000/".to_string();
+        let u = format!("http://127.0.0.1:{localhost_port}/");
         debug!("using localhost-qr fallback = {}", u);
-        return Ok(u);
+        return Ok((u, "localhost-qr"));
+    }
+    Err(QrError::UrlResolution("no URL configured and no viable fallback".to_string()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pages(repo: &str, server: Option<&str>) -> Option<String> {
+        github_pages_url_with(|name| match name {
+            "GITHUB_REPOSITORY" => Some(repo.to_string()),
+            "GITHUB_SERVER_URL" => server.map(str::to_string),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn project_pages_live_under_the_repository_name() {
+        assert_eq!(
+            pages("octo/docs", None).unwrap(),
+            "https://octo.github.io/docs"
+        );
+        assert_eq!(
+            pages("octo/docs", Some("https://github.com")).unwrap(),
+            "https://octo.github.io/docs"
+        );
+        assert_eq!(pages("no-slash", None), None);
+        assert_eq!(github_pages_url_with(|_| None), None);
+    }
+
+    #[test]
+    fn user_pages_live_at_the_root() {
+        assert_eq!(
+            pages("octo/octo.github.io", None).unwrap(),
+            "https://octo.github.io/"
+        );
+        assert_eq!(
+            pages("Octo/octo.GitHub.io", Some("https://github.com/")).unwrap(),
+            "https://octo.github.io/"
+        );
+        // Another owner's pages repository is an ordinary project
+        assert_eq!(
+            pages("octo/other.github.io", None).unwrap(),
+            "https://octo.github.io/other.github.io"
+        );
+    }
+
+    #[test]
+    fn enterprise_pages_use_the_server_host() {
+        assert_eq!(
+            pages("team/docs", Some("https://GHE.corp.example/")).unwrap(),
+            "https://team.pages.ghe.corp.example/docs"
+        );
+        assert_eq!(
+            pages("team/docs", Some("ghe.corp.example")).unwrap(),
+            "https://team.pages.ghe.corp.example/docs"
+        );
+        // No github.io shortcut on Enterprise
+        assert_eq!(
+            pages("team/team.github.io", Some("https://ghe.corp.example")).unwrap(),
+            "https://team.pages.ghe.corp.example/team.github.io"
+        );
+    }
+    const CHAPTER: UrlVars = UrlVars {
+        slug: "qr_code",
+        path: Some("guide/intro.html"),
+    };
+
+    fn expand(template: &str, vars: &UrlVars) -> Result<String> {
+        expand_url_template_with(template, vars, |name| {
+            (name == "GITHUB_REPOSITORY").then(|| "octo/docs".to_string())
+        })
+    }
+
+    #[test]
+    fn templates_expand_every_placeholder() {
+        assert_eq!(
+            expand("https://{owner}.example/{repo}/{slug}/{path}", &CHAPTER).unwrap(),
+            "https://octo.example/docs/qr_code/guide/intro.html"
+        );
+        assert_eq!(
+            expand("https://example.com/", &CHAPTER).unwrap(),
+            "https://example.com/"
+        );
+        let e = expand_url_template_with("https://x/{repo}", &CHAPTER, |_| None).unwrap_err();
+        assert!(
+            e.to_string().contains("GITHUB_REPOSITORY is not set"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(
+            expand("https://x/{{slug}}/a}}b?q={{{slug}}}", &CHAPTER).unwrap(),
+            "https://x/{slug}/a}b?q={qr_code}"
+        );
+        assert!(template_uses_path("a/{path}"));
+        assert!(!template_uses_path("a/{{path}}"));
+        assert!(template_uses_path("a/{{{path}}}"));
+    }
+
+    #[test]
+    fn bad_templates_are_errors() {
+        let e = expand("https://x/{nope}", &CHAPTER).unwrap_err();
+        assert!(e.to_string().contains("unknown placeholder {nope}"), "{e}");
+        for template in ["https://x/{path", "https://x/}y", "https://x/{"] {
+            let e = expand(template, &CHAPTER).unwrap_err();
+            assert!(e.to_string().contains("unmatched brace"), "{template}: {e}");
+        }
+        let book_wide = UrlVars {
+            slug: "qr_code",
+            path: None,
+        };
+        assert!(expand("https://x/{path}", &book_wide).is_err());
+    }
+
+    #[test]
+    fn hosts_drop_credentials_paths_and_queries() {
+        assert_eq!(
+            url_host("https://docs.example.com/book/?token=s3cret"),
+            "docs.example.com"
+        );
+        assert_eq!(
+            url_host("http://user:pw@localhost:3000/x#y"),
+            "localhost:3000"
+        );
+        assert_eq!(url_host("https://example.com"), "example.com");
+        assert_eq!(url_host("mailto:a@example.com"), "mailto:a@example.com");
     }
-    Err(anyhow!("no URL configured and no viable fallback"))
 }
@@ -1,11 +1,30 @@
+use crate::cache::WriteCache;
 use crate::config::FitConfig;
-use anyhow::{Context, Result};
+use crate::error::QrError;
+use anyhow::Result;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use mdbook::book::{BookItem, Chapter};
+use rayon::prelude::*;
 use std::fs;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub const DEFAULT_SIZE: u32 = 200;
 
+/// True when environment variable `name` is set to a truthy value (`1`, `true`, `yes`, `on`).
+pub fn env_flag(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|v| is_truthy(&v))
+}
+
+/// `1`, `true`, `yes` or `on`, in any case, around whitespace.
+pub fn is_truthy(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "1" | "true" | "yes" | "on"
+    )
+}
+
 pub fn pass_fit_dims(fit: &FitConfig) -> (u32, u32) {
     match (fit.width, fit.height) {
         (None, None) => (DEFAULT_SIZE, DEFAULT_SIZE),
@@ -35,7 +54,10 @@ pub fn clamp_nonzero(_label: &str, value: u32, fallback: u32) -> u32 {
     }
 }
 
-/// Slug from marker like "{{QR-FLYER}}" → "qr_flyer"
+/// Slug from marker like "{{QR-FLYER}}" → "qr_flyer". Non-ASCII characters
+/// become `_`, so a marker containing any also gets the first 8 hex digits of
+/// its blake3 hash ("{{QR_КУРС}}" → "qr_" + hash), keeping such markers
+/// apart from each other and from their ASCII part.
 pub fn slug_from_marker(marker: &str) -> String {
     let mut s = marker
         .trim()
@@ -54,7 +76,16 @@ pub fn slug_from_marker(marker: &str) -> String {
     while out.contains("__") {
         out = out.replace("__", "_");
     }
-    out.trim_matches('_').to_string()
+    let out = out.trim_matches('_');
+    if s.is_ascii() {
+        return out.to_string();
+    }
+    let hash = blake3::hash(marker.trim().as_bytes()).to_hex();
+    if out.is_empty() {
+        hash[..8].to_string()
+    } else {
+        format!("{out}_{}", &hash[..8])
+    }
 }
 
 /// Default derived path: `<src_dir>/qr/<slug>.png`
@@ -80,27 +111,156 @@ pub fn resolve_profile_path(src_dir: &Path, qr_path: Option<&str>, marker: &str)
     }
 }
 
+/// `path` with a `.png` extension, as images are written.
+pub fn with_png_extension(path: &Path) -> PathBuf {
+    let is_png = path
+        .extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    if is_png {
+        path.to_path_buf()
+    } else {
+        path.with_extension("png")
+    }
+}
+
+/// Whether `path` (absolute, or relative to `root`) stays inside `root` once
+/// `.` and `..` are resolved and the part that exists is canonicalized, so
+/// symlinked directories count where they point.
+///
+/// The answer is the same on every platform, so a `book.toml` accepted on one
+/// is accepted on all: `\` separates components, and a path with a drive
+/// (`C:`) or UNC (`\\server`) prefix is never inside.
+pub fn within_root(root: &Path, path: &Path) -> bool {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let drive = path
+        .as_bytes()
+        .get(..2)
+        .is_some_and(|p| p[0].is_ascii_alphabetic() && p[1] == b':');
+    if drive || path.starts_with("//") {
+        return false;
+    }
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    resolve_path(&root.join(path)).starts_with(&root)
+}
+
+/// `path` with `.` and `..` removed, then its longest existing ancestor
+/// canonicalized.
+fn resolve_path(path: &Path) -> PathBuf {
+    let mut lexical = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                lexical.pop();
+            }
+            c => lexical.push(c),
+        }
+    }
+    let mut existing = lexical.as_path();
+    let mut missing = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return missing
+                .iter()
+                .rev()
+                .fold(canonical, |path, name| path.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return lexical,
+        }
+    }
+}
+
+/// Whether the file at `path` starts with the PNG signature.
+pub fn is_png_file(path: &Path) -> bool {
+    const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    let mut head = [0u8; 8];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut head))
+        .is_ok_and(|()| head == SIGNATURE)
+}
+
 /// Fixed dev path when `localhost-qr = true`:
-/// {book.src}/localhost/qr_localhost.png  (absolute, under repo root)
+/// {book.src}/mdbook_qr/qr_localhost.png  (relative to the book root)
 pub fn localhost_fixed_path(src_dir: &Path) -> PathBuf {
     src_dir.join("mdbook_qr").join("qr_localhost.png")
 }
 
+/// Path of the dark-mode variant of an image: `<stem>-dark.png` beside it.
+pub fn dark_variant_path(rel: &Path) -> PathBuf {
+    let stem = rel
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    rel.with_file_name(format!("{stem}-dark.png"))
+}
+
+/// Path of the `ratio`× density variant of an image: `<stem>@2x.png` beside it.
+pub fn density_variant_path(rel: &Path, ratio: u32) -> PathBuf {
+    let stem = rel
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    rel.with_file_name(format!("{stem}@{ratio}x.png"))
+}
+
+/// Output path of a chapter as served: `guide/intro.md` → `guide/intro.html`.
+pub fn chapter_output_path(chapter: &Path) -> String {
+    chapter
+        .with_extension("html")
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+/// Per-chapter image for a URL that uses `{path}`: `<stem>-<chapter slug>.png`
+/// beside `rel`, e.g. `qr/qr_code-guide_intro.png`.
+pub fn chapter_variant_path(rel: &Path, chapter: &Path) -> PathBuf {
+    let stem = rel
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let chapter = slug_from_marker(&chapter.with_extension("").to_string_lossy());
+    rel.with_file_name(format!("{stem}-{chapter}.png"))
+}
+
+/// Image of its own for `marker` when another profile already uses `rel`:
+/// `<stem>-<marker slug>.png` beside it, or `<stem>-<hash>.png` with the
+/// first 8 hex digits of the marker's blake3 hash when `hashed`.
+pub fn marker_variant_path(rel: &Path, marker: &str, hashed: bool) -> PathBuf {
+    let stem = rel
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let suffix = if hashed {
+        blake3::hash(marker.as_bytes()).to_hex()[..8].to_string()
+    } else {
+        slug_from_marker(marker)
+    };
+    rel.with_file_name(format!("{stem}-{suffix}.png"))
+}
+
+/// `<stem>-<key8>.png` beside `rel`, for `path-style = "hashed"`; `key`
+/// is the image's render key.
+pub fn options_hashed_path(rel: &Path, key: &str) -> PathBuf {
+    let stem = rel
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    rel.with_file_name(format!("{stem}-{}.png", &key[..8]))
+}
+
 /// Ensure `.gitignore` has a glob ignoring:
-///    /{book.src}/**/mdbook_qr/qr_localhost.png
+///    /{base}/**/mdbook_qr/qr_localhost.png
+/// where `base` is `book.src`, or empty for images under the root.
 /// Creates `.gitignore` if missing; idempotent.
-pub fn ensure_gitignore_for_localhost(root: &Path, src_dir: &Path) -> Result<bool> {
+pub fn ensure_gitignore_for_localhost(root: &Path, base: &Path) -> Result<bool> {
     let gi_path = root.join(".gitignore");
-
-    // Compute repo-relative src path
-    let mut glob = format!(
-        "*{}/mdbook_qr/",
-        src_dir.to_string_lossy().replace('\\', "/")
-    );
-
-    while glob.contains("//") {
-        glob = glob.replace("//", "/");
-    }
+    let glob = localhost_gitignore_glob(base);
 
     let mut contents = fs::read_to_string(&gi_path).unwrap_or_default();
     if contents.lines().any(|l| l.trim() == glob) {
@@ -110,7 +270,8 @@ pub fn ensure_gitignore_for_localhost(root: &Path, src_dir: &Path) -> Result<boo
         contents.push('\n');
     }
     // Optional: tag for discoverability
-    contents.push_str("# mdbook-qr (localhost image)\n");
+    contents.push_str(GITIGNORE_TAG);
+    contents.push('\n');
     contents.push_str(&glob);
     contents.push('\n');
 
@@ -119,8 +280,406 @@ pub fn ensure_gitignore_for_localhost(root: &Path, src_dir: &Path) -> Result<boo
         .write(true)
         .truncate(true)
         .open(&gi_path)
-        .with_context(|| format!("opening {}", gi_path.display()))?;
+        .map_err(QrError::io(&gi_path))?;
     f.write_all(contents.as_bytes())
-        .with_context(|| format!("writing {}", gi_path.display()))?;
+        .map_err(QrError::io(&gi_path))?;
+    Ok(true)
+}
+
+/// Comment written above the glob by [`ensure_gitignore_for_localhost`].
+const GITIGNORE_TAG: &str = "# mdbook-qr (localhost image)";
+
+/// The `.gitignore` glob for the localhost image under `base`.
+fn localhost_gitignore_glob(base: &Path) -> String {
+    // Compute repo-relative base path
+    let base = base.to_string_lossy().replace('\\', "/");
+    let mut glob = if base.is_empty() {
+        "/mdbook_qr/".to_string()
+    } else {
+        format!("*{base}/mdbook_qr/")
+    };
+
+    while glob.contains("//") {
+        glob = glob.replace("//", "/");
+    }
+    glob
+}
+
+/// Undo [`ensure_gitignore_for_localhost`]: drop the glob for `base` and the
+/// comment above it from `.gitignore`, unless `dry_run`. Returns `true` if
+/// there was anything to drop.
+pub fn remove_gitignore_for_localhost(root: &Path, base: &Path, dry_run: bool) -> Result<bool> {
+    let gi_path = root.join(".gitignore");
+    let glob = localhost_gitignore_glob(base);
+    let Ok(contents) = fs::read_to_string(&gi_path) else {
+        return Ok(false);
+    };
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut kept: Vec<&str> = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        let tag_of_glob =
+            line.trim() == GITIGNORE_TAG && lines.get(i + 1).is_some_and(|l| l.trim() == glob);
+        if tag_of_glob || line.trim() == glob {
+            continue;
+        }
+        kept.push(line);
+    }
+    if kept.len() == lines.len() {
+        return Ok(false);
+    }
+    if !dry_run {
+        let mut out = kept.join("\n");
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        write_atomic(&gi_path, out.as_bytes())?;
+    }
     Ok(true)
 }
+
+/// Buffer size used when hashing existing files.
+const HASH_BUF: usize = 64 * 1024;
+
+/// blake3 of a file's contents, read through a fixed-size buffer.
+pub fn hash_file(path: &Path) -> io::Result<blake3::Hash> {
+    hash_reader(fs::File::open(path)?)
+}
+
+/// blake3 of everything `reader` yields, using a bounded buffer.
+pub fn hash_reader<R: Read>(mut reader: R) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; HASH_BUF];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Atomically write `bytes` (whose blake3 is `hash`) to `path` unless the file
+/// already holds the same content. Returns `true` if the file was written.
+///
+/// - With a `cache` entry whose hash, size and mtime match, the file is not read.
+/// - Otherwise a same-sized file is compared by streaming hash, never loaded whole.
+pub fn write_if_changed(
+    path: &Path,
+    bytes: &[u8],
+    hash: &blake3::Hash,
+    cache: Option<&mut WriteCache>,
+) -> Result<bool> {
+    if cache.as_deref().is_some_and(|c| c.is_fresh(path, hash)) {
+        return Ok(false);
+    }
+
+    let unchanged = match fs::metadata(path) {
+        Ok(meta) if meta.len() == bytes.len() as u64 => {
+            hash_file(path).map(|h| h == *hash).unwrap_or(false)
+        }
+        _ => false,
+    };
+
+    if !unchanged {
+        write_atomic(path, bytes)?;
+    }
+    if let Some(c) = cache {
+        c.record(path, hash);
+    }
+    Ok(!unchanged)
+}
+
+/// Write via a sibling temp file and rename, so readers never see a partial file.
+/// The temp name is unique per process and call, so concurrent writers of the
+/// same path never share one.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+    let parent = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(parent).map_err(QrError::io(parent))?;
+    let tmp = parent.join(format!(
+        ".{}.{}-{}.tmp",
+        path.file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or("qr-image"),
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    {
+        let mut f = fs::File::create(&tmp).map_err(QrError::io(&tmp))?;
+        f.write_all(bytes).map_err(QrError::io(&tmp))?;
+        let _ = f.sync_all();
+    }
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(QrError::io(path)(e).into());
+    }
+    Ok(())
+}
+
+/// Levenshtein distance between `a` and `b`, counted in chars.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Whether `ch` passes an allowlist compiled by [`chapter_glob_set`]; an empty
+/// set admits every chapter, a non-empty one only chapters with a matching path.
+pub fn in_scope(scope: &GlobSet, ch: &Chapter) -> bool {
+    scope.is_empty()
+        || ch
+            .path
+            .as_deref()
+            .is_some_and(|p| scope.is_match(glob_path(p)))
+}
+
+/// `path` with `/` separators on every platform, for glob matching.
+fn glob_path(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// A profile's `chapters` and `exclude-chapters` globs.
+pub struct ChapterFilter {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl ChapterFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Self {
+        ChapterFilter {
+            include: chapter_glob_set("chapters", include),
+            exclude: chapter_glob_set("exclude-chapters", exclude),
+        }
+    }
+
+    /// Whether `ch` is included (an empty list includes every chapter) and
+    /// not excluded. Chapters without a source file only pass an empty filter.
+    pub fn matches(&self, ch: &Chapter) -> bool {
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return true;
+        }
+        let Some(path) = ch.path.as_deref() else {
+            return false;
+        };
+        let path = glob_path(path);
+        (self.include.is_empty() || self.include.is_match(&path)) && !self.exclude.is_match(&path)
+    }
+}
+
+/// Compile `patterns` (matched against chapter paths relative to `src`, where
+/// `*` stops at `/`). Invalid patterns are skipped with a warning naming `key`.
+pub fn chapter_glob_set(key: &str, patterns: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match GlobBuilder::new(pattern).literal_separator(true).build() {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => log::warn!("mdbook-qr: ignoring invalid {key} pattern '{pattern}': {e}"),
+        }
+    }
+    builder.build().unwrap_or_else(|e| {
+        log::warn!("mdbook-qr: ignoring {key}: {e}");
+        GlobSet::empty()
+    })
+}
+
+/// Run `f` on every chapter under `items`, nested ones included, in
+/// parallel. Results come back in book order; `None` is dropped.
+pub fn par_map_chapters<T, F>(items: &mut [BookItem], f: &F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(&mut Chapter) -> Option<T> + Sync,
+{
+    items
+        .par_iter_mut()
+        .flat_map_iter(|item| {
+            let ch = match item {
+                BookItem::Chapter(ch) => ch,
+                // No content and no sub-items
+                BookItem::PartTitle(_) | BookItem::Separator => return Vec::new(),
+            };
+            let mut out: Vec<T> = f(ch).into_iter().collect();
+            out.extend(par_map_chapters(&mut ch.sub_items, f));
+            out
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::time::Duration;
+
+    #[test]
+    fn ascii_markers_slug_to_their_name() {
+        assert_eq!(slug_from_marker("{{QR_CODE}}"), "qr_code");
+        assert_eq!(slug_from_marker(" {{QR-Flyer 2}} "), "qr_flyer_2");
+        assert_eq!(
+            derived_default_path(Path::new("src"), "{{QR_CODE}}"),
+            Path::new("src/qr/qr_code.png")
+        );
+    }
+
+    #[test]
+    fn non_ascii_markers_get_distinct_ascii_slugs() {
+        let markers = [
+            "{{QR_КУРС}}",
+            "{{QR_КУРСЫ}}",
+            "{{QR_课程}}",
+            "{{QR_课}}",
+            "{{QR_📱}}",
+            "{{QR_🎓}}",
+            "{{📱}}",
+        ];
+        let slugs: Vec<String> = markers.iter().map(|m| slug_from_marker(m)).collect();
+        for (i, slug) in slugs.iter().enumerate() {
+            assert!(slug.is_ascii() && !slug.is_empty(), "{slug}");
+            assert_ne!(slug, "qr", "{} collapsed to its ASCII part", markers[i]);
+            for other in &slugs[i + 1..] {
+                assert_ne!(slug, other);
+            }
+        }
+        assert!(slugs[0].starts_with("qr_") && slugs[0].len() == "qr_".len() + 8);
+        assert_eq!(slugs[6].len(), 8);
+        // Stable across runs
+        assert_eq!(slug_from_marker("{{QR_课程}}"), slugs[2]);
+        let path = derived_default_path(Path::new("src"), "{{QR_课程}}");
+        assert_eq!(path, Path::new("src/qr").join(format!("{}.png", slugs[2])));
+    }
+
+    fn write(path: &Path, bytes: &[u8], cache: Option<&mut WriteCache>) -> bool {
+        write_if_changed(path, bytes, &blake3::hash(bytes), cache).unwrap()
+    }
+
+    fn set_mtime(path: &Path, mtime: std::time::SystemTime) {
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(mtime).unwrap();
+    }
+
+    #[test]
+    fn missing_files_are_written() {
+        let dir = TempDir::new("write-missing");
+        let path = dir.path().join("qr/a.png");
+        assert!(write(&path, b"first", None));
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+    }
+
+    #[test]
+    fn unchanged_files_are_left_alone() {
+        let dir = TempDir::new("write-unchanged");
+        let path = dir.path().join("a.png");
+        fs::write(&path, b"same").unwrap();
+        let old = fs::metadata(&path).unwrap().modified().unwrap() - Duration::from_secs(60);
+        set_mtime(&path, old);
+        assert!(!write(&path, b"same", None));
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), old);
+    }
+
+    #[test]
+    fn changed_files_are_rewritten() {
+        let dir = TempDir::new("write-changed");
+        let path = dir.path().join("a.png");
+        fs::write(&path, b"aaaa").unwrap();
+        // Same size, different bytes: only the hash tells them apart
+        assert!(write(&path, b"bbbb", None));
+        assert_eq!(fs::read(&path).unwrap(), b"bbbb");
+        assert!(write(&path, b"longer", None));
+        assert_eq!(fs::read(&path).unwrap(), b"longer");
+    }
+
+    #[test]
+    fn stale_cache_entries_force_a_rehash() {
+        let dir = TempDir::new("write-stale");
+        let path = dir.path().join("a.png");
+        let mut cache = WriteCache::default();
+        assert!(write(&path, b"aaaa", Some(&mut cache)));
+        let recorded = fs::metadata(&path).unwrap().modified().unwrap();
+
+        // Size and mtime as recorded: the cache is trusted and the file not read
+        fs::write(&path, b"bbbb").unwrap();
+        set_mtime(&path, recorded);
+        assert!(!write(&path, b"aaaa", Some(&mut cache)));
+        assert_eq!(fs::read(&path).unwrap(), b"bbbb");
+
+        // A different mtime makes the entry stale, so the contents are compared
+        set_mtime(&path, recorded + Duration::from_secs(1));
+        assert!(write(&path, b"aaaa", Some(&mut cache)));
+        assert_eq!(fs::read(&path).unwrap(), b"aaaa");
+        assert!(cache.is_fresh(&path, &blake3::hash(b"aaaa")));
+    }
+
+    /// Reads through to `inner`, noting the largest buffer it was handed.
+    struct Counting<R> {
+        inner: R,
+        largest: usize,
+    }
+
+    impl<R: Read> Read for Counting<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.largest = self.largest.max(buf.len());
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn hashing_reads_through_a_bounded_buffer() {
+        let len = 5 * HASH_BUF as u64 + 7;
+        let mut reader = Counting {
+            inner: io::repeat(0x5a).take(len),
+            largest: 0,
+        };
+        let hash = hash_reader(&mut reader).unwrap();
+        assert_eq!(hash, blake3::hash(&vec![0x5a; len as usize]));
+        assert!(reader.largest > 0 && reader.largest <= HASH_BUF);
+    }
+
+    #[test]
+    fn within_root_resolves_parent_dirs() {
+        let dir = TempDir::new("within-root");
+        let root = dir.path();
+        assert!(within_root(root, Path::new("src/qr/a.png")));
+        assert!(within_root(root, Path::new("src/./qr/../a.png")));
+        assert!(!within_root(root, Path::new("../../x.png")));
+        assert!(!within_root(root, Path::new("src/../../x.png")));
+    }
+
+    #[test]
+    fn within_root_rejects_windows_escapes_on_every_platform() {
+        let dir = TempDir::new("within-root-backslash");
+        let root = dir.path();
+        assert!(within_root(root, Path::new("src\\qr\\a.png")));
+        assert!(!within_root(root, Path::new("..\\..\\x.png")));
+        assert!(!within_root(root, Path::new("src\\..\\..\\x.png")));
+        assert!(!within_root(root, Path::new("C:\\Windows\\x.png")));
+        assert!(!within_root(root, Path::new("c:/Windows/x.png")));
+        assert!(!within_root(root, Path::new("C:x.png")));
+        assert!(!within_root(root, Path::new("\\\\server\\share\\x.png")));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn within_root_rejects_the_drive_of_the_root() {
+        let dir = TempDir::new("within-root-windows");
+        let root = dir.path();
+        // Same drive as the (canonical, possibly `\\?\`-prefixed) root.
+        let drive = root.components().next().unwrap().as_os_str();
+        let outside = Path::new(drive).join("\\Windows\\System32\\x.png");
+        assert!(!within_root(root, &outside));
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+/// Decode the QR in the PNG at `path` and check it carries `expected`.
+/// Transparent pixels are composited over `backdrop`, the page color behind
+/// the image. The error lists the usual reasons a code that encodes fine
+/// does not scan.
+pub(crate) fn verify_png(path: &Path, expected: &str, backdrop: [u8; 3]) -> Result<()> {
+    let img = image::open(path)
+        .with_context(|| format!("reading {} to verify it", path.display()))?
+        .to_rgba8();
+    let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(
+        img.width() as usize,
+        img.height() as usize,
+        |x, y| luma_over(img.get_pixel(x as u32, y as u32).0, backdrop),
+    );
+    let grids = prepared.detect_grids();
+    if grids.is_empty() {
+        bail!(
+            "{} could not be read back: no QR code found. {}",
+            path.display(),
+            LIKELY_CAUSES
+        );
+    }
+    let mut last_error = None;
+    for grid in &grids {
+        match grid.decode() {
+            Ok((_, content)) if content == expected => return Ok(()),
+            Ok((_, content)) => bail!(
+                "{} decodes to '{content}' rather than the encoded text",
+                path.display()
+            ),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    bail!(
+        "{} could not be read back ({}). {}",
+        path.display(),
+        last_error.map(|e| e.to_string()).unwrap_or_default(),
+        LIKELY_CAUSES
+    )
+}
+
+const LIKELY_CAUSES: &str = "Likely causes: too little contrast between `module` and \
+    `background` (or modules lighter than the background), a `fit` too small for the \
+    amount of data, a logo covering too much of the code, or a `shape` scanners struggle \
+    with; set `verify = false` to accept it anyway";
+
+/// Rec. 601 luma of `rgba` composited over `backdrop`.
+fn luma_over([r, g, b, a]: [u8; 4], [br, bg, bb]: [u8; 3]) -> u8 {
+    let over = |c: u8, under: u8| (c as u32 * a as u32 + under as u32 * (255 - a as u32)) / 255;
+    ((over(r, br) * 299 + over(g, bg) * 587 + over(b, bb) * 114) / 1000) as u8
+}
@@ -0,0 +1,402 @@
+//! The `mdbook-qr` subcommands, run as a user would against books on disk.
+
+mod common;
+
+use common::{files_in, TempDir};
+use mdbook_qr::cli::{build_cli, ENV_VARS};
+use mdbook_qr::test_support::BookFixture;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+const BOOK_TOML: &str = r#"[book]
+title = "CLI"
+
+[preprocessor.qr]
+url = "https://example.com/book/"
+"#;
+
+/// A book whose only profile cannot resolve its URL and fails the build.
+const BAIL_TOML: &str = r#"[book]
+title = "CLI"
+
+[preprocessor.qr]
+url = "not a url"
+on-failure = "bail"
+"#;
+
+/// A book with `{{QR_CODE}}` in its only chapter.
+fn book(name: &str) -> TempDir {
+    let dir = TempDir::new(name);
+    dir.write("book.toml", BOOK_TOML);
+    dir.write("src/SUMMARY.md", "# Summary\n\n- [Intro](intro.md)\n");
+    dir.write("src/intro.md", "# Intro\n\n{{QR_CODE}}\n");
+    dir
+}
+
+/// Run the binary in `dir`, without the CI and mdBook variables of the
+/// environment running the tests.
+fn mdbook_qr(dir: &Path, args: &[&str], stdin: Option<&str>) -> Output {
+    mdbook_qr_with_env(dir, args, &[], stdin)
+}
+
+/// [`mdbook_qr`] with `env` set for the child only.
+fn mdbook_qr_with_env(
+    dir: &Path,
+    args: &[&str],
+    env: &[(&str, &str)],
+    stdin: Option<&str>,
+) -> Output {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_mdbook-qr"));
+    cmd.args(args)
+        .current_dir(dir)
+        .env("RUST_LOG", "warn")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    for (name, _) in std::env::vars() {
+        if ["MDBOOK_", "GITHUB_", "CI_"]
+            .iter()
+            .any(|p| name.starts_with(p))
+        {
+            cmd.env_remove(name);
+        }
+    }
+    cmd.envs(env.iter().copied());
+    let mut child = cmd.spawn().expect("the binary runs");
+    let input = stdin.unwrap_or_default().to_string();
+    let mut pipe = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || pipe.write_all(input.as_bytes()));
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap().unwrap();
+    output
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+fn subcommands() -> Vec<String> {
+    build_cli()
+        .get_subcommands()
+        .map(|c| c.get_name().to_string())
+        .collect()
+}
+
+/// The `[context, book]` pair mdBook writes to a preprocessor's stdin, for
+/// a book in `dir` configured by `toml`.
+fn mdbook_input(dir: &Path, toml: &str) -> String {
+    let (ctx, book) = BookFixture::new()
+        .root(dir)
+        .chapter("intro.md", "# Intro\n\n{{QR_CODE}}\n")
+        .context_with_config(toml)
+        .build();
+    serde_json::to_string(&(ctx, book)).unwrap()
+}
+
+/// Run the binary as mdBook does, with `[context, book]` on stdin.
+fn preprocess(dir: &Path, toml: &str, env: &[(&str, &str)]) -> Output {
+    mdbook_qr_with_env(dir, &[], env, Some(&mdbook_input(dir, toml)))
+}
+
+/// Every file under `dir`, relative to it, with its contents.
+fn snapshot(dir: &Path) -> Vec<(String, Vec<u8>)> {
+    fn walk(root: &Path, dir: &Path, out: &mut Vec<(String, Vec<u8>)>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                walk(root, &path, out);
+            } else {
+                let rel = path.strip_prefix(root).unwrap().display().to_string();
+                out.push((rel, fs::read(&path).unwrap()));
+            }
+        }
+    }
+    let mut files = Vec::new();
+    walk(dir, dir, &mut files);
+    files.sort();
+    files
+}
+
+#[test]
+fn supports_follows_the_book_renderers() {
+    let dir = book("cli-supports");
+    assert!(mdbook_qr(dir.path(), &["supports", "html"], None)
+        .status
+        .success());
+    assert!(mdbook_qr(dir.path(), &["supports", "pdf"], None)
+        .status
+        .success());
+
+    dir.write("book.toml", format!("{BOOK_TOML}renderers = [\"html\"]\n"));
+    assert!(mdbook_qr(dir.path(), &["supports", "html"], None)
+        .status
+        .success());
+    let pdf = mdbook_qr(dir.path(), &["supports", "pdf"], None);
+    assert_eq!(pdf.status.code(), Some(1));
+}
+
+#[test]
+fn completions_cover_every_subcommand() {
+    let dir = TempDir::new("cli-completions");
+    for shell in ["bash", "zsh", "fish", "powershell"] {
+        let output = mdbook_qr(dir.path(), &["completions", shell], None);
+        assert!(output.status.success(), "{shell}: {}", stderr(&output));
+        let script = stdout(&output);
+        for name in subcommands() {
+            assert!(script.contains(&name), "{shell} completions lack '{name}'");
+        }
+    }
+    let unknown = mdbook_qr(dir.path(), &["completions", "cmd.exe"], None);
+    assert_eq!(unknown.status.code(), Some(2));
+}
+
+#[test]
+fn man_page_covers_every_subcommand() {
+    let dir = TempDir::new("cli-man");
+    let output = mdbook_qr(dir.path(), &["man"], None);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let page = stdout(&output);
+    assert!(page.contains(".TH mdbook-qr"), "{page}");
+    for name in subcommands() {
+        assert!(page.contains(&name), "man page lacks '{name}'");
+    }
+}
+
+#[test]
+fn info_lists_the_version_and_environment() {
+    let dir = TempDir::new("cli-info");
+    let output = mdbook_qr(dir.path(), &["info"], None);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let info = stdout(&output);
+    assert!(info.starts_with(&format!("mdbook-qr {}\n", env!("CARGO_PKG_VERSION"))));
+    for (name, _) in ENV_VARS {
+        assert!(info.contains(name), "info lacks {name}");
+    }
+    assert!(info.contains("GITHUB_REPOSITORY"));
+    assert!(info.contains("[unset]"));
+}
+
+#[test]
+fn audit_reports_modified_images() {
+    let dir = TempDir::new("cli-audit");
+    let png = dir.write("book/qr/qr_code.png", b"image bytes");
+    dir.write(
+        "book/qr/qr_code.provenance.json",
+        serde_json::json!({
+            "format": 2,
+            "kind": "provenance",
+            "asset": "qr_code.png",
+            "payload": "https://example.com/book/",
+            "blake3": blake3::hash(b"image bytes").to_hex().as_str(),
+            "public_key": "",
+            "signature": "",
+        })
+        .to_string(),
+    );
+
+    let ok = mdbook_qr(dir.path(), &["audit"], None);
+    assert!(ok.status.success(), "{}", stderr(&ok));
+    let report = stdout(&ok);
+    assert!(report.starts_with("ok    "), "{report}");
+    assert!(report.ends_with("1 checked, 0 failed\n"), "{report}");
+
+    fs::write(&png, b"swapped by hand").unwrap();
+    let failed = mdbook_qr(dir.path(), &["audit", "book"], None);
+    assert_eq!(failed.status.code(), Some(1));
+    let report = stdout(&failed);
+    assert!(report.contains("FAIL  ") && report.contains("does not match its recorded hash"));
+    assert!(report.ends_with("1 checked, 1 failed\n"), "{report}");
+
+    let missing = mdbook_qr(dir.path(), &["audit", "no-such-dir"], None);
+    assert_eq!(missing.status.code(), Some(1));
+}
+
+#[test]
+fn check_reports_unused_markers() {
+    let dir = book("cli-check");
+    let ok = mdbook_qr(dir.path(), &["check"], None);
+    assert!(ok.status.success(), "{}", stdout(&ok));
+    let report = stdout(&ok);
+    assert!(
+        report.contains("{{QR_CODE}}") && report.contains("intro.md"),
+        "{report}"
+    );
+    assert!(report.ends_with("1 profile(s), 0 problem(s)\n"), "{report}");
+
+    dir.write(
+        "book.toml",
+        format!("{BOOK_TOML}\n[preprocessor.qr.custom.flyer]\nmarker = \"{{{{QR_FLYER}}}}\"\n"),
+    );
+    let unused = mdbook_qr(dir.path(), &["check", "."], None);
+    assert_eq!(unused.status.code(), Some(1));
+    assert!(stdout(&unused).contains("error: '{{QR_FLYER}}' appears in no chapter"));
+    let allowed = mdbook_qr(dir.path(), &["check", "--allow-unused"], None);
+    assert!(allowed.status.success(), "{}", stdout(&allowed));
+
+    dir.write("book.toml", "[book]\ntitle = \"CLI\"\n");
+    let bare = mdbook_qr(dir.path(), &["check"], None);
+    assert_eq!(bare.status.code(), Some(1));
+    assert!(stdout(&bare).contains("has no [preprocessor.qr] table"));
+}
+
+#[test]
+fn preprocess_then_clean() {
+    let dir = book("cli-clean");
+    let built = preprocess(dir.path(), BOOK_TOML, &[]);
+    assert!(built.status.success(), "{}", stderr(&built));
+    let book: serde_json::Value = serde_json::from_slice(&built.stdout).unwrap();
+    assert!(book.to_string().contains("qr/qr_code.png"));
+    let qr = dir.path().join("src/qr");
+    assert_eq!(files_in(&qr), ["qr_code.png"]);
+
+    let dry = mdbook_qr(dir.path(), &["clean", "--dry-run"], None);
+    assert!(dry.status.success(), "{}", stderr(&dry));
+    assert!(stdout(&dry).contains("would remove"), "{}", stdout(&dry));
+    assert_eq!(files_in(&qr), ["qr_code.png"]);
+
+    let clean = mdbook_qr(dir.path(), &["clean"], None);
+    assert!(clean.status.success(), "{}", stderr(&clean));
+    let report = stdout(&clean);
+    assert!(
+        report.contains("removed") && report.contains("qr_code.png"),
+        "{report}"
+    );
+    assert!(files_in(&qr).is_empty());
+}
+
+#[test]
+fn list_prints_text_and_json() {
+    let dir = book("cli-list");
+    dir.write(
+        "book.toml",
+        format!(
+            "{BOOK_TOML}module = \"#112233\"\n\n[preprocessor.qr.custom.flyer]\n\
+             marker = [\"{{{{QR_FLYER}}}}\", \"{{{{QR_F}}}}\"]\nshape = \"circle\"\n"
+        ),
+    );
+    let text = mdbook_qr(dir.path(), &["list"], None);
+    assert!(text.status.success(), "{}", stderr(&text));
+    let text = stdout(&text);
+    assert!(text.contains("flyer {{QR_FLYER}}, {{QR_F}}"), "{text}");
+    assert!(
+        text.contains("circle") && text.contains("#112233"),
+        "{text}"
+    );
+    assert!(text.ends_with("2 profile(s)\n"), "{text}");
+
+    let json = mdbook_qr(dir.path(), &["list", "--json", "--renderer", "pdf"], None);
+    assert!(json.status.success(), "{}", stderr(&json));
+    let rows: serde_json::Value = serde_json::from_slice(&json.stdout).unwrap();
+    assert_eq!(rows.as_array().map(Vec::len), Some(2));
+
+    let empty = TempDir::new("cli-list-empty");
+    assert_eq!(
+        mdbook_qr(empty.path(), &["list"], None).status.code(),
+        Some(1)
+    );
+}
+
+#[test]
+fn render_writes_one_png() {
+    let dir = TempDir::new("cli-render");
+    let output = mdbook_qr(
+        dir.path(),
+        &[
+            "render",
+            "https://example.com/",
+            "--out",
+            "out/code.png",
+            "--fit-width",
+            "120",
+            "--module",
+            "navy",
+        ],
+        None,
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let line = stdout(&output);
+    let (path, hash) = line.trim_end().split_once(' ').unwrap();
+    assert!(path.ends_with("code.png"), "{line}");
+    let bytes = fs::read(dir.path().join("out/code.png")).unwrap();
+    assert!(blake3::hash(&bytes).to_hex().starts_with(hash));
+
+    let bad = mdbook_qr(
+        dir.path(),
+        &["render", "x", "--out", "bad.png", "--shape", "blob"],
+        None,
+    );
+    assert_eq!(bad.status.code(), Some(1));
+    assert!(stderr(&bad).contains("unknown shape 'blob'"));
+    assert!(!dir.path().join("bad.png").exists());
+
+    let no_out = mdbook_qr(dir.path(), &["render", "x"], None);
+    assert_eq!(no_out.status.code(), Some(2));
+}
+
+#[test]
+fn install_adds_the_table_once() {
+    let dir = TempDir::new("cli-install");
+    dir.write("book.toml", "[book]\ntitle = \"CLI\"\n");
+    let first = mdbook_qr(dir.path(), &["install", "--marker", "flyer"], None);
+    assert!(first.status.success(), "{}", stderr(&first));
+    let report = stdout(&first);
+    assert!(report.contains("added [preprocessor.qr]"), "{report}");
+    assert!(report.contains("with marker {{QR_FLYER}}"), "{report}");
+    assert!(report.contains("updated"), "{report}");
+    let toml = fs::read_to_string(dir.path().join("book.toml")).unwrap();
+    assert!(toml.contains("[preprocessor.qr]") && toml.contains("[preprocessor.qr.custom.flyer]"));
+
+    let again = mdbook_qr(dir.path(), &["install", ".", "--marker", "flyer"], None);
+    assert!(again.status.success(), "{}", stderr(&again));
+    assert!(stdout(&again).contains("unchanged"));
+    assert_eq!(
+        fs::read_to_string(dir.path().join("book.toml")).unwrap(),
+        toml
+    );
+
+    let missing = TempDir::new("cli-install-missing");
+    assert_eq!(
+        mdbook_qr(missing.path(), &["install"], None).status.code(),
+        Some(1)
+    );
+}
+
+#[test]
+fn kill_switch_passes_the_book_through_untouched() {
+    let dir = book("cli-disable");
+    // Would fail the build without the switch
+    let toml = BAIL_TOML;
+    dir.write("book.toml", toml);
+    let before = snapshot(dir.path());
+    let input = mdbook_input(dir.path(), toml);
+
+    let output = preprocess(dir.path(), toml, &[("MDBOOK_QR_DISABLE", "1")]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let (_, book): (serde_json::Value, serde_json::Value) = serde_json::from_str(&input).unwrap();
+    let out: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(out, book);
+    assert_eq!(snapshot(dir.path()), before);
+
+    let off = preprocess(dir.path(), toml, &[("MDBOOK_QR_DISABLE", "0")]);
+    assert_eq!(off.status.code(), Some(1));
+}
+
+#[test]
+fn force_continue_downgrades_bail() {
+    let dir = book("cli-force-continue");
+    let bail = preprocess(dir.path(), BAIL_TOML, &[]);
+    assert_eq!(bail.status.code(), Some(1));
+
+    let forced = preprocess(dir.path(), BAIL_TOML, &[("MDBOOK_QR_FORCE_CONTINUE", "1")]);
+    assert!(forced.status.success(), "{}", stderr(&forced));
+    assert!(stderr(&forced).contains("MDBOOK_QR_FORCE_CONTINUE is set"));
+    let out: serde_json::Value = serde_json::from_slice(&forced.stdout).unwrap();
+    assert!(out.to_string().contains("{{QR_CODE}}"));
+    assert!(files_in(&dir.path().join("src/qr")).is_empty());
+}
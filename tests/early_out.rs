@@ -0,0 +1,74 @@
+//! Books without any configured marker pass through untouched.
+
+mod common;
+
+use common::TempDir;
+use mdbook::book::{Book, BookItem};
+use mdbook::preprocess::Preprocessor;
+use mdbook_qr::test_support::{chapter_content, BookFixture};
+use mdbook_qr::QrPreprocessor;
+use std::fs;
+
+const CHAPTER: &str = "# Intro\n\n<!-- mdbook-qr: fit = 200 -->\nNo code here.\n";
+
+fn run(dir: &TempDir, config: &str) -> Book {
+    let (ctx, book) = BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", CHAPTER)
+        .nested("guide/setup.md", "Nothing to see.\n")
+        .context_with_config(config)
+        .build();
+    QrPreprocessor::new().run(&ctx, book).unwrap()
+}
+
+#[test]
+fn markerless_book_touches_nothing() {
+    let dir = TempDir::new("markerless");
+    // No `url`: resolving one would warn, and must not be attempted
+    let book = run(
+        &dir,
+        "[preprocessor.qr]\nreport-path = \"report.json\"\nmanifest = \"qr.json\"\n",
+    );
+    assert_eq!(chapter_content(&book, "intro.md"), Some(CHAPTER));
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn markerless_book_empties_an_existing_published_manifest() {
+    let dir = TempDir::new("markerless-manifest");
+    let manifest = dir.write(
+        "qr.json",
+        r#"{"{{QR_CODE}}": {"url": "https://example.com/"}}"#,
+    );
+    run(&dir, "[preprocessor.qr]\nmanifest = \"qr.json\"\n");
+    let json: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&manifest).unwrap()).unwrap();
+    assert!(!json.to_string().contains("QR_CODE"), "{json}");
+    assert!(!dir.path().join("src").exists());
+}
+
+#[test]
+fn marker_only_in_a_name_is_not_skipped_with_replace_in_names() {
+    let dir = TempDir::new("markerless-names");
+    let (ctx, mut book) = BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", "No marker in the text.\n")
+        .context_with_config(
+            "[preprocessor.qr]\nurl = \"https://example.com/\"\nreplace-in-names = true\n",
+        )
+        .build();
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(ch) = item {
+            ch.name = "Scan {{QR_CODE}}".to_string();
+        }
+    });
+    let book = QrPreprocessor::new().run(&ctx, book).unwrap();
+    let names: Vec<&str> = book
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(ch) => Some(ch.name.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(names, ["Scan"]);
+}
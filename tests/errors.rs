@@ -0,0 +1,60 @@
+//! Failures callers can tell apart through `QrError`.
+
+mod common;
+
+use common::TempDir;
+use mdbook::preprocess::Preprocessor;
+use mdbook_qr::test_support::BookFixture;
+use mdbook_qr::{QrError, QrPreprocessor};
+
+const STRICT: &str = "[preprocessor.qr]\nurl = \"https://example.com/book/\"\nstrict = true\n";
+
+fn run_err(dir: &TempDir, config: &str, chapter: &str) -> QrError {
+    let (ctx, book) = BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", chapter)
+        .context_with_config(config)
+        .build();
+    let err = QrPreprocessor::new().run(&ctx, book).unwrap_err();
+    assert!(QrError::find(&err).is_some(), "{err:#}");
+    QrError::from(err)
+}
+
+#[test]
+fn strict_fails_on_markers_no_profile_is_configured_for() {
+    let dir = TempDir::new("errors-unknown");
+    let err = run_err(
+        &dir,
+        STRICT,
+        "{{QR_CODE}}\n\n{{QR_COD}}\n\n`{{QR_CODE_X}}`\n",
+    );
+    match err.kind() {
+        QrError::MarkerNotFound(found) => assert_eq!(found, &["intro.md: {{QR_COD}}"]),
+        other => panic!("{other:?}"),
+    }
+}
+
+#[test]
+fn strict_fails_on_markers_left_in_the_book() {
+    let dir = TempDir::new("errors-unreplaced");
+    let config = format!(
+        "{STRICT}\n[preprocessor.qr.custom.off]\nmarker = \"{{{{QR_OFF}}}}\"\nenable = false\n"
+    );
+    let err = run_err(&dir, &config, "{{QR_OFF}}\n");
+    assert!(
+        matches!(err.kind(), QrError::UnreplacedMarkers(found) if found == &["intro.md: {{QR_OFF}} (1x)"]),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn generate_reports_unwritable_output_as_io() {
+    let dir = TempDir::new("errors-generate");
+    let file = dir.write("not-a-dir", "");
+    let profile = mdbook_qr::config::QrConfig::default().default_profile();
+    let err = mdbook_qr::generate(&profile, "https://example.com", &file).unwrap_err();
+    match err.kind() {
+        QrError::Io { path, .. } => assert!(path.starts_with(&file), "{}", path.display()),
+        other => panic!("{other:?}"),
+    }
+}
@@ -0,0 +1,509 @@
+//! Book scenarios driven through `test_support::BookFixture`.
+
+mod common;
+
+use common::{files_in, TempDir};
+use mdbook::book::{Book, BookItem};
+use mdbook::preprocess::Preprocessor;
+use mdbook_qr::test_support::{assert_injected, assert_not_injected, chapter_content, BookFixture};
+use mdbook_qr::QrPreprocessor;
+
+const URL: &str = "[preprocessor.qr]\nurl = \"https://example.com/book/\"\n";
+
+fn run(fixture: BookFixture) -> Book {
+    let (ctx, book) = fixture.build();
+    QrPreprocessor::new().run(&ctx, book).unwrap()
+}
+
+#[test]
+fn fixture_nests_chapters_under_their_directory() {
+    let (ctx, book) = BookFixture::new()
+        .chapter("intro.md", "Intro")
+        .nested("guide/setup.md", "Setup")
+        .nested("guide/more-info.md", "More")
+        .renderer("markdown")
+        .context_with_config("[book]\nsrc = \"doc\"\n")
+        .build();
+    assert_eq!(ctx.renderer, "markdown");
+    assert_eq!(ctx.config.book.src, std::path::Path::new("doc"));
+
+    let chapters: Vec<(String, Vec<String>)> = book
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(ch) => Some((ch.name.clone(), ch.parent_names.clone())),
+            _ => None,
+        })
+        .collect();
+    let guide = vec!["Guide".to_string()];
+    assert_eq!(
+        chapters,
+        [
+            ("Intro".to_string(), vec![]),
+            ("Guide".to_string(), vec![]),
+            ("Setup".to_string(), guide.clone()),
+            ("More info".to_string(), guide),
+        ]
+    );
+    assert_eq!(chapter_content(&book, "guide/README.md"), Some(""));
+}
+
+#[test]
+fn nested_chapters_link_the_shared_image_relatively() {
+    let dir = TempDir::new("fixture-nested");
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", "{{QR_CODE}}\n")
+        .nested("guide/setup.md", "{{QR_CODE}}\n")
+        .context_with_config(URL));
+    assert_injected(&book, "intro.md", "{{QR_CODE}}");
+    assert_injected(&book, "guide/setup.md", "{{QR_CODE}}");
+    assert!(chapter_content(&book, "guide/setup.md")
+        .unwrap()
+        .contains("../qr/qr_code.png"));
+    assert!(dir.path().join("src/qr/qr_code.png").is_file());
+}
+
+#[test]
+fn markers_in_code_are_left_alone() {
+    let dir = TempDir::new("fixture-code");
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", "{{QR_CODE}}\n")
+        .chapter(
+            "code.md",
+            "```\n{{QR_CODE}}\n```\n\nInline `{{QR_CODE}}`.\n",
+        )
+        .context_with_config(URL));
+    assert_injected(&book, "intro.md", "{{QR_CODE}}");
+    assert_not_injected(&book, "code.md", "{{QR_CODE}}");
+}
+
+#[test]
+fn images_follow_book_src() {
+    let dir = TempDir::new("fixture-src");
+    run(BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", "{{QR_CODE}}\n")
+        .context_with_config(&format!("[book]\nsrc = \"doc\"\n{URL}")));
+    assert!(dir.path().join("doc/qr/qr_code.png").is_file());
+    assert!(!dir.path().join("src").exists());
+}
+
+#[test]
+fn auto_output_writes_markdown_for_other_renderers() {
+    let dir = TempDir::new("fixture-markdown");
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .renderer("markdown")
+        .chapter("intro.md", "{{QR_CODE}}\n")
+        .context_with_config(&format!("{URL}output = \"auto\"\n")));
+    assert_injected(&book, "intro.md", "{{QR_CODE}}");
+    let content = chapter_content(&book, "intro.md").unwrap();
+    assert!(content.starts_with("!["), "{content}");
+}
+
+#[test]
+fn custom_profiles_write_their_own_path() {
+    let dir = TempDir::new("fixture-custom");
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", "{{QR_CODE}}\n\n{{QR_SLIDES}}\n")
+        .context_with_config(&format!(
+            "{URL}\n[preprocessor.qr.custom.slides]\nmarker = \"{{{{QR_SLIDES}}}}\"\nqr-path = \"img/slides.png\"\n"
+        )));
+    assert_injected(&book, "intro.md", "{{QR_CODE}}");
+    assert_injected(&book, "intro.md", "{{QR_SLIDES}}");
+    assert!(dir.path().join("src/img/slides.png").is_file());
+}
+
+#[test]
+fn include_default_false_leaves_the_default_marker() {
+    let dir = TempDir::new("fixture-include-default");
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", "{{QR_CODE}}\n\n{{QR_SLIDES}}\n")
+        .context_with_config(&format!(
+            "{URL}include-default = false\n[preprocessor.qr.custom.slides]\nmarker = \"{{{{QR_SLIDES}}}}\"\n"
+        )));
+    assert_not_injected(&book, "intro.md", "{{QR_CODE}}");
+    assert_injected(&book, "intro.md", "{{QR_SLIDES}}");
+    assert!(!dir.path().join("src/qr/qr_code.png").exists());
+}
+
+#[test]
+fn same_book_renders_html_for_html_and_markdown_for_pdf() {
+    let config = format!(
+        "{URL}output = \"auto\"\ncache-bust = \"query\"\n\n\
+         [preprocessor.qr.custom.slides]\nmarker = \"{{{{QR_SLIDES}}}}\"\noutput = \"markdown\"\n"
+    );
+    let build = |renderer: &str| {
+        let dir = TempDir::new("fixture-per-renderer");
+        let book = run(BookFixture::new()
+            .root(dir.path())
+            .renderer(renderer)
+            .chapter("intro.md", "{{QR_CODE}}\n\n{{QR_SLIDES}}\n")
+            .context_with_config(&config));
+        chapter_content(&book, "intro.md").unwrap().to_string()
+    };
+
+    let html = build("html");
+    assert!(html.starts_with("<img src=\"qr/qr_code.png?v="), "{html}");
+    assert!(html.contains("loading=\"eager\""), "{html}");
+    // A profile's own `output` wins over `auto`
+    assert!(html.contains("](qr/qr_slides.png)"), "{html}");
+
+    let pdf = build("pdf");
+    assert_eq!(
+        pdf,
+        "![QR code linking to https://example.com/book/](qr/qr_code.png)\n\n\
+         ![QR code linking to https://example.com/book/](qr/qr_slides.png)\n"
+    );
+}
+
+#[test]
+fn path_templates_give_each_chapter_its_own_image() {
+    let dir = TempDir::new("fixture-url-path");
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", "{{QR_CODE}}\n")
+        .nested("guide/setup.md", "{{QR_CODE}}\n")
+        .context_with_config(
+            "[preprocessor.qr]\nurl = \"https://example.com/book/{path}?s={slug}\"\n",
+        ));
+    let intro = chapter_content(&book, "intro.md").unwrap();
+    let setup = chapter_content(&book, "guide/setup.md").unwrap();
+    assert!(
+        intro.contains("https://example.com/book/intro.html?s=qr_code"),
+        "{intro}"
+    );
+    assert!(
+        setup.contains("https://example.com/book/guide/setup.html?s=qr_code"),
+        "{setup}"
+    );
+    assert!(intro.contains("qr/qr_code-intro.png"), "{intro}");
+    assert!(setup.contains("../qr/qr_code-guide_setup.png"), "{setup}");
+    assert_eq!(
+        files_in(&dir.path().join("src/qr")),
+        ["qr_code-guide_setup.png", "qr_code-intro.png"]
+    );
+}
+
+#[test]
+fn unknown_placeholders_bail_or_skip_by_on_failure() {
+    let config = |mode: &str| {
+        format!(
+            "[preprocessor.qr]\nurl = \"https://example.com/{{nope}}\"\non-failure = \"{mode}\"\n"
+        )
+    };
+    let dir = TempDir::new("fixture-url-unknown");
+    let (ctx, book) = BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", "{{QR_CODE}}\n")
+        .context_with_config(&config("bail"))
+        .build();
+    let e = QrPreprocessor::new().run(&ctx, book).unwrap_err();
+    assert!(
+        format!("{e:#}").contains("unknown placeholder {nope}"),
+        "{e:#}"
+    );
+
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", "{{QR_CODE}}\n")
+        .context_with_config(&config("continue")));
+    assert_not_injected(&book, "intro.md", "{{QR_CODE}}");
+    assert!(files_in(&dir.path().join("src/qr")).is_empty());
+}
+
+#[test]
+fn on_failure_applies_per_profile() {
+    let chapter = "{{QR_CODE}}\n\n{{QR_EXTRA}}\n";
+    let config = |global: &str, extra: &str| {
+        format!(
+            "{URL}on-failure = \"{global}\"\n\n[preprocessor.qr.custom.extra]\n\
+             marker = \"{{{{QR_EXTRA}}}}\"\nurl = \"not a url\"\non-failure = \"{extra}\"\n"
+        )
+    };
+
+    // A mandatory profile fails the build even when the default is lenient
+    let dir = TempDir::new("fixture-on-failure-bail");
+    let (ctx, book) = BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", chapter)
+        .context_with_config(&config("continue", "bail"))
+        .build();
+    let e = QrPreprocessor::new().run(&ctx, book).unwrap_err();
+    assert!(format!("{e:#}").contains("{{QR_EXTRA}}"), "{e:#}");
+
+    // An optional one is skipped even when the default is strict
+    let dir = TempDir::new("fixture-on-failure-continue");
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", chapter)
+        .context_with_config(&config("bail", "continue")));
+    assert_injected(&book, "intro.md", "{{QR_CODE}}");
+    assert_not_injected(&book, "intro.md", "{{QR_EXTRA}}");
+    assert_eq!(files_in(&dir.path().join("src/qr")), ["qr_code.png"]);
+}
+
+#[test]
+fn deterministic_output_is_byte_identical_across_builds() {
+    let config = format!(
+        "{URL}deterministic = true\nshape = \"circle\"\nborder = {{ width = 4, color = \"#333333\", radius = 12 }}\n"
+    );
+    let build = || {
+        let dir = TempDir::new("fixture-deterministic");
+        run(BookFixture::new()
+            .root(dir.path())
+            .chapter("intro.md", "{{QR_CODE}}\n")
+            .context_with_config(&config));
+        std::fs::read(dir.path().join("src/qr/qr_code.png")).unwrap()
+    };
+    let first = build();
+    assert_eq!(&first[..8], b"\x89PNG\r\n\x1a\n");
+    assert!(first == build(), "a second build wrote different bytes");
+}
+
+#[test]
+fn cache_bust_param_is_renamed_or_turned_off_per_profile() {
+    let dir = TempDir::new("fixture-cache-bust-param");
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .renderer("html")
+        .chapter("intro.md", "{{QR_CODE}}\n\n{{QR_SLIDES}}\n\n{{QR_PLAIN}}\n")
+        .context_with_config(&format!(
+            "{URL}output = \"html\"\ncache-bust-param = \"version\"\n\n\
+             [preprocessor.qr.custom.slides]\nmarker = \"{{{{QR_SLIDES}}}}\"\ncache-bust-param = \"build\"\n\n\
+             [preprocessor.qr.custom.plain]\nmarker = \"{{{{QR_PLAIN}}}}\"\ncache-bust = false\n"
+        )));
+    let content = chapter_content(&book, "intro.md").unwrap();
+    assert!(
+        content.contains("src=\"qr/qr_code.png?version="),
+        "{content}"
+    );
+    assert!(
+        content.contains("src=\"qr/qr_slides.png?build="),
+        "{content}"
+    );
+    assert!(content.contains("src=\"qr/qr_plain.png\""), "{content}");
+    assert!(!content.contains("?v="), "{content}");
+    assert!(dir.path().join("src/qr/qr_plain.png").is_file());
+}
+
+#[test]
+fn chapters_outside_the_allowlist_are_neither_scanned_nor_counted_as_used() {
+    let dir = TempDir::new("fixture-allowlist");
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .nested("guides/intro.md", "{{QR_CODE}}\n")
+        .nested("api/types.md", "{{QR_CODE}}\n\n{{QR_API}}\n")
+        .chapter("faq.md", "{{QR_API}}\n")
+        .context_with_config(&format!(
+            "{URL}chapters = [\"guides/**\"]\nreport-path = \"report.json\"\n\n\
+             [preprocessor.qr.custom.api]\nmarker = \"{{{{QR_API}}}}\"\n"
+        )));
+    assert_injected(&book, "guides/intro.md", "{{QR_CODE}}");
+    assert_not_injected(&book, "api/types.md", "{{QR_CODE}}");
+    assert_not_injected(&book, "api/types.md", "{{QR_API}}");
+    assert_not_injected(&book, "faq.md", "{{QR_API}}");
+    assert_eq!(files_in(&dir.path().join("src/qr")), ["qr_code.png"]);
+
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.path().join("report.json")).unwrap()).unwrap();
+    // `api/README.md`, `api/types.md` and `faq.md`
+    assert_eq!(report["skipped_chapters"], 3, "{report:#}");
+    assert_eq!(report["replaced"], 1, "{report:#}");
+}
+
+#[test]
+fn display_size_changes_reuse_the_raster() {
+    let dir = TempDir::new("fixture-reuse-raster");
+    let build = |width: u32| {
+        let book = run(BookFixture::new()
+            .root(dir.path())
+            .renderer("html")
+            .chapter("intro.md", "{{QR_CODE}}\n")
+            .context_with_config(&format!(
+                "{URL}output = \"html\"\nsize-mode = \"attributes\"\nscale = 4\nfit = {{ width = {width}, height = {width} }}\n\
+                 report-path = \"report.json\"\n"
+            )));
+        let report: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.path().join("report.json")).unwrap())
+                .unwrap();
+        (
+            chapter_content(&book, "intro.md").unwrap().to_string(),
+            report,
+        )
+    };
+    let png = dir.path().join("src/qr/qr_code.png");
+
+    let (first, report) = build(200);
+    assert!(first.contains("width=\"200\" height=\"200\""), "{first}");
+    assert_eq!(report["generated"], 1, "{report:#}");
+    let bytes = std::fs::read(&png).unwrap();
+    let mtime = std::fs::metadata(&png).unwrap().modified().unwrap();
+
+    let (second, report) = build(320);
+    assert!(second.contains("width=\"320\" height=\"320\""), "{second}");
+    assert_eq!(std::fs::read(&png).unwrap(), bytes);
+    assert_eq!(std::fs::metadata(&png).unwrap().modified().unwrap(), mtime);
+    assert_eq!(report["generated"], 0, "{report:#}");
+    assert_eq!(
+        report["reused"],
+        serde_json::json!([{ "path": "src/qr/qr_code.png", "reason": "render parameters unchanged" }]),
+        "{report:#}"
+    );
+}
+
+#[test]
+fn debug_comments_name_the_profile_and_redact_the_url() {
+    let build = |mode: &str, renderer: &str| {
+        let dir = TempDir::new("fixture-debug-comments");
+        let book = run(BookFixture::new()
+            .root(dir.path())
+            .renderer(renderer)
+            .chapter("intro.md", "{{QR_FLYER}}\n")
+            .context_with_config(&format!(
+                "[preprocessor.qr]\ninclude-default = false\noutput = \"auto\"\ndebug-comments = {mode}\n\n\
+                 [preprocessor.qr.custom.\"print--a4\"]\nmarker = \"{{{{QR_FLYER}}}}\"\n\
+                 url = \"https://user:pw@docs.example.com/flyer/?token=s3cret\"\n"
+            )));
+        chapter_content(&book, "intro.md").unwrap().to_string()
+    };
+
+    let html = build("true", "html");
+    let start = html.find("<!-- mdbook-qr ").expect(&html);
+    let comment = &html[start..start + html[start..].find("-->").unwrap() + 3];
+    assert!(
+        comment.starts_with(&format!(
+            "<!-- mdbook-qr {} profile=print- -a4 marker={{{{QR_FLYER}}}} url=docs.example.com params=",
+            env!("CARGO_PKG_VERSION")
+        )),
+        "{html}"
+    );
+    assert!(
+        !html.contains("s3cret") || !comment.contains("s3cret"),
+        "{html}"
+    );
+    assert!(
+        !comment.contains("pw@") && !comment.contains("/flyer/"),
+        "{comment}"
+    );
+
+    let full = build("\"full\"", "html");
+    assert!(
+        full.contains("url=https://user:pw@docs.example.com/flyer/?token=s3cret params="),
+        "{full}"
+    );
+
+    assert!(!build("false", "html").contains("<!--"));
+    assert!(!build("true", "pdf").contains("<!--"));
+}
+
+#[test]
+fn generated_chapters_without_a_source_file_skip_the_derived_default_guard() {
+    // A PNG left by an earlier version, outside any manifest
+    let earlier = TempDir::new("fixture-generated-earlier");
+    run(BookFixture::new()
+        .root(earlier.path())
+        .chapter("intro.md", "{{QR_CODE}}\n")
+        .context_with_config(&URL.replace("example.com", "example.org")));
+    let foreign = std::fs::read(earlier.path().join("src/qr/qr_code.png")).unwrap();
+
+    let build = |globs: &str| {
+        let dir = TempDir::new("fixture-generated");
+        let png = dir.path().join("src/qr/qr_code.png");
+        std::fs::create_dir_all(png.parent().unwrap()).unwrap();
+        std::fs::write(&png, &foreign).unwrap();
+        let book = run(BookFixture::new()
+            .root(dir.path())
+            .nested("generated/summary.md", "{{QR_CODE}}\n")
+            .context_with_config(&format!(
+                "{URL}overwrite-existing = true\ngenerated-chapter-globs = [{globs}]\n"
+            )));
+        // Written later by another tool: nothing is on disk yet
+        assert!(!dir.path().join("src/generated/summary.md").exists());
+        let written = std::fs::read(&png).unwrap();
+        (book, written, dir)
+    };
+
+    let (book, written, _dir) = build("");
+    assert_not_injected(&book, "generated/summary.md", "{{QR_CODE}}");
+    assert_eq!(written, foreign);
+
+    let (book, written, dir) = build("\"generated/**\"");
+    assert_injected(&book, "generated/summary.md", "{{QR_CODE}}");
+    let content = chapter_content(&book, "generated/summary.md").unwrap();
+    assert!(content.contains("src=\"../qr/qr_code.png"), "{content}");
+    assert_ne!(written, foreign);
+    assert_eq!(files_in(&dir.path().join("src/qr")), ["qr_code.png"]);
+}
+
+#[test]
+fn renderers_outside_the_allowlist_leave_the_book_untouched() {
+    for (renderer, config) in [
+        ("epub", "renderers = [\"html\", \"pdf\"]\n"),
+        ("test", "skip-non-html = true\n"),
+    ] {
+        let dir = TempDir::new("fixture-renderers");
+        let book = run(BookFixture::new()
+            .root(dir.path())
+            .renderer(renderer)
+            .chapter("intro.md", "{{QR_CODE}}\n")
+            .context_with_config(&format!("{URL}{config}")));
+        assert_eq!(chapter_content(&book, "intro.md"), Some("{{QR_CODE}}\n"));
+        assert!(!dir.path().join("src").exists(), "{renderer}");
+    }
+
+    let dir = TempDir::new("fixture-renderers-listed");
+    let book = run(BookFixture::new()
+        .root(dir.path())
+        .renderer("pdf")
+        .chapter("intro.md", "{{QR_CODE}}\n")
+        .context_with_config(&format!("{URL}renderers = [\"html\", \"pdf\"]\n")));
+    assert_injected(&book, "intro.md", "{{QR_CODE}}");
+}
+
+#[test]
+fn renderer_overrides_change_the_html_only_for_their_renderer() {
+    let build = |renderer: &str| {
+        let dir = TempDir::new("fixture-renderer-override");
+        let book = run(BookFixture::new()
+            .root(dir.path())
+            .renderer(renderer)
+            .chapter("intro.md", "{{QR_CODE}}\n\n{{QR_SLIDES}}\n")
+            .context_with_config(&format!(
+                "{URL}output = \"html\"\nsize-mode = \"attributes\"\nfit = {{ width = 200, height = 200 }}\n\n\
+                 [preprocessor.qr.custom.slides]\nmarker = \"{{{{QR_SLIDES}}}}\"\nqr-path = \"img/slides.png\"\n\n\
+                 [preprocessor.qr.renderer.epub]\nfit = {{ width = 320, height = 320 }}\n\
+                 caption = \"Scan for the online version\"\nqr-path = \"img/ignored.png\"\n"
+            )));
+        let content = chapter_content(&book, "intro.md").unwrap().to_string();
+        (content, dir)
+    };
+
+    let (html, _dir) = build("html");
+    assert_eq!(
+        html.matches("width=\"200\" height=\"200\"").count(),
+        2,
+        "{html}"
+    );
+    assert!(!html.contains("Scan for the online version"), "{html}");
+
+    let (epub, dir) = build("epub");
+    assert!(epub.starts_with('<'), "{epub}");
+    assert_eq!(
+        epub.matches("width=\"320\" height=\"320\"").count(),
+        2,
+        "{epub}"
+    );
+    assert_eq!(
+        epub.matches("Scan for the online version").count(),
+        2,
+        "{epub}"
+    );
+    // Each profile keeps its own marker and path
+    assert!(epub.contains("img/slides.png"), "{epub}");
+    assert!(dir.path().join("src/img/slides.png").is_file());
+    assert!(!dir.path().join("src/img/ignored.png").exists());
+}
@@ -0,0 +1,129 @@
+//! Stale-file cleanup across runs, through the manifest.
+
+mod common;
+
+use common::{files_in, TempDir};
+use mdbook::preprocess::Preprocessor;
+use mdbook_qr::test_support::{assert_injected, assert_not_injected, BookFixture};
+use mdbook_qr::QrPreprocessor;
+use std::fs;
+
+const URL: &str = r#"
+[preprocessor.qr]
+url = "https://example.com/book/"
+"#;
+
+fn run(dir: &TempDir, config: &str, chapter: &str) -> mdbook::book::Book {
+    let (ctx, book) = BookFixture::new()
+        .root(dir.path())
+        .chapter("intro.md", chapter)
+        .context_with_config(config)
+        .build();
+    QrPreprocessor::new().run(&ctx, book).unwrap()
+}
+
+fn custom(marker: &str) -> String {
+    format!("{URL}\n[preprocessor.qr.custom.page]\nmarker = \"{marker}\"\n")
+}
+
+#[test]
+fn rerun_rewrites_its_own_default_image() {
+    let dir = TempDir::new("rerun");
+    run(&dir, URL, "{{QR_CODE}}\n");
+    let image = dir.path().join("src/qr/qr_code.png");
+    assert!(image.is_file());
+
+    // The manifest lists the image, so the derived-default guard lets it through
+    let book = run(&dir, URL, "{{QR_CODE}}\n");
+    assert_injected(&book, "intro.md", "{{QR_CODE}}");
+    assert!(image.is_file());
+}
+
+#[test]
+fn foreign_default_image_is_left_alone() {
+    let dir = TempDir::new("foreign-default");
+    let image = dir.write("src/qr/qr_code.png", b"not ours");
+
+    let book = run(&dir, URL, "{{QR_CODE}}\n");
+    assert_not_injected(&book, "intro.md", "{{QR_CODE}}");
+    assert_eq!(fs::read(&image).unwrap(), b"not ours");
+}
+
+#[test]
+fn renamed_marker_removes_the_old_image() {
+    let dir = TempDir::new("rename");
+    let qr = dir.path().join("src/qr");
+    run(&dir, &custom("{{QR_OLD}}"), "{{QR_OLD}}\n");
+    assert_eq!(files_in(&qr), ["qr_old.png"]);
+
+    run(&dir, &custom("{{QR_NEW}}"), "{{QR_NEW}}\n");
+    assert_eq!(files_in(&qr), ["qr_new.png"]);
+}
+
+#[test]
+fn cleanup_false_keeps_the_old_image() {
+    let dir = TempDir::new("no-cleanup");
+    let qr = dir.path().join("src/qr");
+    run(&dir, &custom("{{QR_OLD}}"), "{{QR_OLD}}\n");
+
+    let config = custom("{{QR_NEW}}").replace(
+        "[preprocessor.qr]\n",
+        "[preprocessor.qr]\ncleanup = false\n",
+    );
+    run(&dir, &config, "{{QR_NEW}}\n");
+    assert_eq!(files_in(&qr), ["qr_new.png", "qr_old.png"]);
+}
+
+#[test]
+fn edited_images_are_not_removed() {
+    let dir = TempDir::new("edited");
+    let qr = dir.path().join("src/qr");
+    run(&dir, &custom("{{QR_OLD}}"), "{{QR_OLD}}\n");
+    fs::write(qr.join("qr_old.png"), b"edited by hand").unwrap();
+
+    run(&dir, &custom("{{QR_NEW}}"), "{{QR_NEW}}\n");
+    assert_eq!(files_in(&qr), ["qr_new.png", "qr_old.png"]);
+}
+
+#[test]
+fn corrupt_manifest_deletes_nothing_and_is_regenerated() {
+    let dir = TempDir::new("corrupt");
+    let qr = dir.path().join("src/qr");
+    run(&dir, &custom("{{QR_OLD}}"), "{{QR_OLD}}\n");
+    let manifest = fs::read_dir(&qr)
+        .unwrap()
+        .filter_map(|e| Some(e.ok()?.path()))
+        .find(|p| p.to_string_lossy().contains(".mdbook-qr-manifest-"))
+        .expect("a manifest was written");
+    fs::write(&manifest, "{ not json").unwrap();
+
+    run(&dir, &custom("{{QR_NEW}}"), "{{QR_NEW}}\n");
+    assert_eq!(files_in(&qr), ["qr_new.png", "qr_old.png"]);
+    let rewritten = fs::read_to_string(&manifest).unwrap();
+    assert!(rewritten.contains("qr_new.png"), "{rewritten}");
+    assert!(!rewritten.contains("qr_old.png"), "{rewritten}");
+}
+
+#[test]
+fn hashed_copies_are_removed_only_through_the_manifest() {
+    let dir = TempDir::new("hashed-copies");
+    let qr = dir.path().join("src/qr");
+    let config =
+        |url: &str| format!("[preprocessor.qr]\nurl = \"{url}\"\ncache-bust = \"filename\"\n");
+    // Named like a hashed copy, but not written by mdbook-qr
+    dir.write("src/qr/qr_code.0123456789ab.png", b"a user's file");
+
+    run(&dir, &config("https://example.com/a/"), "{{QR_CODE}}\n");
+    let first = files_in(&qr);
+    assert_eq!(first.len(), 2, "{first:?}");
+
+    run(&dir, &config("https://example.com/b/"), "{{QR_CODE}}\n");
+    let second = files_in(&qr);
+    assert_eq!(second.len(), 2, "{second:?}");
+    assert!(second.contains(&"qr_code.0123456789ab.png".to_string()));
+    let old = first
+        .iter()
+        .find(|n| !n.starts_with("qr_code.0123"))
+        .unwrap();
+    assert!(!second.contains(old), "{old} was kept: {second:?}");
+}
@@ -0,0 +1,120 @@
+//! The parallel render must produce what a single thread does, even when
+//! many profiles and chapters share output paths.
+
+mod common;
+
+use common::{files_in, TempDir};
+use mdbook::book::{Book, BookItem};
+use mdbook::preprocess::Preprocessor;
+use mdbook_qr::test_support::BookFixture;
+use mdbook_qr::QrPreprocessor;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+const PROFILES: usize = 12;
+const CHAPTERS: usize = 24;
+
+/// Profiles colliding on three shared paths, half with per-chapter URLs.
+fn config() -> String {
+    let mut toml = String::from(
+        "[preprocessor.qr]\nurl = \"https://example.com/book/\"\ncollision = \"warn\"\n",
+    );
+    for p in 0..PROFILES {
+        let url = if p % 2 == 0 {
+            "https://example.com/book/{path}"
+        } else {
+            "https://example.com/other/"
+        };
+        write!(
+            toml,
+            "\n[preprocessor.qr.custom.p{p}]\nmarker = \"{{{{QR_P{p}}}}}\"\n\
+             qr-path = \"qr/shared-{}.png\"\nurl = \"{url}\"\nmodule = \"#{:02x}0000\"\n",
+            p % 3,
+            p * 16,
+        )
+        .unwrap();
+    }
+    toml
+}
+
+fn fixture(root: &Path) -> BookFixture {
+    let mut fixture = BookFixture::new().root(root).context_with_config(&config());
+    for c in 0..CHAPTERS {
+        let mut content = format!("# Chapter {c}\n\n{{{{QR_CODE}}}}\n");
+        for p in (c % 4..PROFILES).step_by(2) {
+            writeln!(content, "\n{{{{QR_P{p}}}}}").unwrap();
+        }
+        fixture = if c % 3 == 0 {
+            fixture.chapter(&format!("c{c}.md"), &content)
+        } else {
+            fixture.nested(&format!("part{}/c{c}.md", c % 3), &content)
+        };
+    }
+    fixture
+}
+
+/// Chapter contents in book order, and every image written with its bytes.
+fn render(threads: usize) -> (Vec<String>, Vec<(String, Vec<u8>)>) {
+    let dir = TempDir::new("parallel");
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap();
+    // `PreprocessorContext` is not `Sync`; build it on the pool's thread
+    let book: Book = pool.install(|| {
+        let (ctx, book) = fixture(dir.path()).build();
+        QrPreprocessor::new()
+            .no_cache(true)
+            .run(&ctx, book)
+            .unwrap()
+    });
+    let chapters = book
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(ch) => Some(ch.content.clone()),
+            _ => None,
+        })
+        .collect();
+    let qr = dir.path().join("src/qr");
+    let images = files_in(&qr)
+        .into_iter()
+        .map(|name| {
+            let bytes = fs::read(qr.join(&name)).unwrap();
+            (name, bytes)
+        })
+        .collect();
+    (chapters, images)
+}
+
+#[test]
+fn parallel_render_matches_the_sequential_one() {
+    let (chapters, images) = render(1);
+    assert_eq!(chapters.len(), CHAPTERS + 2);
+    assert!(
+        chapters.iter().all(|c| !c.contains("{{QR_")),
+        "{chapters:#?}"
+    );
+    let names: Vec<&str> = images.iter().map(|(name, _)| name.as_str()).collect();
+    for shared in [
+        "shared-0.png",
+        "shared-1.png",
+        "shared-2.png",
+        "qr_code.png",
+    ] {
+        assert!(names.contains(&shared), "{names:?}");
+    }
+    assert!(
+        names.len() > CHAPTERS,
+        "per-chapter images missing: {names:?}"
+    );
+
+    for _ in 0..4 {
+        let (par_chapters, par_images) = render(8);
+        assert_eq!(par_chapters, chapters);
+        assert_eq!(par_images.iter().map(|(n, _)| n).collect::<Vec<_>>(), names);
+        for ((name, want), (_, got)) in images.iter().zip(&par_images) {
+            assert!(want == got, "{name} differs from the sequential render");
+        }
+    }
+}